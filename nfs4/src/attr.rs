@@ -0,0 +1,530 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use nix::unistd::Uid;
+use std::path::Path;
+
+use crate::auth::{Credentials, MAY_WRITE};
+use crate::backend::{FileAttr, FileSystemBackend, FsStats};
+use crate::export::{ExportEntry, PseudoEntry, PseudoNode};
+use crate::idmap::IdMap;
+use crate::protocol::*;
+use crate::server::{io_error_status, LEASE_TIME, MAX_IO_SIZE};
+use crate::xdr::*;
+
+// Attributes GETATTR and READDIR can return, in ascending bit order.
+const READABLE_ATTRS: &[u32] = &[
+    FATTR4_SUPPORTED_ATTRS,
+    FATTR4_TYPE,
+    FATTR4_FH_EXPIRE_TYPE,
+    FATTR4_CHANGE,
+    FATTR4_SIZE,
+    FATTR4_LINK_SUPPORT,
+    FATTR4_SYMLINK_SUPPORT,
+    FATTR4_NAMED_ATTR,
+    FATTR4_FSID,
+    FATTR4_UNIQUE_HANDLES,
+    FATTR4_LEASE_TIME,
+    FATTR4_RDATTR_ERROR,
+    FATTR4_ACLSUPPORT,
+    FATTR4_CANSETTIME,
+    FATTR4_CASE_INSENSITIVE,
+    FATTR4_CASE_PRESERVING,
+    FATTR4_CHOWN_RESTRICTED,
+    FATTR4_FILEHANDLE,
+    FATTR4_FILEID,
+    FATTR4_FILES_AVAIL,
+    FATTR4_FILES_FREE,
+    FATTR4_FILES_TOTAL,
+    FATTR4_HOMOGENEOUS,
+    FATTR4_MAXFILESIZE,
+    FATTR4_MAXLINK,
+    FATTR4_MAXNAME,
+    FATTR4_MAXREAD,
+    FATTR4_MAXWRITE,
+    FATTR4_MODE,
+    FATTR4_NO_TRUNC,
+    FATTR4_NUMLINKS,
+    FATTR4_OWNER,
+    FATTR4_OWNER_GROUP,
+    FATTR4_RAWDEV,
+    FATTR4_SPACE_AVAIL,
+    FATTR4_SPACE_FREE,
+    FATTR4_SPACE_TOTAL,
+    FATTR4_SPACE_USED,
+    FATTR4_TIME_ACCESS,
+    FATTR4_TIME_DELTA,
+    FATTR4_TIME_METADATA,
+    FATTR4_TIME_MODIFY,
+    FATTR4_MOUNTED_ON_FILEID,
+];
+
+// Attributes of pseudo-filesystem directories, which have nothing behind
+// them but the export table.
+const PSEUDO_ATTRS: &[u32] = &[
+    FATTR4_SUPPORTED_ATTRS,
+    FATTR4_TYPE,
+    FATTR4_FH_EXPIRE_TYPE,
+    FATTR4_CHANGE,
+    FATTR4_SIZE,
+    FATTR4_LINK_SUPPORT,
+    FATTR4_SYMLINK_SUPPORT,
+    FATTR4_NAMED_ATTR,
+    FATTR4_FSID,
+    FATTR4_UNIQUE_HANDLES,
+    FATTR4_LEASE_TIME,
+    FATTR4_RDATTR_ERROR,
+    FATTR4_FILEHANDLE,
+    FATTR4_FILEID,
+    FATTR4_MAXNAME,
+    FATTR4_MODE,
+    FATTR4_NUMLINKS,
+    FATTR4_OWNER,
+    FATTR4_OWNER_GROUP,
+    FATTR4_SPACE_USED,
+    FATTR4_TIME_ACCESS,
+    FATTR4_TIME_METADATA,
+    FATTR4_TIME_MODIFY,
+    FATTR4_MOUNTED_ON_FILEID,
+];
+
+// Attributes that can only be set, never read back.
+pub const WRITE_ONLY_ATTRS: &[u32] = &[FATTR4_TIME_ACCESS_SET, FATTR4_TIME_MODIFY_SET];
+
+// Attributes whose values come from the filesystem rather than the file
+// itself.
+const FS_STAT_ATTRS: &[u32] = &[
+    FATTR4_FILES_AVAIL,
+    FATTR4_FILES_FREE,
+    FATTR4_FILES_TOTAL,
+    FATTR4_MAXLINK,
+    FATTR4_MAXNAME,
+    FATTR4_SPACE_AVAIL,
+    FATTR4_SPACE_FREE,
+    FATTR4_SPACE_TOTAL,
+];
+
+// Settable attributes we accept but cannot store on a local filesystem.
+const UNSUPPORTED_SETTABLE_ATTRS: &[u32] = &[
+    FATTR4_ACL,
+    FATTR4_ARCHIVE,
+    FATTR4_HIDDEN,
+    FATTR4_MIMETYPE,
+    FATTR4_SYSTEM,
+    FATTR4_TIME_BACKUP,
+    FATTR4_TIME_CREATE,
+];
+
+// Highest attribute number defined by RFC 7530.
+const FATTR4_MAX: u32 = 55;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetTime {
+    ServerTime,
+    ClientTime(NfsTime),
+}
+
+// Attribute values decoded from a SETATTR or createattrs fattr4, with
+// owner strings already mapped to ids.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NfsSetAttributes {
+    pub size: Option<u64>,
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub owner_group: Option<u32>,
+    pub time_access: Option<SetTime>,
+    pub time_modify: Option<SetTime>,
+}
+
+// Whether encoding `requested` takes the statistics of the filesystem.
+pub fn wants_fs_stats(requested: &[u32]) -> bool {
+    FS_STAT_ATTRS.iter().any(|&bit| bitmap_isset(requested, bit))
+}
+
+// The supported_attrs bitmap: everything readable plus the write-only
+// time setters.
+pub fn supported_attrs() -> Vec<u32> {
+    let mut bitmap = Vec::new();
+    for &bit in READABLE_ATTRS.iter().chain(WRITE_ONLY_ATTRS) {
+        bitmap_set(&mut bitmap, bit);
+    }
+    bitmap
+}
+
+fn put_time(vals: &mut BytesMut, seconds: i64, nseconds: i64) {
+    NfsTime {
+        seconds: seconds as u64,
+        nseconds: nseconds as u32,
+    }
+    .encode(vals);
+}
+
+// Encode the requested attributes of a file. Attributes that are not
+// supported are left out of the returned mask, as RFC 7530 requires, and
+// so is the filehandle when the caller has none to give. `fs_stats` must
+// be given when `wants_fs_stats` says so.
+pub fn encode_attributes(
+    export: &ExportEntry,
+    attr: &FileAttr,
+    fs_stats: Option<&FsStats>,
+    filehandle: Option<&NfsFileHandle>,
+    requested: &[u32],
+    idmap: &IdMap,
+) -> Fattr4 {
+    let mut attrmask = Vec::new();
+    let mut vals = BytesMut::new();
+
+    for &bit in READABLE_ATTRS {
+        if !bitmap_isset(requested, bit) {
+            continue;
+        }
+        if bit == FATTR4_FILEHANDLE && filehandle.is_none() {
+            continue;
+        }
+        bitmap_set(&mut attrmask, bit);
+
+        match bit {
+            FATTR4_SUPPORTED_ATTRS => put_u32_array(&mut vals, &supported_attrs()),
+            FATTR4_TYPE => vals.put_u32(attr.ftype),
+            FATTR4_FH_EXPIRE_TYPE => vals.put_u32(FH4_PERSISTENT),
+            FATTR4_CHANGE => vals.put_u64(attr.change()),
+            FATTR4_SIZE => vals.put_u64(attr.size),
+            FATTR4_LINK_SUPPORT => put_bool(&mut vals, true),
+            FATTR4_SYMLINK_SUPPORT => put_bool(&mut vals, true),
+            FATTR4_NAMED_ATTR => put_bool(&mut vals, false),
+            FATTR4_FSID => {
+                let (major, minor) = export.fsid();
+                vals.put_u64(major);
+                vals.put_u64(minor);
+            }
+            FATTR4_UNIQUE_HANDLES => put_bool(&mut vals, true),
+            FATTR4_LEASE_TIME => vals.put_u32(LEASE_TIME),
+            FATTR4_RDATTR_ERROR => vals.put_u32(NfsStatus::Ok as u32),
+            FATTR4_ACLSUPPORT => vals.put_u32(0),
+            FATTR4_CANSETTIME => put_bool(&mut vals, true),
+            FATTR4_CASE_INSENSITIVE => put_bool(&mut vals, false),
+            FATTR4_CASE_PRESERVING => put_bool(&mut vals, true),
+            FATTR4_CHOWN_RESTRICTED => put_bool(&mut vals, true),
+            FATTR4_FILEHANDLE => put_opaque(&mut vals, &filehandle.unwrap().data),
+            FATTR4_FILEID => vals.put_u64(attr.ino),
+            FATTR4_FILES_AVAIL => vals.put_u64(fs_stats.unwrap().files_avail),
+            FATTR4_FILES_FREE => vals.put_u64(fs_stats.unwrap().files_free),
+            FATTR4_FILES_TOTAL => vals.put_u64(fs_stats.unwrap().files_total),
+            FATTR4_HOMOGENEOUS => put_bool(&mut vals, true),
+            FATTR4_MAXFILESIZE => vals.put_u64(i64::MAX as u64),
+            FATTR4_MAXLINK => vals.put_u32(fs_stats.unwrap().link_max.unwrap_or(u32::MAX)),
+            FATTR4_MAXNAME => vals.put_u32(fs_stats.unwrap().name_max),
+            FATTR4_MAXREAD => vals.put_u64(MAX_IO_SIZE as u64),
+            FATTR4_MAXWRITE => vals.put_u64(MAX_IO_SIZE as u64),
+            FATTR4_MODE => vals.put_u32(attr.mode),
+            FATTR4_NO_TRUNC => put_bool(&mut vals, true),
+            FATTR4_NUMLINKS => vals.put_u32(attr.nlink as u32),
+            FATTR4_OWNER => put_string(&mut vals, &idmap.user_name(attr.uid)),
+            FATTR4_OWNER_GROUP => put_string(&mut vals, &idmap.group_name(attr.gid)),
+            FATTR4_RAWDEV => attr.rdev.encode(&mut vals),
+            FATTR4_SPACE_AVAIL => vals.put_u64(fs_stats.unwrap().bytes_avail),
+            FATTR4_SPACE_FREE => vals.put_u64(fs_stats.unwrap().bytes_free),
+            FATTR4_SPACE_TOTAL => vals.put_u64(fs_stats.unwrap().bytes_total),
+            FATTR4_SPACE_USED => vals.put_u64(attr.used),
+            FATTR4_TIME_ACCESS => attr.atime.encode(&mut vals),
+            FATTR4_TIME_DELTA => put_time(&mut vals, 0, 1),
+            FATTR4_TIME_METADATA => attr.ctime.encode(&mut vals),
+            FATTR4_TIME_MODIFY => attr.mtime.encode(&mut vals),
+            // Every export is its own filesystem, so nothing below the
+            // root is ever a mount point.
+            FATTR4_MOUNTED_ON_FILEID => vals.put_u64(attr.ino),
+            _ => unreachable!("attribute {} listed as readable but not encoded", bit),
+        }
+    }
+
+    Fattr4 {
+        attrmask,
+        attr_vals: vals.to_vec(),
+    }
+}
+
+// Encode the requested attributes of a pseudo-filesystem directory. It
+// belongs to root, can be read and searched by anyone, and has not changed
+// since the server started at `boot`.
+pub fn encode_pseudo_attributes(
+    index: usize,
+    node: &PseudoNode,
+    filehandle: &NfsFileHandle,
+    requested: &[u32],
+    boot: &NfsTime,
+    idmap: &IdMap,
+) -> Fattr4 {
+    let mut attrmask = Vec::new();
+    let mut vals = BytesMut::new();
+    let fileid = pseudo_fileid(index);
+
+    for &bit in PSEUDO_ATTRS {
+        if !bitmap_isset(requested, bit) {
+            continue;
+        }
+        bitmap_set(&mut attrmask, bit);
+
+        match bit {
+            FATTR4_SUPPORTED_ATTRS => {
+                let mut supported = Vec::new();
+                for &bit in PSEUDO_ATTRS {
+                    bitmap_set(&mut supported, bit);
+                }
+                put_u32_array(&mut vals, &supported);
+            }
+            FATTR4_TYPE => vals.put_u32(NF4DIR),
+            FATTR4_FH_EXPIRE_TYPE => vals.put_u32(FH4_PERSISTENT),
+            FATTR4_CHANGE => vals.put_u64(boot.seconds * 1_000_000_000 + boot.nseconds as u64),
+            FATTR4_SIZE | FATTR4_SPACE_USED => vals.put_u64(0),
+            FATTR4_LINK_SUPPORT | FATTR4_SYMLINK_SUPPORT | FATTR4_NAMED_ATTR => put_bool(&mut vals, false),
+            // The pseudo-filesystem is filesystem 0, and exports are
+            // numbered from 1.
+            FATTR4_FSID => {
+                vals.put_u64(0);
+                vals.put_u64(0);
+            }
+            FATTR4_UNIQUE_HANDLES => put_bool(&mut vals, true),
+            FATTR4_LEASE_TIME => vals.put_u32(LEASE_TIME),
+            FATTR4_RDATTR_ERROR => vals.put_u32(NfsStatus::Ok as u32),
+            FATTR4_FILEHANDLE => put_opaque(&mut vals, &filehandle.data),
+            FATTR4_FILEID | FATTR4_MOUNTED_ON_FILEID => vals.put_u64(fileid),
+            FATTR4_MAXNAME => vals.put_u32(255),
+            FATTR4_MODE => vals.put_u32(0o555),
+            FATTR4_NUMLINKS => {
+                let subdirs = node.entries.values().filter(|e| matches!(e, PseudoEntry::Dir(_))).count();
+                vals.put_u32(2 + subdirs as u32);
+            }
+            FATTR4_OWNER => put_string(&mut vals, &idmap.user_name(0)),
+            FATTR4_OWNER_GROUP => put_string(&mut vals, &idmap.group_name(0)),
+            FATTR4_TIME_ACCESS | FATTR4_TIME_METADATA | FATTR4_TIME_MODIFY => boot.encode(&mut vals),
+            _ => unreachable!("attribute {} listed for the pseudo-filesystem but not encoded", bit),
+        }
+    }
+
+    Fattr4 {
+        attrmask,
+        attr_vals: vals.to_vec(),
+    }
+}
+
+// Pseudo-filesystem fileids start at 1, as some clients take 0 to mean
+// there is no file.
+pub fn pseudo_fileid(index: usize) -> u64 {
+    index as u64 + 1
+}
+
+// The fattr4 returned in place of a READDIR entry's attributes when they
+// could not be read.
+pub fn rdattr_error(status: NfsStatus) -> Fattr4 {
+    let mut attrmask = Vec::new();
+    bitmap_set(&mut attrmask, FATTR4_RDATTR_ERROR);
+    Fattr4 {
+        attrmask,
+        attr_vals: (status as u32).to_be_bytes().to_vec(),
+    }
+}
+
+// Size of a fattr4 once XDR encoded.
+pub fn fattr4_encoded_len(attrs: &Fattr4) -> usize {
+    4 + attrs.attrmask.len() * 4 + 4 + attrs.attr_vals.len().div_ceil(4) * 4
+}
+
+fn get_settime(buf: &mut Bytes) -> XdrResult<SetTime> {
+    match get_u32(buf)? {
+        SET_TO_SERVER_TIME4 => Ok(SetTime::ServerTime),
+        SET_TO_CLIENT_TIME4 => Ok(SetTime::ClientTime(NfsTime::decode(buf)?)),
+        value => Err(XdrError::InvalidDiscriminant { what: "time_how4", value }),
+    }
+}
+
+// Decode the attributes a client asked to set. Read-only attributes are
+// rejected with NFS4ERR_INVAL, settable ones we cannot store with
+// NFS4ERR_ATTRNOTSUPP and owners with no id with NFS4ERR_BADOWNER.
+pub fn decode_settable(attrs: &Fattr4, idmap: &IdMap) -> std::result::Result<NfsSetAttributes, NfsStatus> {
+    let mut buf = Bytes::copy_from_slice(&attrs.attr_vals);
+    let mut out = NfsSetAttributes::default();
+
+    for bit in 0..(attrs.attrmask.len() * 32) as u32 {
+        if !bitmap_isset(&attrs.attrmask, bit) {
+            continue;
+        }
+
+        let decoded = match bit {
+            FATTR4_SIZE => get_u64(&mut buf).map(|v| out.size = Some(v)),
+            FATTR4_MODE => get_u32(&mut buf).map(|v| out.mode = Some(v)),
+            FATTR4_OWNER => {
+                let owner = get_string(&mut buf).map_err(|_| NfsStatus::BadXdr)?;
+                out.owner = Some(idmap.uid(&owner).ok_or(NfsStatus::BadOwner)?);
+                Ok(())
+            }
+            FATTR4_OWNER_GROUP => {
+                let group = get_string(&mut buf).map_err(|_| NfsStatus::BadXdr)?;
+                out.owner_group = Some(idmap.gid(&group).ok_or(NfsStatus::BadOwner)?);
+                Ok(())
+            }
+            FATTR4_TIME_ACCESS_SET => get_settime(&mut buf).map(|v| out.time_access = Some(v)),
+            FATTR4_TIME_MODIFY_SET => get_settime(&mut buf).map(|v| out.time_modify = Some(v)),
+            _ if bit > FATTR4_MAX || UNSUPPORTED_SETTABLE_ATTRS.contains(&bit) => {
+                return Err(NfsStatus::AttrNotSupp);
+            }
+            _ => return Err(NfsStatus::Inval),
+        };
+        decoded.map_err(|_| NfsStatus::BadXdr)?;
+    }
+
+    if buf.has_remaining() {
+        return Err(NfsStatus::BadXdr);
+    }
+    if out.mode.is_some_and(|mode| mode & !0o7777 != 0) {
+        return Err(NfsStatus::Inval);
+    }
+    Ok(out)
+}
+
+// Check that a caller may make the changes a SETATTR asks for. Only root
+// gives files away, and only the owner may change the mode, the group (to
+// one of its own groups) or set the times to a value of its choosing.
+// Anyone with write permission may set the times to the server's clock,
+// as for touch. Size changes are left to the caller, since an open of the
+// file for writing is enough for those.
+//
+// As with chmod, a caller outside the file's group cannot set the setgid
+// bit; it is dropped from the mode rather than refusing the change.
+pub fn check_setattr(cred: &Credentials, attr: &FileAttr, attrs: &mut NfsSetAttributes) -> Result<(), NfsStatus> {
+    if cred.is_root() {
+        return Ok(());
+    }
+    let owner = cred.uid == attr.uid;
+
+    if attrs.owner.is_some_and(|uid| uid != attr.uid) {
+        return Err(NfsStatus::Perm);
+    }
+    if let Some(gid) = attrs.owner_group {
+        if gid != attr.gid && !(owner && cred.in_group(gid)) {
+            return Err(NfsStatus::Perm);
+        }
+    }
+    if attrs.mode.is_some() && !owner {
+        return Err(NfsStatus::Perm);
+    }
+    if !cred.in_group(attrs.owner_group.unwrap_or(attr.gid)) {
+        if let Some(mode) = attrs.mode.as_mut() {
+            *mode &= !0o2000;
+        }
+    }
+
+    let times = [&attrs.time_access, &attrs.time_modify];
+    if !owner && times.iter().any(|t| matches!(t, Some(SetTime::ClientTime(_)))) {
+        return Err(NfsStatus::Perm);
+    }
+    if !owner && times.iter().any(|t| t.is_some()) && !cred.may(attr, MAY_WRITE) {
+        return Err(NfsStatus::Access);
+    }
+    Ok(())
+}
+
+// Hand a newly created object to the caller that created it. The group is
+// the caller's unless the parent directory has the setgid bit, in which
+// case the object keeps the directory's group as it would locally. A
+// server not running as root can only create objects as itself.
+pub async fn set_creator<B: FileSystemBackend>(backend: &B, path: &Path, cred: &Credentials) -> NfsStatus {
+    if !Uid::effective().is_root() {
+        return NfsStatus::Ok;
+    }
+    let setgid_parent = match path.parent() {
+        Some(parent) => backend.getattr(parent).await.is_ok_and(|attr| attr.mode & 0o2000 != 0),
+        None => false,
+    };
+    let owner = NfsSetAttributes {
+        owner: Some(cred.uid),
+        owner_group: if setgid_parent { None } else { Some(cred.gid) },
+        ..Default::default()
+    };
+    match backend.setattr(path, &owner).await {
+        Ok(()) => NfsStatus::Ok,
+        Err(e) => io_error_status(&e),
+    }
+}
+
+// Apply decoded attributes to a file. Returns the resulting status along
+// with the bitmap of attributes that were set before any failure, which
+// SETATTR reports back even when it fails.
+pub async fn apply_attributes<B: FileSystemBackend>(
+    backend: &B,
+    path: &Path,
+    attrs: &NfsSetAttributes,
+) -> (NfsStatus, Vec<u32>) {
+    let mut attrsset = Vec::new();
+
+    let attr = match backend.getattr(path).await {
+        Ok(attr) => attr,
+        Err(e) => return (io_error_status(&e), attrsset),
+    };
+
+    // Ownership goes first since chown clears the setuid and setgid bits.
+    if attrs.owner.is_some() || attrs.owner_group.is_some() {
+        let owner = NfsSetAttributes {
+            owner: attrs.owner,
+            owner_group: attrs.owner_group,
+            ..Default::default()
+        };
+        if let Err(e) = backend.setattr(path, &owner).await {
+            return (io_error_status(&e), attrsset);
+        }
+        if attrs.owner.is_some() {
+            bitmap_set(&mut attrsset, FATTR4_OWNER);
+        }
+        if attrs.owner_group.is_some() {
+            bitmap_set(&mut attrsset, FATTR4_OWNER_GROUP);
+        }
+    }
+
+    if let Some(mode) = attrs.mode {
+        if attr.is_symlink() {
+            return (NfsStatus::Inval, attrsset);
+        }
+        let mode = NfsSetAttributes {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        if let Err(e) = backend.setattr(path, &mode).await {
+            return (io_error_status(&e), attrsset);
+        }
+        bitmap_set(&mut attrsset, FATTR4_MODE);
+    }
+
+    if let Some(size) = attrs.size {
+        if attr.is_dir() {
+            return (NfsStatus::IsDir, attrsset);
+        }
+        if !attr.is_file() {
+            return (NfsStatus::Inval, attrsset);
+        }
+        let size = NfsSetAttributes {
+            size: Some(size),
+            ..Default::default()
+        };
+        if let Err(e) = backend.setattr(path, &size).await {
+            return (io_error_status(&e), attrsset);
+        }
+        bitmap_set(&mut attrsset, FATTR4_SIZE);
+    }
+
+    // Times are set last so a size change does not overwrite the mtime.
+    if attrs.time_access.is_some() || attrs.time_modify.is_some() {
+        let times = NfsSetAttributes {
+            time_access: attrs.time_access.clone(),
+            time_modify: attrs.time_modify.clone(),
+            ..Default::default()
+        };
+        if let Err(e) = backend.setattr(path, &times).await {
+            return (io_error_status(&e), attrsset);
+        }
+        if attrs.time_access.is_some() {
+            bitmap_set(&mut attrsset, FATTR4_TIME_ACCESS_SET);
+        }
+        if attrs.time_modify.is_some() {
+            bitmap_set(&mut attrsset, FATTR4_TIME_MODIFY_SET);
+        }
+    }
+
+    (NfsStatus::Ok, attrsset)
+}
@@ -0,0 +1,103 @@
+use crate::backend::FileAttr;
+use crate::rpc::{AuthSys, OpaqueAuth, AUTH_NONE, AUTH_SYS};
+
+// Caller identity.
+//
+// Every call carries a credential naming the user on the client it is made
+// for. With AUTH_SYS (RFC 5531 appendix A) that is a uid, a gid and up to
+// sixteen supplementary groups, which the server takes at face value.
+// Permission checks are made against this identity rather than that of the
+// server process, which normally runs as root.
+
+// The uid and gid AUTH_NONE callers are treated as.
+pub const NOBODY: u32 = 65534;
+
+// Permission bits, as they appear in each class of a file mode.
+pub const MAY_READ: u32 = 4;
+pub const MAY_WRITE: u32 = 2;
+pub const MAY_EXEC: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub gids: Vec<u32>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::anonymous()
+    }
+}
+
+impl Credentials {
+    pub fn anonymous() -> Self {
+        Self {
+            uid: NOBODY,
+            gid: NOBODY,
+            gids: Vec::new(),
+        }
+    }
+
+    // The identity behind an RPC credential, or None if the flavor is not
+    // one the server accepts or the credential does not decode.
+    pub fn from_auth(cred: &OpaqueAuth) -> Option<Self> {
+        match cred.flavor {
+            AUTH_NONE => Some(Self::anonymous()),
+            AUTH_SYS => {
+                let sys = AuthSys::decode(&cred.body).ok()?;
+                Some(Self {
+                    uid: sys.uid,
+                    gid: sys.gid,
+                    gids: sys.gids,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.gids.contains(&gid)
+    }
+
+    pub fn owns(&self, attr: &FileAttr) -> bool {
+        self.is_root() || self.uid == attr.uid
+    }
+
+    // The MAY_* bits the mode of a file grants this caller. Root may read
+    // and write anything, and execute anything somebody may execute.
+    pub fn permissions(&self, attr: &FileAttr) -> u32 {
+        let mode = attr.mode;
+        if self.is_root() {
+            let exec = if attr.is_dir() || mode & 0o111 != 0 { MAY_EXEC } else { 0 };
+            return MAY_READ | MAY_WRITE | exec;
+        }
+
+        let class = if self.uid == attr.uid {
+            mode >> 6
+        } else if self.in_group(attr.gid) {
+            mode >> 3
+        } else {
+            mode
+        };
+        class & 0o7
+    }
+
+    pub fn may(&self, attr: &FileAttr, want: u32) -> bool {
+        self.permissions(attr) & want == want
+    }
+
+    // Whether the caller may remove or rename an entry of a directory,
+    // which with the sticky bit set takes owning the entry or the
+    // directory.
+    pub fn may_unlink(&self, dir: &FileAttr, entry: &FileAttr) -> bool {
+        if !self.may(dir, MAY_WRITE | MAY_EXEC) {
+            return false;
+        }
+        dir.mode & 0o1000 == 0 || self.owns(dir) || self.owns(entry)
+    }
+}
@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::attr::NfsSetAttributes;
+use crate::protocol::{NfsTime, SpecData, NF4DIR, NF4LNK, NF4REG};
+
+// Storage behind the exports.
+//
+// The server names everything by export path: the directory an export was
+// given, with names a client sent joined onto it. A backend stores what
+// those paths lead to, whether that is a local directory tree (LocalFs),
+// memory or another server. It is never handed a path outside an export,
+// and never asked to follow a symlink; the server checks names, access
+// and state before calling it.
+//
+// Errors are io::Errors with the errno a local filesystem would give, such
+// as ENOENT or ENOTEMPTY, which the server turns into NFSv4 status codes.
+
+// Attributes of a file, as GETATTR reports them and permission checks see
+// them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileAttr {
+    // NF4REG, NF4DIR and so on.
+    pub ftype: u32,
+    // Permission bits, with the setuid, setgid and sticky bits.
+    pub mode: u32,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    // Bytes of storage the file takes up.
+    pub used: u64,
+    pub rdev: SpecData,
+    // The filesystem and file number, which together identify the file in
+    // filehandles. A file keeps them for as long as it exists.
+    pub dev: u64,
+    pub ino: u64,
+    pub atime: NfsTime,
+    pub mtime: NfsTime,
+    // Must move on every change to the data or attributes of the file,
+    // since the change attribute is made from it.
+    pub ctime: NfsTime,
+}
+
+impl FileAttr {
+    pub fn is_dir(&self) -> bool {
+        self.ftype == NF4DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.ftype == NF4REG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.ftype == NF4LNK
+    }
+
+    // The change attribute, which clients compare to tell whether their
+    // cached copy is still good.
+    pub fn change(&self) -> u64 {
+        self.ctime.seconds.wrapping_mul(1_000_000_000).wrapping_add(self.ctime.nseconds as u64)
+    }
+}
+
+// Statistics of the filesystem holding a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FsStats {
+    pub files_total: u64,
+    pub files_free: u64,
+    // Free to an unprivileged user.
+    pub files_avail: u64,
+    pub bytes_total: u64,
+    pub bytes_free: u64,
+    pub bytes_avail: u64,
+    pub name_max: u32,
+    // None when there is no limit.
+    pub link_max: Option<u32>,
+}
+
+#[async_trait]
+pub trait FileSystemBackend: Send + Sync + 'static {
+    // An open file. The opens and delegations reading and writing through
+    // it hold clones of it.
+    type File: Clone + fmt::Debug + Send + Sync + 'static;
+
+    // The entry `name` of directory `dir`.
+    async fn lookup(&self, dir: &Path, name: &str) -> io::Result<FileAttr> {
+        self.getattr(&dir.join(name)).await
+    }
+
+    // Attributes of the object at `path`, which is not followed if it is a
+    // symlink.
+    async fn getattr(&self, path: &Path) -> io::Result<FileAttr>;
+
+    // Make the changes `attrs` asks for: the owner, then the mode, the
+    // size and the times. The server asks for one at a time, so it can
+    // tell which were made when one fails.
+    async fn setattr(&self, path: &Path, attrs: &NfsSetAttributes) -> io::Result<()>;
+
+    async fn open(&self, path: &Path, read: bool, write: bool) -> io::Result<Self::File>;
+
+    // Up to `count` bytes at `offset`, and whether they reach the end of
+    // the file.
+    async fn read(&self, file: &Self::File, offset: u64, count: u32) -> io::Result<(Vec<u8>, bool)>;
+
+    async fn write(&self, file: &Self::File, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    // Get what has been written to `file` to stable storage.
+    async fn sync(&self, file: &Self::File) -> io::Result<()>;
+
+    // Create an object of type `ftype` that must not exist yet, failing
+    // with EEXIST if it does. Symlinks are made with `symlink`, and
+    // `rdev` is only used for devices. The object is owned by the server
+    // until the server gives it to its creator with `setattr`.
+    async fn create(&self, path: &Path, ftype: u32, rdev: SpecData) -> io::Result<()>;
+
+    async fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()>;
+
+    // Move `from` to `to`, replacing what is there.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    // Names in a directory, without "." and "..", in any order.
+    async fn readdir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    // A new name for `from`, which is not followed if it is a symlink.
+    async fn link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    async fn symlink(&self, target: &str, path: &Path) -> io::Result<()>;
+
+    async fn readlink(&self, path: &Path) -> io::Result<OsString>;
+
+    async fn statfs(&self, path: &Path) -> io::Result<FsStats>;
+
+    // Generation number of the file at `path`, which tells it apart from a
+    // later file given the same number once it is gone. 0 when there is
+    // none, which filehandle checks then do without.
+    async fn generation(&self, _path: &Path, _attr: &FileAttr) -> u32 {
+        0
+    }
+}
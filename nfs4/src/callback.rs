@@ -0,0 +1,115 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::Rng;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::protocol::*;
+use crate::rpc::{LAST_FRAGMENT, MSG_ACCEPTED, RPC_CALL, RPC_REPLY, RPC_VERSION, SUCCESS};
+use crate::xdr::*;
+
+// Calls to a client's callback program (RFC 7530 sections 10.2 and 17).
+//
+// NFSv4.0 clients give the address of their callback service in
+// SETCLIENTID, and the server connects to it as an RPC client whenever it
+// has something to say. Callbacks are rare, so each call opens its own
+// connection rather than keeping one around per client.
+
+// How long a client gets to answer a callback before it is taken to be
+// unreachable.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Callback replies are a handful of status words; anything much larger is
+// not a reply to us.
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+// Turn a universal address (RFC 5665 section 5.2.3) into a socket address.
+// The port follows the IP address as two more dotted decimal numbers, high
+// byte first.
+pub fn parse_uaddr(addr: &NetAddr) -> Option<SocketAddr> {
+    if addr.netid != "tcp" && addr.netid != "tcp6" {
+        return None;
+    }
+    let (rest, low) = addr.addr.rsplit_once('.')?;
+    let (host, high) = rest.rsplit_once('.')?;
+    let port = u16::from_be_bytes([high.parse().ok()?, low.parse().ok()?]);
+    let ip: IpAddr = host.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+// CB_NULL, used to check that the callback path works.
+pub async fn cb_null(callback: &CbClient) -> Result<()> {
+    call(callback, CB_NULL, Vec::new()).await.map(|_| ())
+}
+
+pub async fn cb_compound(callback: &CbClient, request: &CbCompoundRequest) -> Result<CbCompoundResponse> {
+    let reply = call(callback, CB_COMPOUND, request.encode()).await?;
+    Ok(CbCompoundResponse::decode(&reply)?)
+}
+
+async fn call(callback: &CbClient, procedure: u32, args: Vec<u8>) -> Result<Vec<u8>> {
+    let addr = parse_uaddr(&callback.location)
+        .ok_or_else(|| anyhow!("unusable callback address {:?}", callback.location))?;
+    tokio::time::timeout(CALLBACK_TIMEOUT, exchange(addr, callback.program, procedure, args))
+        .await
+        .map_err(|_| anyhow!("callback to {} timed out", addr))?
+}
+
+// Send one call and return the results from its reply.
+async fn exchange(addr: SocketAddr, program: u32, procedure: u32, args: Vec<u8>) -> Result<Vec<u8>> {
+    let xid: u32 = rand::thread_rng().gen();
+
+    let mut buf = BytesMut::new();
+    buf.put_u32(0); // record mark, filled in below
+    buf.put_u32(xid);
+    buf.put_u32(RPC_CALL);
+    buf.put_u32(RPC_VERSION);
+    buf.put_u32(program);
+    buf.put_u32(NFS4_CALLBACK_VERSION);
+    buf.put_u32(procedure);
+    // AUTH_NONE credential and verifier
+    buf.put_u32(AUTH_NONE);
+    put_opaque(&mut buf, &[]);
+    buf.put_u32(AUTH_NONE);
+    put_opaque(&mut buf, &[]);
+    buf.put_slice(&args);
+    let mark = (buf.len() - 4) as u32 | LAST_FRAGMENT;
+    buf[..4].copy_from_slice(&mark.to_be_bytes());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&buf).await?;
+
+    let mut reply = Bytes::from(read_record(&mut stream).await?);
+    if get_u32(&mut reply)? != xid || get_u32(&mut reply)? != RPC_REPLY {
+        bail!("unexpected message from callback service at {}", addr);
+    }
+    if get_u32(&mut reply)? != MSG_ACCEPTED {
+        bail!("callback service at {} rejected the call", addr);
+    }
+    let _verifier_flavor = get_u32(&mut reply)?;
+    get_opaque(&mut reply)?;
+    match get_u32(&mut reply)? {
+        SUCCESS => Ok(reply.to_vec()),
+        stat => bail!("callback service at {} failed the call with accept status {}", addr, stat),
+    }
+}
+
+// Read one record, joining its fragments.
+async fn read_record(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut record = Vec::new();
+    loop {
+        let mark = stream.read_u32().await?;
+        let start = record.len();
+        let len = (mark & !LAST_FRAGMENT) as usize;
+        if start + len > MAX_REPLY_SIZE {
+            bail!("callback reply of more than {} bytes", MAX_REPLY_SIZE);
+        }
+        record.resize(start + len, 0);
+        stream.read_exact(&mut record[start..]).await?;
+        if mark & LAST_FRAGMENT != 0 {
+            return Ok(record);
+        }
+    }
+}
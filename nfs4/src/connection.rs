@@ -0,0 +1,124 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use log::warn;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::backend::FileSystemBackend;
+use crate::dispatch::dispatch;
+use crate::rpc::{write_rpc_message, RecordReader, RpcMsg, DEFAULT_MAX_RECORD_SIZE};
+use crate::server::NfsServer;
+
+// Serving the calls of one client connection.
+//
+// The connection is split in two. The reading side takes records off the
+// stream and starts a task for each call, up to a limit, past which it
+// stops reading until one of them finishes. The writing side sends replies
+// as the calls complete, in whatever order that is: clients match replies
+// to calls by xid, so a slow READ need not hold up a GETATTR behind it.
+
+// Calls a connection may have in progress at once.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    // Largest record a client may send. Larger ones close the connection.
+    pub max_record: usize,
+    pub max_in_flight: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_record: DEFAULT_MAX_RECORD_SIZE,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+}
+
+// Serve calls from the client at `client` until it closes `stream`. Calls
+// still in progress then are finished and their replies sent before
+// returning.
+pub async fn serve<S, B>(stream: S, server: NfsServer<B>, client: IpAddr, limits: ConnectionLimits) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    B: FileSystemBackend,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (replies, mut outgoing) = mpsc::channel::<BytesMut>(limits.max_in_flight.max(1));
+
+    // Ends once the reading side and every call it started have dropped
+    // their senders.
+    let writing = tokio::spawn(async move {
+        while let Some(reply) = outgoing.recv().await {
+            writer.write_all(&reply).await?;
+        }
+        writer.flush().await
+    });
+
+    let read = read_calls(&mut reader, server, client, limits, replies).await;
+    let written = writing.await?;
+    read?;
+    Ok(written?)
+}
+
+async fn read_calls<R, B>(
+    reader: &mut R,
+    server: NfsServer<B>,
+    client: IpAddr,
+    limits: ConnectionLimits,
+    replies: mpsc::Sender<BytesMut>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    B: FileSystemBackend,
+{
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut records = RecordReader::new(limits.max_record);
+    let in_flight = Arc::new(Semaphore::new(limits.max_in_flight.max(1)));
+
+    loop {
+        // A record that breaks the framing leaves no way to find where the
+        // next one starts, so the connection is given up.
+        let record = match records.read(&mut buf) {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                if reader.read_buf(&mut buf).await? == 0 {
+                    // Connection closed
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("Closing connection: {}", e);
+                return Ok(());
+            }
+        };
+
+        // A message whose header does not decode cannot be answered, but
+        // the rest of the stream is still good.
+        let msg = match RpcMsg::decode(&record) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Dropping malformed RPC message: {}", e);
+                continue;
+            }
+        };
+
+        let permit = in_flight.clone().acquire_owned().await?;
+        let server = server.clone();
+        let replies = replies.clone();
+        tokio::spawn(async move {
+            if let Some(reply) = dispatch(&server, client, msg).await {
+                let mut out = BytesMut::new();
+                write_rpc_message(&reply, &mut out);
+                // Fails only if the writing side has given up on the
+                // connection, and then there is no one to tell.
+                let _ = replies.send(out).await;
+            }
+            drop(permit);
+        });
+    }
+}
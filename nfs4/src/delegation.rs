@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::filehandle::FileKey;
+use crate::protocol::*;
+use crate::state::make_stateid;
+
+// Delegations (RFC 7530 section 10.4).
+//
+// A client holding a read delegation may serve opens and reads of the file
+// from its cache without asking the server; a write delegation lets it
+// buffer writes as well. They are only handed out while the client is the
+// only one with the file open. When someone else wants access the holder
+// would not expect, the delegation is recalled with CB_RECALL and the
+// request is told to retry with NFS4ERR_DELAY until the holder sends
+// DELEGRETURN. A holder that has not returned the delegation a lease period
+// after the recall loses it: the delegation is revoked and its stateid
+// stops working.
+
+// `F` is the open file of the storage backend.
+#[derive(Debug)]
+pub struct Delegation<F> {
+    pub clientid: u64,
+    pub file_key: FileKey,
+    pub fh: NfsFileHandle,
+    pub write: bool,
+    pub seqid: u32,
+    // For READ and WRITE requests made with the delegation stateid.
+    pub file: F,
+    // When CB_RECALL was sent.
+    pub recalled: Option<Instant>,
+}
+
+// A delegation that has just been recalled, with what CB_RECALL needs.
+#[derive(Debug)]
+pub struct Recall {
+    pub clientid: u64,
+    pub stateid: [u8; 16],
+    pub fh: NfsFileHandle,
+}
+
+#[derive(Debug)]
+pub struct DelegationTable<F> {
+    // Keyed by the part of the stateid that stays the same as its seqid
+    // advances.
+    delegations: HashMap<[u8; 12], Delegation<F>>,
+    // Revoked delegations, the clients that held them and when, so a
+    // client still using one is told it was revoked rather than that the
+    // stateid is unknown. Kept for a lease period, by which time the
+    // client has heard, or until the client's state is released.
+    revoked: HashMap<[u8; 12], (u64, Instant)>,
+    recall_time: Duration,
+}
+
+impl<F> DelegationTable<F> {
+    pub fn new(recall_time: Duration) -> Self {
+        Self {
+            delegations: HashMap::new(),
+            revoked: HashMap::new(),
+            recall_time,
+        }
+    }
+
+    pub fn grant(&mut self, other: [u8; 12], delegation: Delegation<F>) {
+        self.delegations.insert(other, delegation);
+    }
+
+    pub fn get(&self, other: &[u8; 12]) -> Option<&Delegation<F>> {
+        self.delegations.get(other)
+    }
+
+    pub fn is_revoked(&self, other: &[u8; 12]) -> bool {
+        self.revoked.contains_key(other)
+    }
+
+    pub fn is_delegated(&self, file_key: &FileKey) -> bool {
+        self.delegations.values().any(|d| d.file_key == *file_key)
+    }
+
+    pub fn client_has_delegations(&self, clientid: u64) -> bool {
+        self.delegations.values().any(|d| d.clientid == clientid)
+    }
+
+    // Check for delegations on a file that are in the way of access by
+    // `clientid`, or by a client that is not known when it is None. A read
+    // delegation only gets in the way of writing. Delegations not recalled
+    // yet are marked as recalled and returned for the caller to send
+    // CB_RECALL.
+    pub fn check_conflicts(&mut self, file_key: &FileKey, clientid: Option<u64>, write: bool) -> Result<(), Vec<Recall>> {
+        self.revoke_expired(Instant::now());
+
+        let mut conflict = false;
+        let mut recalls = Vec::new();
+        for (other, delegation) in self.delegations.iter_mut() {
+            if delegation.file_key != *file_key || Some(delegation.clientid) == clientid || !(write || delegation.write) {
+                continue;
+            }
+            conflict = true;
+            if delegation.recalled.is_none() {
+                delegation.recalled = Some(Instant::now());
+                recalls.push(Recall {
+                    clientid: delegation.clientid,
+                    stateid: make_stateid(delegation.seqid, other),
+                    fh: delegation.fh.clone(),
+                });
+            }
+        }
+        if conflict {
+            Err(recalls)
+        } else {
+            Ok(())
+        }
+    }
+
+    // DELEGRETURN.
+    pub fn remove(&mut self, other: &[u8; 12]) -> Option<Delegation<F>> {
+        self.delegations.remove(other)
+    }
+
+    pub fn revoke(&mut self, other: &[u8; 12]) -> Option<Delegation<F>> {
+        self.revoke_at(other, Instant::now())
+    }
+
+    fn revoke_at(&mut self, other: &[u8; 12], now: Instant) -> Option<Delegation<F>> {
+        let delegation = self.delegations.remove(other)?;
+        self.revoked.insert(*other, (delegation.clientid, now));
+        Some(delegation)
+    }
+
+    // Revoke delegations that were recalled more than a lease period ago
+    // and return them, and forget those revoked as long ago.
+    pub fn revoke_expired(&mut self, now: Instant) -> Vec<Delegation<F>> {
+        let recall_time = self.recall_time;
+        self.revoked.retain(|_, (_, revoked)| now.saturating_duration_since(*revoked) < recall_time);
+        let expired: Vec<[u8; 12]> = self
+            .delegations
+            .iter()
+            .filter(|(_, d)| d.recalled.is_some_and(|recalled| now.duration_since(recalled) >= recall_time))
+            .map(|(other, _)| *other)
+            .collect();
+        expired.iter().filter_map(|other| self.revoke_at(other, now)).collect()
+    }
+
+    pub fn release_client(&mut self, clientid: u64) {
+        self.delegations.retain(|_, d| d.clientid != clientid);
+        self.revoked.retain(|_, (owner, _)| *owner != clientid);
+    }
+}
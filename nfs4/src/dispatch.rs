@@ -0,0 +1,88 @@
+use log::{debug, warn};
+use std::net::IpAddr;
+
+use crate::auth::Credentials;
+use crate::backend::FileSystemBackend;
+use crate::drc::{is_cacheable, DrcCheck, DrcKey};
+use crate::protocol::{CompoundRequest, NfsProcedure, NFS_PROGRAM, NFS_VERSION};
+use crate::rpc::{RpcMsg, RpcMsgBody, AUTH_BADCRED, RPC_VERSION};
+use crate::server::NfsServer;
+
+// Routing of RPC calls to the NFS program (RFC 5531 section 9).
+//
+// A call is checked from the outside in: the RPC version, then the
+// credential, then the program, its version and the procedure. Each check
+// that fails has its own reply, so a client can tell a server that does not
+// speak its version from one that does not have the program at all.
+
+// Answer a message from the client at `client`. Replies, which a server has
+// no business receiving, get no answer, and neither do retransmissions of
+// calls still in progress.
+pub async fn dispatch<B: FileSystemBackend>(server: &NfsServer<B>, client: IpAddr, msg: RpcMsg) -> Option<RpcMsg> {
+    let xid = msg.xid;
+    let call = match msg.body {
+        RpcMsgBody::Call(call) => call,
+        RpcMsgBody::Reply(_) => {
+            debug!("Ignoring RPC reply {:#x}", xid);
+            return None;
+        }
+    };
+
+    if call.rpc_vers != RPC_VERSION {
+        return Some(RpcMsg::new_rpc_mismatch_reply(xid, RPC_VERSION, RPC_VERSION));
+    }
+    // Only AUTH_NONE and AUTH_SYS callers can be told apart
+    let Some(cred) = Credentials::from_auth(&call.cred) else {
+        return Some(RpcMsg::new_auth_error_reply(xid, AUTH_BADCRED));
+    };
+    if call.prog != NFS_PROGRAM {
+        return Some(RpcMsg::new_prog_unavail_reply(xid));
+    }
+    if call.prog_vers != NFS_VERSION {
+        return Some(RpcMsg::new_prog_mismatch_reply(xid, NFS_VERSION, NFS_VERSION));
+    }
+
+    let reply = match call.proc {
+        proc if proc == NfsProcedure::Null as u32 => RpcMsg::new_success_reply(xid, Vec::new()),
+        proc if proc == NfsProcedure::Compound as u32 => {
+            let request = match CompoundRequest::decode(&call.data) {
+                Ok(request) => request,
+                Err(e) => {
+                    debug!("Undecodable COMPOUND in call {:#x}: {}", xid, e);
+                    return Some(RpcMsg::new_garbage_args_reply(xid));
+                }
+            };
+
+            let key = is_cacheable(&request).then(|| DrcKey::new(client, xid, &call.data));
+            if let Some(key) = key {
+                match server.drc().write().await.check(key) {
+                    DrcCheck::New => {}
+                    DrcCheck::InProgress => {
+                        debug!("Dropping retransmission of call {:#x} in progress", xid);
+                        return None;
+                    }
+                    DrcCheck::Replay(reply) => {
+                        debug!("Replaying cached reply to call {:#x}", xid);
+                        return Some(reply);
+                    }
+                }
+            }
+
+            let (reply, completed) = match server.handle_compound(request, cred).await {
+                Ok(response) => (RpcMsg::new_success_reply(xid, response.encode()), true),
+                Err(e) => {
+                    warn!("COMPOUND in call {:#x} failed: {}", xid, e);
+                    (RpcMsg::new_system_err_reply(xid), false)
+                }
+            };
+            // A call that failed for want of resources may well work when
+            // retried, so only replies with results are kept.
+            if let Some(key) = key {
+                server.drc().write().await.finish(key, completed.then(|| reply.clone()));
+            }
+            reply
+        }
+        _ => RpcMsg::new_proc_unavail_reply(xid),
+    };
+    Some(reply)
+}
@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+
+use crate::protocol::*;
+use crate::rpc::RpcMsg;
+
+// Duplicate request cache for NFSv4.0.
+//
+// A client that loses its connection retransmits the calls it had no reply
+// to, and some of them may already have been carried out. Running a
+// CREATE, REMOVE or RENAME a second time gives a different answer from the
+// first, so the replies to such calls are kept for a while, and a
+// retransmission gets the kept reply rather than being run again. NFSv4.1
+// has sessions for this, and operations carrying a seqid have their own
+// replay detection, so only NFSv4.0 compounds with an operation in
+// `NON_IDEMPOTENT` are cached here.
+
+// Replies kept. The oldest go first when the cache is full.
+pub const DEFAULT_DRC_CAPACITY: usize = 1024;
+
+const NON_IDEMPOTENT: &[u32] = &[OP_CREATE, OP_LINK, OP_REMOVE, OP_RENAME, OP_SETATTR, OP_WRITE];
+
+// Whether the reply to `request` should be cached.
+pub fn is_cacheable(request: &CompoundRequest) -> bool {
+    request.minor_version == 0 && request.operations.iter().any(|op| NON_IDEMPOTENT.contains(&op.opcode()))
+}
+
+// A retransmission comes from the same address with the same xid and the
+// same arguments. A client reusing an xid for a different call is told
+// apart by the checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DrcKey {
+    addr: IpAddr,
+    xid: u32,
+    checksum: u64,
+}
+
+impl DrcKey {
+    pub fn new(addr: IpAddr, xid: u32, args: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        args.hash(&mut hasher);
+        Self {
+            addr,
+            xid,
+            checksum: hasher.finish(),
+        }
+    }
+}
+
+// Outcome of looking a call up in the cache.
+pub enum DrcCheck {
+    // Not seen before; the call is now in progress until `finish`.
+    New,
+    // A retransmission of a call still being carried out. It is dropped:
+    // the reply to the original answers both.
+    InProgress,
+    // A retransmission of a call already answered.
+    Replay(RpcMsg),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrcStats {
+    // Retransmissions answered from the cache.
+    pub hits: u64,
+    pub misses: u64,
+    // Retransmissions dropped while the original was in progress.
+    pub in_progress: u64,
+    pub entries: usize,
+}
+
+#[derive(Debug)]
+pub struct DuplicateRequestCache {
+    capacity: usize,
+    // None while the call is in progress.
+    entries: HashMap<DrcKey, Option<RpcMsg>>,
+    // Keys in the order they were added, for eviction.
+    order: VecDeque<DrcKey>,
+    stats: DrcStats,
+}
+
+impl DuplicateRequestCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            stats: DrcStats::default(),
+        }
+    }
+
+    pub fn check(&mut self, key: DrcKey) -> DrcCheck {
+        match self.entries.get(&key) {
+            Some(Some(reply)) => {
+                self.stats.hits += 1;
+                return DrcCheck::Replay(reply.clone());
+            }
+            Some(None) => {
+                self.stats.in_progress += 1;
+                return DrcCheck::InProgress;
+            }
+            None => self.stats.misses += 1,
+        }
+
+        while self.order.len() >= self.capacity.max(1) {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, None);
+        self.order.push_back(key);
+        DrcCheck::New
+    }
+
+    // Keep the reply to a call `check` found to be new. Without one, the
+    // call is forgotten, and a retransmission runs it again.
+    pub fn finish(&mut self, key: DrcKey, reply: Option<RpcMsg>) {
+        match reply {
+            Some(reply) => {
+                if let Some(entry) = self.entries.get_mut(&key) {
+                    *entry = Some(reply);
+                }
+            }
+            None => {
+                self.entries.remove(&key);
+                self.order.retain(|k| *k != key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DrcStats {
+        DrcStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::auth::{Credentials, NOBODY};
+use crate::resolve::check_name;
+
+// The export table and the pseudo-filesystem joining the exports together
+// (RFC 7530 section 7).
+//
+// Each export publishes a host directory at a path in a tree only the
+// server has. The directories on the way to an export that are not exports
+// themselves make up the pseudo-filesystem: they are read-only, contain
+// nothing but the next step towards an export, and PUTROOTFH starts at
+// its root. A single export at "/" is the whole tree, with no
+// pseudo-filesystem in front of it.
+//
+// Filehandles carry the index of their export in the table, which is also
+// what sets the export apart as its own filesystem in the fsid attribute.
+// Handles therefore survive a restart as long as the table keeps its order.
+
+// Export id of pseudo-filesystem handles.
+pub const PSEUDO_EXPORT_ID: u32 = u32::MAX;
+
+// Settings that apply to everything a client reaches through an export.
+// The defaults let any client read and write with the identity its
+// credential claims, as exports(5) would with rw, no_root_squash and a
+// wildcard client.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    // Let clients create block and character device nodes. Off by default:
+    // a device node on an export gives whoever can open it access to the
+    // device, and clients can set any mode on what they create.
+    pub devices: bool,
+    // Refuse every change with NFS4ERR_ROFS.
+    pub read_only: bool,
+    pub squash: Squash,
+    // The identity squashed callers are given.
+    pub anonuid: u32,
+    pub anongid: u32,
+    // Networks the export is served to. Empty serves it to everyone.
+    pub clients: Vec<ClientNet>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            devices: false,
+            read_only: false,
+            squash: Squash::None,
+            anonuid: NOBODY,
+            anongid: NOBODY,
+            clients: Vec::new(),
+        }
+    }
+}
+
+// Which callers lose the identity their credential claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Squash {
+    None,
+    // uid and gid 0, including 0 among the supplementary groups.
+    Root,
+    // Everyone.
+    All,
+}
+
+impl ExportOptions {
+    // Options in the comma-separated form of exports(5), such as
+    // "ro,root_squash,anonuid=1000,client=10.0.0.0/8". client may be
+    // given more than once.
+    pub fn parse(list: &str) -> Result<Self> {
+        let mut options = Self::default();
+        for option in list.split(',').filter(|option| !option.is_empty()) {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            match (name, value) {
+                ("ro", None) => options.read_only = true,
+                ("rw", None) => options.read_only = false,
+                ("root_squash", None) => options.squash = Squash::Root,
+                ("no_root_squash", None) => options.squash = Squash::None,
+                ("all_squash", None) => options.squash = Squash::All,
+                ("devices", None) => options.devices = true,
+                ("nodevices", None) => options.devices = false,
+                ("anonuid", Some(id)) => options.anonuid = id.parse().with_context(|| format!("anonuid {:?}", id))?,
+                ("anongid", Some(id)) => options.anongid = id.parse().with_context(|| format!("anongid {:?}", id))?,
+                ("client", Some(net)) => options.clients.push(net.parse()?),
+                _ => bail!("unknown export option {:?}", option),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|net| net.contains(addr))
+    }
+
+    // The identity a caller acts as on the export.
+    pub fn squashed(&self, cred: &Credentials) -> Credentials {
+        let squash = |id: u32, anon: u32| if id == 0 { anon } else { id };
+        match self.squash {
+            Squash::None => cred.clone(),
+            Squash::Root => Credentials {
+                uid: squash(cred.uid, self.anonuid),
+                gid: squash(cred.gid, self.anongid),
+                gids: cred.gids.iter().map(|&gid| squash(gid, self.anongid)).collect(),
+            },
+            Squash::All => Credentials {
+                uid: self.anonuid,
+                gid: self.anongid,
+                gids: Vec::new(),
+            },
+        }
+    }
+}
+
+// An address with a prefix length, such as 192.168.0.0/16 or 2001:db8::/32.
+// A bare address is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientNet {
+    // IPv4 clients reaching an IPv6 socket show up as IPv4-mapped
+    // addresses, which match the IPv4 networks they stand for.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for ClientNet {
+    type Err = anyhow::Error;
+
+    fn from_str(net: &str) -> Result<Self> {
+        let (addr, prefix) = match net.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (net, None),
+        };
+        let addr: IpAddr = addr.parse().with_context(|| format!("client {:?}", net))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().with_context(|| format!("client {:?}", net))?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("client {:?}: prefix longer than the address", net);
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+// A host directory and where clients find it.
+#[derive(Debug, Clone)]
+pub struct Export {
+    // Path in the pseudo-filesystem, such as "/" or "/home/alice".
+    pub path: String,
+    pub dir: PathBuf,
+    pub options: ExportOptions,
+}
+
+impl Export {
+    pub fn new(path: &str, dir: PathBuf) -> Self {
+        Self {
+            path: path.to_string(),
+            dir,
+            options: ExportOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ExportOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+// An export being served.
+#[derive(Debug)]
+pub struct ExportEntry {
+    pub id: u32,
+    pub path: String,
+    // The directory served, as the storage backend names it.
+    pub root: PathBuf,
+    pub options: ExportOptions,
+    // The pseudo-filesystem directory the export appears in, or None for
+    // an export at "/".
+    pub parent: Option<usize>,
+}
+
+impl ExportEntry {
+    // Exports are numbered from 1 in fsids, leaving 0 to the
+    // pseudo-filesystem.
+    pub fn fsid(&self) -> (u64, u64) {
+        (self.id as u64 + 1, 0)
+    }
+}
+
+// What a pseudo-filesystem directory contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PseudoEntry {
+    Dir(usize),
+    Export(u32),
+}
+
+#[derive(Debug)]
+pub struct PseudoNode {
+    // Full path, which stands in for the generation in handles so that
+    // one for a node that has gone does not lead to another.
+    pub path: String,
+    pub parent: usize,
+    pub entries: BTreeMap<String, PseudoEntry>,
+}
+
+#[derive(Debug)]
+pub struct ExportTable {
+    exports: Vec<Arc<ExportEntry>>,
+    // Node 0 is the root. Empty when an export is at "/".
+    pseudo: Vec<PseudoNode>,
+}
+
+impl ExportTable {
+    // Exports of directories on the local filesystem, which are looked up
+    // to find their real path.
+    pub fn new(exports: Vec<Export>) -> Result<Self> {
+        let exports = exports
+            .into_iter()
+            .map(|export| {
+                let dir = export.dir.canonicalize().with_context(|| format!("export {:?}", export.dir))?;
+                Ok(Export { dir, ..export })
+            })
+            .collect::<Result<_>>()?;
+        Self::from_paths(exports)
+    }
+
+    // Exports of directories as another backend names them, which must be
+    // absolute paths without "." or "..".
+    pub fn from_paths(exports: Vec<Export>) -> Result<Self> {
+        if exports.is_empty() {
+            bail!("no exports");
+        }
+        let mut table = Self {
+            exports: Vec::new(),
+            pseudo: Vec::new(),
+        };
+        for (id, export) in exports.into_iter().enumerate() {
+            let names = split_path(&export.path)?;
+            if (names.is_empty() && id > 0) || (!table.exports.is_empty() && table.pseudo.is_empty()) {
+                bail!("export {:?}: an export at \"/\" must be the only one", export.path);
+            }
+            let root = export.dir;
+            let normal = root.components().skip(1).all(|c| matches!(c, Component::Normal(_)));
+            if !root.is_absolute() || !normal {
+                bail!("export {:?}: not an absolute path", root);
+            }
+            for other in &table.exports {
+                let (a, b) = (&other.root, &root);
+                if a.starts_with(b) || b.starts_with(a) {
+                    bail!("export {:?} overlaps export {:?}", b, a);
+                }
+            }
+            let parent = match names.split_last() {
+                Some((name, dirs)) => {
+                    let parent = table.pseudo_dir(&export.path, dirs)?;
+                    let entries = &mut table.pseudo[parent].entries;
+                    if entries.contains_key(*name) {
+                        bail!("export {:?}: path is already taken", export.path);
+                    }
+                    entries.insert(name.to_string(), PseudoEntry::Export(id as u32));
+                    Some(parent)
+                }
+                None => None,
+            };
+            table.exports.push(Arc::new(ExportEntry {
+                id: id as u32,
+                path: export.path,
+                root,
+                options: export.options,
+                parent,
+            }));
+        }
+        Ok(table)
+    }
+
+    // The pseudo-filesystem directory at `names`, created as needed.
+    fn pseudo_dir(&mut self, path: &str, names: &[&str]) -> Result<usize> {
+        if self.pseudo.is_empty() {
+            self.pseudo.push(PseudoNode {
+                path: "/".to_string(),
+                parent: 0,
+                entries: BTreeMap::new(),
+            });
+        }
+        let mut node = 0;
+        for name in names {
+            node = match self.pseudo[node].entries.get(*name) {
+                Some(PseudoEntry::Dir(child)) => *child,
+                Some(PseudoEntry::Export(_)) => bail!("export {:?} is inside another export", path),
+                None => {
+                    let child = self.pseudo.len();
+                    let child_path = match self.pseudo[node].path.as_str() {
+                        "/" => format!("/{}", name),
+                        parent => format!("{}/{}", parent, name),
+                    };
+                    self.pseudo.push(PseudoNode {
+                        path: child_path,
+                        parent: node,
+                        entries: BTreeMap::new(),
+                    });
+                    self.pseudo[node].entries.insert(name.to_string(), PseudoEntry::Dir(child));
+                    child
+                }
+            };
+        }
+        Ok(node)
+    }
+
+    pub fn exports(&self) -> &[Arc<ExportEntry>] {
+        &self.exports
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Arc<ExportEntry>> {
+        self.exports.get(id as usize)
+    }
+
+    // The export a host path is in.
+    pub fn containing(&self, path: &Path) -> Option<&Arc<ExportEntry>> {
+        self.exports.iter().find(|export| path.starts_with(&export.root))
+    }
+
+    // The export at "/", when there is no pseudo-filesystem.
+    pub fn root_export(&self) -> Option<&Arc<ExportEntry>> {
+        match self.pseudo.is_empty() {
+            true => self.exports.first(),
+            false => None,
+        }
+    }
+
+    pub fn pseudo_node(&self, node: usize) -> Option<&PseudoNode> {
+        self.pseudo.get(node)
+    }
+}
+
+// The names in an export path, each of which must be a valid component.
+fn split_path(path: &str) -> Result<Vec<&str>> {
+    let Some(rest) = path.strip_prefix('/') else {
+        bail!("export path {:?} is not absolute", path);
+    };
+    let names: Vec<&str> = rest.split('/').filter(|name| !name.is_empty()).collect();
+    for name in &names {
+        if check_name(name).is_err() {
+            bail!("export path {:?} has a bad component {:?}", path, name);
+        }
+    }
+    Ok(names)
+}
+
+// A stand-in for the inode generation of a pseudo-filesystem node.
+pub fn pseudo_generation(path: &str) -> u32 {
+    // FNV-1a
+    path.bytes().fold(0x811c9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
@@ -0,0 +1,328 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::backend::FileSystemBackend;
+use crate::export::{pseudo_generation, PSEUDO_EXPORT_ID};
+use crate::protocol::{NfsFileHandle, NfsStatus};
+use crate::xdr::{get_opaque, get_u32, get_u64, put_opaque, XdrError, XdrResult};
+
+// Filehandles name a file by its identity on disk rather than by its path,
+// so the same file always gets the same handle, and that handle survives
+// renames and server restarts. The layout is:
+//
+//   version (1 byte), 3 bytes of padding, export id (4 bytes),
+//   device (8 bytes), inode (8 bytes), generation (4 bytes)
+//
+// all big-endian. Directories of the pseudo-filesystem have no device or
+// inode, and carry their index in the pseudo-filesystem as the inode.
+
+const FH_VERSION: u8 = 1;
+const FH_LEN: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub export_id: u32,
+    pub dev: u64,
+    pub ino: u64,
+    // Inode generation number, or 0 when the filesystem does not report one.
+    pub generation: u32,
+}
+
+// A file as the server keeps state for it: its export, device and inode.
+// Open state, locks and delegations are found by it rather than by path,
+// so they follow the file through renames and apply through every link
+// to it.
+pub type FileKey = (u32, u64, u64);
+
+impl FileId {
+    pub fn key(&self) -> FileKey {
+        (self.export_id, self.dev, self.ino)
+    }
+
+    pub fn to_handle(&self) -> NfsFileHandle {
+        let mut data = Vec::with_capacity(FH_LEN);
+        data.extend_from_slice(&[FH_VERSION, 0, 0, 0]);
+        data.extend_from_slice(&self.export_id.to_be_bytes());
+        data.extend_from_slice(&self.dev.to_be_bytes());
+        data.extend_from_slice(&self.ino.to_be_bytes());
+        data.extend_from_slice(&self.generation.to_be_bytes());
+        NfsFileHandle { data }
+    }
+
+    // Anything that is not a handle this server could have issued is
+    // NFS4ERR_BADHANDLE.
+    pub fn from_handle(fh: &NfsFileHandle) -> Result<Self, NfsStatus> {
+        let data = &fh.data;
+        if data.len() != FH_LEN || data[0] != FH_VERSION {
+            return Err(NfsStatus::BadHandle);
+        }
+        Ok(FileId {
+            export_id: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            dev: u64::from_be_bytes(data[8..16].try_into().unwrap()),
+            ino: u64::from_be_bytes(data[16..24].try_into().unwrap()),
+            generation: u32::from_be_bytes(data[24..28].try_into().unwrap()),
+        })
+    }
+
+    // Handle of pseudo-filesystem directory `node`. Its path stands in for
+    // the generation, so a handle kept across a restart with a different
+    // export table is stale rather than leading to another directory.
+    pub fn pseudo(node: usize, path: &str) -> Self {
+        FileId {
+            export_id: PSEUDO_EXPORT_ID,
+            dev: 0,
+            ino: node as u64,
+            generation: pseudo_generation(path),
+        }
+    }
+
+    // Identity of the file at `path`. Symlinks are not followed.
+    pub async fn for_path<B: FileSystemBackend>(export_id: u32, backend: &B, path: &Path) -> io::Result<Self> {
+        let attr = backend.getattr(path).await?;
+        Ok(FileId {
+            export_id,
+            dev: attr.dev,
+            ino: attr.ino,
+            generation: backend.generation(path, &attr).await,
+        })
+    }
+
+    // Whether `path` is still this file. A file number can be reused once
+    // the file is deleted, which the generation number catches on
+    // filesystems that keep one.
+    pub async fn matches<B: FileSystemBackend>(&self, backend: &B, path: &Path) -> bool {
+        let attr = match backend.getattr(path).await {
+            Ok(attr) => attr,
+            Err(_) => return false,
+        };
+        if attr.dev != self.dev || attr.ino != self.ino {
+            return false;
+        }
+        let current = backend.generation(path, &attr).await;
+        self.generation == 0 || current == 0 || current == self.generation
+    }
+}
+
+// Where the files handles have been given out for were last seen, so that
+// a handle can be turned back into a path without searching the export.
+// A file with several links may be known by more than one path. With a
+// journal, the index outlives the server, and handles given out before a
+// restart still lead to their files; without one, only the export roots
+// do. A handle for a file that is not at any path the server knows of,
+// having been moved by something other than this server, is stale.
+//
+// The journal is a series of XDR records, each a change to the index,
+// rewritten with just the paths still known on open and whenever it grows
+// to several times that.
+#[derive(Debug, Default)]
+pub struct HandleIndex {
+    paths: HashMap<FileKey, Vec<PathBuf>>,
+    journal: Option<Journal>,
+}
+
+#[derive(Debug)]
+struct Journal {
+    path: PathBuf,
+    file: File,
+    records: usize,
+    // Records at which it is rewritten.
+    limit: usize,
+}
+
+// Journals are rewritten once they have grown to twice their size when
+// last written, and this many records more.
+const JOURNAL_SLACK: usize = 4096;
+
+enum Record {
+    Insert(FileKey, PathBuf),
+    Remove(FileKey, PathBuf),
+    // A removed object, and everything that was beneath it.
+    Forget(PathBuf),
+    // A renamed object, and everything beneath it.
+    Rename(PathBuf, PathBuf),
+}
+
+const RECORD_INSERT: u32 = 1;
+const RECORD_REMOVE: u32 = 2;
+const RECORD_FORGET: u32 = 3;
+const RECORD_RENAME: u32 = 4;
+
+impl HandleIndex {
+    // Load the index kept in the journal at `path`, creating it if need
+    // be. A record cut short by a crash ends it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut index = HandleIndex::default();
+        let mut data = match std::fs::read(path) {
+            Ok(data) => Bytes::from(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Bytes::new(),
+            Err(e) => return Err(e),
+        };
+        while let Ok(record) = Record::decode(&mut data) {
+            index.apply(record);
+        }
+        index.compact(path)?;
+        Ok(index)
+    }
+
+    // Paths the file has been seen at, most recent first.
+    pub fn paths(&self, key: &FileKey) -> Vec<PathBuf> {
+        self.paths.get(key).cloned().unwrap_or_default()
+    }
+
+    pub fn insert(&mut self, key: FileKey, path: PathBuf) {
+        if !self.paths.get(&key).is_some_and(|paths| paths.contains(&path)) {
+            self.record(Record::Insert(key, path));
+        }
+    }
+
+    // The file is no longer at `path`.
+    pub fn remove(&mut self, key: FileKey, path: PathBuf) {
+        if self.paths.get(&key).is_some_and(|paths| paths.contains(&path)) {
+            self.record(Record::Remove(key, path));
+        }
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.record(Record::Forget(path.to_path_buf()));
+    }
+
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        self.record(Record::Rename(from.to_path_buf(), to.to_path_buf()));
+    }
+
+    // Make a change and add it to the journal. The index in memory is
+    // right either way, so a journal that cannot be written only costs
+    // handles across a restart.
+    fn record(&mut self, record: Record) {
+        if let Some(journal) = &mut self.journal {
+            let mut buf = BytesMut::new();
+            record.encode(&mut buf);
+            if let Err(e) = journal.file.write_all(&buf) {
+                warn!("Cannot write handle index {:?}: {}", journal.path, e);
+            }
+            journal.records += 1;
+        }
+        self.apply(record);
+
+        if let Some(journal) = &self.journal {
+            if journal.records > journal.limit {
+                let path = journal.path.clone();
+                if let Err(e) = self.compact(&path) {
+                    warn!("Cannot rewrite handle index {:?}: {}", path, e);
+                    // Carry on with the old one, and try again later.
+                    if let Some(journal) = &mut self.journal {
+                        journal.limit = journal.records + JOURNAL_SLACK;
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Insert(key, path) => {
+                let paths = self.paths.entry(key).or_default();
+                if !paths.contains(&path) {
+                    paths.insert(0, path);
+                }
+            }
+            Record::Remove(key, path) => {
+                if let Some(paths) = self.paths.get_mut(&key) {
+                    paths.retain(|p| *p != path);
+                }
+            }
+            Record::Forget(prefix) => {
+                for paths in self.paths.values_mut() {
+                    paths.retain(|p| !p.starts_with(&prefix));
+                }
+            }
+            Record::Rename(from, to) => {
+                for p in self.paths.values_mut().flatten() {
+                    if let Ok(rest) = p.strip_prefix(&from) {
+                        *p = to.join(rest);
+                    }
+                }
+            }
+        }
+        self.paths.retain(|_, paths| !paths.is_empty());
+    }
+
+    // Write the paths known now to a new journal at `path`, and carry on
+    // with that one.
+    fn compact(&mut self, path: &Path) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        let mut records = 0;
+        for (key, paths) in &self.paths {
+            // Oldest first, so they come back in the same order.
+            for p in paths.iter().rev() {
+                Record::Insert(*key, p.clone()).encode(&mut buf);
+                records += 1;
+            }
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".new");
+        std::fs::write(&tmp, &buf)?;
+        std::fs::rename(&tmp, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        self.journal = Some(Journal {
+            path: path.to_path_buf(),
+            file,
+            records,
+            limit: records * 2 + JOURNAL_SLACK,
+        });
+        Ok(())
+    }
+}
+
+impl Record {
+    fn encode(&self, buf: &mut BytesMut) {
+        let put_key = |buf: &mut BytesMut, (export_id, dev, ino): &FileKey| {
+            buf.put_u32(*export_id);
+            buf.put_u64(*dev);
+            buf.put_u64(*ino);
+        };
+        let put_path = |buf: &mut BytesMut, path: &Path| put_opaque(buf, path.as_os_str().as_bytes());
+        match self {
+            Record::Insert(key, path) => {
+                buf.put_u32(RECORD_INSERT);
+                put_key(buf, key);
+                put_path(buf, path);
+            }
+            Record::Remove(key, path) => {
+                buf.put_u32(RECORD_REMOVE);
+                put_key(buf, key);
+                put_path(buf, path);
+            }
+            Record::Forget(path) => {
+                buf.put_u32(RECORD_FORGET);
+                put_path(buf, path);
+            }
+            Record::Rename(from, to) => {
+                buf.put_u32(RECORD_RENAME);
+                put_path(buf, from);
+                put_path(buf, to);
+            }
+        }
+    }
+
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let get_key = |buf: &mut Bytes| -> XdrResult<FileKey> { Ok((get_u32(buf)?, get_u64(buf)?, get_u64(buf)?)) };
+        let get_path = |buf: &mut Bytes| -> XdrResult<PathBuf> { Ok(PathBuf::from(OsString::from_vec(get_opaque(buf)?))) };
+        match get_u32(buf)? {
+            RECORD_INSERT => Ok(Record::Insert(get_key(buf)?, get_path(buf)?)),
+            RECORD_REMOVE => Ok(Record::Remove(get_key(buf)?, get_path(buf)?)),
+            RECORD_FORGET => Ok(Record::Forget(get_path(buf)?)),
+            RECORD_RENAME => Ok(Record::Rename(get_path(buf)?, get_path(buf)?)),
+            value => Err(XdrError::InvalidDiscriminant {
+                what: "handle index record",
+                value,
+            }),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Translation between uids and gids and the owner strings of the owner
+// and owner_group attributes (RFC 7530 section 5.9).
+//
+// NFSv4 names owners as "user@dns_domain", and a client only takes a name
+// as meaning the same user on both sides when the domains match. A server
+// may instead send ids as decimal strings, which Linux clients using
+// AUTH_SYS also accept and send back; numeric mode sends nothing else, and
+// ids without a name fall back to it. Incoming decimal strings are
+// accepted whatever the mode.
+
+const DEFAULT_DOMAIN: &str = "localdomain";
+
+#[derive(Debug, Default)]
+pub struct IdMap {
+    domain: String,
+    users: Names,
+    groups: Names,
+}
+
+// Both directions of one name table. Where a name or id appears twice the
+// first entry wins, as with getpwnam and getpwuid.
+#[derive(Debug, Default)]
+struct Names {
+    by_id: HashMap<u32, String>,
+    by_name: HashMap<String, u32>,
+}
+
+impl Names {
+    fn insert(&mut self, name: &str, id: u32) {
+        self.by_id.entry(id).or_insert_with(|| name.to_string());
+        self.by_name.entry(name.to_string()).or_insert(id);
+    }
+}
+
+impl IdMap {
+    // Send and accept decimal ids only.
+    pub fn numeric() -> Self {
+        Self::named(DEFAULT_DOMAIN)
+    }
+
+    // Names from the host's /etc/passwd and /etc/group.
+    pub fn from_system(domain: &str) -> Result<Self> {
+        let passwd = std::fs::read_to_string("/etc/passwd").context("reading /etc/passwd")?;
+        let group = std::fs::read_to_string("/etc/group").context("reading /etc/group")?;
+        let mut idmap = Self::named(domain);
+        idmap.users = parse_db(&passwd);
+        idmap.groups = parse_db(&group);
+        Ok(idmap)
+    }
+
+    // Names from a static mapping file, for exports whose ids do not match
+    // the host's accounts. Each line is "user NAME ID" or "group NAME ID";
+    // blank lines and lines starting with '#' are skipped.
+    pub fn from_file(path: &Path, domain: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        let mut idmap = Self::named(domain);
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (kind, name, id) = match fields[..] {
+                [kind, name, id] if !name.contains('@') => match id.parse::<u32>() {
+                    Ok(id) => (kind, name, id),
+                    Err(_) => bail!("{:?} line {}: bad id {:?}", path, n + 1, id),
+                },
+                _ => bail!("{:?} line {}: expected \"user|group NAME ID\"", path, n + 1),
+            };
+            match kind {
+                "user" => idmap.users.insert(name, id),
+                "group" => idmap.groups.insert(name, id),
+                _ => bail!("{:?} line {}: unknown kind {:?}", path, n + 1, kind),
+            }
+        }
+        Ok(idmap)
+    }
+
+    fn named(domain: &str) -> Self {
+        Self {
+            domain: domain.to_ascii_lowercase(),
+            ..Default::default()
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn user_name(&self, uid: u32) -> String {
+        self.to_name(&self.users, uid)
+    }
+
+    pub fn group_name(&self, gid: u32) -> String {
+        self.to_name(&self.groups, gid)
+    }
+
+    // The id an incoming owner string stands for, or None if it names
+    // nobody we know, which SETATTR reports as NFS4ERR_BADOWNER.
+    pub fn uid(&self, owner: &str) -> Option<u32> {
+        self.to_id(&self.users, owner)
+    }
+
+    pub fn gid(&self, group: &str) -> Option<u32> {
+        self.to_id(&self.groups, group)
+    }
+
+    fn to_name(&self, names: &Names, id: u32) -> String {
+        match names.by_id.get(&id) {
+            Some(name) => format!("{}@{}", name, self.domain),
+            None => id.to_string(),
+        }
+    }
+
+    fn to_id(&self, names: &Names, owner: &str) -> Option<u32> {
+        if let Ok(id) = owner.parse() {
+            return Some(id);
+        }
+        // Domains are DNS names and so compare without regard to case.
+        let (name, domain) = owner.rsplit_once('@')?;
+        if !domain.eq_ignore_ascii_case(&self.domain) {
+            return None;
+        }
+        names.by_name.get(name).copied()
+    }
+}
+
+// The NFSv4 domain when none is configured: the DNS domain of the host,
+// as nfsidmap picks it.
+pub fn default_domain() -> String {
+    match hostname().split_once('.') {
+        Some((_, domain)) if !domain.is_empty() => domain.to_string(),
+        _ => DEFAULT_DOMAIN.to_string(),
+    }
+}
+
+// The host's name as the kernel has it, or an empty string if it cannot be
+// read.
+pub(crate) fn hostname() -> String {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    hostname.trim().to_string()
+}
+
+// The name and id fields of /etc/passwd or /etc/group lines, which both
+// start "name:password:id:".
+fn parse_db(contents: &str) -> Names {
+    let mut names = Names::default();
+    for line in contents.lines() {
+        let mut fields = line.split(':');
+        let (Some(name), Some(_), Some(id)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        if name.is_empty() || name.starts_with('#') || name.starts_with('+') || name.starts_with('-') {
+            continue;
+        }
+        if let Ok(id) = id.parse() {
+            names.insert(name, id);
+        }
+    }
+    names
+}
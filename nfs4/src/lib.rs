@@ -1,9 +1,28 @@
+pub mod attr;
+pub mod auth;
+pub mod backend;
+pub mod callback;
+pub mod connection;
+pub mod delegation;
+pub mod dispatch;
+pub mod drc;
+pub mod export;
+pub mod filehandle;
+pub mod idmap;
+pub mod local;
+pub mod lock;
 pub mod protocol;
+pub mod resolve;
 pub mod rpc;
 pub mod server;
+pub mod session;
+pub mod state;
+pub mod xdr;
 
 pub use protocol::{
-    CompoundRequest, CompoundResponse, NfsFileAttributes, NfsFileHandle, NfsOperation, NfsStatus,
+    CompoundRequest, CompoundResponse, NfsFileHandle, NfsOperation, NfsStatus,
     NfsTime, OperationData, OperationResult, NFS_PROGRAM, NFS_VERSION,
 };
+pub use backend::FileSystemBackend;
+pub use local::LocalFs;
 pub use server::NfsServer;
@@ -0,0 +1,272 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sys::stat::{major, makedev, minor, SFlag};
+use nix::sys::time::TimeSpec;
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::attr::{NfsSetAttributes, SetTime};
+use crate::backend::{FileAttr, FileSystemBackend, FsStats};
+use crate::protocol::*;
+use crate::resolve::ExportRoot;
+
+// The exports as directories of the local filesystem.
+//
+// Every access goes through the ExportRoot of the export it is in, which
+// keeps it inside (see resolve.rs). The system calls block, so they are
+// made off the async threads.
+
+#[derive(Debug)]
+pub struct LocalFs {
+    roots: Vec<Arc<ExportRoot>>,
+}
+
+impl LocalFs {
+    // Serve the directories at `dirs`, which must not overlap.
+    pub fn new<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> anyhow::Result<Self> {
+        let roots = dirs
+            .into_iter()
+            .map(|dir| ExportRoot::new(dir).map(Arc::new).with_context(|| format!("export {:?}", dir)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { roots })
+    }
+
+    // Run `work` on the root of the export holding `at`.
+    async fn in_export<T, F>(&self, at: &Path, work: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ExportRoot) -> io::Result<T> + Send + 'static,
+    {
+        let root = self
+            .roots
+            .iter()
+            .find(|root| at.starts_with(root.path()))
+            .cloned()
+            .ok_or(Errno::EXDEV)?;
+        blocking(move || work(&root)).await
+    }
+}
+
+#[async_trait]
+impl FileSystemBackend for LocalFs {
+    type File = Arc<File>;
+
+    async fn getattr(&self, path: &Path) -> io::Result<FileAttr> {
+        let owned = path.to_path_buf();
+        let metadata = self.in_export(path, move |root| root.metadata(&owned)).await?;
+        Ok(file_attr(&metadata))
+    }
+
+    async fn setattr(&self, path: &Path, attrs: &NfsSetAttributes) -> io::Result<()> {
+        let (owned, attrs) = (path.to_path_buf(), attrs.clone());
+        self.in_export(path, move |root| set_attributes(root, &owned, &attrs)).await
+    }
+
+    async fn open(&self, path: &Path, read: bool, write: bool) -> io::Result<Self::File> {
+        let flags = match (read, write) {
+            (true, true) => OFlag::O_RDWR,
+            (false, true) => OFlag::O_WRONLY,
+            _ => OFlag::O_RDONLY,
+        };
+        let owned = path.to_path_buf();
+        let file = self.in_export(path, move |root| root.open(&owned, flags)).await?;
+        Ok(Arc::new(file))
+    }
+
+    // Reads and writes go to an offset rather than moving a file position,
+    // as the file may be in use by several requests at once.
+    async fn read(&self, file: &Self::File, offset: u64, count: u32) -> io::Result<(Vec<u8>, bool)> {
+        let file = file.clone();
+        blocking(move || {
+            let size = file.metadata()?.len();
+            let mut buf = vec![0u8; count as usize];
+            let n = file.read_at(&mut buf, offset)?;
+            buf.truncate(n);
+            Ok((buf, offset + n as u64 >= size))
+        })
+        .await
+    }
+
+    async fn write(&self, file: &Self::File, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (file, data) = (file.clone(), data.to_vec());
+        blocking(move || file.write_all_at(&data, offset)).await
+    }
+
+    async fn sync(&self, file: &Self::File) -> io::Result<()> {
+        let file = file.clone();
+        blocking(move || file.sync_all()).await
+    }
+
+    async fn create(&self, path: &Path, ftype: u32, rdev: SpecData) -> io::Result<()> {
+        let owned = path.to_path_buf();
+        self.in_export(path, move |root| match ftype {
+            NF4REG => root.create_file(&owned).map(|_| ()),
+            NF4DIR => root.create_dir(&owned),
+            NF4BLK => root.mknod(&owned, SFlag::S_IFBLK, makedev(rdev.major as u64, rdev.minor as u64)),
+            NF4CHR => root.mknod(&owned, SFlag::S_IFCHR, makedev(rdev.major as u64, rdev.minor as u64)),
+            NF4FIFO => root.mknod(&owned, SFlag::S_IFIFO, 0),
+            NF4SOCK => root.mknod(&owned, SFlag::S_IFSOCK, 0),
+            _ => Err(Errno::EINVAL.into()),
+        })
+        .await
+    }
+
+    async fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let owned = path.to_path_buf();
+        self.in_export(path, move |root| root.remove(&owned, is_dir)).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_buf, to_buf) = (from.to_path_buf(), to.to_path_buf());
+        self.in_export(from, move |root| root.rename(&from_buf, &to_buf)).await
+    }
+
+    async fn readdir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let owned = path.to_path_buf();
+        self.in_export(path, move |root| root.read_dir(&owned)).await
+    }
+
+    async fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_buf, to_buf) = (from.to_path_buf(), to.to_path_buf());
+        self.in_export(from, move |root| root.hard_link(&from_buf, &to_buf)).await
+    }
+
+    async fn symlink(&self, target: &str, path: &Path) -> io::Result<()> {
+        let (target, owned) = (target.to_string(), path.to_path_buf());
+        self.in_export(path, move |root| root.symlink(&target, &owned)).await
+    }
+
+    async fn readlink(&self, path: &Path) -> io::Result<OsString> {
+        let owned = path.to_path_buf();
+        self.in_export(path, move |root| root.read_link(&owned)).await
+    }
+
+    async fn statfs(&self, path: &Path) -> io::Result<FsStats> {
+        let owned = path.to_path_buf();
+        self.in_export(path, move |root| {
+            let stats = root.statvfs(&owned)?;
+            let link_max = root.link_max(&owned).ok().flatten();
+            Ok(FsStats {
+                files_total: stats.files(),
+                files_free: stats.files_free(),
+                files_avail: stats.files_available(),
+                bytes_total: stats.blocks() * stats.fragment_size(),
+                bytes_free: stats.blocks_free() * stats.fragment_size(),
+                bytes_avail: stats.blocks_available() * stats.fragment_size(),
+                name_max: stats.name_max() as u32,
+                link_max: link_max.map(|max| max as u32),
+            })
+        })
+        .await
+    }
+
+    async fn generation(&self, path: &Path, attr: &FileAttr) -> u32 {
+        let (owned, attr) = (path.to_path_buf(), attr.clone());
+        self.in_export(path, move |root| Ok(generation(root, &owned, &attr))).await.unwrap_or(0)
+    }
+}
+
+async fn blocking<T, F>(work: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+fn file_attr(metadata: &Metadata) -> FileAttr {
+    let file_type = metadata.file_type();
+    let ftype = if file_type.is_dir() {
+        NF4DIR
+    } else if file_type.is_symlink() {
+        NF4LNK
+    } else if file_type.is_block_device() {
+        NF4BLK
+    } else if file_type.is_char_device() {
+        NF4CHR
+    } else if file_type.is_socket() {
+        NF4SOCK
+    } else if file_type.is_fifo() {
+        NF4FIFO
+    } else {
+        NF4REG
+    };
+    let time = |seconds: i64, nseconds: i64| NfsTime {
+        seconds: seconds as u64,
+        nseconds: nseconds as u32,
+    };
+    FileAttr {
+        ftype,
+        mode: metadata.mode() & 0o7777,
+        nlink: metadata.nlink(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        size: metadata.size(),
+        used: metadata.blocks() * 512,
+        rdev: SpecData {
+            major: major(metadata.rdev()) as u32,
+            minor: minor(metadata.rdev()) as u32,
+        },
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        atime: time(metadata.atime(), metadata.atime_nsec()),
+        mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+        ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+    }
+}
+
+fn set_attributes(root: &ExportRoot, path: &Path, attrs: &NfsSetAttributes) -> io::Result<()> {
+    // Ownership goes first since chown clears the setuid and setgid bits.
+    if attrs.owner.is_some() || attrs.owner_group.is_some() {
+        root.set_owner(path, attrs.owner, attrs.owner_group)?;
+    }
+    if let Some(mode) = attrs.mode {
+        root.set_mode(path, mode)?;
+    }
+    if let Some(size) = attrs.size {
+        root.truncate(path, size)?;
+    }
+    // Times are set last so a size change does not overwrite the mtime.
+    if attrs.time_access.is_some() || attrs.time_modify.is_some() {
+        root.set_times(path, &to_timespec(&attrs.time_access), &to_timespec(&attrs.time_modify))?;
+    }
+    Ok(())
+}
+
+fn to_timespec(time: &Option<SetTime>) -> TimeSpec {
+    match time {
+        None => TimeSpec::new(0, libc::UTIME_OMIT),
+        Some(SetTime::ServerTime) => TimeSpec::new(0, libc::UTIME_NOW),
+        Some(SetTime::ClientTime(t)) => TimeSpec::new(t.seconds as libc::time_t, t.nseconds as _),
+    }
+}
+
+// Read the inode generation with FS_IOC_GETVERSION. Only regular files and
+// directories are opened for this; opening a device or FIFO can have side
+// effects. Returns 0 when the generation is unavailable.
+fn generation(root: &ExportRoot, path: &Path, attr: &FileAttr) -> u32 {
+    if !attr.is_file() && !attr.is_dir() {
+        return 0;
+    }
+    let file = match root.open(path, OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_NOCTTY) {
+        Ok(file) => file,
+        Err(_) => return 0,
+    };
+
+    let mut generation: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETVERSION, &mut generation) };
+    if ret < 0 {
+        return 0;
+    }
+    generation as u32
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::filehandle::FileKey;
+use crate::protocol::*;
+
+// Byte-range locks (RFC 7530 section 9.4).
+//
+// Locks are advisory and held by lock-owners, each identified by its
+// client and an opaque owner string. A lock-owner's locks on a file are
+// kept as non-overlapping ranges; taking a lock over part of an existing
+// one replaces that part, so the same owner can upgrade, downgrade or split
+// its own locks. Locks of different owners conflict when they overlap and
+// either of them is a write lock.
+//
+// Clients wait for a blocking lock by polling: a denied READW_LT or
+// WRITEW_LT request is remembered, and for one lease period afterwards
+// nobody else may take a lock that would get in its way. Without this a
+// waiter could keep losing the range to clients that happen to retry
+// first.
+
+pub type LockOwnerKey = (u64, Vec<u8>);
+
+#[derive(Debug, Clone)]
+struct ByteRange {
+    owner: LockOwnerKey,
+    offset: u64,
+    // Exclusive, with u64::MAX standing for the end of the file.
+    end: u64,
+    write: bool,
+}
+
+impl ByteRange {
+    fn overlaps(&self, offset: u64, end: u64) -> bool {
+        self.offset < end && offset < self.end
+    }
+
+    fn conflicts(&self, owner: &LockOwnerKey, offset: u64, end: u64, write: bool) -> bool {
+        self.owner != *owner && (self.write || write) && self.overlaps(offset, end)
+    }
+
+    fn denied(&self) -> LockDenied {
+        LockDenied {
+            offset: self.offset,
+            length: if self.end == u64::MAX { u64::MAX } else { self.end - self.offset },
+            locktype: if self.write { WRITE_LT } else { READ_LT },
+            owner: LockOwner {
+                clientid: self.owner.0,
+                owner: self.owner.1.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    file: FileKey,
+    range: ByteRange,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct LockTable {
+    files: HashMap<FileKey, Vec<ByteRange>>,
+    waiters: Vec<Waiter>,
+    wait_time: Duration,
+}
+
+// The end of the range an offset and length describe. A length of all ones
+// means up to the end of the file; any other range must be non-empty and
+// fit in 64 bits.
+pub fn range_end(offset: u64, length: u64) -> Result<u64, NfsStatus> {
+    match length {
+        0 => Err(NfsStatus::Inval),
+        u64::MAX => Ok(u64::MAX),
+        _ => offset.checked_add(length).ok_or(NfsStatus::Inval),
+    }
+}
+
+impl LockTable {
+    pub fn new(wait_time: Duration) -> Self {
+        Self {
+            files: HashMap::new(),
+            waiters: Vec::new(),
+            wait_time,
+        }
+    }
+
+    // Check whether `owner` could take the lock, returning the first lock
+    // in the way if not.
+    pub fn test(&self, file: &FileKey, owner: &LockOwnerKey, offset: u64, end: u64, write: bool) -> Result<(), LockDenied> {
+        let conflict = self
+            .files
+            .get(file)
+            .and_then(|ranges| ranges.iter().find(|r| r.conflicts(owner, offset, end, write)));
+        match conflict {
+            Some(range) => Err(range.denied()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn lock(
+        &mut self,
+        file: &FileKey,
+        owner: &LockOwnerKey,
+        offset: u64,
+        end: u64,
+        locktype: u32,
+    ) -> Result<(), LockDenied> {
+        let write = locktype == WRITE_LT || locktype == WRITEW_LT;
+        let blocking = locktype == READW_LT || locktype == WRITEW_LT;
+        let now = Instant::now();
+        let wait_time = self.wait_time;
+        self.waiters.retain(|w| now.duration_since(w.since) < wait_time);
+
+        let result = self.test(file, owner, offset, end, write).and_then(|()| {
+            // A free range still goes to a client that was already waiting
+            // for it.
+            let waiter = self
+                .waiters
+                .iter()
+                .find(|w| w.file == *file && w.range.conflicts(owner, offset, end, write));
+            match waiter {
+                Some(w) => Err(w.range.denied()),
+                None => Ok(()),
+            }
+        });
+
+        let range = ByteRange {
+            owner: owner.clone(),
+            offset,
+            end,
+            write,
+        };
+        if let Err(denied) = result {
+            if blocking {
+                match self
+                    .waiters
+                    .iter_mut()
+                    .find(|w| w.file == *file && w.range.owner == *owner)
+                {
+                    Some(waiter) => waiter.range = range,
+                    None => self.waiters.push(Waiter {
+                        file: *file,
+                        range,
+                        since: now,
+                    }),
+                }
+            }
+            return Err(denied);
+        }
+
+        self.waiters.retain(|w| !(w.file == *file && w.range.owner == *owner));
+        let ranges = self.files.entry(*file).or_default();
+        remove_range(ranges, owner, offset, end);
+        ranges.push(range);
+        merge_ranges(ranges, owner);
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, file: &FileKey, owner: &LockOwnerKey, offset: u64, end: u64) {
+        if let Some(ranges) = self.files.get_mut(file) {
+            remove_range(ranges, owner, offset, end);
+            if ranges.is_empty() {
+                self.files.remove(file);
+            }
+        }
+    }
+
+    pub fn has_locks(&self, file: &FileKey, owner: &LockOwnerKey) -> bool {
+        self.files
+            .get(file)
+            .is_some_and(|ranges| ranges.iter().any(|r| r.owner == *owner))
+    }
+
+    pub fn owner_has_locks(&self, owner: &LockOwnerKey) -> bool {
+        self.files.values().flatten().any(|r| r.owner == *owner)
+    }
+
+    // Drop every lock held by a lock-owner on a file, and its wait for one.
+    pub fn release(&mut self, file: &FileKey, owner: &LockOwnerKey) {
+        self.unlock(file, owner, 0, u64::MAX);
+        self.waiters.retain(|w| !(w.file == *file && w.range.owner == *owner));
+    }
+
+    pub fn release_client(&mut self, clientid: u64) {
+        for ranges in self.files.values_mut() {
+            ranges.retain(|r| r.owner.0 != clientid);
+        }
+        self.files.retain(|_, ranges| !ranges.is_empty());
+        self.waiters.retain(|w| w.range.owner.0 != clientid);
+    }
+}
+
+// Cut [offset, end) out of an owner's ranges, splitting any that extend
+// past it on both sides.
+fn remove_range(ranges: &mut Vec<ByteRange>, owner: &LockOwnerKey, offset: u64, end: u64) {
+    let mut kept = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        if range.owner != *owner || !range.overlaps(offset, end) {
+            kept.push(range);
+            continue;
+        }
+        if range.offset < offset {
+            kept.push(ByteRange {
+                end: offset,
+                ..range.clone()
+            });
+        }
+        if range.end > end {
+            kept.push(ByteRange { offset: end, ..range });
+        }
+    }
+    *ranges = kept;
+}
+
+// Join an owner's adjacent ranges of the same type.
+fn merge_ranges(ranges: &mut Vec<ByteRange>, owner: &LockOwnerKey) {
+    let (mut own, mut merged): (Vec<_>, Vec<_>) = ranges.drain(..).partition(|r| r.owner == *owner);
+    own.sort_by_key(|r| r.offset);
+    for range in own {
+        match merged.last_mut() {
+            Some(last) if last.owner == *owner && last.write == range.write && last.end == range.offset => {
+                last.end = range.end;
+            }
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
@@ -1,17 +1,13 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use log::{info, warn};
 use std::path::PathBuf;
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use bytes::BytesMut;
 
-mod protocol;
-mod server;
-mod rpc;
-
-use crate::server::NfsServer;
-use crate::protocol::{NFS_VERSION, NFS_PROGRAM};
-use crate::rpc::{RpcMsg, RpcMsgBody, read_rpc_message};
+use nfs4::connection::{self, ConnectionLimits};
+use nfs4::export::{Export, ExportOptions};
+use nfs4::filehandle::HandleIndex;
+use nfs4::idmap::{default_domain, IdMap};
+use nfs4::server::NfsServer;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -19,33 +15,64 @@ async fn main() -> Result<()> {
     info!("Starting NFSv4 server...");
 
     let bind_addr = "127.0.0.1:2049";
-    let export_path = PathBuf::from("/tmp/nfs_root");
 
     // Ensure we have root privileges (NFS typically requires port 2049)
-    if !sudo::check() {
+    if sudo::check() != sudo::RunningAs::Root {
         warn!("NFSv4 server typically requires root privileges to bind to port 2049");
         warn!("Please run with sudo");
         std::process::exit(1);
     }
 
-    // Create export directory if it doesn't exist
-    std::fs::create_dir_all(&export_path)?;
+    let options = parse_args(std::env::args().skip(1))?;
+
+    // Without --export, serve /tmp/nfs_root as the whole tree, creating it
+    // if it doesn't exist
+    let exports = if options.exports.is_empty() {
+        let export_path = PathBuf::from("/tmp/nfs_root");
+        std::fs::create_dir_all(&export_path)?;
+        vec![Export::new("/", export_path)]
+    } else {
+        options.exports
+    };
+    let exports: Vec<Export> = exports
+        .into_iter()
+        .map(|mut export| {
+            export.options.devices |= options.devices;
+            export
+        })
+        .collect();
+    for export in &exports {
+        info!("Exporting {:?} as {} ({:?})", export.dir, export.path, export.options);
+    }
+
+    if let Some(dir) = options.handles.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let handles = HandleIndex::open(&options.handles)
+        .with_context(|| format!("Cannot open handle index {:?}", options.handles))?;
 
     // Initialize NFS server
-    let nfs_server = NfsServer::new(export_path.clone());
+    let nfs_server = NfsServer::from_exports(exports)?
+        .with_idmap(options.idmap)
+        .with_handle_index(handles);
+    nfs_server.spawn_lease_reaper();
+    let limits = options.limits;
 
     info!("Binding to {}", bind_addr);
     let listener = TcpListener::bind(bind_addr).await?;
     info!("NFSv4 server listening on {}", bind_addr);
-    info!("Exporting directory: {:?}", export_path);
 
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
+                // Dropping the socket closes the connection.
+                let Some(server) = nfs_server.for_client(addr.ip()) else {
+                    warn!("Refusing connection from {}: no export is served to it", addr);
+                    continue;
+                };
                 info!("New connection from: {}", addr);
-                let server = nfs_server.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, server).await {
+                    if let Err(e) = connection::serve(socket, server, addr.ip(), limits).await {
                         warn!("Error handling client: {}", e);
                     }
                 });
@@ -57,46 +84,91 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn handle_client(mut socket: tokio::net::TcpStream, server: NfsServer) -> Result<()> {
-    let mut buf = BytesMut::with_capacity(4096);
-
-    loop {
-        // Read data into buffer
-        let n = socket.read_buf(&mut buf).await?;
-        if n == 0 {
-            // Connection closed
-            return Ok(());
-        }
-
-        // Process RPC messages
-        while let Some(msg_result) = read_rpc_message(&mut buf) {
-            let msg = msg_result?;
-
-            match msg.body {
-                RpcMsgBody::Call(call) if call.prog == NFS_PROGRAM && call.prog_vers == NFS_VERSION => {
-                    // Decode and handle the NFS request
-                    let request = serde_xdr::from_bytes(&call.data)?;
-                    let response = server.handle_compound(request).await?;
-
-                    // Encode and send the response
-                    let response_data = serde_xdr::to_bytes(&response)?;
-                    let response_msg = RpcMsg::new_success_reply(msg.xid, response_data);
-                    let encoded = response_msg.encode()?;
-
-                    let msg_len = (encoded.len() as u32).to_be_bytes();
-                    socket.write_all(&msg_len).await?;
-                    socket.write_all(&encoded).await?;
-                }
-                _ => {
-                    // Send error response for unsupported operations
-                    let response_msg = RpcMsg::new_prog_mismatch_reply(msg.xid);
-                    let encoded = response_msg.encode()?;
+struct Options {
+    idmap: IdMap,
+    exports: Vec<Export>,
+    devices: bool,
+    handles: PathBuf,
+    limits: ConnectionLimits,
+}
 
-                    let msg_len = (encoded.len() as u32).to_be_bytes();
-                    socket.write_all(&msg_len).await?;
-                    socket.write_all(&encoded).await?;
+// Owner name mapping options:
+//   --domain DOMAIN    NFSv4 domain of owner names (default: the host's DNS domain)
+//   --idmap FILE       map names with FILE instead of /etc/passwd and /etc/group
+//   --numeric-ids      send owners as numeric ids
+// Exports:
+//   --export PATH=DIR[,OPTION...]
+//                      serve DIR at PATH in the pseudo-filesystem, with
+//                      options as in exports(5): ro, rw, root_squash,
+//                      no_root_squash, all_squash, anonuid=UID, anongid=GID,
+//                      client=CIDR (repeatable) and devices; may be repeated
+//                      (default: /tmp/nfs_root as the whole tree)
+//   --devices          let clients create block and character devices on
+//                      every export
+//   --handles FILE     where to keep the paths of files handles were given
+//                      out for, so that they still work after a restart
+//                      (default: /var/lib/nfs4/handles)
+// Connections:
+//   --max-record BYTES largest RPC record a client may send; larger ones
+//                      close the connection (default: 1114112)
+//   --max-in-flight N  calls each connection may have in progress at once
+//                      (default: 64)
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut domain = None;
+    let mut file = None;
+    let mut numeric = false;
+    let mut exports = Vec::new();
+    let mut devices = false;
+    let mut handles = PathBuf::from("/var/lib/nfs4/handles");
+    let mut limits = ConnectionLimits::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--domain" | "--idmap" | "--export" | "--handles" | "--max-record" | "--max-in-flight" => {
+                let Some(value) = args.next() else {
+                    bail!("{} needs a value", arg);
+                };
+                if arg == "--domain" {
+                    domain = Some(value);
+                } else if arg == "--idmap" {
+                    file = Some(PathBuf::from(value));
+                } else if arg == "--handles" {
+                    handles = PathBuf::from(value);
+                } else if arg == "--max-record" {
+                    limits.max_record = value.parse().with_context(|| format!("--max-record {:?}", value))?;
+                } else if arg == "--max-in-flight" {
+                    limits.max_in_flight = value.parse().with_context(|| format!("--max-in-flight {:?}", value))?;
+                } else {
+                    let (export, list) = value.split_once(',').unwrap_or((&value, ""));
+                    let Some((path, dir)) = export.split_once('=') else {
+                        bail!("--export takes PATH=DIR[,OPTION...], not {:?}", value);
+                    };
+                    let options = ExportOptions::parse(list)?;
+                    exports.push(Export::new(path, PathBuf::from(dir)).with_options(options));
                 }
             }
+            "--numeric-ids" => numeric = true,
+            "--devices" => devices = true,
+            _ => bail!("unknown argument {:?}", arg),
         }
     }
+
+    let idmap = if numeric {
+        info!("Sending owners as numeric ids");
+        IdMap::numeric()
+    } else {
+        let domain = domain.unwrap_or_else(default_domain);
+        let idmap = match file {
+            Some(file) => IdMap::from_file(&file, &domain)?,
+            None => IdMap::from_system(&domain)?,
+        };
+        info!("Mapping owner names in domain {}", idmap.domain());
+        idmap
+    };
+    Ok(Options {
+        idmap,
+        exports,
+        devices,
+        handles,
+        limits,
+    })
 }
@@ -1,8 +1,11 @@
-use serde::{Deserialize, Serialize};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::xdr::*;
 
 // NFSv4 constants
 pub const NFS_VERSION: u32 = 4;
 pub const NFS_PROGRAM: u32 = 100003;
+pub const NFS4_FHSIZE: usize = 128;
 
 // NFSv4 procedures
 #[derive(Debug, Clone, Copy)]
@@ -11,6 +14,75 @@ pub enum NfsProcedure {
     Compound = 1,
 }
 
+// Callback program procedures and operations (RFC 7530 section 17). The
+// program number is whatever the client registered with SETCLIENTID.
+pub const NFS4_CALLBACK_VERSION: u32 = 1;
+pub const CB_NULL: u32 = 0;
+pub const CB_COMPOUND: u32 = 1;
+pub const OP_CB_GETATTR: u32 = 3;
+pub const OP_CB_RECALL: u32 = 4;
+
+// Operation numbers (RFC 7530 section 16)
+pub const OP_ACCESS: u32 = 3;
+pub const OP_CLOSE: u32 = 4;
+pub const OP_COMMIT: u32 = 5;
+pub const OP_CREATE: u32 = 6;
+pub const OP_DELEGPURGE: u32 = 7;
+pub const OP_DELEGRETURN: u32 = 8;
+pub const OP_GETATTR: u32 = 9;
+pub const OP_GETFH: u32 = 10;
+pub const OP_LINK: u32 = 11;
+pub const OP_LOCK: u32 = 12;
+pub const OP_LOCKT: u32 = 13;
+pub const OP_LOCKU: u32 = 14;
+pub const OP_LOOKUP: u32 = 15;
+pub const OP_LOOKUPP: u32 = 16;
+pub const OP_NVERIFY: u32 = 17;
+pub const OP_OPEN: u32 = 18;
+pub const OP_OPENATTR: u32 = 19;
+pub const OP_OPEN_CONFIRM: u32 = 20;
+pub const OP_OPEN_DOWNGRADE: u32 = 21;
+pub const OP_PUTFH: u32 = 22;
+pub const OP_PUTPUBFH: u32 = 23;
+pub const OP_PUTROOTFH: u32 = 24;
+pub const OP_READ: u32 = 25;
+pub const OP_READDIR: u32 = 26;
+pub const OP_READLINK: u32 = 27;
+pub const OP_REMOVE: u32 = 28;
+pub const OP_RENAME: u32 = 29;
+pub const OP_RENEW: u32 = 30;
+pub const OP_RESTOREFH: u32 = 31;
+pub const OP_SAVEFH: u32 = 32;
+pub const OP_SECINFO: u32 = 33;
+pub const OP_SETATTR: u32 = 34;
+pub const OP_SETCLIENTID: u32 = 35;
+pub const OP_SETCLIENTID_CONFIRM: u32 = 36;
+pub const OP_VERIFY: u32 = 37;
+pub const OP_WRITE: u32 = 38;
+pub const OP_RELEASE_LOCKOWNER: u32 = 39;
+
+// Operations added by NFSv4.1 (RFC 8881 section 18)
+pub const OP_BACKCHANNEL_CTL: u32 = 40;
+pub const OP_BIND_CONN_TO_SESSION: u32 = 41;
+pub const OP_EXCHANGE_ID: u32 = 42;
+pub const OP_CREATE_SESSION: u32 = 43;
+pub const OP_DESTROY_SESSION: u32 = 44;
+pub const OP_FREE_STATEID: u32 = 45;
+pub const OP_GET_DIR_DELEGATION: u32 = 46;
+pub const OP_GETDEVICEINFO: u32 = 47;
+pub const OP_GETDEVICELIST: u32 = 48;
+pub const OP_LAYOUTCOMMIT: u32 = 49;
+pub const OP_LAYOUTGET: u32 = 50;
+pub const OP_LAYOUTRETURN: u32 = 51;
+pub const OP_SECINFO_NO_NAME: u32 = 52;
+pub const OP_SEQUENCE: u32 = 53;
+pub const OP_SET_SSV: u32 = 54;
+pub const OP_TEST_STATEID: u32 = 55;
+pub const OP_WANT_DELEGATION: u32 = 56;
+pub const OP_DESTROY_CLIENTID: u32 = 57;
+pub const OP_RECLAIM_COMPLETE: u32 = 58;
+pub const OP_ILLEGAL: u32 = 10044;
+
 // File types
 pub const NF4REG: u32 = 1; // Regular file
 pub const NF4DIR: u32 = 2; // Directory
@@ -30,115 +102,602 @@ pub const ACCESS4_EXTEND: u32 = 0x00000008;
 pub const ACCESS4_DELETE: u32 = 0x00000010;
 pub const ACCESS4_EXECUTE: u32 = 0x00000020;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// OPEN share access and deny modes
+pub const OPEN4_SHARE_ACCESS_READ: u32 = 0x00000001;
+pub const OPEN4_SHARE_ACCESS_WRITE: u32 = 0x00000002;
+pub const OPEN4_SHARE_ACCESS_BOTH: u32 = 0x00000003;
+pub const OPEN4_SHARE_DENY_NONE: u32 = 0x00000000;
+pub const OPEN4_SHARE_DENY_READ: u32 = 0x00000001;
+pub const OPEN4_SHARE_DENY_WRITE: u32 = 0x00000002;
+pub const OPEN4_SHARE_DENY_BOTH: u32 = 0x00000003;
+
+// OPEN result flags
+pub const OPEN4_RESULT_CONFIRM: u32 = 0x00000002;
+pub const OPEN4_RESULT_LOCKTYPE_POSIX: u32 = 0x00000004;
+
+// openflag4 / createmode4 / open_claim_type4 / open_delegation_type4
+pub const OPEN4_NOCREATE: u32 = 0;
+pub const OPEN4_CREATE: u32 = 1;
+pub const UNCHECKED4: u32 = 0;
+pub const GUARDED4: u32 = 1;
+pub const EXCLUSIVE4: u32 = 2;
+pub const EXCLUSIVE4_1: u32 = 3;
+pub const CLAIM_NULL: u32 = 0;
+pub const CLAIM_PREVIOUS: u32 = 1;
+pub const CLAIM_DELEGATE_CUR: u32 = 2;
+pub const CLAIM_DELEGATE_PREV: u32 = 3;
+pub const CLAIM_FH: u32 = 4;
+pub const CLAIM_DELEG_CUR_FH: u32 = 5;
+pub const CLAIM_DELEG_PREV_FH: u32 = 6;
+pub const OPEN_DELEGATE_NONE: u32 = 0;
+pub const OPEN_DELEGATE_READ: u32 = 1;
+pub const OPEN_DELEGATE_WRITE: u32 = 2;
+
+// limit_by4
+pub const NFS_LIMIT_SIZE: u32 = 1;
+pub const NFS_LIMIT_BLOCKS: u32 = 2;
+
+// acetype4
+pub const ACE4_ACCESS_ALLOWED_ACE_TYPE: u32 = 0;
+pub const ACE4_ACCESS_DENIED_ACE_TYPE: u32 = 1;
+
+// EXCHANGE_ID flags
+pub const EXCHGID4_FLAG_SUPP_MOVED_REFER: u32 = 0x00000001;
+pub const EXCHGID4_FLAG_SUPP_MOVED_MIGR: u32 = 0x00000002;
+pub const EXCHGID4_FLAG_BIND_PRINC_STATEID: u32 = 0x00000100;
+pub const EXCHGID4_FLAG_USE_NON_PNFS: u32 = 0x00010000;
+pub const EXCHGID4_FLAG_USE_PNFS_MDS: u32 = 0x00020000;
+pub const EXCHGID4_FLAG_USE_PNFS_DS: u32 = 0x00040000;
+pub const EXCHGID4_FLAG_UPD_CONFIRMED_REC_A: u32 = 0x40000000;
+pub const EXCHGID4_FLAG_CONFIRMED_R: u32 = 0x80000000;
+
+// state_protect_how4
+pub const SP4_NONE: u32 = 0;
+pub const SP4_MACH_CRED: u32 = 1;
+pub const SP4_SSV: u32 = 2;
+
+// CREATE_SESSION flags
+pub const CREATE_SESSION4_FLAG_PERSIST: u32 = 0x00000001;
+pub const CREATE_SESSION4_FLAG_CONN_BACK_CHAN: u32 = 0x00000002;
+pub const CREATE_SESSION4_FLAG_CONN_RDMA: u32 = 0x00000004;
+
+// Callback security flavors
+pub const AUTH_NONE: u32 = 0;
+pub const AUTH_SYS: u32 = 1;
+pub const RPCSEC_GSS: u32 = 6;
+
+// nfs_lock_type4. The W variants ask for a blocking lock.
+pub const READ_LT: u32 = 1;
+pub const WRITE_LT: u32 = 2;
+pub const READW_LT: u32 = 3;
+pub const WRITEW_LT: u32 = 4;
+
+// stable_how4
+pub const UNSTABLE4: u32 = 0;
+pub const DATA_SYNC4: u32 = 1;
+pub const FILE_SYNC4: u32 = 2;
+
+// Attribute numbers
+pub const FATTR4_SUPPORTED_ATTRS: u32 = 0;
+pub const FATTR4_TYPE: u32 = 1;
+pub const FATTR4_FH_EXPIRE_TYPE: u32 = 2;
+pub const FATTR4_CHANGE: u32 = 3;
+pub const FATTR4_SIZE: u32 = 4;
+pub const FATTR4_LINK_SUPPORT: u32 = 5;
+pub const FATTR4_SYMLINK_SUPPORT: u32 = 6;
+pub const FATTR4_NAMED_ATTR: u32 = 7;
+pub const FATTR4_FSID: u32 = 8;
+pub const FATTR4_UNIQUE_HANDLES: u32 = 9;
+pub const FATTR4_LEASE_TIME: u32 = 10;
+pub const FATTR4_RDATTR_ERROR: u32 = 11;
+pub const FATTR4_ACL: u32 = 12;
+pub const FATTR4_ACLSUPPORT: u32 = 13;
+pub const FATTR4_ARCHIVE: u32 = 14;
+pub const FATTR4_CANSETTIME: u32 = 15;
+pub const FATTR4_CASE_INSENSITIVE: u32 = 16;
+pub const FATTR4_CASE_PRESERVING: u32 = 17;
+pub const FATTR4_CHOWN_RESTRICTED: u32 = 18;
+pub const FATTR4_FILEHANDLE: u32 = 19;
+pub const FATTR4_FILEID: u32 = 20;
+pub const FATTR4_FILES_AVAIL: u32 = 21;
+pub const FATTR4_FILES_FREE: u32 = 22;
+pub const FATTR4_FILES_TOTAL: u32 = 23;
+pub const FATTR4_FS_LOCATIONS: u32 = 24;
+pub const FATTR4_HIDDEN: u32 = 25;
+pub const FATTR4_HOMOGENEOUS: u32 = 26;
+pub const FATTR4_MAXFILESIZE: u32 = 27;
+pub const FATTR4_MAXLINK: u32 = 28;
+pub const FATTR4_MAXNAME: u32 = 29;
+pub const FATTR4_MAXREAD: u32 = 30;
+pub const FATTR4_MAXWRITE: u32 = 31;
+pub const FATTR4_MIMETYPE: u32 = 32;
+pub const FATTR4_MODE: u32 = 33;
+pub const FATTR4_NO_TRUNC: u32 = 34;
+pub const FATTR4_NUMLINKS: u32 = 35;
+pub const FATTR4_OWNER: u32 = 36;
+pub const FATTR4_OWNER_GROUP: u32 = 37;
+pub const FATTR4_QUOTA_AVAIL_HARD: u32 = 38;
+pub const FATTR4_QUOTA_AVAIL_SOFT: u32 = 39;
+pub const FATTR4_QUOTA_USED: u32 = 40;
+pub const FATTR4_RAWDEV: u32 = 41;
+pub const FATTR4_SPACE_AVAIL: u32 = 42;
+pub const FATTR4_SPACE_FREE: u32 = 43;
+pub const FATTR4_SPACE_TOTAL: u32 = 44;
+pub const FATTR4_SPACE_USED: u32 = 45;
+pub const FATTR4_SYSTEM: u32 = 46;
+pub const FATTR4_TIME_ACCESS: u32 = 47;
+pub const FATTR4_TIME_ACCESS_SET: u32 = 48;
+pub const FATTR4_TIME_BACKUP: u32 = 49;
+pub const FATTR4_TIME_CREATE: u32 = 50;
+pub const FATTR4_TIME_DELTA: u32 = 51;
+pub const FATTR4_TIME_METADATA: u32 = 52;
+pub const FATTR4_TIME_MODIFY: u32 = 53;
+pub const FATTR4_TIME_MODIFY_SET: u32 = 54;
+pub const FATTR4_MOUNTED_ON_FILEID: u32 = 55;
+
+// fh_expire_type values
+pub const FH4_PERSISTENT: u32 = 0x00;
+pub const FH4_NOEXPIRE_WITH_OPEN: u32 = 0x01;
+pub const FH4_VOLATILE_ANY: u32 = 0x02;
+pub const FH4_VOL_MIGRATION: u32 = 0x04;
+pub const FH4_VOL_RENAME: u32 = 0x08;
+
+// time_how4
+pub const SET_TO_SERVER_TIME4: u32 = 0;
+pub const SET_TO_CLIENT_TIME4: u32 = 1;
+
+// Helpers for bitmap4 values
+pub fn bitmap_set(bitmap: &mut Vec<u32>, bit: u32) {
+    let word = (bit / 32) as usize;
+    if bitmap.len() <= word {
+        bitmap.resize(word + 1, 0);
+    }
+    bitmap[word] |= 1 << (bit % 32);
+}
+
+pub fn bitmap_isset(bitmap: &[u32], bit: u32) -> bool {
+    bitmap
+        .get((bit / 32) as usize)
+        .is_some_and(|word| word & (1 << (bit % 32)) != 0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct NfsFileHandle {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct NfsTime {
     pub seconds: u64,
     pub nseconds: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NfsFileAttributes {
-    pub type_: u32,
-    pub mode: u32,
-    pub size: u64,
-    pub space_used: u64,
-    pub time_access: NfsTime,
-    pub time_modify: NfsTime,
-    pub owner: String,
-    pub group: String,
+// An encoded fattr4: the attribute bitmap plus the packed attribute values
+// in ascending attribute number order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fattr4 {
+    pub attrmask: Vec<u32>,
+    pub attr_vals: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangeInfo {
+    pub atomic: bool,
+    pub before: u64,
+    pub after: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SpecData {
+    pub major: u32,
+    pub minor: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum NfsOperation {
     Access(AccessOperation),
     Close(CloseOperation),
     Commit(CommitOperation),
     Create(CreateOperation),
+    CreateSession(CreateSessionOperation),
+    DelegReturn(DelegReturnOperation),
+    DestroyClientId(DestroyClientIdOperation),
+    DestroySession(DestroySessionOperation),
+    ExchangeId(ExchangeIdOperation),
     GetAttr(GetAttrOperation),
     GetFh(GetFhOperation),
+    Link(LinkOperation),
+    Lock(LockOperation),
+    Lockt(LocktOperation),
+    Locku(LockuOperation),
     Lookup(LookupOperation),
     Lookupp(LookuppOperation),
     Open(OpenOperation),
     OpenConfirm(OpenConfirmOperation),
+    OpenDowngrade(OpenDowngradeOperation),
+    PutFh(PutFhOperation),
+    PutPubFh(PutPubFhOperation),
+    PutRootFh(PutRootFhOperation),
     Read(ReadOperation),
+    ReadDir(ReadDirOperation),
+    ReadLink(ReadLinkOperation),
+    ReclaimComplete(ReclaimCompleteOperation),
+    ReleaseLockOwner(ReleaseLockOwnerOperation),
+    Remove(RemoveOperation),
+    Rename(RenameOperation),
+    Renew(RenewOperation),
+    RestoreFh(RestoreFhOperation),
+    SaveFh(SaveFhOperation),
+    Sequence(SequenceOperation),
+    SetAttr(SetAttrOperation),
+    SetClientId(SetClientIdOperation),
+    SetClientIdConfirm(SetClientIdConfirmOperation),
     Write(WriteOperation),
+    // An operation the codec has no argument layout for. Decoding stops
+    // here since the remaining arguments cannot be located.
+    Unsupported(u32),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AccessOperation {
     pub access: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CloseOperation {
     pub seqid: u32,
     pub open_stateid: [u8; 16],
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CommitOperation {
     pub offset: u64,
     pub count: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateOperation {
     pub object_type: u32,
+    pub link_data: Option<String>,   // NF4LNK only
+    pub spec_data: Option<SpecData>, // NF4BLK and NF4CHR only
     pub object_name: String,
-    pub attributes: NfsFileAttributes,
+    pub attributes: Fattr4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateSessionOperation {
+    pub clientid: u64,
+    pub sequence: u32,
+    pub flags: u32,
+    pub fore_chan_attrs: ChannelAttrs,
+    pub back_chan_attrs: ChannelAttrs,
+    pub cb_program: u32,
+    pub sec_parms: Vec<CallbackSecParms>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelAttrs {
+    pub header_pad_size: u32,
+    pub max_request_size: u32,
+    pub max_response_size: u32,
+    pub max_response_size_cached: u32,
+    pub max_operations: u32,
+    pub max_requests: u32,
+    pub rdma_ird: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallbackSecParms {
+    AuthNone,
+    AuthSys(AuthSysParms),
+    RpcSecGss {
+        service: u32,
+        handle_from_server: Vec<u8>,
+        handle_from_client: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthSysParms {
+    pub stamp: u32,
+    pub machine_name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gids: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestroyClientIdOperation {
+    pub clientid: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestroySessionOperation {
+    pub sessionid: [u8; 16],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeIdOperation {
+    pub owner: ClientOwner,
+    pub flags: u32,
+    pub state_protect: StateProtect,
+    pub impl_id: Option<NfsImplId>,
+}
+
+// client_owner4, the NFSv4.1 counterpart of nfs_client_id4.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientOwner {
+    pub verifier: [u8; 8],
+    pub ownerid: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateProtect {
+    None,
+    MachCred(StateProtectOps),
+    Ssv(SsvSpParms),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateProtectOps {
+    pub must_enforce: Vec<u32>,
+    pub must_allow: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsvSpParms {
+    pub ops: StateProtectOps,
+    pub hash_algs: Vec<Vec<u8>>,
+    pub encr_algs: Vec<Vec<u8>>,
+    pub window: u32,
+    pub num_gss_handles: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NfsImplId {
+    pub domain: String,
+    pub name: String,
+    pub date: NfsTime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct GetAttrOperation {
     pub attr_request: Vec<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct GetFhOperation;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockOperation {
+    pub locktype: u32,
+    pub reclaim: bool,
+    pub offset: u64,
+    pub length: u64,
+    pub locker: Locker,
+}
+
+// locker4: the first lock of a lock-owner on a file is taken under an open
+// stateid, later ones under the lock stateid it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Locker {
+    New {
+        open_seqid: u32,
+        open_stateid: [u8; 16],
+        lock_seqid: u32,
+        lock_owner: LockOwner,
+    },
+    Existing {
+        lock_stateid: [u8; 16],
+        lock_seqid: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockOwner {
+    pub clientid: u64,
+    pub owner: Vec<u8>,
+}
+
+// LINK makes a new name in the current filehandle's directory for the
+// file of the saved filehandle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkOperation {
+    pub new_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocktOperation {
+    pub locktype: u32,
+    pub offset: u64,
+    pub length: u64,
+    pub owner: LockOwner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockuOperation {
+    pub locktype: u32,
+    pub seqid: u32,
+    pub lock_stateid: [u8; 16],
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct LookupOperation {
     pub object_name: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LookuppOperation;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct OpenOperation {
     pub seqid: u32,
     pub share_access: u32,
     pub share_deny: u32,
+    pub clientid: u64,
     pub owner: Vec<u8>,
+    pub open_how: OpenHow,
     pub open_claim: OpenClaim,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpenHow {
+    NoCreate,
+    Create(CreateHow),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CreateHow {
+    Unchecked(Fattr4),
+    Guarded(Fattr4),
+    Exclusive([u8; 8]),
+    Exclusive41([u8; 8], Fattr4), // NFSv4.1 only
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum OpenClaim {
     Null(String),
-    Previous(String),
-    Delegate(String),
+    Previous(u32),
+    Delegate([u8; 16], String),
+    DelegatePrev(String),
+    // NFSv4.1 claims on the current filehandle rather than a name.
+    Fh,
+    DelegateCurFh([u8; 16]),
+    DelegatePrevFh,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenDowngradeOperation {
+    pub open_stateid: [u8; 16],
+    pub seqid: u32,
+    pub share_access: u32,
+    pub share_deny: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct OpenConfirmOperation {
     pub open_stateid: [u8; 16],
     pub seqid: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutFhOperation {
+    pub object: NfsFileHandle,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutPubFhOperation;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutRootFhOperation;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReleaseLockOwnerOperation {
+    pub lock_owner: LockOwner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadLinkOperation;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReclaimCompleteOperation {
+    pub one_fs: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoveOperation {
+    pub target: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenameOperation {
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelegReturnOperation {
+    pub stateid: [u8; 16],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenewOperation {
+    pub clientid: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreFhOperation;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveFhOperation;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceOperation {
+    pub sessionid: [u8; 16],
+    pub sequenceid: u32,
+    pub slotid: u32,
+    pub highest_slotid: u32,
+    pub cache_this: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetAttrOperation {
+    pub stateid: [u8; 16],
+    pub attributes: Fattr4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetClientIdOperation {
+    pub client: NfsClientId,
+    pub callback: CbClient,
+    pub callback_ident: u32,
+}
+
+// nfs_client_id4: the verifier changes each time the client reboots, the
+// id stays the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NfsClientId {
+    pub verifier: [u8; 8],
+    pub id: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbClient {
+    pub program: u32,
+    pub location: NetAddr,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetAddr {
+    pub netid: String,
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetClientIdConfirmOperation {
+    pub clientid: u64,
+    pub confirm: [u8; 8],
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ReadOperation {
     pub stateid: [u8; 16],
     pub offset: u64,
     pub count: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadDirOperation {
+    pub cookie: u64,
+    pub cookieverf: [u8; 8],
+    pub dircount: u32,
+    pub maxcount: u32,
+    pub attr_request: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct WriteOperation {
     pub stateid: [u8; 16],
     pub offset: u64,
@@ -146,48 +705,1812 @@ pub struct WriteOperation {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CompoundRequest {
     pub tag: String,
     pub minor_version: u32,
     pub operations: Vec<NfsOperation>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CompoundResponse {
     pub tag: String,
     pub status: NfsStatus,
     pub results: Vec<OperationResult>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum NfsStatus {
     Ok = 0,
-    Error = 1,
+    Perm = 1,
+    NoEnt = 2,
+    IoError = 5,
+    NxIo = 6,
+    Access = 13,
+    Exist = 17,
+    XDev = 18,
+    NotDir = 20,
+    IsDir = 21,
+    Inval = 22,
+    FBig = 27,
+    NoSpace = 28,
+    RoFs = 30,
+    MLink = 31,
+    NameTooLong = 63,
+    NotEmpty = 66,
+    DQuot = 69,
+    StaleFileHandle = 70,
     BadHandle = 10001,
-    BadType = 10002,
-    NoEnt = 10003,
-    IoError = 10004,
-    NoSpace = 10005,
-    BadName = 10006,
-    RoFs = 10007,
-    StaleFileHandle = 10008,
-    BadStateid = 10009,
-    BadSeqid = 10010,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    BadCookie = 10003,
+    NotSupp = 10004,
+    TooSmall = 10005,
+    ServerFault = 10006,
+    BadType = 10007,
+    Delay = 10008,
+    Same = 10009,
+    Denied = 10010,
+    Expired = 10011,
+    Locked = 10012,
+    Grace = 10013,
+    FhExpired = 10014,
+    ShareDenied = 10015,
+    WrongSec = 10016,
+    ClidInUse = 10017,
+    Resource = 10018,
+    Moved = 10019,
+    NoFileHandle = 10020,
+    MinorVersMismatch = 10021,
+    StaleClientid = 10022,
+    StaleStateid = 10023,
+    OldStateid = 10024,
+    BadStateid = 10025,
+    BadSeqid = 10026,
+    NotSame = 10027,
+    LockRange = 10028,
+    Symlink = 10029,
+    RestoreFh = 10030,
+    LeaseMoved = 10031,
+    AttrNotSupp = 10032,
+    NoGrace = 10033,
+    ReclaimBad = 10034,
+    ReclaimConflict = 10035,
+    BadXdr = 10036,
+    LocksHeld = 10037,
+    OpenMode = 10038,
+    BadOwner = 10039,
+    BadChar = 10040,
+    BadName = 10041,
+    BadRange = 10042,
+    LockNotSupp = 10043,
+    OpIllegal = 10044,
+    Deadlock = 10045,
+    FileOpen = 10046,
+    AdminRevoked = 10047,
+    CbPathDown = 10048,
+    // NFSv4.1 (RFC 8881 section 15.1)
+    BadIoMode = 10049,
+    BadLayout = 10050,
+    BadSessionDigest = 10051,
+    BadSession = 10052,
+    BadSlot = 10053,
+    CompleteAlready = 10054,
+    ConnNotBoundToSession = 10055,
+    DelegAlreadyWanted = 10056,
+    BackChanBusy = 10057,
+    LayoutTryLater = 10058,
+    LayoutUnavailable = 10059,
+    NoMatchingLayout = 10060,
+    RecallConflict = 10061,
+    UnknownLayoutType = 10062,
+    SeqMisordered = 10063,
+    SequencePos = 10064,
+    ReqTooBig = 10065,
+    RepTooBig = 10066,
+    RepTooBigToCache = 10067,
+    RetryUncachedRep = 10068,
+    UnsafeCompound = 10069,
+    TooManyOps = 10070,
+    OpNotInSession = 10071,
+    HashAlgUnsupp = 10072,
+    ClientidBusy = 10074,
+    PnfsIoHole = 10075,
+    SeqFalseRetry = 10076,
+    BadHighSlot = 10077,
+    DeadSession = 10078,
+    EncrAlgUnsupp = 10079,
+    PnfsNoLayout = 10080,
+    NotOnlyOp = 10081,
+    WrongCred = 10082,
+    WrongType = 10083,
+    DirDelegUnavail = 10084,
+    RejectDeleg = 10085,
+    ReturnConflict = 10086,
+    DelegRevoked = 10087,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct OperationResult {
+    pub op: u32,
     pub status: NfsStatus,
     pub result: Option<OperationData>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OperationData {
-    Access(u32),
-    GetAttr(NfsFileAttributes),
+    Access(AccessResult),
+    Close([u8; 16]), // stateid
+    Commit([u8; 8]), // write verifier
+    Create(CreateResult),
+    CreateSession(CreateSessionResult),
+    ExchangeId(ExchangeIdResult),
+    GetAttr(Fattr4),
     GetFh(NfsFileHandle),
-    Read(Vec<u8>),
-    Write(u32),
-    Open([u8; 16]), // stateid
+    Link(ChangeInfo),
+    Lock([u8; 16]),  // lock stateid
+    Locku([u8; 16]), // lock stateid
+    LockDenied(LockDenied), // LOCK or LOCKT failing with NFS4ERR_DENIED
+    Open(OpenResult),
+    OpenConfirm([u8; 16]),   // stateid
+    OpenDowngrade([u8; 16]), // stateid
+    Read(ReadResult),
+    ReadDir(ReadDirResult),
+    ReadLink(String), // link text
+    Remove(ChangeInfo),
+    Rename(RenameResult),
+    Sequence(SequenceResult),
+    SetAttr(Vec<u32>), // attrsset, returned on success and failure
+    SetClientId(SetClientIdResult),
+    ClientInUse(NetAddr), // SETCLIENTID failing with NFS4ERR_CLID_INUSE
+    Write(WriteResult),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessResult {
+    pub supported: u32,
+    pub access: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateResult {
+    pub change_info: ChangeInfo,
+    pub attrset: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateSessionResult {
+    pub sessionid: [u8; 16],
+    pub sequence: u32,
+    pub flags: u32,
+    pub fore_chan_attrs: ChannelAttrs,
+    pub back_chan_attrs: ChannelAttrs,
+}
+
+// The server only offers SP4_NONE state protection, so the result carries
+// no state_protect4_r body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeIdResult {
+    pub clientid: u64,
+    pub sequenceid: u32,
+    pub flags: u32,
+    pub server_owner: ServerOwner,
+    pub server_scope: Vec<u8>,
+    pub impl_id: Option<NfsImplId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerOwner {
+    pub minor_id: u64,
+    pub major_id: Vec<u8>,
+}
+
+// LOCK4denied: the conflicting lock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockDenied {
+    pub offset: u64,
+    pub length: u64,
+    pub locktype: u32,
+    pub owner: LockOwner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenResult {
+    pub stateid: [u8; 16],
+    pub change_info: ChangeInfo,
+    pub rflags: u32,
+    pub attrset: Vec<u32>,
+    pub delegation: OpenDelegation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpenDelegation {
+    None,
+    Read(OpenReadDelegation),
+    Write(OpenWriteDelegation),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenReadDelegation {
+    pub stateid: [u8; 16],
+    // Set when the server already knows it will recall the delegation.
+    pub recall: bool,
+    pub permissions: Nfsace4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenWriteDelegation {
+    pub stateid: [u8; 16],
+    pub recall: bool,
+    pub space_limit: SpaceLimit,
+    pub permissions: Nfsace4,
+}
+
+// nfs_space_limit4: how much a client holding a write delegation may grow
+// the file before it has to flush its writes to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpaceLimit {
+    Size(u64),
+    Blocks { num_blocks: u32, bytes_per_block: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nfsace4 {
+    pub acetype: u32,
+    pub flag: u32,
+    pub access_mask: u32,
+    pub who: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadResult {
+    pub eof: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadDirResult {
+    pub cookieverf: [u8; 8],
+    pub entries: Vec<DirEntry>,
+    pub eof: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub cookie: u64,
+    pub name: String,
+    pub attrs: Fattr4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenameResult {
+    pub source_cinfo: ChangeInfo,
+    pub target_cinfo: ChangeInfo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceResult {
+    pub sessionid: [u8; 16],
+    pub sequenceid: u32,
+    pub slotid: u32,
+    pub highest_slotid: u32,
+    pub target_highest_slotid: u32,
+    pub status_flags: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetClientIdResult {
+    pub clientid: u64,
+    pub confirm: [u8; 8],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteResult {
+    pub count: u32,
+    pub committed: u32,
+    pub verifier: [u8; 8],
+}
+
+
+impl NfsOperation {
+    pub fn opcode(&self) -> u32 {
+        match self {
+            NfsOperation::Access(_) => OP_ACCESS,
+            NfsOperation::Close(_) => OP_CLOSE,
+            NfsOperation::Commit(_) => OP_COMMIT,
+            NfsOperation::Create(_) => OP_CREATE,
+            NfsOperation::CreateSession(_) => OP_CREATE_SESSION,
+            NfsOperation::DelegReturn(_) => OP_DELEGRETURN,
+            NfsOperation::DestroyClientId(_) => OP_DESTROY_CLIENTID,
+            NfsOperation::DestroySession(_) => OP_DESTROY_SESSION,
+            NfsOperation::ExchangeId(_) => OP_EXCHANGE_ID,
+            NfsOperation::GetAttr(_) => OP_GETATTR,
+            NfsOperation::GetFh(_) => OP_GETFH,
+            NfsOperation::Link(_) => OP_LINK,
+            NfsOperation::Lock(_) => OP_LOCK,
+            NfsOperation::Lockt(_) => OP_LOCKT,
+            NfsOperation::Locku(_) => OP_LOCKU,
+            NfsOperation::Lookup(_) => OP_LOOKUP,
+            NfsOperation::Lookupp(_) => OP_LOOKUPP,
+            NfsOperation::Open(_) => OP_OPEN,
+            NfsOperation::OpenConfirm(_) => OP_OPEN_CONFIRM,
+            NfsOperation::OpenDowngrade(_) => OP_OPEN_DOWNGRADE,
+            NfsOperation::PutFh(_) => OP_PUTFH,
+            NfsOperation::PutPubFh(_) => OP_PUTPUBFH,
+            NfsOperation::PutRootFh(_) => OP_PUTROOTFH,
+            NfsOperation::Read(_) => OP_READ,
+            NfsOperation::ReadDir(_) => OP_READDIR,
+            NfsOperation::ReadLink(_) => OP_READLINK,
+            NfsOperation::ReclaimComplete(_) => OP_RECLAIM_COMPLETE,
+            NfsOperation::ReleaseLockOwner(_) => OP_RELEASE_LOCKOWNER,
+            NfsOperation::Remove(_) => OP_REMOVE,
+            NfsOperation::Rename(_) => OP_RENAME,
+            NfsOperation::Renew(_) => OP_RENEW,
+            NfsOperation::RestoreFh(_) => OP_RESTOREFH,
+            NfsOperation::SaveFh(_) => OP_SAVEFH,
+            NfsOperation::Sequence(_) => OP_SEQUENCE,
+            NfsOperation::SetAttr(_) => OP_SETATTR,
+            NfsOperation::SetClientId(_) => OP_SETCLIENTID,
+            NfsOperation::SetClientIdConfirm(_) => OP_SETCLIENTID_CONFIRM,
+            NfsOperation::Write(_) => OP_WRITE,
+            NfsOperation::Unsupported(op) => *op,
+        }
+    }
+}
+
+impl OperationResult {
+    pub fn ok(op: u32, result: Option<OperationData>) -> Self {
+        OperationResult {
+            op,
+            status: NfsStatus::Ok,
+            result,
+        }
+    }
+
+    pub fn error(op: u32, status: NfsStatus) -> Self {
+        OperationResult {
+            op,
+            status,
+            result: None,
+        }
+    }
+}
+
+impl NfsStatus {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        use NfsStatus::*;
+        let status = match value {
+            0 => Ok,
+            1 => Perm,
+            2 => NoEnt,
+            5 => IoError,
+            6 => NxIo,
+            13 => Access,
+            17 => Exist,
+            18 => XDev,
+            20 => NotDir,
+            21 => IsDir,
+            22 => Inval,
+            27 => FBig,
+            28 => NoSpace,
+            30 => RoFs,
+            31 => MLink,
+            63 => NameTooLong,
+            66 => NotEmpty,
+            69 => DQuot,
+            70 => StaleFileHandle,
+            10001 => BadHandle,
+            10003 => BadCookie,
+            10004 => NotSupp,
+            10005 => TooSmall,
+            10006 => ServerFault,
+            10007 => BadType,
+            10008 => Delay,
+            10009 => Same,
+            10010 => Denied,
+            10011 => Expired,
+            10012 => Locked,
+            10013 => Grace,
+            10014 => FhExpired,
+            10015 => ShareDenied,
+            10016 => WrongSec,
+            10017 => ClidInUse,
+            10018 => Resource,
+            10019 => Moved,
+            10020 => NoFileHandle,
+            10021 => MinorVersMismatch,
+            10022 => StaleClientid,
+            10023 => StaleStateid,
+            10024 => OldStateid,
+            10025 => BadStateid,
+            10026 => BadSeqid,
+            10027 => NotSame,
+            10028 => LockRange,
+            10029 => Symlink,
+            10030 => RestoreFh,
+            10031 => LeaseMoved,
+            10032 => AttrNotSupp,
+            10033 => NoGrace,
+            10034 => ReclaimBad,
+            10035 => ReclaimConflict,
+            10036 => BadXdr,
+            10037 => LocksHeld,
+            10038 => OpenMode,
+            10039 => BadOwner,
+            10040 => BadChar,
+            10041 => BadName,
+            10042 => BadRange,
+            10043 => LockNotSupp,
+            10044 => OpIllegal,
+            10045 => Deadlock,
+            10046 => FileOpen,
+            10047 => AdminRevoked,
+            10048 => CbPathDown,
+            10049 => BadIoMode,
+            10050 => BadLayout,
+            10051 => BadSessionDigest,
+            10052 => BadSession,
+            10053 => BadSlot,
+            10054 => CompleteAlready,
+            10055 => ConnNotBoundToSession,
+            10056 => DelegAlreadyWanted,
+            10057 => BackChanBusy,
+            10058 => LayoutTryLater,
+            10059 => LayoutUnavailable,
+            10060 => NoMatchingLayout,
+            10061 => RecallConflict,
+            10062 => UnknownLayoutType,
+            10063 => SeqMisordered,
+            10064 => SequencePos,
+            10065 => ReqTooBig,
+            10066 => RepTooBig,
+            10067 => RepTooBigToCache,
+            10068 => RetryUncachedRep,
+            10069 => UnsafeCompound,
+            10070 => TooManyOps,
+            10071 => OpNotInSession,
+            10072 => HashAlgUnsupp,
+            10074 => ClientidBusy,
+            10075 => PnfsIoHole,
+            10076 => SeqFalseRetry,
+            10077 => BadHighSlot,
+            10078 => DeadSession,
+            10079 => EncrAlgUnsupp,
+            10080 => PnfsNoLayout,
+            10081 => NotOnlyOp,
+            10082 => WrongCred,
+            10083 => WrongType,
+            10084 => DirDelegUnavail,
+            10085 => RejectDeleg,
+            10086 => ReturnConflict,
+            10087 => DelegRevoked,
+            _ => return None,
+        };
+        Some(status)
+    }
+}
+
+// COMPOUND4args / COMPOUND4res entry points
+
+impl CompoundRequest {
+    pub fn decode(data: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(data);
+        XdrDecode::decode(&mut buf)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        XdrEncode::encode(self, &mut buf);
+        buf.to_vec()
+    }
+}
+
+impl CompoundResponse {
+    pub fn decode(data: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(data);
+        XdrDecode::decode(&mut buf)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        XdrEncode::encode(self, &mut buf);
+        buf.to_vec()
+    }
+}
+
+impl XdrEncode for CompoundRequest {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_string(buf, &self.tag);
+        buf.put_u32(self.minor_version);
+        put_array(buf, &self.operations, |buf, op| op.encode(buf));
+    }
+}
+
+impl XdrDecode for CompoundRequest {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let tag = get_string(buf)?;
+        let minor_version = get_u32(buf)?;
+        let count = get_u32(buf)? as usize;
+        let mut operations = Vec::new();
+        for _ in 0..count {
+            let op = NfsOperation::decode(buf)?;
+            let unsupported = matches!(op, NfsOperation::Unsupported(_));
+            operations.push(op);
+            if unsupported {
+                break;
+            }
+        }
+        Ok(CompoundRequest {
+            tag,
+            minor_version,
+            operations,
+        })
+    }
+}
+
+impl XdrEncode for CompoundResponse {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.status as u32);
+        put_string(buf, &self.tag);
+        put_array(buf, &self.results, |buf, res| res.encode(buf));
+    }
+}
+
+impl XdrDecode for CompoundResponse {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let status = NfsStatus::decode(buf)?;
+        let tag = get_string(buf)?;
+        let results = get_array(buf, OperationResult::decode)?;
+        Ok(CompoundResponse {
+            tag,
+            status,
+            results,
+        })
+    }
+}
+
+// Common types
+
+impl XdrEncode for NfsStatus {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(*self as u32);
+    }
+}
+
+impl XdrDecode for NfsStatus {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let value = get_u32(buf)?;
+        NfsStatus::from_u32(value).ok_or(XdrError::InvalidDiscriminant { what: "nfsstat4", value })
+    }
+}
+
+impl XdrEncode for NfsFileHandle {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_opaque(buf, &self.data);
+    }
+}
+
+impl XdrDecode for NfsFileHandle {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(NfsFileHandle { data: get_opaque(buf)? })
+    }
+}
+
+impl XdrEncode for NfsTime {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.seconds);
+        buf.put_u32(self.nseconds);
+    }
+}
+
+impl XdrDecode for NfsTime {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(NfsTime {
+            seconds: get_u64(buf)?,
+            nseconds: get_u32(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for Fattr4 {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_u32_array(buf, &self.attrmask);
+        put_opaque(buf, &self.attr_vals);
+    }
+}
+
+impl XdrDecode for Fattr4 {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(Fattr4 {
+            attrmask: get_u32_array(buf)?,
+            attr_vals: get_opaque(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for ChangeInfo {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_bool(buf, self.atomic);
+        buf.put_u64(self.before);
+        buf.put_u64(self.after);
+    }
+}
+
+impl XdrDecode for ChangeInfo {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(ChangeInfo {
+            atomic: get_bool(buf)?,
+            before: get_u64(buf)?,
+            after: get_u64(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for NetAddr {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_string(buf, &self.netid);
+        put_string(buf, &self.addr);
+    }
+}
+
+impl XdrDecode for NetAddr {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(NetAddr {
+            netid: get_string(buf)?,
+            addr: get_string(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for LockOwner {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.clientid);
+        put_opaque(buf, &self.owner);
+    }
+}
+
+impl XdrDecode for LockOwner {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(LockOwner {
+            clientid: get_u64(buf)?,
+            owner: get_opaque(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for LockOperation {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.locktype);
+        put_bool(buf, self.reclaim);
+        buf.put_u64(self.offset);
+        buf.put_u64(self.length);
+        match &self.locker {
+            Locker::New {
+                open_seqid,
+                open_stateid,
+                lock_seqid,
+                lock_owner,
+            } => {
+                put_bool(buf, true);
+                buf.put_u32(*open_seqid);
+                put_fixed_opaque(buf, open_stateid);
+                buf.put_u32(*lock_seqid);
+                lock_owner.encode(buf);
+            }
+            Locker::Existing {
+                lock_stateid,
+                lock_seqid,
+            } => {
+                put_bool(buf, false);
+                put_fixed_opaque(buf, lock_stateid);
+                buf.put_u32(*lock_seqid);
+            }
+        }
+    }
+}
+
+impl XdrDecode for LockOperation {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let locktype = get_u32(buf)?;
+        let reclaim = get_bool(buf)?;
+        let offset = get_u64(buf)?;
+        let length = get_u64(buf)?;
+        let locker = if get_bool(buf)? {
+            Locker::New {
+                open_seqid: get_u32(buf)?,
+                open_stateid: get_fixed_opaque(buf)?,
+                lock_seqid: get_u32(buf)?,
+                lock_owner: LockOwner::decode(buf)?,
+            }
+        } else {
+            Locker::Existing {
+                lock_stateid: get_fixed_opaque(buf)?,
+                lock_seqid: get_u32(buf)?,
+            }
+        };
+        Ok(LockOperation {
+            locktype,
+            reclaim,
+            offset,
+            length,
+            locker,
+        })
+    }
+}
+
+impl XdrEncode for LockDenied {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.offset);
+        buf.put_u64(self.length);
+        buf.put_u32(self.locktype);
+        self.owner.encode(buf);
+    }
+}
+
+impl XdrDecode for LockDenied {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(LockDenied {
+            offset: get_u64(buf)?,
+            length: get_u64(buf)?,
+            locktype: get_u32(buf)?,
+            owner: LockOwner::decode(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for SpecData {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.major);
+        buf.put_u32(self.minor);
+    }
+}
+
+impl XdrDecode for SpecData {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(SpecData {
+            major: get_u32(buf)?,
+            minor: get_u32(buf)?,
+        })
+    }
+}
+
+// nfs_argop4
+
+impl XdrEncode for NfsOperation {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.opcode());
+        match self {
+            NfsOperation::Access(args) => buf.put_u32(args.access),
+            NfsOperation::Close(args) => {
+                buf.put_u32(args.seqid);
+                put_fixed_opaque(buf, &args.open_stateid);
+            }
+            NfsOperation::Commit(args) => {
+                buf.put_u64(args.offset);
+                buf.put_u32(args.count);
+            }
+            NfsOperation::Create(args) => {
+                buf.put_u32(args.object_type);
+                match args.object_type {
+                    NF4LNK => put_string(buf, args.link_data.as_deref().unwrap_or_default()),
+                    NF4BLK | NF4CHR => args.spec_data.unwrap_or_default().encode(buf),
+                    _ => {}
+                }
+                put_string(buf, &args.object_name);
+                args.attributes.encode(buf);
+            }
+            NfsOperation::CreateSession(args) => args.encode(buf),
+            NfsOperation::DelegReturn(args) => put_fixed_opaque(buf, &args.stateid),
+            NfsOperation::DestroyClientId(args) => buf.put_u64(args.clientid),
+            NfsOperation::DestroySession(args) => put_fixed_opaque(buf, &args.sessionid),
+            NfsOperation::ExchangeId(args) => args.encode(buf),
+            NfsOperation::GetAttr(args) => put_u32_array(buf, &args.attr_request),
+            NfsOperation::GetFh(_) => {}
+            NfsOperation::Link(args) => put_string(buf, &args.new_name),
+            NfsOperation::Lock(args) => args.encode(buf),
+            NfsOperation::Lockt(args) => {
+                buf.put_u32(args.locktype);
+                buf.put_u64(args.offset);
+                buf.put_u64(args.length);
+                args.owner.encode(buf);
+            }
+            NfsOperation::Locku(args) => {
+                buf.put_u32(args.locktype);
+                buf.put_u32(args.seqid);
+                put_fixed_opaque(buf, &args.lock_stateid);
+                buf.put_u64(args.offset);
+                buf.put_u64(args.length);
+            }
+            NfsOperation::Lookup(args) => put_string(buf, &args.object_name),
+            NfsOperation::Lookupp(_) => {}
+            NfsOperation::Open(args) => args.encode(buf),
+            NfsOperation::OpenConfirm(args) => {
+                put_fixed_opaque(buf, &args.open_stateid);
+                buf.put_u32(args.seqid);
+            }
+            NfsOperation::OpenDowngrade(args) => {
+                put_fixed_opaque(buf, &args.open_stateid);
+                buf.put_u32(args.seqid);
+                buf.put_u32(args.share_access);
+                buf.put_u32(args.share_deny);
+            }
+            NfsOperation::PutFh(args) => args.object.encode(buf),
+            NfsOperation::PutPubFh(_) | NfsOperation::PutRootFh(_) => {}
+            NfsOperation::Renew(args) => buf.put_u64(args.clientid),
+            NfsOperation::RestoreFh(_) | NfsOperation::SaveFh(_) => {}
+            NfsOperation::Sequence(args) => {
+                put_fixed_opaque(buf, &args.sessionid);
+                buf.put_u32(args.sequenceid);
+                buf.put_u32(args.slotid);
+                buf.put_u32(args.highest_slotid);
+                put_bool(buf, args.cache_this);
+            }
+            NfsOperation::SetAttr(args) => {
+                put_fixed_opaque(buf, &args.stateid);
+                args.attributes.encode(buf);
+            }
+            NfsOperation::SetClientId(args) => {
+                put_fixed_opaque(buf, &args.client.verifier);
+                put_opaque(buf, &args.client.id);
+                buf.put_u32(args.callback.program);
+                args.callback.location.encode(buf);
+                buf.put_u32(args.callback_ident);
+            }
+            NfsOperation::SetClientIdConfirm(args) => {
+                buf.put_u64(args.clientid);
+                put_fixed_opaque(buf, &args.confirm);
+            }
+            NfsOperation::Read(args) => {
+                put_fixed_opaque(buf, &args.stateid);
+                buf.put_u64(args.offset);
+                buf.put_u32(args.count);
+            }
+            NfsOperation::ReadDir(args) => {
+                buf.put_u64(args.cookie);
+                put_fixed_opaque(buf, &args.cookieverf);
+                buf.put_u32(args.dircount);
+                buf.put_u32(args.maxcount);
+                put_u32_array(buf, &args.attr_request);
+            }
+            NfsOperation::ReadLink(_) => {}
+            NfsOperation::ReclaimComplete(args) => put_bool(buf, args.one_fs),
+            NfsOperation::ReleaseLockOwner(args) => args.lock_owner.encode(buf),
+            NfsOperation::Remove(args) => put_string(buf, &args.target),
+            NfsOperation::Rename(args) => {
+                put_string(buf, &args.old_name);
+                put_string(buf, &args.new_name);
+            }
+            NfsOperation::Write(args) => {
+                put_fixed_opaque(buf, &args.stateid);
+                buf.put_u64(args.offset);
+                buf.put_u32(args.stable);
+                put_opaque(buf, &args.data);
+            }
+            NfsOperation::Unsupported(_) => {}
+        }
+    }
+}
+
+impl XdrDecode for NfsOperation {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let op = match get_u32(buf)? {
+            OP_ACCESS => NfsOperation::Access(AccessOperation { access: get_u32(buf)? }),
+            OP_CLOSE => NfsOperation::Close(CloseOperation {
+                seqid: get_u32(buf)?,
+                open_stateid: get_fixed_opaque(buf)?,
+            }),
+            OP_COMMIT => NfsOperation::Commit(CommitOperation {
+                offset: get_u64(buf)?,
+                count: get_u32(buf)?,
+            }),
+            OP_DELEGRETURN => NfsOperation::DelegReturn(DelegReturnOperation {
+                stateid: get_fixed_opaque(buf)?,
+            }),
+            OP_CREATE => {
+                let object_type = get_u32(buf)?;
+                let mut link_data = None;
+                let mut spec_data = None;
+                match object_type {
+                    NF4LNK => link_data = Some(get_string(buf)?),
+                    NF4BLK | NF4CHR => spec_data = Some(SpecData::decode(buf)?),
+                    _ => {}
+                }
+                NfsOperation::Create(CreateOperation {
+                    object_type,
+                    link_data,
+                    spec_data,
+                    object_name: get_string(buf)?,
+                    attributes: Fattr4::decode(buf)?,
+                })
+            }
+            OP_CREATE_SESSION => NfsOperation::CreateSession(CreateSessionOperation::decode(buf)?),
+            OP_DESTROY_CLIENTID => NfsOperation::DestroyClientId(DestroyClientIdOperation { clientid: get_u64(buf)? }),
+            OP_DESTROY_SESSION => NfsOperation::DestroySession(DestroySessionOperation {
+                sessionid: get_fixed_opaque(buf)?,
+            }),
+            OP_EXCHANGE_ID => NfsOperation::ExchangeId(ExchangeIdOperation::decode(buf)?),
+            OP_GETATTR => NfsOperation::GetAttr(GetAttrOperation {
+                attr_request: get_u32_array(buf)?,
+            }),
+            OP_GETFH => NfsOperation::GetFh(GetFhOperation),
+            OP_LINK => NfsOperation::Link(LinkOperation {
+                new_name: get_string(buf)?,
+            }),
+            OP_LOCK => NfsOperation::Lock(LockOperation::decode(buf)?),
+            OP_LOCKT => NfsOperation::Lockt(LocktOperation {
+                locktype: get_u32(buf)?,
+                offset: get_u64(buf)?,
+                length: get_u64(buf)?,
+                owner: LockOwner::decode(buf)?,
+            }),
+            OP_LOCKU => NfsOperation::Locku(LockuOperation {
+                locktype: get_u32(buf)?,
+                seqid: get_u32(buf)?,
+                lock_stateid: get_fixed_opaque(buf)?,
+                offset: get_u64(buf)?,
+                length: get_u64(buf)?,
+            }),
+            OP_LOOKUP => NfsOperation::Lookup(LookupOperation {
+                object_name: get_string(buf)?,
+            }),
+            OP_LOOKUPP => NfsOperation::Lookupp(LookuppOperation),
+            OP_OPEN => NfsOperation::Open(OpenOperation::decode(buf)?),
+            OP_OPEN_CONFIRM => NfsOperation::OpenConfirm(OpenConfirmOperation {
+                open_stateid: get_fixed_opaque(buf)?,
+                seqid: get_u32(buf)?,
+            }),
+            OP_OPEN_DOWNGRADE => NfsOperation::OpenDowngrade(OpenDowngradeOperation {
+                open_stateid: get_fixed_opaque(buf)?,
+                seqid: get_u32(buf)?,
+                share_access: get_u32(buf)?,
+                share_deny: get_u32(buf)?,
+            }),
+            OP_PUTFH => NfsOperation::PutFh(PutFhOperation {
+                object: NfsFileHandle::decode(buf)?,
+            }),
+            OP_PUTPUBFH => NfsOperation::PutPubFh(PutPubFhOperation),
+            OP_PUTROOTFH => NfsOperation::PutRootFh(PutRootFhOperation),
+            OP_READ => NfsOperation::Read(ReadOperation {
+                stateid: get_fixed_opaque(buf)?,
+                offset: get_u64(buf)?,
+                count: get_u32(buf)?,
+            }),
+            OP_WRITE => NfsOperation::Write(WriteOperation {
+                stateid: get_fixed_opaque(buf)?,
+                offset: get_u64(buf)?,
+                stable: get_u32(buf)?,
+                data: get_opaque(buf)?,
+            }),
+            OP_READDIR => NfsOperation::ReadDir(ReadDirOperation {
+                cookie: get_u64(buf)?,
+                cookieverf: get_fixed_opaque(buf)?,
+                dircount: get_u32(buf)?,
+                maxcount: get_u32(buf)?,
+                attr_request: get_u32_array(buf)?,
+            }),
+            OP_READLINK => NfsOperation::ReadLink(ReadLinkOperation),
+            OP_RECLAIM_COMPLETE => NfsOperation::ReclaimComplete(ReclaimCompleteOperation { one_fs: get_bool(buf)? }),
+            OP_RELEASE_LOCKOWNER => NfsOperation::ReleaseLockOwner(ReleaseLockOwnerOperation {
+                lock_owner: LockOwner::decode(buf)?,
+            }),
+            OP_REMOVE => NfsOperation::Remove(RemoveOperation {
+                target: get_string(buf)?,
+            }),
+            OP_RENAME => NfsOperation::Rename(RenameOperation {
+                old_name: get_string(buf)?,
+                new_name: get_string(buf)?,
+            }),
+            OP_RENEW => NfsOperation::Renew(RenewOperation { clientid: get_u64(buf)? }),
+            OP_RESTOREFH => NfsOperation::RestoreFh(RestoreFhOperation),
+            OP_SAVEFH => NfsOperation::SaveFh(SaveFhOperation),
+            OP_SEQUENCE => NfsOperation::Sequence(SequenceOperation {
+                sessionid: get_fixed_opaque(buf)?,
+                sequenceid: get_u32(buf)?,
+                slotid: get_u32(buf)?,
+                highest_slotid: get_u32(buf)?,
+                cache_this: get_bool(buf)?,
+            }),
+            OP_SETATTR => NfsOperation::SetAttr(SetAttrOperation {
+                stateid: get_fixed_opaque(buf)?,
+                attributes: Fattr4::decode(buf)?,
+            }),
+            OP_SETCLIENTID => NfsOperation::SetClientId(SetClientIdOperation {
+                client: NfsClientId {
+                    verifier: get_fixed_opaque(buf)?,
+                    id: get_opaque(buf)?,
+                },
+                callback: CbClient {
+                    program: get_u32(buf)?,
+                    location: NetAddr::decode(buf)?,
+                },
+                callback_ident: get_u32(buf)?,
+            }),
+            OP_SETCLIENTID_CONFIRM => NfsOperation::SetClientIdConfirm(SetClientIdConfirmOperation {
+                clientid: get_u64(buf)?,
+                confirm: get_fixed_opaque(buf)?,
+            }),
+            other => NfsOperation::Unsupported(other),
+        };
+        Ok(op)
+    }
+}
+
+impl XdrEncode for OpenOperation {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.seqid);
+        buf.put_u32(self.share_access);
+        buf.put_u32(self.share_deny);
+        buf.put_u64(self.clientid);
+        put_opaque(buf, &self.owner);
+
+        match &self.open_how {
+            OpenHow::NoCreate => buf.put_u32(OPEN4_NOCREATE),
+            OpenHow::Create(how) => {
+                buf.put_u32(OPEN4_CREATE);
+                match how {
+                    CreateHow::Unchecked(attrs) => {
+                        buf.put_u32(UNCHECKED4);
+                        attrs.encode(buf);
+                    }
+                    CreateHow::Guarded(attrs) => {
+                        buf.put_u32(GUARDED4);
+                        attrs.encode(buf);
+                    }
+                    CreateHow::Exclusive(verifier) => {
+                        buf.put_u32(EXCLUSIVE4);
+                        put_fixed_opaque(buf, verifier);
+                    }
+                    CreateHow::Exclusive41(verifier, attrs) => {
+                        buf.put_u32(EXCLUSIVE4_1);
+                        put_fixed_opaque(buf, verifier);
+                        attrs.encode(buf);
+                    }
+                }
+            }
+        }
+
+        match &self.open_claim {
+            OpenClaim::Null(name) => {
+                buf.put_u32(CLAIM_NULL);
+                put_string(buf, name);
+            }
+            OpenClaim::Previous(delegate_type) => {
+                buf.put_u32(CLAIM_PREVIOUS);
+                buf.put_u32(*delegate_type);
+            }
+            OpenClaim::Delegate(stateid, name) => {
+                buf.put_u32(CLAIM_DELEGATE_CUR);
+                put_fixed_opaque(buf, stateid);
+                put_string(buf, name);
+            }
+            OpenClaim::DelegatePrev(name) => {
+                buf.put_u32(CLAIM_DELEGATE_PREV);
+                put_string(buf, name);
+            }
+            OpenClaim::Fh => buf.put_u32(CLAIM_FH),
+            OpenClaim::DelegateCurFh(stateid) => {
+                buf.put_u32(CLAIM_DELEG_CUR_FH);
+                put_fixed_opaque(buf, stateid);
+            }
+            OpenClaim::DelegatePrevFh => buf.put_u32(CLAIM_DELEG_PREV_FH),
+        }
+    }
+}
+
+impl XdrDecode for OpenOperation {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let seqid = get_u32(buf)?;
+        let share_access = get_u32(buf)?;
+        let share_deny = get_u32(buf)?;
+        let clientid = get_u64(buf)?;
+        let owner = get_opaque(buf)?;
+
+        let open_how = match get_u32(buf)? {
+            OPEN4_NOCREATE => OpenHow::NoCreate,
+            OPEN4_CREATE => OpenHow::Create(match get_u32(buf)? {
+                UNCHECKED4 => CreateHow::Unchecked(Fattr4::decode(buf)?),
+                GUARDED4 => CreateHow::Guarded(Fattr4::decode(buf)?),
+                EXCLUSIVE4 => CreateHow::Exclusive(get_fixed_opaque(buf)?),
+                EXCLUSIVE4_1 => CreateHow::Exclusive41(get_fixed_opaque(buf)?, Fattr4::decode(buf)?),
+                value => return Err(XdrError::InvalidDiscriminant { what: "createmode4", value }),
+            }),
+            value => return Err(XdrError::InvalidDiscriminant { what: "openflag4", value }),
+        };
+
+        let open_claim = match get_u32(buf)? {
+            CLAIM_NULL => OpenClaim::Null(get_string(buf)?),
+            CLAIM_PREVIOUS => OpenClaim::Previous(get_u32(buf)?),
+            CLAIM_DELEGATE_CUR => OpenClaim::Delegate(get_fixed_opaque(buf)?, get_string(buf)?),
+            CLAIM_DELEGATE_PREV => OpenClaim::DelegatePrev(get_string(buf)?),
+            CLAIM_FH => OpenClaim::Fh,
+            CLAIM_DELEG_CUR_FH => OpenClaim::DelegateCurFh(get_fixed_opaque(buf)?),
+            CLAIM_DELEG_PREV_FH => OpenClaim::DelegatePrevFh,
+            value => return Err(XdrError::InvalidDiscriminant { what: "open_claim_type4", value }),
+        };
+
+        Ok(OpenOperation {
+            seqid,
+            share_access,
+            share_deny,
+            clientid,
+            owner,
+            open_how,
+            open_claim,
+        })
+    }
+}
+
+// NFSv4.1 session arguments
+
+impl XdrEncode for ExchangeIdOperation {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_fixed_opaque(buf, &self.owner.verifier);
+        put_opaque(buf, &self.owner.ownerid);
+        buf.put_u32(self.flags);
+        match &self.state_protect {
+            StateProtect::None => buf.put_u32(SP4_NONE),
+            StateProtect::MachCred(ops) => {
+                buf.put_u32(SP4_MACH_CRED);
+                ops.encode(buf);
+            }
+            StateProtect::Ssv(parms) => {
+                buf.put_u32(SP4_SSV);
+                parms.ops.encode(buf);
+                put_array(buf, &parms.hash_algs, |buf, oid| put_opaque(buf, oid));
+                put_array(buf, &parms.encr_algs, |buf, oid| put_opaque(buf, oid));
+                buf.put_u32(parms.window);
+                buf.put_u32(parms.num_gss_handles);
+            }
+        }
+        put_impl_id(buf, &self.impl_id);
+    }
+}
+
+impl XdrDecode for ExchangeIdOperation {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let owner = ClientOwner {
+            verifier: get_fixed_opaque(buf)?,
+            ownerid: get_opaque(buf)?,
+        };
+        let flags = get_u32(buf)?;
+        let state_protect = match get_u32(buf)? {
+            SP4_NONE => StateProtect::None,
+            SP4_MACH_CRED => StateProtect::MachCred(StateProtectOps::decode(buf)?),
+            SP4_SSV => StateProtect::Ssv(SsvSpParms {
+                ops: StateProtectOps::decode(buf)?,
+                hash_algs: get_array(buf, get_opaque)?,
+                encr_algs: get_array(buf, get_opaque)?,
+                window: get_u32(buf)?,
+                num_gss_handles: get_u32(buf)?,
+            }),
+            value => return Err(XdrError::InvalidDiscriminant { what: "state_protect_how4", value }),
+        };
+        Ok(ExchangeIdOperation {
+            owner,
+            flags,
+            state_protect,
+            impl_id: get_impl_id(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for StateProtectOps {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_u32_array(buf, &self.must_enforce);
+        put_u32_array(buf, &self.must_allow);
+    }
+}
+
+impl XdrDecode for StateProtectOps {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(StateProtectOps {
+            must_enforce: get_u32_array(buf)?,
+            must_allow: get_u32_array(buf)?,
+        })
+    }
+}
+
+// nfs_impl_id4 is sent as an array of at most one element.
+fn put_impl_id(buf: &mut BytesMut, impl_id: &Option<NfsImplId>) {
+    let ids: Vec<&NfsImplId> = impl_id.iter().collect();
+    put_array(buf, &ids, |buf, id| {
+        put_string(buf, &id.domain);
+        put_string(buf, &id.name);
+        id.date.encode(buf);
+    });
+}
+
+fn get_impl_id(buf: &mut Bytes) -> XdrResult<Option<NfsImplId>> {
+    let mut ids = get_array(buf, |buf| {
+        Ok(NfsImplId {
+            domain: get_string(buf)?,
+            name: get_string(buf)?,
+            date: NfsTime::decode(buf)?,
+        })
+    })?;
+    if ids.len() > 1 {
+        return Err(XdrError::LengthTooLarge(ids.len()));
+    }
+    Ok(ids.pop())
+}
+
+impl XdrEncode for CreateSessionOperation {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.clientid);
+        buf.put_u32(self.sequence);
+        buf.put_u32(self.flags);
+        self.fore_chan_attrs.encode(buf);
+        self.back_chan_attrs.encode(buf);
+        buf.put_u32(self.cb_program);
+        put_array(buf, &self.sec_parms, |buf, parms| match parms {
+            CallbackSecParms::AuthNone => buf.put_u32(AUTH_NONE),
+            CallbackSecParms::AuthSys(sys) => {
+                buf.put_u32(AUTH_SYS);
+                buf.put_u32(sys.stamp);
+                put_string(buf, &sys.machine_name);
+                buf.put_u32(sys.uid);
+                buf.put_u32(sys.gid);
+                put_u32_array(buf, &sys.gids);
+            }
+            CallbackSecParms::RpcSecGss {
+                service,
+                handle_from_server,
+                handle_from_client,
+            } => {
+                buf.put_u32(RPCSEC_GSS);
+                buf.put_u32(*service);
+                put_opaque(buf, handle_from_server);
+                put_opaque(buf, handle_from_client);
+            }
+        });
+    }
+}
+
+impl XdrDecode for CreateSessionOperation {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(CreateSessionOperation {
+            clientid: get_u64(buf)?,
+            sequence: get_u32(buf)?,
+            flags: get_u32(buf)?,
+            fore_chan_attrs: ChannelAttrs::decode(buf)?,
+            back_chan_attrs: ChannelAttrs::decode(buf)?,
+            cb_program: get_u32(buf)?,
+            sec_parms: get_array(buf, |buf| {
+                Ok(match get_u32(buf)? {
+                    AUTH_NONE => CallbackSecParms::AuthNone,
+                    AUTH_SYS => CallbackSecParms::AuthSys(AuthSysParms {
+                        stamp: get_u32(buf)?,
+                        machine_name: get_string(buf)?,
+                        uid: get_u32(buf)?,
+                        gid: get_u32(buf)?,
+                        gids: get_u32_array(buf)?,
+                    }),
+                    RPCSEC_GSS => CallbackSecParms::RpcSecGss {
+                        service: get_u32(buf)?,
+                        handle_from_server: get_opaque(buf)?,
+                        handle_from_client: get_opaque(buf)?,
+                    },
+                    value => return Err(XdrError::InvalidDiscriminant { what: "callback_sec_parms4", value }),
+                })
+            })?,
+        })
+    }
+}
+
+impl XdrEncode for ChannelAttrs {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.header_pad_size);
+        buf.put_u32(self.max_request_size);
+        buf.put_u32(self.max_response_size);
+        buf.put_u32(self.max_response_size_cached);
+        buf.put_u32(self.max_operations);
+        buf.put_u32(self.max_requests);
+        let ird: Vec<u32> = self.rdma_ird.into_iter().collect();
+        put_u32_array(buf, &ird);
+    }
+}
+
+impl XdrDecode for ChannelAttrs {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let mut attrs = ChannelAttrs {
+            header_pad_size: get_u32(buf)?,
+            max_request_size: get_u32(buf)?,
+            max_response_size: get_u32(buf)?,
+            max_response_size_cached: get_u32(buf)?,
+            max_operations: get_u32(buf)?,
+            max_requests: get_u32(buf)?,
+            rdma_ird: None,
+        };
+        let ird = get_u32_array(buf)?;
+        if ird.len() > 1 {
+            return Err(XdrError::LengthTooLarge(ird.len()));
+        }
+        attrs.rdma_ird = ird.first().copied();
+        Ok(attrs)
+    }
+}
+
+// nfs_resop4
+
+impl XdrEncode for OperationResult {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.op);
+        self.status.encode(buf);
+        match &self.result {
+            Some(data @ OperationData::ClientInUse(_)) if self.status == NfsStatus::ClidInUse => data.encode(buf),
+            Some(data @ OperationData::LockDenied(_)) if self.status == NfsStatus::Denied => data.encode(buf),
+            Some(data) if self.status == NfsStatus::Ok || self.op == OP_SETATTR => data.encode(buf),
+            None if self.op == OP_SETATTR => put_u32_array(buf, &[]),
+            _ => {}
+        }
+    }
+}
+
+impl XdrDecode for OperationResult {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let op = get_u32(buf)?;
+        let status = NfsStatus::decode(buf)?;
+        if op == OP_SETATTR {
+            let attrsset = get_u32_array(buf)?;
+            return Ok(OperationResult {
+                op,
+                status,
+                result: Some(OperationData::SetAttr(attrsset)),
+            });
+        }
+        if op == OP_SETCLIENTID && status == NfsStatus::ClidInUse {
+            return Ok(OperationResult {
+                op,
+                status,
+                result: Some(OperationData::ClientInUse(NetAddr::decode(buf)?)),
+            });
+        }
+        if (op == OP_LOCK || op == OP_LOCKT) && status == NfsStatus::Denied {
+            return Ok(OperationResult {
+                op,
+                status,
+                result: Some(OperationData::LockDenied(LockDenied::decode(buf)?)),
+            });
+        }
+        if status != NfsStatus::Ok {
+            return Ok(OperationResult::error(op, status));
+        }
+
+        let result = match op {
+            OP_ACCESS => Some(OperationData::Access(AccessResult {
+                supported: get_u32(buf)?,
+                access: get_u32(buf)?,
+            })),
+            OP_CLOSE => Some(OperationData::Close(get_fixed_opaque(buf)?)),
+            OP_COMMIT => Some(OperationData::Commit(get_fixed_opaque(buf)?)),
+            OP_CREATE => Some(OperationData::Create(CreateResult {
+                change_info: ChangeInfo::decode(buf)?,
+                attrset: get_u32_array(buf)?,
+            })),
+            OP_CREATE_SESSION => Some(OperationData::CreateSession(CreateSessionResult::decode(buf)?)),
+            OP_EXCHANGE_ID => Some(OperationData::ExchangeId(ExchangeIdResult::decode(buf)?)),
+            OP_GETATTR => Some(OperationData::GetAttr(Fattr4::decode(buf)?)),
+            OP_GETFH => Some(OperationData::GetFh(NfsFileHandle::decode(buf)?)),
+            OP_LINK => Some(OperationData::Link(ChangeInfo::decode(buf)?)),
+            OP_LOCK => Some(OperationData::Lock(get_fixed_opaque(buf)?)),
+            OP_LOCKU => Some(OperationData::Locku(get_fixed_opaque(buf)?)),
+            OP_OPEN => Some(OperationData::Open(OpenResult::decode(buf)?)),
+            OP_OPEN_CONFIRM => Some(OperationData::OpenConfirm(get_fixed_opaque(buf)?)),
+            OP_OPEN_DOWNGRADE => Some(OperationData::OpenDowngrade(get_fixed_opaque(buf)?)),
+            OP_READ => Some(OperationData::Read(ReadResult {
+                eof: get_bool(buf)?,
+                data: get_opaque(buf)?,
+            })),
+            OP_READDIR => Some(OperationData::ReadDir(ReadDirResult::decode(buf)?)),
+            OP_READLINK => Some(OperationData::ReadLink(get_string(buf)?)),
+            OP_REMOVE => Some(OperationData::Remove(ChangeInfo::decode(buf)?)),
+            OP_RENAME => Some(OperationData::Rename(RenameResult {
+                source_cinfo: ChangeInfo::decode(buf)?,
+                target_cinfo: ChangeInfo::decode(buf)?,
+            })),
+            OP_SEQUENCE => Some(OperationData::Sequence(SequenceResult {
+                sessionid: get_fixed_opaque(buf)?,
+                sequenceid: get_u32(buf)?,
+                slotid: get_u32(buf)?,
+                highest_slotid: get_u32(buf)?,
+                target_highest_slotid: get_u32(buf)?,
+                status_flags: get_u32(buf)?,
+            })),
+            OP_SETCLIENTID => Some(OperationData::SetClientId(SetClientIdResult {
+                clientid: get_u64(buf)?,
+                confirm: get_fixed_opaque(buf)?,
+            })),
+            OP_WRITE => Some(OperationData::Write(WriteResult {
+                count: get_u32(buf)?,
+                committed: get_u32(buf)?,
+                verifier: get_fixed_opaque(buf)?,
+            })),
+            OP_LOOKUP | OP_LOOKUPP | OP_PUTFH | OP_PUTPUBFH | OP_PUTROOTFH | OP_RENEW | OP_RESTOREFH | OP_SAVEFH
+            | OP_SETCLIENTID_CONFIRM | OP_DESTROY_CLIENTID | OP_DESTROY_SESSION | OP_RECLAIM_COMPLETE | OP_LOCKT
+            | OP_RELEASE_LOCKOWNER | OP_DELEGRETURN => None,
+            value => return Err(XdrError::InvalidDiscriminant { what: "nfs_opnum4", value }),
+        };
+
+        Ok(OperationResult { op, status, result })
+    }
+}
+
+impl XdrEncode for OperationData {
+    fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            OperationData::Access(res) => {
+                buf.put_u32(res.supported);
+                buf.put_u32(res.access);
+            }
+            OperationData::Close(stateid) => put_fixed_opaque(buf, stateid),
+            OperationData::Commit(verifier) => put_fixed_opaque(buf, verifier),
+            OperationData::Create(res) => {
+                res.change_info.encode(buf);
+                put_u32_array(buf, &res.attrset);
+            }
+            OperationData::CreateSession(res) => res.encode(buf),
+            OperationData::ExchangeId(res) => res.encode(buf),
+            OperationData::GetAttr(attrs) => attrs.encode(buf),
+            OperationData::GetFh(fh) => fh.encode(buf),
+            OperationData::Link(cinfo) => cinfo.encode(buf),
+            OperationData::Lock(stateid) | OperationData::Locku(stateid) => put_fixed_opaque(buf, stateid),
+            OperationData::LockDenied(denied) => denied.encode(buf),
+            OperationData::Open(res) => res.encode(buf),
+            OperationData::OpenConfirm(stateid) | OperationData::OpenDowngrade(stateid) => {
+                put_fixed_opaque(buf, stateid)
+            }
+            OperationData::Read(res) => {
+                put_bool(buf, res.eof);
+                put_opaque(buf, &res.data);
+            }
+            OperationData::ReadDir(res) => res.encode(buf),
+            OperationData::ReadLink(link) => put_string(buf, link),
+            OperationData::Remove(cinfo) => cinfo.encode(buf),
+            OperationData::Sequence(res) => {
+                put_fixed_opaque(buf, &res.sessionid);
+                buf.put_u32(res.sequenceid);
+                buf.put_u32(res.slotid);
+                buf.put_u32(res.highest_slotid);
+                buf.put_u32(res.target_highest_slotid);
+                buf.put_u32(res.status_flags);
+            }
+            OperationData::SetAttr(attrsset) => put_u32_array(buf, attrsset),
+            OperationData::SetClientId(res) => {
+                buf.put_u64(res.clientid);
+                put_fixed_opaque(buf, &res.confirm);
+            }
+            OperationData::ClientInUse(addr) => addr.encode(buf),
+            OperationData::Rename(res) => {
+                res.source_cinfo.encode(buf);
+                res.target_cinfo.encode(buf);
+            }
+            OperationData::Write(res) => {
+                buf.put_u32(res.count);
+                buf.put_u32(res.committed);
+                put_fixed_opaque(buf, &res.verifier);
+            }
+        }
+    }
+}
+
+impl XdrEncode for OpenResult {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_fixed_opaque(buf, &self.stateid);
+        self.change_info.encode(buf);
+        buf.put_u32(self.rflags);
+        put_u32_array(buf, &self.attrset);
+        match &self.delegation {
+            OpenDelegation::None => buf.put_u32(OPEN_DELEGATE_NONE),
+            OpenDelegation::Read(read) => {
+                buf.put_u32(OPEN_DELEGATE_READ);
+                put_fixed_opaque(buf, &read.stateid);
+                put_bool(buf, read.recall);
+                read.permissions.encode(buf);
+            }
+            OpenDelegation::Write(write) => {
+                buf.put_u32(OPEN_DELEGATE_WRITE);
+                put_fixed_opaque(buf, &write.stateid);
+                put_bool(buf, write.recall);
+                match write.space_limit {
+                    SpaceLimit::Size(filesize) => {
+                        buf.put_u32(NFS_LIMIT_SIZE);
+                        buf.put_u64(filesize);
+                    }
+                    SpaceLimit::Blocks { num_blocks, bytes_per_block } => {
+                        buf.put_u32(NFS_LIMIT_BLOCKS);
+                        buf.put_u32(num_blocks);
+                        buf.put_u32(bytes_per_block);
+                    }
+                }
+                write.permissions.encode(buf);
+            }
+        }
+    }
+}
+
+impl XdrDecode for OpenResult {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let stateid = get_fixed_opaque(buf)?;
+        let change_info = ChangeInfo::decode(buf)?;
+        let rflags = get_u32(buf)?;
+        let attrset = get_u32_array(buf)?;
+        let delegation = match get_u32(buf)? {
+            OPEN_DELEGATE_NONE => OpenDelegation::None,
+            OPEN_DELEGATE_READ => OpenDelegation::Read(OpenReadDelegation {
+                stateid: get_fixed_opaque(buf)?,
+                recall: get_bool(buf)?,
+                permissions: Nfsace4::decode(buf)?,
+            }),
+            OPEN_DELEGATE_WRITE => OpenDelegation::Write(OpenWriteDelegation {
+                stateid: get_fixed_opaque(buf)?,
+                recall: get_bool(buf)?,
+                space_limit: match get_u32(buf)? {
+                    NFS_LIMIT_SIZE => SpaceLimit::Size(get_u64(buf)?),
+                    NFS_LIMIT_BLOCKS => SpaceLimit::Blocks {
+                        num_blocks: get_u32(buf)?,
+                        bytes_per_block: get_u32(buf)?,
+                    },
+                    value => return Err(XdrError::InvalidDiscriminant { what: "limit_by4", value }),
+                },
+                permissions: Nfsace4::decode(buf)?,
+            }),
+            value => return Err(XdrError::InvalidDiscriminant { what: "open_delegation_type4", value }),
+        };
+        Ok(OpenResult {
+            stateid,
+            change_info,
+            rflags,
+            attrset,
+            delegation,
+        })
+    }
+}
+
+impl XdrEncode for ExchangeIdResult {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.clientid);
+        buf.put_u32(self.sequenceid);
+        buf.put_u32(self.flags);
+        buf.put_u32(SP4_NONE);
+        buf.put_u64(self.server_owner.minor_id);
+        put_opaque(buf, &self.server_owner.major_id);
+        put_opaque(buf, &self.server_scope);
+        put_impl_id(buf, &self.impl_id);
+    }
+}
+
+impl XdrDecode for ExchangeIdResult {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let clientid = get_u64(buf)?;
+        let sequenceid = get_u32(buf)?;
+        let flags = get_u32(buf)?;
+        match get_u32(buf)? {
+            SP4_NONE => {}
+            value => return Err(XdrError::InvalidDiscriminant { what: "state_protect_how4", value }),
+        }
+        Ok(ExchangeIdResult {
+            clientid,
+            sequenceid,
+            flags,
+            server_owner: ServerOwner {
+                minor_id: get_u64(buf)?,
+                major_id: get_opaque(buf)?,
+            },
+            server_scope: get_opaque(buf)?,
+            impl_id: get_impl_id(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for CreateSessionResult {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_fixed_opaque(buf, &self.sessionid);
+        buf.put_u32(self.sequence);
+        buf.put_u32(self.flags);
+        self.fore_chan_attrs.encode(buf);
+        self.back_chan_attrs.encode(buf);
+    }
+}
+
+impl XdrDecode for CreateSessionResult {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(CreateSessionResult {
+            sessionid: get_fixed_opaque(buf)?,
+            sequence: get_u32(buf)?,
+            flags: get_u32(buf)?,
+            fore_chan_attrs: ChannelAttrs::decode(buf)?,
+            back_chan_attrs: ChannelAttrs::decode(buf)?,
+        })
+    }
+}
+
+// dirlist4 is an XDR optional-data linked list: each entry is preceded by a
+// "value follows" flag and the list is terminated by FALSE.
+
+impl XdrEncode for ReadDirResult {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_fixed_opaque(buf, &self.cookieverf);
+        for entry in &self.entries {
+            put_bool(buf, true);
+            buf.put_u64(entry.cookie);
+            put_string(buf, &entry.name);
+            entry.attrs.encode(buf);
+        }
+        put_bool(buf, false);
+        put_bool(buf, self.eof);
+    }
+}
+
+impl XdrDecode for ReadDirResult {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let cookieverf = get_fixed_opaque(buf)?;
+        let mut entries = Vec::new();
+        while get_bool(buf)? {
+            entries.push(DirEntry {
+                cookie: get_u64(buf)?,
+                name: get_string(buf)?,
+                attrs: Fattr4::decode(buf)?,
+            });
+        }
+        Ok(ReadDirResult {
+            cookieverf,
+            entries,
+            eof: get_bool(buf)?,
+        })
+    }
+}
+
+impl XdrEncode for Nfsace4 {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.acetype);
+        buf.put_u32(self.flag);
+        buf.put_u32(self.access_mask);
+        put_string(buf, &self.who);
+    }
+}
+
+impl XdrDecode for Nfsace4 {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        Ok(Nfsace4 {
+            acetype: get_u32(buf)?,
+            flag: get_u32(buf)?,
+            access_mask: get_u32(buf)?,
+            who: get_string(buf)?,
+        })
+    }
+}
+
+// Callback program messages. The server is the RPC client here: it sends
+// CB_COMPOUND requests and decodes the replies.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbCompoundRequest {
+    pub tag: String,
+    pub minor_version: u32,
+    // The callback_ident the client gave in SETCLIENTID.
+    pub callback_ident: u32,
+    pub operations: Vec<CbOperation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CbOperation {
+    Recall(CbRecallOperation),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbRecallOperation {
+    pub stateid: [u8; 16],
+    pub truncate: bool,
+    pub fh: NfsFileHandle,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbCompoundResponse {
+    pub status: NfsStatus,
+    pub tag: String,
+    pub results: Vec<CbOperationResult>,
+}
+
+// CB_RECALL results carry nothing but the status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbOperationResult {
+    pub op: u32,
+    pub status: NfsStatus,
+}
+
+impl CbOperation {
+    pub fn opcode(&self) -> u32 {
+        match self {
+            CbOperation::Recall(_) => OP_CB_RECALL,
+        }
+    }
+}
+
+impl CbCompoundRequest {
+    pub fn decode(data: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(data);
+        XdrDecode::decode(&mut buf)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        XdrEncode::encode(self, &mut buf);
+        buf.to_vec()
+    }
+}
+
+impl CbCompoundResponse {
+    pub fn decode(data: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(data);
+        XdrDecode::decode(&mut buf)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        XdrEncode::encode(self, &mut buf);
+        buf.to_vec()
+    }
+}
+
+impl XdrEncode for CbCompoundRequest {
+    fn encode(&self, buf: &mut BytesMut) {
+        put_string(buf, &self.tag);
+        buf.put_u32(self.minor_version);
+        buf.put_u32(self.callback_ident);
+        put_array(buf, &self.operations, |buf, op| {
+            buf.put_u32(op.opcode());
+            match op {
+                CbOperation::Recall(args) => {
+                    put_fixed_opaque(buf, &args.stateid);
+                    put_bool(buf, args.truncate);
+                    args.fh.encode(buf);
+                }
+            }
+        });
+    }
+}
+
+impl XdrDecode for CbCompoundRequest {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let tag = get_string(buf)?;
+        let minor_version = get_u32(buf)?;
+        let callback_ident = get_u32(buf)?;
+        let operations = get_array(buf, |buf| match get_u32(buf)? {
+            OP_CB_RECALL => Ok(CbOperation::Recall(CbRecallOperation {
+                stateid: get_fixed_opaque(buf)?,
+                truncate: get_bool(buf)?,
+                fh: NfsFileHandle::decode(buf)?,
+            })),
+            value => Err(XdrError::InvalidDiscriminant { what: "nfs_cb_opnum4", value }),
+        })?;
+        Ok(CbCompoundRequest {
+            tag,
+            minor_version,
+            callback_ident,
+            operations,
+        })
+    }
+}
+
+impl XdrEncode for CbCompoundResponse {
+    fn encode(&self, buf: &mut BytesMut) {
+        self.status.encode(buf);
+        put_string(buf, &self.tag);
+        put_array(buf, &self.results, |buf, res| {
+            buf.put_u32(res.op);
+            res.status.encode(buf);
+        });
+    }
+}
+
+impl XdrDecode for CbCompoundResponse {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let status = NfsStatus::decode(buf)?;
+        let tag = get_string(buf)?;
+        let results = get_array(buf, |buf| {
+            Ok(CbOperationResult {
+                op: get_u32(buf)?,
+                status: NfsStatus::decode(buf)?,
+            })
+        })?;
+        Ok(CbCompoundResponse { status, tag, results })
+    }
 }
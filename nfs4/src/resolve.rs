@@ -0,0 +1,316 @@
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{openat, readlinkat, renameat, OFlag};
+use nix::libc;
+use nix::sys::stat::{fchmodat, mkdirat, mknodat, utimensat, FchmodatFlags, Mode, SFlag, UtimensatFlags};
+use nix::sys::statvfs::{fstatvfs, Statvfs};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{
+    fchownat, fpathconf, linkat, symlinkat, unlinkat, FchownatFlags, Gid, LinkatFlags, PathconfVar, Uid, UnlinkatFlags,
+};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::protocol::NfsStatus;
+
+// Confining file access to an export.
+//
+// Handles and names from clients end up as paths below the export root,
+// and a path is only as trustworthy as every directory on it. A symlink
+// put in place of one of them, by a client or by anything else with
+// access to the filesystem, would lead a plain path lookup out of the
+// export. So nothing here resolves a path from the top: every lookup
+// starts at a descriptor for the export root and never follows a symlink,
+// with openat2 and RESOLVE_BENEATH where the kernel has it and a walk one
+// O_NOFOLLOW component at a time where it does not. Operations on an
+// entry go through a descriptor for its directory, so the entry acted on
+// is the one that was checked.
+//
+// Clients never need the server to follow a symlink: NFSv4 hands symlinks
+// back to the client, which resolves them itself.
+
+// Longest name a component may have, as on Linux filesystems.
+const NAME_MAX: usize = 255;
+
+// Checks for a component4 (RFC 7530 section 12.7): a single name that is
+// neither empty nor "." or "..", and contains no '/' or NUL.
+pub fn check_name(name: &str) -> Result<(), NfsStatus> {
+    if name.is_empty() {
+        return Err(NfsStatus::Inval);
+    }
+    if name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(NfsStatus::BadName);
+    }
+    if name.len() > NAME_MAX {
+        return Err(NfsStatus::NameTooLong);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ExportRoot {
+    path: PathBuf,
+    fd: OwnedFd,
+    // Whether openat2 can be used. It is missing before Linux 5.6 and
+    // may be refused by a seccomp filter.
+    openat2: bool,
+}
+
+impl ExportRoot {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let fd = openat(libc::AT_FDCWD, path, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let openat2 = match openat2(fd.as_raw_fd(), Path::new("."), OFlag::O_PATH, 0) {
+            Ok(_) => true,
+            Err(Errno::ENOSYS | Errno::EPERM) => false,
+            Err(errno) => return Err(errno.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            fd,
+            openat2,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The part of an export path below the root. The handlers only ever
+    // build paths by joining checked names onto the root, so anything
+    // else is refused rather than interpreted.
+    fn relative<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        let relative = path.strip_prefix(&self.path).map_err(|_| Errno::EXDEV)?;
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(Errno::EXDEV.into());
+        }
+        // The root itself.
+        if relative.as_os_str().is_empty() {
+            return Ok(Path::new("."));
+        }
+        Ok(relative)
+    }
+
+    // Open an export path. A symlink anywhere on the path, including at
+    // the end, fails with ELOOP, except that an O_PATH descriptor is given
+    // for a symlink at the end.
+    fn open_fd(&self, path: &Path, flags: OFlag, mode: u32) -> io::Result<OwnedFd> {
+        let relative = self.relative(path)?;
+        let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        if self.openat2 {
+            // EAGAIN means a rename elsewhere raced with the lookup.
+            let mut tries = 0;
+            loop {
+                match openat2(self.fd.as_raw_fd(), relative, flags, mode) {
+                    Err(Errno::EAGAIN) if tries < 3 => tries += 1,
+                    result => return Ok(result?),
+                }
+            }
+        }
+        self.walk(relative, flags, mode)
+    }
+
+    // The descriptor-at-a-time lookup for kernels without openat2. The
+    // components are all plain names, so ".." never comes up.
+    fn walk(&self, relative: &Path, flags: OFlag, mode: u32) -> io::Result<OwnedFd> {
+        let components: Vec<&OsStr> = relative.iter().collect();
+        let Some((last, dirs)) = components.split_last() else {
+            return open_owned(self.fd.as_raw_fd(), OsStr::new("."), flags, mode);
+        };
+        let dir_flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let mut dir = open_owned(self.fd.as_raw_fd(), OsStr::new("."), dir_flags, 0)?;
+        for name in dirs {
+            dir = open_owned(dir.as_raw_fd(), name, dir_flags, 0).map_err(not_dir_is_loop(dir.as_raw_fd(), name))?;
+        }
+        open_owned(dir.as_raw_fd(), last, flags, mode)
+    }
+
+    // The directory holding an export path, and the entry's name in it.
+    fn parent<'a>(&self, path: &'a Path) -> io::Result<(OwnedFd, &'a OsStr)> {
+        let name = path.file_name().ok_or(Errno::EINVAL)?;
+        let dir = path.parent().ok_or(Errno::EINVAL)?;
+        let fd = self.open_fd(dir, OFlag::O_PATH | OFlag::O_DIRECTORY, 0)?;
+        Ok((fd, name))
+    }
+
+    pub fn open(&self, path: &Path, flags: OFlag) -> io::Result<File> {
+        Ok(File::from(self.open_fd(path, flags, 0)?))
+    }
+
+    // Like lstat.
+    pub fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        File::from(self.open_fd(path, OFlag::O_PATH, 0)?).metadata()
+    }
+
+    // Create a regular file that must not exist yet.
+    pub fn create_file(&self, path: &Path) -> io::Result<File> {
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL;
+        Ok(File::from(self.open_fd(path, flags, 0o666)?))
+    }
+
+    pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        mkdirat(dir.as_raw_fd(), name, Mode::from_bits_truncate(0o777))?;
+        Ok(())
+    }
+
+    pub fn symlink(&self, target: &str, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        symlinkat(target, Some(dir.as_raw_fd()), name)?;
+        Ok(())
+    }
+
+    pub fn mknod(&self, path: &Path, kind: SFlag, dev: libc::dev_t) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        mknodat(dir.as_raw_fd(), name, kind, Mode::from_bits_truncate(0o666), dev)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        let flag = if is_dir {
+            UnlinkatFlags::RemoveDir
+        } else {
+            UnlinkatFlags::NoRemoveDir
+        };
+        unlinkat(Some(dir.as_raw_fd()), name, flag)?;
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        renameat(Some(from_dir.as_raw_fd()), from_name, Some(to_dir.as_raw_fd()), to_name)?;
+        Ok(())
+    }
+
+    // A new name for `from`, which is not followed if it is a symlink.
+    pub fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        linkat(
+            Some(from_dir.as_raw_fd()),
+            from_name,
+            Some(to_dir.as_raw_fd()),
+            to_name,
+            LinkatFlags::NoSymlinkFollow,
+        )?;
+        Ok(())
+    }
+
+    pub fn truncate(&self, path: &Path, size: u64) -> io::Result<()> {
+        // Not blocking if the file has been replaced with a FIFO.
+        self.open(path, OFlag::O_WRONLY | OFlag::O_NONBLOCK)?.set_len(size)
+    }
+
+    // Statistics of the filesystem holding an export path. For a symlink
+    // that is the filesystem of the symlink itself, not of its target.
+    pub fn statvfs(&self, path: &Path) -> io::Result<Statvfs> {
+        let file = File::from(self.open_fd(path, OFlag::O_PATH, 0)?);
+        Ok(fstatvfs(&file)?)
+    }
+
+    pub fn link_max(&self, path: &Path) -> io::Result<Option<libc::c_long>> {
+        let file = File::from(self.open_fd(path, OFlag::O_PATH, 0)?);
+        Ok(fpathconf(file.as_raw_fd(), PathconfVar::LINK_MAX)?)
+    }
+
+    pub fn read_link(&self, path: &Path) -> io::Result<OsString> {
+        let (dir, name) = self.parent(path)?;
+        Ok(readlinkat(dir.as_raw_fd(), name)?)
+    }
+
+    // Names in a directory, without "." and "..".
+    pub fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let fd = self.open_fd(path, OFlag::O_RDONLY | OFlag::O_DIRECTORY, 0)?;
+        let mut dir = Dir::from_fd(fd.as_raw_fd())?;
+        // The Dir owns the descriptor now.
+        std::mem::forget(fd);
+        let mut names = Vec::new();
+        for entry in dir.iter() {
+            let name = entry?.file_name().to_bytes().to_vec();
+            if name != b"." && name != b".." {
+                names.push(OsStr::from_bytes(&name).to_os_string());
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn set_owner(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        fchownat(
+            Some(dir.as_raw_fd()),
+            name,
+            uid.map(Uid::from_raw),
+            gid.map(Gid::from_raw),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    // There is no chmod that refuses to follow a symlink, so the file is
+    // pinned with an O_PATH descriptor first and changed through its
+    // /proc/self/fd link, which leads to exactly that file.
+    pub fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let file = File::from(self.open_fd(path, OFlag::O_PATH, 0)?);
+        if file.metadata()?.file_type().is_symlink() {
+            return Err(Errno::EINVAL.into());
+        }
+        let pinned = format!("/proc/self/fd/{}", file.as_raw_fd());
+        fchmodat(None, pinned.as_str(), Mode::from_bits_truncate(mode), FchmodatFlags::FollowSymlink)?;
+        Ok(())
+    }
+
+    pub fn set_times(&self, path: &Path, atime: &TimeSpec, mtime: &TimeSpec) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        utimensat(Some(dir.as_raw_fd()), name, atime, mtime, UtimensatFlags::NoFollowSymlink)?;
+        Ok(())
+    }
+}
+
+fn openat2(dirfd: RawFd, path: &Path, flags: OFlag, mode: u32) -> nix::Result<OwnedFd> {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    // open_how may grow fields, which must then be zero.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = flags.bits() as u64;
+    // A mode is only allowed along with O_CREAT.
+    how.mode = if flags.contains(OFlag::O_CREAT) { mode as u64 } else { 0 };
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd,
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    Errno::result(fd).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn open_owned(dirfd: RawFd, name: &OsStr, flags: OFlag, mode: u32) -> io::Result<OwnedFd> {
+    let fd = openat(dirfd, name, flags, Mode::from_bits_truncate(mode))?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// O_DIRECTORY | O_NOFOLLOW on a symlink fails with ENOTDIR. Report it as
+// ELOOP, as openat2 does, when the name really is a symlink.
+fn not_dir_is_loop(dirfd: RawFd, name: &OsStr) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |err| {
+        if err.raw_os_error() != Some(libc::ENOTDIR) {
+            return err;
+        }
+        let is_symlink = open_owned(dirfd, name, OFlag::O_PATH | OFlag::O_NOFOLLOW, 0)
+            .and_then(|fd| File::from(fd).metadata())
+            .is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_symlink {
+            Errno::ELOOP.into()
+        } else {
+            err
+        }
+    }
+}
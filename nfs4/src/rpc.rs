@@ -1,6 +1,11 @@
-use anyhow::Result;
-use bytes::{Buf, BufMut, BytesMut};
-use serde::{Deserialize, Serialize};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::xdr::*;
+
+// ONC RPC messages (RFC 5531 section 9). The arguments of a call and the
+// results of a reply follow the header as they are, so they are kept as
+// raw bytes for the program to decode.
 
 // RPC message types
 pub const RPC_CALL: u32 = 0;
@@ -19,25 +24,40 @@ pub const PROG_UNAVAIL: u32 = 1;
 pub const PROG_MISMATCH: u32 = 2;
 pub const PROC_UNAVAIL: u32 = 3;
 pub const GARBAGE_ARGS: u32 = 4;
+pub const SYSTEM_ERR: u32 = 5;
+
+// Reject status
+pub const RPC_MISMATCH: u32 = 0;
+pub const AUTH_ERROR: u32 = 1;
+
+// Auth status, the reason for an AUTH_ERROR
+pub const AUTH_BADCRED: u32 = 1;
 
 // Auth flavors
 pub const AUTH_NONE: u32 = 0;
 pub const AUTH_SYS: u32 = 1;
 pub const AUTH_SHORT: u32 = 2;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Credentials and verifiers are at most this long
+const MAX_AUTH_BYTES: usize = 400;
+
+// Limits on AUTH_SYS credentials (RFC 5531 appendix A)
+const AUTH_SYS_MAX_MACHINENAME: usize = 255;
+const AUTH_SYS_MAX_GIDS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct RpcMsg {
     pub xid: u32,
     pub body: RpcMsgBody,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RpcMsgBody {
     Call(CallBody),
     Reply(ReplyBody),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CallBody {
     pub rpc_vers: u32,
     pub prog: u32,
@@ -48,38 +68,65 @@ pub struct CallBody {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ReplyBody {
     pub reply_stat: u32,
     pub data: ReplyData,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ReplyData {
     Accepted(AcceptedReply),
     Rejected(RejectedReply),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// `data` is whatever follows the accept status: the results of the call on
+// SUCCESS, or the supported versions on PROG_MISMATCH.
+#[derive(Debug, Clone, PartialEq)]
 pub struct AcceptedReply {
     pub verf: OpaqueAuth,
     pub stat: u32,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// `data` is the supported RPC versions on RPC_MISMATCH, or the auth status
+// on AUTH_ERROR.
+#[derive(Debug, Clone, PartialEq)]
 pub struct RejectedReply {
     pub stat: u32,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct OpaqueAuth {
     pub flavor: u32,
     pub body: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl OpaqueAuth {
+    pub fn none() -> Self {
+        Self {
+            flavor: AUTH_NONE,
+            body: vec![],
+        }
+    }
+
+    fn decode(buf: &mut Bytes) -> XdrResult<Self> {
+        let flavor = get_u32(buf)?;
+        let body = get_opaque(buf)?;
+        if body.len() > MAX_AUTH_BYTES {
+            return Err(XdrError::LengthTooLarge(body.len()));
+        }
+        Ok(Self { flavor, body })
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.flavor);
+        put_opaque(buf, &self.body);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AuthSys {
     pub stamp: u32,
     pub machinename: String,
@@ -88,6 +135,37 @@ pub struct AuthSys {
     pub gids: Vec<u32>,
 }
 
+impl AuthSys {
+    // Decode the body of an AUTH_SYS credential.
+    pub fn decode(body: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(body);
+        let stamp = get_u32(&mut buf)?;
+        let machinename = get_string(&mut buf)?;
+        if machinename.len() > AUTH_SYS_MAX_MACHINENAME {
+            return Err(XdrError::LengthTooLarge(machinename.len()));
+        }
+        let uid = get_u32(&mut buf)?;
+        let gid = get_u32(&mut buf)?;
+        let gids = get_array(&mut buf, get_u32)?;
+        if gids.len() > AUTH_SYS_MAX_GIDS {
+            return Err(XdrError::LengthTooLarge(gids.len()));
+        }
+        Ok(AuthSys {
+            stamp,
+            machinename,
+            uid,
+            gid,
+            gids,
+        })
+    }
+}
+
+// The lowest and highest versions supported, sent with PROG_MISMATCH and
+// RPC_MISMATCH.
+fn mismatch_info(low: u32, high: u32) -> Vec<u8> {
+    [low.to_be_bytes(), high.to_be_bytes()].concat()
+}
+
 impl RpcMsg {
     pub fn new_call(xid: u32, prog: u32, prog_vers: u32, proc: u32, data: Vec<u8>) -> Self {
         RpcMsg {
@@ -97,102 +175,217 @@ impl RpcMsg {
                 prog,
                 prog_vers,
                 proc,
-                cred: OpaqueAuth {
-                    flavor: AUTH_NONE,
-                    body: vec![],
-                },
-                verf: OpaqueAuth {
-                    flavor: AUTH_NONE,
-                    body: vec![],
-                },
+                cred: OpaqueAuth::none(),
+                verf: OpaqueAuth::none(),
                 data,
             }),
         }
     }
 
-    pub fn new_success_reply(xid: u32, data: Vec<u8>) -> Self {
+    fn new_accepted_reply(xid: u32, stat: u32, data: Vec<u8>) -> Self {
         RpcMsg {
             xid,
             body: RpcMsgBody::Reply(ReplyBody {
                 reply_stat: MSG_ACCEPTED,
                 data: ReplyData::Accepted(AcceptedReply {
-                    verf: OpaqueAuth {
-                        flavor: AUTH_NONE,
-                        body: vec![],
-                    },
-                    stat: SUCCESS,
+                    verf: OpaqueAuth::none(),
+                    stat,
                     data,
                 }),
             }),
         }
     }
 
-    pub fn new_prog_mismatch_reply(xid: u32) -> Self {
+    fn new_rejected_reply(xid: u32, stat: u32, data: Vec<u8>) -> Self {
         RpcMsg {
             xid,
             body: RpcMsgBody::Reply(ReplyBody {
-                reply_stat: MSG_ACCEPTED,
-                data: ReplyData::Accepted(AcceptedReply {
-                    verf: OpaqueAuth {
-                        flavor: AUTH_NONE,
-                        body: vec![],
-                    },
-                    stat: PROG_MISMATCH,
-                    data: vec![],
-                }),
+                reply_stat: MSG_DENIED,
+                data: ReplyData::Rejected(RejectedReply { stat, data }),
             }),
         }
     }
 
+    pub fn new_success_reply(xid: u32, data: Vec<u8>) -> Self {
+        Self::new_accepted_reply(xid, SUCCESS, data)
+    }
+
+    pub fn new_prog_unavail_reply(xid: u32) -> Self {
+        Self::new_accepted_reply(xid, PROG_UNAVAIL, vec![])
+    }
+
+    pub fn new_prog_mismatch_reply(xid: u32, low: u32, high: u32) -> Self {
+        Self::new_accepted_reply(xid, PROG_MISMATCH, mismatch_info(low, high))
+    }
+
+    pub fn new_proc_unavail_reply(xid: u32) -> Self {
+        Self::new_accepted_reply(xid, PROC_UNAVAIL, vec![])
+    }
+
     pub fn new_garbage_args_reply(xid: u32) -> Self {
-        RpcMsg {
-            xid,
-            body: RpcMsgBody::Reply(ReplyBody {
-                reply_stat: MSG_ACCEPTED,
-                data: ReplyData::Accepted(AcceptedReply {
-                    verf: OpaqueAuth {
-                        flavor: AUTH_NONE,
-                        body: vec![],
-                    },
-                    stat: GARBAGE_ARGS,
-                    data: vec![],
-                }),
-            }),
-        }
+        Self::new_accepted_reply(xid, GARBAGE_ARGS, vec![])
+    }
+
+    pub fn new_system_err_reply(xid: u32) -> Self {
+        Self::new_accepted_reply(xid, SYSTEM_ERR, vec![])
+    }
+
+    pub fn new_rpc_mismatch_reply(xid: u32, low: u32, high: u32) -> Self {
+        Self::new_rejected_reply(xid, RPC_MISMATCH, mismatch_info(low, high))
+    }
+
+    pub fn new_auth_error_reply(xid: u32, auth_stat: u32) -> Self {
+        Self::new_rejected_reply(xid, AUTH_ERROR, auth_stat.to_be_bytes().to_vec())
     }
 
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        serde_xdr::to_writer(&mut buf, self)?;
-        Ok(buf)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u32(self.xid);
+        match &self.body {
+            RpcMsgBody::Call(call) => {
+                buf.put_u32(RPC_CALL);
+                buf.put_u32(call.rpc_vers);
+                buf.put_u32(call.prog);
+                buf.put_u32(call.prog_vers);
+                buf.put_u32(call.proc);
+                call.cred.encode(&mut buf);
+                call.verf.encode(&mut buf);
+                buf.put_slice(&call.data);
+            }
+            RpcMsgBody::Reply(reply) => {
+                buf.put_u32(RPC_REPLY);
+                buf.put_u32(reply.reply_stat);
+                match &reply.data {
+                    ReplyData::Accepted(accepted) => {
+                        accepted.verf.encode(&mut buf);
+                        buf.put_u32(accepted.stat);
+                        buf.put_slice(&accepted.data);
+                    }
+                    ReplyData::Rejected(rejected) => {
+                        buf.put_u32(rejected.stat);
+                        buf.put_slice(&rejected.data);
+                    }
+                }
+            }
+        }
+        buf.to_vec()
     }
 
-    pub fn decode(buf: &[u8]) -> Result<Self> {
-        Ok(serde_xdr::from_bytes(buf)?)
+    pub fn decode(data: &[u8]) -> XdrResult<Self> {
+        let mut buf = Bytes::copy_from_slice(data);
+        let xid = get_u32(&mut buf)?;
+        let body = match get_u32(&mut buf)? {
+            RPC_CALL => RpcMsgBody::Call(CallBody {
+                rpc_vers: get_u32(&mut buf)?,
+                prog: get_u32(&mut buf)?,
+                prog_vers: get_u32(&mut buf)?,
+                proc: get_u32(&mut buf)?,
+                cred: OpaqueAuth::decode(&mut buf)?,
+                verf: OpaqueAuth::decode(&mut buf)?,
+                data: buf.to_vec(),
+            }),
+            RPC_REPLY => {
+                let reply_stat = get_u32(&mut buf)?;
+                let data = match reply_stat {
+                    MSG_ACCEPTED => ReplyData::Accepted(AcceptedReply {
+                        verf: OpaqueAuth::decode(&mut buf)?,
+                        stat: get_u32(&mut buf)?,
+                        data: buf.to_vec(),
+                    }),
+                    MSG_DENIED => ReplyData::Rejected(RejectedReply {
+                        stat: get_u32(&mut buf)?,
+                        data: buf.to_vec(),
+                    }),
+                    value => return Err(XdrError::InvalidDiscriminant { what: "reply_stat", value }),
+                };
+                RpcMsgBody::Reply(ReplyBody { reply_stat, data })
+            }
+            value => return Err(XdrError::InvalidDiscriminant { what: "msg_type", value }),
+        };
+        Ok(RpcMsg { xid, body })
     }
 }
 
-// Helper function to read a complete RPC message from a buffer
-pub fn read_rpc_message(buf: &mut BytesMut) -> Option<Result<RpcMsg>> {
-    if buf.len() < 4 {
-        return None;
+// Record marking (RFC 5531 section 11). On a stream, each message is sent
+// as a record of one or more fragments, every fragment preceded by a word
+// holding its length, with the top bit set on the last fragment of the
+// record.
+pub const LAST_FRAGMENT: u32 = 0x8000_0000;
+
+// Room for a COMPOUND carrying a maximal WRITE.
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 1024 * 1024 + 64 * 1024;
+
+// Replies longer than this go out in several fragments.
+const REPLY_FRAGMENT_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum RecordError {
+    #[error("record of more than {0} bytes")]
+    TooLarge(usize),
+    #[error("empty record")]
+    Empty,
+}
+
+// Reassembles records from the bytes read off a connection.
+#[derive(Debug)]
+pub struct RecordReader {
+    max_size: usize,
+    // The fragments of the record being read so far.
+    record: BytesMut,
+}
+
+impl RecordReader {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            record: BytesMut::new(),
+        }
     }
 
-    let size = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-    if buf.len() < size + 4 {
-        return None;
+    // Take the next complete record out of `buf`, or None if more data is
+    // needed. Fragments are checked against the maximum as soon as their
+    // length is known, so an oversized record is refused before it is read.
+    pub fn read(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, RecordError> {
+        loop {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let mark = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let len = (mark & !LAST_FRAGMENT) as usize;
+            if self.record.len() + len > self.max_size {
+                return Err(RecordError::TooLarge(self.max_size));
+            }
+            if buf.len() < 4 + len {
+                return Ok(None);
+            }
+
+            buf.advance(4);
+            self.record.extend_from_slice(&buf.split_to(len));
+            if mark & LAST_FRAGMENT != 0 {
+                if self.record.is_empty() {
+                    return Err(RecordError::Empty);
+                }
+                return Ok(Some(std::mem::take(&mut self.record)));
+            }
+        }
     }
+}
 
-    buf.advance(4);
-    let msg_buf = buf.split_to(size);
-    Some(RpcMsg::decode(&msg_buf))
+// Append `data` to `buf` as one record, in fragments of at most
+// `fragment_size` bytes.
+pub fn write_record(data: &[u8], fragment_size: usize, buf: &mut BytesMut) {
+    let mut chunks = data.chunks(fragment_size).peekable();
+    if chunks.peek().is_none() {
+        buf.put_u32(LAST_FRAGMENT);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = if chunks.peek().is_none() { LAST_FRAGMENT } else { 0 };
+        buf.put_u32(chunk.len() as u32 | last);
+        buf.put_slice(chunk);
+    }
 }
 
-// Helper function to write an RPC message to a buffer
-pub fn write_rpc_message(msg: &RpcMsg, buf: &mut BytesMut) -> Result<()> {
-    let encoded = msg.encode()?;
-    let len = (encoded.len() as u32).to_be_bytes();
-    buf.put_slice(&len);
-    buf.put_slice(&encoded);
-    Ok(())
+// Append an RPC message to `buf` as a record.
+pub fn write_rpc_message(msg: &RpcMsg, buf: &mut BytesMut) {
+    write_record(&msg.encode(), REPLY_FRAGMENT_SIZE, buf);
 }
@@ -1,83 +1,843 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use anyhow::{Result, anyhow};
-use std::time::{SystemTime, UNIX_EPOCH};
-use rand::Rng;
-use tokio::fs::{self, File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncSeekExt};
-use std::os::unix::fs::MetadataExt;
-use nix::unistd::{Uid, Gid};
+use anyhow::Result;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use nix::errno::Errno;
+use log::{debug, info, warn};
 
+use crate::attr::{
+    apply_attributes, check_setattr, decode_settable, encode_attributes, encode_pseudo_attributes, fattr4_encoded_len,
+    rdattr_error, set_creator, wants_fs_stats,
+    NfsSetAttributes, SetTime, WRITE_ONLY_ATTRS,
+};
+use crate::auth::{Credentials, MAY_EXEC, MAY_READ, MAY_WRITE};
+use crate::backend::{FileAttr, FileSystemBackend};
+use crate::callback;
+use crate::delegation::{Delegation, DelegationTable, Recall};
+use crate::drc::{DrcStats, DuplicateRequestCache, DEFAULT_DRC_CAPACITY};
+use crate::export::{Export, ExportEntry, ExportTable, PseudoEntry, pseudo_generation, PSEUDO_EXPORT_ID};
+use crate::filehandle::{FileId, FileKey, HandleIndex};
+use crate::idmap::{hostname, IdMap};
+use crate::local::LocalFs;
+use crate::lock::{range_end, LockTable};
 use crate::protocol::*;
+use crate::resolve::check_name;
+use crate::session::{SessionTable, SlotCheck};
+use crate::state::{make_stateid, stateid_other, stateid_seqid, ClientTable, SeqidCheck, StateOwner};
 
-#[derive(Clone)]
-pub struct NfsServer {
-    export_root: PathBuf,
-    handles: Arc<RwLock<HashMap<Vec<u8>, PathBuf>>>,
-    stateids: Arc<RwLock<HashMap<[u8; 16], FileState>>>,
+// Lease period in seconds advertised to clients.
+pub(crate) const LEASE_TIME: u32 = 90;
+
+// Largest READ or WRITE payload, advertised as maxread and maxwrite.
+pub(crate) const MAX_IO_SIZE: u32 = 1024 * 1024;
+
+// READDIR cookies 0, 1 and 2 are reserved, so the entry at index i of the
+// sorted directory listing is given cookie i + COOKIE_BASE.
+const COOKIE_BASE: u64 = 3;
+
+// Encoded size of a READDIR4resok with no entries: cookieverf, the list
+// terminator and the eof flag.
+const READDIR_RESOK_BASE: usize = 8 + 4 + 4;
+
+// An NFSv4 server for the exports of a storage backend, the local
+// filesystem unless another is given.
+pub struct NfsServer<B: FileSystemBackend = LocalFs> {
+    backend: Arc<B>,
+    exports: Arc<ExportTable>,
+    // The exports the client of a connection may use, set by for_client.
+    // None serves every export.
+    allowed: Option<Arc<HashSet<u32>>>,
+    // Known paths of each (export, device, inode) a handle has been issued
+    // for.
+    handles: Arc<RwLock<HandleIndex>>,
+    // Open state, keyed by the part of the stateid that stays the same as
+    // its seqid advances.
+    stateids: Arc<RwLock<StateTable<B::File>>>,
+    clients: Arc<RwLock<ClientTable>>,
+    open_owners: Arc<RwLock<HashMap<OwnerKey, StateOwner>>>,
+    lock_owners: Arc<RwLock<HashMap<OwnerKey, StateOwner>>>,
+    locks: Arc<RwLock<LockTable>>,
+    delegations: Arc<RwLock<DelegationTable<B::File>>>,
+    sessions: Arc<RwLock<SessionTable>>,
+    drc: Arc<RwLock<DuplicateRequestCache>>,
+    idmap: Arc<IdMap>,
+    // Identifies this server to NFSv4.1 clients, which use it to tell
+    // whether two addresses lead to the same server.
+    server_owner: Vec<u8>,
+    write_verifier: [u8; 8],
+    // Times of the pseudo-filesystem, which only changes with a restart.
+    boot_time: NfsTime,
+}
+
+// Not derived, which would require the backend to be Clone as well.
+impl<B: FileSystemBackend> Clone for NfsServer<B> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            exports: self.exports.clone(),
+            allowed: self.allowed.clone(),
+            handles: self.handles.clone(),
+            stateids: self.stateids.clone(),
+            clients: self.clients.clone(),
+            open_owners: self.open_owners.clone(),
+            lock_owners: self.lock_owners.clone(),
+            locks: self.locks.clone(),
+            delegations: self.delegations.clone(),
+            sessions: self.sessions.clone(),
+            drc: self.drc.clone(),
+            idmap: self.idmap.clone(),
+            server_owner: self.server_owner.clone(),
+            write_verifier: self.write_verifier,
+            boot_time: self.boot_time.clone(),
+        }
+    }
+}
+
+// Filehandle slots carried from one operation to the next within a single
+// COMPOUND request.
+#[derive(Default)]
+struct CompoundState {
+    minor_version: u32,
+    // The caller, from the credential of the RPC call.
+    caller: Credentials,
+    // Who the caller acts as on the export of the current filehandle,
+    // once its squash options are applied.
+    cred: Credentials,
+    current_fh: Option<NfsFileHandle>,
+    saved_fh: Option<NfsFileHandle>,
+    // Set by SEQUENCE in NFSv4.1 requests.
+    slot: Option<SessionSlot>,
+    // The cached reply when SEQUENCE finds the request is a retransmission.
+    replay: Option<CompoundResponse>,
+}
+
+struct SessionSlot {
+    sessionid: [u8; 16],
+    slotid: u32,
+    clientid: u64,
+    cache_this: bool,
 }
 
+// Open-owners and lock-owners are identified by their client and an
+// opaque owner string.
+type OwnerKey = (u64, Vec<u8>);
+
+// Open and lock state by stateid, `F` being the backend's open file.
+type StateTable<F> = HashMap<[u8; 12], FileState<F>>;
+
+// State behind an open stateid, or a lock stateid when `open` is set.
 #[derive(Debug)]
-struct FileState {
-    path: PathBuf,
+struct FileState<F> {
+    clientid: u64,
+    owner: Vec<u8>,
+    file_key: FileKey,
+    // Share reservation: the access the open has and the access it denies
+    // to others.
     open_mode: u32,
+    share_deny: u32,
+    // Every share_access and share_deny value the owner has opened the file
+    // with, as bitmaps indexed by the value. OPEN_DOWNGRADE may only go
+    // back to one of them.
+    access_modes: u32,
+    deny_modes: u32,
+    // Current seqid of the stateid.
     seqid: u32,
-    file: Option<File>,
+    // False until the owner's first OPEN is confirmed.
+    confirmed: bool,
+    file: Option<F>,
+    // For a lock stateid, the open stateid its locks were first taken under.
+    open: Option<[u8; 12]>,
+}
+
+// Whether an open with the given access and deny modes conflicts with the
+// share reservation of another open of the file (RFC 7530 section 9.9).
+fn share_conflict<F>(
+    stateids: &StateTable<F>,
+    file_key: &FileKey,
+    except: Option<&[u8; 12]>,
+    access: u32,
+    deny: u32,
+) -> bool {
+    stateids.iter().any(|(other, state)| {
+        state.open.is_none()
+            && Some(other) != except
+            && state.file_key == *file_key
+            && (access & state.share_deny != 0 || deny & state.open_mode != 0)
+    })
+}
+
+// Check the seqid of a stateid against the open state it refers to.
+fn check_stateid_seqid<F>(state: &FileState<F>, stateid: &[u8; 16]) -> std::result::Result<(), NfsStatus> {
+    if !state.confirmed {
+        return Err(NfsStatus::BadStateid);
+    }
+    check_current_seqid(state.seqid, stateid)
+}
+
+// Check the seqid of a stateid against the current one. Zero stands for
+// the current seqid (RFC 8881 section 8.2.2).
+fn check_current_seqid(current: u32, stateid: &[u8; 16]) -> std::result::Result<(), NfsStatus> {
+    let seqid = stateid_seqid(stateid);
+    if seqid == 0 || seqid == current {
+        Ok(())
+    } else if seqid < current {
+        Err(NfsStatus::OldStateid)
+    } else {
+        Err(NfsStatus::BadStateid)
+    }
+}
+
+// The error for a stateid of a delegation that was revoked. NFSv4.0 has
+// no error of its own for it.
+fn revoked_status(sessions: bool) -> NfsStatus {
+    if sessions {
+        NfsStatus::DelegRevoked
+    } else {
+        NfsStatus::Expired
+    }
+}
+
+// Map an error from the storage backend to the closest NFSv4 status.
+// Errors without an errno are told apart by their kind, as far as that
+// goes.
+pub(crate) fn io_error_status(err: &std::io::Error) -> NfsStatus {
+    let errno = match err.raw_os_error() {
+        Some(code) => Errno::from_i32(code),
+        None => {
+            return match err.kind() {
+                std::io::ErrorKind::NotFound => NfsStatus::NoEnt,
+                std::io::ErrorKind::PermissionDenied => NfsStatus::Access,
+                std::io::ErrorKind::AlreadyExists => NfsStatus::Exist,
+                _ => NfsStatus::IoError,
+            }
+        }
+    };
+    match errno {
+        Errno::EPERM => NfsStatus::Perm,
+        Errno::ENOENT => NfsStatus::NoEnt,
+        Errno::ENXIO => NfsStatus::NxIo,
+        Errno::EACCES => NfsStatus::Access,
+        Errno::EEXIST => NfsStatus::Exist,
+        Errno::EXDEV => NfsStatus::XDev,
+        Errno::ENOTDIR => NfsStatus::NotDir,
+        Errno::EISDIR => NfsStatus::IsDir,
+        Errno::EINVAL => NfsStatus::Inval,
+        Errno::EFBIG => NfsStatus::FBig,
+        Errno::ENOSPC => NfsStatus::NoSpace,
+        Errno::EROFS => NfsStatus::RoFs,
+        Errno::EMLINK => NfsStatus::MLink,
+        Errno::ENAMETOOLONG => NfsStatus::NameTooLong,
+        Errno::ENOTEMPTY => NfsStatus::NotEmpty,
+        Errno::EDQUOT => NfsStatus::DQuot,
+        Errno::ESTALE => NfsStatus::StaleFileHandle,
+        Errno::ELOOP => NfsStatus::Symlink,
+        _ => NfsStatus::IoError,
+    }
+}
+
+// Exclusive creates keep the client's verifier in the file's access and
+// modify times so a retransmitted OPEN can be told apart from a
+// conflicting one.
+fn exclusive_attributes(verifier: &[u8; 8], attrs: NfsSetAttributes) -> NfsSetAttributes {
+    NfsSetAttributes {
+        time_access: Some(SetTime::ClientTime(NfsTime {
+            seconds: u32::from_be_bytes([verifier[0], verifier[1], verifier[2], verifier[3]]) as u64,
+            nseconds: 0,
+        })),
+        time_modify: Some(SetTime::ClientTime(NfsTime {
+            seconds: u32::from_be_bytes([verifier[4], verifier[5], verifier[6], verifier[7]]) as u64,
+            nseconds: 0,
+        })),
+        ..attrs
+    }
+}
+
+// Operations that may start an NFSv4.1 COMPOUND without a SEQUENCE, as
+// long as they are its only operation (RFC 8881 section 18.46.3).
+fn sessionless_op(op: u32) -> bool {
+    matches!(
+        op,
+        OP_EXCHANGE_ID | OP_CREATE_SESSION | OP_DESTROY_SESSION | OP_DESTROY_CLIENTID | OP_BIND_CONN_TO_SESSION
+    )
+}
+
+// NFSv4.0 operations that NFSv4.1 replaced with sessions.
+fn v40_only_op(op: u32) -> bool {
+    matches!(
+        op,
+        OP_OPEN_CONFIRM | OP_RELEASE_LOCKOWNER | OP_RENEW | OP_SETCLIENTID | OP_SETCLIENTID_CONFIRM
+    )
+}
+
+// Check that an operation may appear at this position in a COMPOUND of the
+// request's minor version.
+fn check_operation(
+    state: &CompoundState,
+    index: usize,
+    op_count: usize,
+    operation: &NfsOperation,
+) -> std::result::Result<(), NfsStatus> {
+    let op = operation.opcode();
+    let last_op = match state.minor_version {
+        0 => OP_RELEASE_LOCKOWNER,
+        _ => OP_RECLAIM_COMPLETE,
+    };
+    if !(OP_ACCESS..=last_op).contains(&op) {
+        return Err(NfsStatus::OpIllegal);
+    }
+    if state.minor_version == 0 {
+        return Ok(());
+    }
+
+    if v40_only_op(op) {
+        return Err(NfsStatus::NotSupp);
+    }
+    if index > 0 {
+        return match op {
+            OP_SEQUENCE => Err(NfsStatus::SequencePos),
+            _ => Ok(()),
+        };
+    }
+    match op {
+        OP_SEQUENCE => Ok(()),
+        op if sessionless_op(op) && op_count == 1 => Ok(()),
+        op if sessionless_op(op) => Err(NfsStatus::NotOnlyOp),
+        _ => Err(NfsStatus::OpNotInSession),
+    }
 }
 
 impl NfsServer {
-    pub fn new(export_root: PathBuf) -> Self {
+    // Serve a single directory as the whole tree.
+    pub fn new(export_root: PathBuf) -> Result<Self> {
+        Self::from_exports(vec![Export::new("/", export_root)])
+    }
+
+    // Serve `exports`, joined by a pseudo-filesystem unless there is just
+    // one at "/".
+    pub fn from_exports(exports: Vec<Export>) -> Result<Self> {
+        let exports = ExportTable::new(exports)?;
+        let backend = LocalFs::new(exports.exports().iter().map(|export| export.root.as_path()))?;
+        Ok(NfsServer::from_table(backend, exports))
+    }
+}
+
+impl<B: FileSystemBackend> NfsServer<B> {
+    // Serve `exports` from `backend`, which their directories are paths
+    // in.
+    pub fn with_backend(backend: B, exports: Vec<Export>) -> Result<Self> {
+        Ok(Self::from_table(backend, ExportTable::from_paths(exports)?))
+    }
+
+    fn from_table(backend: B, exports: ExportTable) -> Self {
+        // Clients compare the write verifier across WRITE and COMMIT replies
+        // to detect a server restart, so it only has to be unique per boot.
+        let boot_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let server_owner = match hostname().as_str() {
+            "" => b"localhost".to_vec(),
+            name => name.as_bytes().to_vec(),
+        };
+
         Self {
-            export_root,
-            handles: Arc::new(RwLock::new(HashMap::new())),
+            backend: Arc::new(backend),
+            exports: Arc::new(exports),
+            allowed: None,
+            handles: Arc::new(RwLock::new(HandleIndex::default())),
             stateids: Arc::new(RwLock::new(HashMap::new())),
+            clients: Arc::new(RwLock::new(ClientTable::new(Duration::from_secs(LEASE_TIME as u64)))),
+            open_owners: Arc::new(RwLock::new(HashMap::new())),
+            lock_owners: Arc::new(RwLock::new(HashMap::new())),
+            locks: Arc::new(RwLock::new(LockTable::new(Duration::from_secs(LEASE_TIME as u64)))),
+            delegations: Arc::new(RwLock::new(DelegationTable::new(Duration::from_secs(LEASE_TIME as u64)))),
+            sessions: Arc::new(RwLock::new(SessionTable::new())),
+            drc: Arc::new(RwLock::new(DuplicateRequestCache::new(DEFAULT_DRC_CAPACITY))),
+            idmap: Arc::new(IdMap::numeric()),
+            server_owner,
+            write_verifier: boot_time.to_be_bytes(),
+            boot_time: NfsTime {
+                seconds: boot_time / 1_000_000_000,
+                nseconds: (boot_time % 1_000_000_000) as u32,
+            },
+        }
+    }
+
+    // The server as seen by a connection from `addr`, which only reaches
+    // the exports serving it. None if there are none, and the connection
+    // should be refused.
+    pub fn for_client(&self, addr: IpAddr) -> Option<Self> {
+        let allowed: HashSet<u32> = self
+            .exports
+            .exports()
+            .iter()
+            .filter(|export| export.options.allows(addr))
+            .map(|export| export.id)
+            .collect();
+        if allowed.is_empty() {
+            return None;
+        }
+        Some(Self {
+            allowed: Some(Arc::new(allowed)),
+            ..self.clone()
+        })
+    }
+
+    // Keep the paths of handles given out in `index`, which may be kept
+    // across restarts, rather than only in memory.
+    pub fn with_handle_index(mut self, index: HandleIndex) -> Self {
+        self.handles = Arc::new(RwLock::new(index));
+        self
+    }
+
+    // Map owners to and from names with `idmap` rather than sending
+    // numeric ids.
+    pub fn with_idmap(mut self, idmap: IdMap) -> Self {
+        self.idmap = Arc::new(idmap);
+        self
+    }
+
+    // Keep up to `capacity` replies to non-idempotent NFSv4.0 calls.
+    pub fn with_drc_capacity(mut self, capacity: usize) -> Self {
+        self.drc = Arc::new(RwLock::new(DuplicateRequestCache::new(capacity)));
+        self
+    }
+
+    pub(crate) fn drc(&self) -> &RwLock<DuplicateRequestCache> {
+        &self.drc
+    }
+
+    pub async fn drc_stats(&self) -> DrcStats {
+        self.drc.read().await.stats()
+    }
+
+    // Periodically expire clients that have stopped renewing their lease
+    // and release everything they held.
+    pub fn spawn_lease_reaper(&self) -> tokio::task::JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(LEASE_TIME as u64 / 4));
+            loop {
+                interval.tick().await;
+                let expired = server.clients.write().await.expire(Instant::now());
+                for clientid in expired {
+                    info!("Lease of client {:x} expired, releasing its state", clientid);
+                    server.release_client_state(clientid).await;
+                }
+                let revoked = server.delegations.write().await.revoke_expired(Instant::now());
+                for delegation in revoked {
+                    info!(
+                        "Client {:x} did not return its delegation of {:?}, revoking it",
+                        delegation.clientid, delegation.file_key
+                    );
+                }
+            }
+        })
+    }
+
+    async fn release_client_state(&self, clientid: u64) {
+        self.stateids.write().await.retain(|_, state| state.clientid != clientid);
+        self.open_owners.write().await.retain(|(owner_client, _), _| *owner_client != clientid);
+        self.lock_owners.write().await.retain(|(owner_client, _), _| *owner_client != clientid);
+        self.locks.write().await.release_client(clientid);
+        self.delegations.write().await.release_client(clientid);
+        self.sessions.write().await.destroy_client(clientid);
+    }
+
+    // Operations that use a client's state count as a renewal of its lease.
+    async fn renew_lease(&self, clientid: u64) {
+        let _ = self.clients.write().await.renew(clientid);
+    }
+
+    async fn stateid_error(&self, stateid: &[u8; 16]) -> NfsStatus {
+        self.clients.read().await.stateid_error(stateid)
+    }
+
+    // Find the open state a stateid refers to and check that the stateid is
+    // current. A lock stateid stands for the open it was taken under.
+    async fn open_state<'a>(
+        &self,
+        stateids: &'a StateTable<B::File>,
+        stateid: &[u8; 16],
+    ) -> std::result::Result<&'a FileState<B::File>, NfsStatus> {
+        let state = match stateids.get(&stateid_other(stateid)) {
+            Some(state) => state,
+            None => return Err(self.stateid_error(stateid).await),
+        };
+        check_stateid_seqid(state, stateid)?;
+        match state.open {
+            Some(open) => stateids.get(&open).ok_or(NfsStatus::BadStateid),
+            None => Ok(state),
+        }
+    }
+
+    // Find the lock state a lock stateid refers to.
+    async fn lock_state<'a>(
+        &self,
+        stateids: &'a StateTable<B::File>,
+        stateid: &[u8; 16],
+    ) -> std::result::Result<&'a FileState<B::File>, NfsStatus> {
+        match stateids.get(&stateid_other(stateid)) {
+            Some(state) if state.open.is_some() => {
+                check_stateid_seqid(state, stateid)?;
+                Ok(state)
+            }
+            Some(_) => Err(NfsStatus::BadStateid),
+            None => Err(self.stateid_error(stateid).await),
+        }
+    }
+
+    // Find the state behind the stateid of a READ, WRITE or size-changing
+    // SETATTR: an open, a lock taken under one, or a delegation. Returns the
+    // client it belongs to, the access it allows and the open file.
+    //
+    // The state has to be for the file the current filehandle refers to;
+    // a stateid for one file is no licence to change another.
+    async fn io_state(
+        &self,
+        stateid: &[u8; 16],
+        current_fh: &Option<NfsFileHandle>,
+        sessions: bool,
+    ) -> std::result::Result<(u64, u32, B::File), NfsStatus> {
+        let fh = current_fh.as_ref().ok_or(NfsStatus::NoFileHandle)?;
+        let file_key = FileId::from_handle(fh)?.key();
+        let other = stateid_other(stateid);
+        {
+            let stateids = self.stateids.read().await;
+            if stateids.contains_key(&other) {
+                let state = self.open_state(&stateids, stateid).await?;
+                if state.file_key != file_key {
+                    return Err(NfsStatus::BadStateid);
+                }
+                self.check_client_file(&state.file_key)?;
+                let file = state.file.clone().ok_or(NfsStatus::IoError)?;
+                return Ok((state.clientid, state.open_mode, file));
+            }
+        }
+
+        let delegations = self.delegations.read().await;
+        match delegations.get(&other) {
+            Some(delegation) => {
+                check_current_seqid(delegation.seqid, stateid)?;
+                if delegation.file_key != file_key {
+                    return Err(NfsStatus::BadStateid);
+                }
+                self.check_client_file(&delegation.file_key)?;
+                let access = if delegation.write {
+                    OPEN4_SHARE_ACCESS_BOTH
+                } else {
+                    OPEN4_SHARE_ACCESS_READ
+                };
+                Ok((delegation.clientid, access, delegation.file.clone()))
+            }
+            None if delegations.is_revoked(&other) => Err(revoked_status(sessions)),
+            None => Err(self.stateid_error(stateid).await),
+        }
+    }
+
+    // Recall the delegations that are in the way of access to a file by
+    // `clientid`, or by any client when it is None. Fails with
+    // NFS4ERR_DELAY while any are outstanding, so the client retries after
+    // the holders have returned them.
+    async fn recall_delegations(
+        &self,
+        path: &Path,
+        clientid: Option<u64>,
+        write: bool,
+    ) -> std::result::Result<(), NfsStatus> {
+        // A file that is not there has no delegations.
+        let Ok(file_key) = self.file_key(path).await else {
+            return Ok(());
+        };
+        let recalls = match self.delegations.write().await.check_conflicts(&file_key, clientid, write) {
+            Ok(()) => return Ok(()),
+            Err(recalls) => recalls,
+        };
+        for recall in recalls {
+            self.send_recall(recall);
+        }
+        Err(NfsStatus::Delay)
+    }
+
+    // Send CB_RECALL in the background. A holder that cannot be reached
+    // would never return the delegation, so it is revoked straight away.
+    fn send_recall(&self, recall: Recall) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let callback = server
+                .clients
+                .read()
+                .await
+                .client(recall.clientid)
+                .and_then(|c| c.callback.clone().map(|callback| (callback, c.callback_ident)));
+            let request = CbCompoundRequest {
+                tag: String::new(),
+                minor_version: 0,
+                callback_ident: callback.as_ref().map_or(0, |(_, ident)| *ident),
+                operations: vec![CbOperation::Recall(CbRecallOperation {
+                    stateid: recall.stateid,
+                    truncate: false,
+                    fh: recall.fh,
+                })],
+            };
+            let result = match &callback {
+                Some((callback, _)) => callback::cb_compound(callback, &request).await,
+                None => Err(anyhow::anyhow!("no callback address")),
+            };
+
+            let other = stateid_other(&recall.stateid);
+            match result {
+                Ok(reply) if reply.status == NfsStatus::Ok => {
+                    debug!("Recalled delegation {:02x?} of client {:x}", other, recall.clientid);
+                }
+                Ok(reply) => {
+                    warn!("Client {:x} refused CB_RECALL with {:?}, revoking its delegation", recall.clientid, reply.status);
+                    server.delegations.write().await.revoke(&other);
+                }
+                Err(e) => {
+                    warn!("CB_RECALL to client {:x} failed: {}, revoking its delegation", recall.clientid, e);
+                    server.delegations.write().await.revoke(&other);
+                    server.clients.write().await.set_callback_up(recall.clientid, false);
+                }
+            }
+        });
+    }
+
+    // Check in the background that a client's callback program answers.
+    // The client gets no delegations until it does.
+    fn probe_callback(&self, clientid: u64) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let callback = match server.clients.read().await.client(clientid) {
+                Some(record) => record.callback.clone(),
+                None => None,
+            };
+            let callback = match callback {
+                Some(callback) => callback,
+                None => return,
+            };
+            let up = match callback::cb_null(&callback).await {
+                Ok(()) => true,
+                Err(e) => {
+                    info!("Callback path to client {:x} is down: {}", clientid, e);
+                    false
+                }
+            };
+            server.clients.write().await.set_callback_up(clientid, up);
+        });
+    }
+
+    // Offer a delegation to a client that has just opened a file, if
+    // nobody else has it open and the client's callback path works.
+    async fn grant_delegation(
+        &self,
+        clientid: u64,
+        file_key: FileKey,
+        path: &Path,
+        fh: &NfsFileHandle,
+        share_access: u32,
+    ) -> OpenDelegation {
+        // Only NFSv4.0 clients have a callback path, which is probed after
+        // SETCLIENTID_CONFIRM. NFSv4.1 clients would be called back over
+        // the backchannel of a session, which this server does not offer,
+        // so they are never marked as reachable and get no delegations.
+        let callback_up = self.clients.read().await.client(clientid).is_some_and(|c| c.callback_up);
+        if !callback_up {
+            return OpenDelegation::None;
+        }
+
+        let stateids = self.stateids.read().await;
+        let shared = stateids
+            .values()
+            .any(|state| state.open.is_none() && state.file_key == file_key && state.clientid != clientid);
+        let mut delegations = self.delegations.write().await;
+        if shared || delegations.is_delegated(&file_key) {
+            return OpenDelegation::None;
+        }
+
+        let write = share_access & OPEN4_SHARE_ACCESS_WRITE != 0;
+        let file = match self.backend.open(path, true, write).await {
+            Ok(file) => file,
+            Err(_) => return OpenDelegation::None,
+        };
+        let stateid = self.clients.write().await.new_stateid(clientid);
+        delegations.grant(
+            stateid_other(&stateid),
+            Delegation {
+                clientid,
+                file_key,
+                fh: fh.clone(),
+                write,
+                seqid: stateid_seqid(&stateid),
+                file,
+                recalled: None,
+            },
+        );
+
+        // An empty ACE grants nothing, so the client keeps asking the
+        // server with ACCESS rather than deciding access from its cache.
+        let permissions = Nfsace4 {
+            acetype: ACE4_ACCESS_ALLOWED_ACE_TYPE,
+            flag: 0,
+            access_mask: 0,
+            who: String::new(),
+        };
+        if write {
+            OpenDelegation::Write(OpenWriteDelegation {
+                stateid,
+                recall: false,
+                space_limit: SpaceLimit::Size(u64::MAX),
+                permissions,
+            })
+        } else {
+            OpenDelegation::Read(OpenReadDelegation {
+                stateid,
+                recall: false,
+                permissions,
+            })
+        }
+    }
+
+    async fn check_owner_seqid(&self, key: &OwnerKey, op: u32, seqid: u32) -> std::result::Result<SeqidCheck, NfsStatus> {
+        match self.open_owners.read().await.get(key) {
+            Some(owner) => owner.check_seqid(op, seqid),
+            None => Err(NfsStatus::BadSeqid),
+        }
+    }
+
+    async fn record_owner_reply(&self, key: &OwnerKey, seqid: u32, reply: &OperationResult, fh: Option<NfsFileHandle>) {
+        if let Some(owner) = self.open_owners.write().await.get_mut(key) {
+            owner.record(seqid, reply, fh);
+        }
+    }
+
+    async fn check_lock_owner_seqid(&self, key: &OwnerKey, op: u32, seqid: u32) -> std::result::Result<SeqidCheck, NfsStatus> {
+        match self.lock_owners.read().await.get(key) {
+            Some(owner) => owner.check_seqid(op, seqid),
+            None => Err(NfsStatus::BadSeqid),
+        }
+    }
+
+    async fn record_lock_owner_reply(&self, key: &OwnerKey, seqid: u32, reply: &OperationResult) {
+        if let Some(owner) = self.lock_owners.write().await.get_mut(key) {
+            owner.record(seqid, reply, None);
+        }
+    }
+
+    pub async fn handle_compound(&self, request: CompoundRequest, cred: Credentials) -> Result<CompoundResponse> {
+        if request.minor_version > 1 {
+            return Ok(CompoundResponse {
+                tag: request.tag,
+                status: NfsStatus::MinorVersMismatch,
+                results: Vec::new(),
+            });
+        }
+
+        let mut state = CompoundState {
+            minor_version: request.minor_version,
+            caller: cred.clone(),
+            cred,
+            ..Default::default()
+        };
+        let response = self.run_compound(request, &mut state).await;
+
+        // Free the slot SEQUENCE took, keeping the reply if the client asked
+        // for it to be cached.
+        if let Some(slot) = &state.slot {
+            let reply = match &response {
+                Ok(response) if slot.cache_this => Some(response.clone()),
+                _ => None,
+            };
+            if let Some(session) = self.sessions.write().await.get_mut(&slot.sessionid) {
+                session.finish(slot.slotid, reply);
+            }
         }
+        response
     }
 
-    pub async fn handle_compound(&self, request: CompoundRequest) -> Result<CompoundResponse> {
+    async fn run_compound(&self, request: CompoundRequest, state: &mut CompoundState) -> Result<CompoundResponse> {
         let mut results = Vec::new();
         let mut current_status = NfsStatus::Ok;
-        let mut current_fh: Option<NfsFileHandle> = None;
+        let op_count = request.operations.len();
 
-        for operation in request.operations {
+        for (index, operation) in request.operations.into_iter().enumerate() {
             if current_status != NfsStatus::Ok {
                 break;
             }
 
+            if let Err(status) = check_operation(state, index, op_count, &operation) {
+                let op = if status == NfsStatus::OpIllegal { OP_ILLEGAL } else { operation.opcode() };
+                current_status = status;
+                results.push(OperationResult::error(op, status));
+                continue;
+            }
+
+            state.cred = self.squashed(&state.caller, &state.current_fh);
             let result = match operation {
-                NfsOperation::Access(args) => self.handle_access(args, &current_fh).await,
-                NfsOperation::Close(args) => self.handle_close(args).await,
-                NfsOperation::Commit(args) => self.handle_commit(args, &current_fh).await,
-                NfsOperation::Create(args) => self.handle_create(args, &current_fh).await,
-                NfsOperation::GetAttr(args) => self.handle_getattr(args, &current_fh).await,
-                NfsOperation::GetFh(args) => {
-                    let res = self.handle_getfh(args).await?;
-                    if let Some(OperationData::GetFh(ref fh)) = res.result {
-                        current_fh = Some(fh.clone());
+                NfsOperation::Access(args) => self.handle_access(args, state).await,
+                NfsOperation::Close(args) => self.handle_close(args, state.slot.is_some()).await,
+                NfsOperation::Commit(args) => self.handle_commit(args, &state.current_fh).await,
+                NfsOperation::Create(args) => self.handle_create(args, state).await,
+                NfsOperation::CreateSession(args) => self.handle_create_session(args).await,
+                NfsOperation::DelegReturn(args) => self.handle_delegreturn(args, state).await,
+                NfsOperation::DestroyClientId(args) => self.handle_destroy_clientid(args).await,
+                NfsOperation::DestroySession(args) => self.handle_destroy_session(args).await,
+                NfsOperation::ExchangeId(args) => self.handle_exchange_id(args).await,
+                NfsOperation::GetAttr(args) => self.handle_getattr(args, &state.current_fh).await,
+                NfsOperation::GetFh(args) => self.handle_getfh(args, &state.current_fh).await,
+                NfsOperation::Link(args) => self.handle_link(args, state).await,
+                NfsOperation::Lock(args) => self.handle_lock(args, state.slot.is_some()).await,
+                NfsOperation::Lockt(args) => self.handle_lockt(args, state).await,
+                NfsOperation::Locku(args) => self.handle_locku(args, state.slot.is_some()).await,
+                NfsOperation::Lookup(args) => self.handle_lookup(args, &mut state.current_fh).await,
+                NfsOperation::Lookupp(args) => self.handle_lookupp(args, &mut state.current_fh).await,
+                NfsOperation::Open(args) => self.handle_open(args, state).await,
+                NfsOperation::OpenConfirm(args) => self.handle_open_confirm(args).await,
+                NfsOperation::OpenDowngrade(args) => self.handle_open_downgrade(args, state.slot.is_some()).await,
+                NfsOperation::PutFh(args) => self.handle_putfh(args, &mut state.current_fh).await,
+                // The public filehandle is the export root.
+                NfsOperation::PutPubFh(_) => self.handle_putrootfh(OP_PUTPUBFH, &mut state.current_fh).await,
+                NfsOperation::PutRootFh(_) => self.handle_putrootfh(OP_PUTROOTFH, &mut state.current_fh).await,
+                NfsOperation::Read(args) => self.handle_read(args, &state.current_fh, state.slot.is_some()).await,
+                NfsOperation::ReadDir(args) => self.handle_readdir(args, &state.current_fh).await,
+                NfsOperation::ReadLink(args) => self.handle_readlink(args, &state.current_fh).await,
+                NfsOperation::ReclaimComplete(args) => self.handle_reclaim_complete(args, state).await,
+                NfsOperation::ReleaseLockOwner(args) => self.handle_release_lockowner(args).await,
+                NfsOperation::Remove(args) => self.handle_remove(args, state).await,
+                NfsOperation::Rename(args) => self.handle_rename(args, state).await,
+                NfsOperation::Renew(args) => self.handle_renew(args).await,
+                NfsOperation::RestoreFh(_) => match &state.saved_fh {
+                    Some(fh) => {
+                        state.current_fh = Some(fh.clone());
+                        Ok(OperationResult::ok(OP_RESTOREFH, None))
                     }
-                    Ok(res)
-                }
-                NfsOperation::Lookup(args) => {
-                    let res = self.handle_lookup(args, &current_fh).await?;
-                    if res.status == NfsStatus::Ok {
-                        // Update current filehandle after successful lookup
-                        if let Some(OperationData::GetFh(ref fh)) = res.result {
-                            current_fh = Some(fh.clone());
-                        }
+                    None => Ok(OperationResult::error(OP_RESTOREFH, NfsStatus::RestoreFh)),
+                },
+                NfsOperation::Sequence(args) => self.handle_sequence(args, op_count, state).await,
+                NfsOperation::SetAttr(args) => self.handle_setattr(args, state).await,
+                NfsOperation::SetClientId(args) => self.handle_setclientid(args).await,
+                NfsOperation::SetClientIdConfirm(args) => self.handle_setclientid_confirm(args).await,
+                NfsOperation::SaveFh(_) => match &state.current_fh {
+                    Some(fh) => {
+                        state.saved_fh = Some(fh.clone());
+                        Ok(OperationResult::ok(OP_SAVEFH, None))
                     }
-                    Ok(res)
-                }
-                NfsOperation::Open(args) => self.handle_open(args, &current_fh).await,
-                NfsOperation::Read(args) => self.handle_read(args).await,
-                NfsOperation::Write(args) => self.handle_write(args).await,
-                _ => Ok(OperationResult {
-                    status: NfsStatus::Error,
-                    result: None,
-                }),
+                    None => Ok(OperationResult::error(OP_SAVEFH, NfsStatus::NoFileHandle)),
+                },
+                NfsOperation::Write(args) => self.handle_write(args, &state.current_fh, state.slot.is_some()).await,
+                other => Ok(OperationResult::error(other.opcode(), NfsStatus::NotSupp)),
             }?;
 
+            // A retransmitted NFSv4.1 request gets exactly the reply the
+            // original did, without executing anything again.
+            if let Some(reply) = state.replay.take() {
+                return Ok(reply);
+            }
+
             current_status = result.status;
             results.push(result);
         }
@@ -89,381 +849,2177 @@ impl NfsServer {
         })
     }
 
-    async fn handle_access(&self, args: AccessOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        if let Some(fh) = current_fh {
-            let handles = self.handles.read().await;
-            if let Some(path) = handles.get(&fh.data) {
-                if let Ok(metadata) = fs::metadata(path).await {
-                    let uid = Uid::current().as_raw();
-                    let gid = Gid::current().as_raw();
-
-                    let mode = metadata.mode();
-                    let file_uid = metadata.uid();
-                    let file_gid = metadata.gid();
+    // Where PUTROOTFH leads: the root of the pseudo-filesystem, or of the
+    // export at "/".
+    async fn root_fh(&self) -> std::result::Result<NfsFileHandle, NfsStatus> {
+        match self.exports.root_export() {
+            Some(export) => self.register_handle(export.root.clone()).await,
+            None => Ok(self.pseudo_fh(0)),
+        }
+    }
 
-                    let mut allowed_access = 0u32;
+    async fn current_path(&self, current_fh: &Option<NfsFileHandle>) -> std::result::Result<PathBuf, NfsStatus> {
+        let fh = current_fh.as_ref().ok_or(NfsStatus::NoFileHandle)?;
+        self.resolve_handle(fh).await
+    }
 
-                    // Owner
-                    if uid == file_uid {
-                        if mode & 0o400 != 0 { allowed_access |= ACCESS4_READ; }
-                        if mode & 0o200 != 0 { allowed_access |= ACCESS4_MODIFY | ACCESS4_EXTEND; }
-                        if mode & 0o100 != 0 { allowed_access |= ACCESS4_EXECUTE; }
-                    }
-                    // Group
-                    else if gid == file_gid {
-                        if mode & 0o040 != 0 { allowed_access |= ACCESS4_READ; }
-                        if mode & 0o020 != 0 { allowed_access |= ACCESS4_MODIFY | ACCESS4_EXTEND; }
-                        if mode & 0o010 != 0 { allowed_access |= ACCESS4_EXECUTE; }
-                    }
-                    // Others
-                    else {
-                        if mode & 0o004 != 0 { allowed_access |= ACCESS4_READ; }
-                        if mode & 0o002 != 0 { allowed_access |= ACCESS4_MODIFY | ACCESS4_EXTEND; }
-                        if mode & 0o001 != 0 { allowed_access |= ACCESS4_EXECUTE; }
-                    }
+    // Map a handle back to a path, checking that the file there is still the
+    // one the handle was issued for. Handles for files that no longer exist,
+    // or for exports that are no longer in the table, are stale.
+    //
+    // Pseudo-filesystem directories have no path. Operations that take one
+    // would change the directory, which cannot be done.
+    async fn resolve_handle(&self, fh: &NfsFileHandle) -> std::result::Result<PathBuf, NfsStatus> {
+        let id = FileId::from_handle(fh)?;
+        if id.export_id == PSEUDO_EXPORT_ID {
+            return Err(match self.pseudo_node(&id) {
+                Some(_) => NfsStatus::RoFs,
+                None => NfsStatus::StaleFileHandle,
+            });
+        }
+        let export = self.exports.get(id.export_id).ok_or(NfsStatus::StaleFileHandle)?.clone();
+        if !self.may_use(export.id) {
+            return Err(NfsStatus::Access);
+        }
 
-                    Ok(OperationResult {
-                        status: NfsStatus::Ok,
-                        result: Some(OperationData::Access(allowed_access & args.access)),
-                    })
-                } else {
-                    Ok(OperationResult {
-                        status: NfsStatus::NoEnt,
-                        result: None,
-                    })
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::StaleFileHandle,
-                    result: None,
-                })
+        let key = id.key();
+        let known = self.handles.read().await.paths(&key);
+        for path in known {
+            if id.matches(&*self.backend, &path).await {
+                return Ok(path);
             }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadHandle,
-                result: None,
-            })
+            self.handles.write().await.remove(key, path);
+        }
+        // Export roots are always where they were.
+        if id.matches(&*self.backend, &export.root).await {
+            self.handles.write().await.insert(key, export.root.clone());
+            return Ok(export.root.clone());
         }
+        Err(NfsStatus::StaleFileHandle)
     }
 
-    async fn handle_close(&self, args: CloseOperation) -> Result<OperationResult> {
-        let mut stateids = self.stateids.write().await;
-        if let Some(state) = stateids.remove(&args.open_stateid) {
-            Ok(OperationResult {
-                status: NfsStatus::Ok,
-                result: None,
-            })
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadStateid,
-                result: None,
-            })
+    async fn register_handle(&self, path: PathBuf) -> std::result::Result<NfsFileHandle, NfsStatus> {
+        let export = self.export_of(&path)?;
+        let id = FileId::for_path(export.id, &*self.backend, &path)
+            .await
+            .map_err(|e| io_error_status(&e))?;
+
+        self.handles.write().await.insert(id.key(), path);
+        Ok(id.to_handle())
+    }
+
+    // The pseudo-filesystem directory a handle is for, if it is one that
+    // exists.
+    fn pseudo_node(&self, id: &FileId) -> Option<usize> {
+        if id.export_id != PSEUDO_EXPORT_ID {
+            return None;
         }
+        let node = self.exports.pseudo_node(id.ino as usize)?;
+        (id.generation == pseudo_generation(&node.path)).then_some(id.ino as usize)
     }
 
-    async fn handle_commit(&self, args: CommitOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        if let Some(fh) = current_fh {
-            let handles = self.handles.read().await;
-            if let Some(path) = handles.get(&fh.data) {
-                if let Ok(mut file) = File::open(path).await {
-                    file.sync_all().await?;
-                    Ok(OperationResult {
-                        status: NfsStatus::Ok,
-                        result: None,
-                    })
-                } else {
-                    Ok(OperationResult {
-                        status: NfsStatus::IoError,
-                        result: None,
-                    })
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::StaleFileHandle,
-                    result: None,
-                })
+    fn pseudo_dir(&self, fh: &Option<NfsFileHandle>) -> Option<usize> {
+        let id = FileId::from_handle(fh.as_ref()?).ok()?;
+        self.pseudo_node(&id)
+    }
+
+    fn pseudo_fh(&self, node: usize) -> NfsFileHandle {
+        let path = self.exports.pseudo_node(node).map(|node| node.path.as_str()).unwrap_or_default();
+        FileId::pseudo(node, path).to_handle()
+    }
+
+    // Where an entry of a pseudo-filesystem directory leads: another one,
+    // or the root of an export.
+    async fn pseudo_entry_fh(&self, entry: PseudoEntry) -> std::result::Result<NfsFileHandle, NfsStatus> {
+        match entry {
+            PseudoEntry::Dir(node) => Ok(self.pseudo_fh(node)),
+            PseudoEntry::Export(id) => {
+                let export = self.exports.get(id).ok_or(NfsStatus::ServerFault)?;
+                self.register_handle(export.root.clone()).await
             }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadHandle,
-                result: None,
-            })
         }
     }
 
-    async fn handle_create(&self, args: CreateOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        if let Some(fh) = current_fh {
-            let handles = self.handles.read().await;
-            if let Some(parent_path) = handles.get(&fh.data) {
-                let new_path = parent_path.join(&args.object_name);
-
-                match args.object_type {
-                    NF4REG => {
-                        if let Ok(file) = File::create(&new_path).await {
-                            // Generate new file handle
-                            let mut handle_data = vec![0u8; 16];
-                            rand::thread_rng().fill(&mut handle_data[..]);
-
-                            let mut handles = self.handles.write().await;
-                            handles.insert(handle_data.clone(), new_path);
-
-                            Ok(OperationResult {
-                                status: NfsStatus::Ok,
-                                result: Some(OperationData::GetFh(NfsFileHandle { data: handle_data })),
-                            })
-                        } else {
-                            Ok(OperationResult {
-                                status: NfsStatus::IoError,
-                                result: None,
-                            })
-                        }
-                    },
-                    NF4DIR => {
-                        if let Ok(_) = fs::create_dir(&new_path).await {
-                            let mut handle_data = vec![0u8; 16];
-                            rand::thread_rng().fill(&mut handle_data[..]);
-
-                            let mut handles = self.handles.write().await;
-                            handles.insert(handle_data.clone(), new_path);
-
-                            Ok(OperationResult {
-                                status: NfsStatus::Ok,
-                                result: Some(OperationData::GetFh(NfsFileHandle { data: handle_data })),
-                            })
-                        } else {
-                            Ok(OperationResult {
-                                status: NfsStatus::IoError,
-                                result: None,
-                            })
-                        }
-                    },
-                    _ => Ok(OperationResult {
-                        status: NfsStatus::BadType,
-                        result: None,
-                    }),
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::StaleFileHandle,
-                    result: None,
-                })
-            }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadHandle,
-                result: None,
-            })
+    fn pseudo_attributes(&self, node: usize, fh: &NfsFileHandle, requested: &[u32]) -> Fattr4 {
+        let pseudo = self.exports.pseudo_node(node).expect("pseudo-filesystem node");
+        encode_pseudo_attributes(node, pseudo, fh, requested, &self.boot_time, &self.idmap)
+    }
+
+    fn may_use(&self, export_id: u32) -> bool {
+        self.allowed.as_ref().is_none_or(|allowed| allowed.contains(&export_id))
+    }
+
+    // Whether a pseudo-filesystem entry leads to an export the client may
+    // use. The others are left out, as if they were not there.
+    fn pseudo_visible(&self, entry: PseudoEntry) -> bool {
+        match entry {
+            PseudoEntry::Export(id) => self.may_use(id),
+            PseudoEntry::Dir(node) => self
+                .exports
+                .pseudo_node(node)
+                .is_some_and(|node| node.entries.values().any(|&entry| self.pseudo_visible(entry))),
         }
     }
 
-    async fn handle_getattr(&self, args: GetAttrOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        if let Some(fh) = current_fh {
-            let handles = self.handles.read().await;
-            if let Some(path) = handles.get(&fh.data) {
-                if let Ok(metadata) = fs::metadata(path).await {
-                    let attrs = NfsFileAttributes {
-                        type_: if metadata.is_dir() { NF4DIR } else { NF4REG },
-                        mode: metadata.mode(),
-                        size: metadata.len(),
-                        space_used: metadata.blocks() * 512,
-                        time_access: NfsTime {
-                            seconds: metadata.atime() as u64,
-                            nseconds: metadata.atime_nsec() as u32,
-                        },
-                        time_modify: NfsTime {
-                            seconds: metadata.mtime() as u64,
-                            nseconds: metadata.mtime_nsec() as u32,
-                        },
-                        owner: metadata.uid().to_string(),
-                        group: metadata.gid().to_string(),
-                    };
-
-                    Ok(OperationResult {
-                        status: NfsStatus::Ok,
-                        result: Some(OperationData::GetAttr(attrs)),
-                    })
-                } else {
-                    Ok(OperationResult {
-                        status: NfsStatus::NoEnt,
-                        result: None,
-                    })
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::StaleFileHandle,
-                    result: None,
-                })
-            }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadHandle,
-                result: None,
-            })
+    // Who `caller` acts as on the export of a filehandle.
+    fn squashed(&self, caller: &Credentials, fh: &Option<NfsFileHandle>) -> Credentials {
+        let export = fh
+            .as_ref()
+            .and_then(|fh| FileId::from_handle(fh).ok())
+            .and_then(|id| self.exports.get(id.export_id));
+        match export {
+            Some(export) => export.options.squashed(caller),
+            None => caller.clone(),
         }
     }
 
-    async fn handle_getfh(&self, _args: GetFhOperation) -> Result<OperationResult> {
-        let mut handle_data = vec![0u8; 16];
-        rand::thread_rng().fill(&mut handle_data[..]);
+    // Stateids are not tied to a connection, so one used for I/O is checked
+    // against the exports the connection may use.
+    fn check_client_file(&self, file_key: &FileKey) -> std::result::Result<(), NfsStatus> {
+        match self.may_use(file_key.0) {
+            true => Ok(()),
+            false => Err(NfsStatus::Access),
+        }
+    }
 
-        let handle = NfsFileHandle { data: handle_data };
+    // Changes are refused on read-only exports.
+    fn check_writable(&self, path: &Path) -> std::result::Result<(), NfsStatus> {
+        match self.export_of(path)?.options.read_only {
+            true => Err(NfsStatus::RoFs),
+            false => Ok(()),
+        }
+    }
 
-        Ok(OperationResult {
-            status: NfsStatus::Ok,
-            result: Some(OperationData::GetFh(handle)),
-        })
+    // The export a path is in. Paths are only made by joining names onto
+    // the path of a handle, so there always is one.
+    fn export_of(&self, path: &Path) -> std::result::Result<Arc<ExportEntry>, NfsStatus> {
+        self.exports.containing(path).cloned().ok_or(NfsStatus::XDev)
     }
 
-    async fn handle_lookup(&self, args: LookupOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        if let Some(fh) = current_fh {
-            let handles = self.handles.read().await;
-            if let Some(parent_path) = handles.get(&fh.data) {
-                let path = parent_path.join(&args.object_name);
-                if path.exists() {
-                    let mut handle_data = vec![0u8; 16];
-                    rand::thread_rng().fill(&mut handle_data[..]);
+    // Drop known paths for a removed object and anything that was beneath
+    // it. Handles for a file with other links lead to it through those.
+    async fn forget_handles(&self, path: &Path) {
+        self.handles.write().await.forget(path);
+    }
 
-                    let mut handles = self.handles.write().await;
-                    handles.insert(handle_data.clone(), path);
+    // Point known paths for a renamed object, and anything beneath it, at
+    // the new location.
+    async fn move_handles(&self, from: &Path, to: &Path) {
+        self.handles.write().await.rename(from, to);
+    }
 
-                    Ok(OperationResult {
-                        status: NfsStatus::Ok,
-                        result: Some(OperationData::GetFh(NfsFileHandle { data: handle_data })),
-                    })
-                } else {
-                    Ok(OperationResult {
-                        status: NfsStatus::NoEnt,
-                        result: None,
-                    })
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::StaleFileHandle,
-                    result: None,
-                })
-            }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadHandle,
-                result: None,
-            })
-        }
+    // Attributes of the object at an export path, which is not followed if
+    // it is a symlink.
+    async fn metadata(&self, path: &Path) -> std::io::Result<FileAttr> {
+        self.backend.getattr(path).await
     }
 
-    async fn handle_open(&self, args: OpenOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
-        let mut stateid = [0u8; 16];
-        rand::thread_rng().fill(&mut stateid[..]);
+    // Whether `path` is a directory. A symlink to one is not: operations
+    // that take a directory filehandle never follow the symlink a handle
+    // names.
+    async fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).await.is_ok_and(|m| m.is_dir())
+    }
 
-        let mut stateids = self.stateids.write().await;
-        match &args.open_claim {
-            OpenClaim::Null(path) => {
-                let full_path = self.export_root.join(path);
-                let file = OpenOptions::new()
-                    .read((args.share_access & ACCESS4_READ) != 0)
-                    .write((args.share_access & (ACCESS4_MODIFY | ACCESS4_EXTEND)) != 0)
-                    .create(true)
-                    .open(&full_path)
-                    .await;
-
-                match file {
-                    Ok(file) => {
-                        stateids.insert(
-                            stateid,
-                            FileState {
-                                path: full_path,
-                                open_mode: args.share_access,
-                                seqid: args.seqid,
-                                file: Some(file),
-                            },
-                        );
-
-                        Ok(OperationResult {
-                            status: NfsStatus::Ok,
-                            result: Some(OperationData::Open(stateid)),
-                        })
-                    }
-                    Err(_) => Ok(OperationResult {
-                        status: NfsStatus::IoError,
-                        result: None,
-                    }),
-                }
-            }
-            _ => Ok(OperationResult {
-                status: NfsStatus::Error,
-                result: None,
-            }),
+    // Identity of the file at an export path, which is what state is kept
+    // for.
+    async fn file_key(&self, path: &Path) -> std::result::Result<FileKey, NfsStatus> {
+        let export = self.export_of(path)?;
+        let metadata = self.metadata(path).await.map_err(|e| io_error_status(&e))?;
+        Ok((export.id, metadata.dev, metadata.ino))
+    }
+
+    async fn dir_change(&self, path: &Path) -> u64 {
+        self.metadata(path).await.map(|m| m.change()).unwrap_or_default()
+    }
+
+    // Encode the attributes of a file, with the statistics of its
+    // filesystem when they are asked for.
+    async fn file_attributes(
+        &self,
+        export: &ExportEntry,
+        path: &Path,
+        attr: &FileAttr,
+        fh: Option<&NfsFileHandle>,
+        requested: &[u32],
+    ) -> std::result::Result<Fattr4, NfsStatus> {
+        let fs_stats = match wants_fs_stats(requested) {
+            true => Some(self.backend.statfs(path).await.map_err(|e| io_error_status(&e))?),
+            false => None,
+        };
+        Ok(encode_attributes(export, attr, fs_stats.as_ref(), fh, requested, &self.idmap))
+    }
+
+    async fn set_attributes(&self, path: &Path, attrs: NfsSetAttributes) -> Result<(NfsStatus, Vec<u32>)> {
+        if attrs == NfsSetAttributes::default() {
+            return Ok((NfsStatus::Ok, Vec::new()));
         }
+        if let Err(status) = self.export_of(path) {
+            return Ok((status, Vec::new()));
+        }
+        Ok(apply_attributes(&*self.backend, path, &attrs).await)
     }
 
-    async fn handle_read(&self, args: ReadOperation) -> Result<OperationResult> {
-        let stateids = self.stateids.read().await;
-        if let Some(state) = stateids.get(&args.stateid) {
-            if let Some(ref file) = state.file {
-                let mut file = file.try_clone().await?;
-                file.seek(std::io::SeekFrom::Start(args.offset)).await?;
-
-                let mut buf = vec![0u8; args.count as usize];
-                match file.read(&mut buf).await {
-                    Ok(n) => {
-                        buf.truncate(n);
-                        Ok(OperationResult {
-                            status: NfsStatus::Ok,
-                            result: Some(OperationData::Read(buf)),
-                        })
-                    }
-                    Err(_) => Ok(OperationResult {
-                        status: NfsStatus::IoError,
-                        result: None,
-                    }),
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::IoError,
-                    result: None,
-                })
-            }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadStateid,
-                result: None,
-            })
+    // Give a new object to its creator, then apply the attributes it was
+    // created with, which the creator must be allowed to set as its owner.
+    async fn set_new_attributes(
+        &self,
+        path: &Path,
+        cred: &Credentials,
+        mut attrs: NfsSetAttributes,
+    ) -> Result<(NfsStatus, Vec<u32>)> {
+        if let Err(status) = self.export_of(path) {
+            return Ok((status, Vec::new()));
+        }
+        let status = set_creator(&*self.backend, path, cred).await;
+        if status != NfsStatus::Ok {
+            return Ok((status, Vec::new()));
+        }
+        let metadata = match self.metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok((io_error_status(&e), Vec::new())),
+        };
+        if let Err(status) = check_setattr(cred, &metadata, &mut attrs) {
+            return Ok((status, Vec::new()));
         }
+        self.set_attributes(path, attrs).await
     }
 
-    async fn handle_write(&self, args: WriteOperation) -> Result<OperationResult> {
-        let stateids = self.stateids.read().await;
-        if let Some(state) = stateids.get(&args.stateid) {
-            if let Some(ref file) = state.file {
-                let mut file = file.try_clone().await?;
-                file.seek(std::io::SeekFrom::Start(args.offset)).await?;
-
-                match file.write_all(&args.data).await {
-                    Ok(_) => {
-                        if args.stable != 0 {
-                            file.sync_all().await?;
-                        }
-                        Ok(OperationResult {
-                            status: NfsStatus::Ok,
-                            result: Some(OperationData::Write(args.data.len() as u32)),
-                        })
-                    }
-                    Err(_) => Ok(OperationResult {
-                        status: NfsStatus::IoError,
-                        result: None,
-                    }),
-                }
-            } else {
-                Ok(OperationResult {
-                    status: NfsStatus::IoError,
-                    result: None,
-                })
-            }
-        } else {
-            Ok(OperationResult {
-                status: NfsStatus::BadStateid,
-                result: None,
-            })
+    // Creating, removing or renaming entries takes write and search
+    // permission on the directory.
+    async fn check_dir_write(&self, dir: &Path, cred: &Credentials) -> std::result::Result<(), NfsStatus> {
+        self.check_writable(dir)?;
+        let metadata = self.metadata(dir).await.map_err(|e| io_error_status(&e))?;
+        if !metadata.is_dir() {
+            return Err(NfsStatus::NotDir);
         }
+        if !cred.may(&metadata, MAY_WRITE | MAY_EXEC) {
+            return Err(NfsStatus::Access);
+        }
+        Ok(())
     }
-}
 
+    async fn handle_access(&self, args: AccessOperation, state: &CompoundState) -> Result<OperationResult> {
+        let supported = args.access
+            & (ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_DELETE | ACCESS4_EXECUTE);
+        // Anyone may list and search the pseudo-filesystem, and nobody may
+        // change it.
+        if self.pseudo_dir(&state.current_fh).is_some() {
+            return Ok(OperationResult::ok(
+                OP_ACCESS,
+                Some(OperationData::Access(AccessResult {
+                    supported,
+                    access: (ACCESS4_READ | ACCESS4_LOOKUP) & supported,
+                })),
+            ));
+        }
+
+        let path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_ACCESS, status)),
+        };
+
+        let metadata = match self.metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(OperationResult::error(OP_ACCESS, NfsStatus::NoEnt)),
+        };
+
+        // Execute permission means searching a directory, and write and
+        // search together allow deleting its entries.
+        let permissions = state.cred.permissions(&metadata);
+        let mut allowed_access = 0u32;
+        if permissions & MAY_READ != 0 {
+            allowed_access |= ACCESS4_READ;
+        }
+        if permissions & MAY_WRITE != 0 {
+            allowed_access |= ACCESS4_MODIFY | ACCESS4_EXTEND;
+        }
+        if permissions & MAY_EXEC != 0 {
+            allowed_access |= if metadata.is_dir() { ACCESS4_LOOKUP } else { ACCESS4_EXECUTE };
+        }
+        if metadata.is_dir() && permissions & (MAY_WRITE | MAY_EXEC) == MAY_WRITE | MAY_EXEC {
+            allowed_access |= ACCESS4_DELETE;
+        }
+        if self.check_writable(&path).is_err() {
+            allowed_access &= !(ACCESS4_MODIFY | ACCESS4_EXTEND | ACCESS4_DELETE);
+        }
+
+        Ok(OperationResult::ok(
+            OP_ACCESS,
+            Some(OperationData::Access(AccessResult {
+                supported,
+                access: allowed_access & supported,
+            })),
+        ))
+    }
+
+    async fn handle_close(&self, args: CloseOperation, sessions: bool) -> Result<OperationResult> {
+        if sessions {
+            return Ok(self.close(&args.open_stateid).await);
+        }
+
+        let other = stateid_other(&args.open_stateid);
+        let key = self
+            .stateids
+            .read()
+            .await
+            .get(&other)
+            .filter(|state| state.open.is_none())
+            .map(|state| (state.clientid, state.owner.clone()));
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let replay = self
+                    .open_owners
+                    .read()
+                    .await
+                    .values()
+                    .find_map(|owner| owner.closed(args.seqid, &args.open_stateid));
+                return match replay {
+                    Some(reply) => Ok(reply),
+                    None => Ok(OperationResult::error(OP_CLOSE, self.stateid_error(&args.open_stateid).await)),
+                };
+            }
+        };
+
+        match self.check_owner_seqid(&key, OP_CLOSE, args.seqid).await {
+            Ok(SeqidCheck::New) => {}
+            Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+            Err(status) => return Ok(OperationResult::error(OP_CLOSE, status)),
+        }
+        let result = self.close(&args.open_stateid).await;
+        self.record_owner_reply(&key, args.seqid, &result, None).await;
+        Ok(result)
+    }
+
+    async fn close(&self, stateid: &[u8; 16]) -> OperationResult {
+        let mut stateids = self.stateids.write().await;
+        let other = stateid_other(stateid);
+        let state = match stateids.get(&other) {
+            Some(state) => state,
+            None => return OperationResult::error(OP_CLOSE, self.stateid_error(stateid).await),
+        };
+        if state.open.is_some() {
+            return OperationResult::error(OP_CLOSE, NfsStatus::BadStateid);
+        }
+        if let Err(status) = check_stateid_seqid(state, stateid) {
+            return OperationResult::error(OP_CLOSE, status);
+        }
+
+        // Locks taken under the open have to be released first. Lock
+        // stateids whose locks are all gone go away with the open.
+        let lock_states: Vec<_> = stateids
+            .iter()
+            .filter(|(_, lock)| lock.open == Some(other))
+            .map(|(lock_other, lock)| (*lock_other, (lock.clientid, lock.owner.clone())))
+            .collect();
+        {
+            let locks = self.locks.read().await;
+            if lock_states.iter().any(|(_, key)| locks.has_locks(&state.file_key, key)) {
+                return OperationResult::error(OP_CLOSE, NfsStatus::LocksHeld);
+            }
+        }
+        for (lock_other, _) in &lock_states {
+            stateids.remove(lock_other);
+        }
+
+        // The stateid returned by CLOSE is never used again, but its seqid
+        // still advances like on any other change to the open.
+        let state = stateids.remove(&other).unwrap();
+        drop(stateids);
+        self.renew_lease(state.clientid).await;
+        OperationResult::ok(OP_CLOSE, Some(OperationData::Close(make_stateid(state.seqid.wrapping_add(1), &other))))
+    }
+
+    async fn handle_commit(&self, _args: CommitOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
+        let path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_COMMIT, status)),
+        };
+
+        match self.backend.open(&path, true, false).await {
+            Ok(file) => match self.backend.sync(&file).await {
+                Ok(()) => Ok(OperationResult::ok(OP_COMMIT, Some(OperationData::Commit(self.write_verifier)))),
+                Err(e) => Ok(OperationResult::error(OP_COMMIT, io_error_status(&e))),
+            },
+            Err(e) => Ok(OperationResult::error(OP_COMMIT, io_error_status(&e))),
+        }
+    }
+
+    async fn handle_create(&self, args: CreateOperation, state: &mut CompoundState) -> Result<OperationResult> {
+        let parent_path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_CREATE, status)),
+        };
+        let mut create_attrs = match decode_settable(&args.attributes, &self.idmap) {
+            Ok(attrs) => attrs,
+            Err(status) => return Ok(OperationResult::error(OP_CREATE, status)),
+        };
+        if let Err(status) = check_name(&args.object_name) {
+            return Ok(OperationResult::error(OP_CREATE, status));
+        }
+        if let Err(status) = self.check_dir_write(&parent_path, &state.cred).await {
+            return Ok(OperationResult::error(OP_CREATE, status));
+        }
+        let new_path = parent_path.join(&args.object_name);
+        let before = self.dir_change(&parent_path).await;
+
+        let created = match args.object_type {
+            NF4REG | NF4DIR => self.backend.create(&new_path, args.object_type, SpecData::default()).await,
+            NF4LNK => match args.link_data.as_deref() {
+                Some(target) if !target.is_empty() => {
+                    // The mode of a symlink is never used, and cannot be
+                    // changed on Linux, so one given for it is ignored.
+                    create_attrs.mode = None;
+                    self.backend.symlink(target, &new_path).await
+                }
+                _ => return Ok(OperationResult::error(OP_CREATE, NfsStatus::Inval)),
+            },
+            // Device nodes only where the export allows them, and only for
+            // root, as mknod needs CAP_MKNOD locally.
+            NF4BLK | NF4CHR if !self.export_of(&parent_path).is_ok_and(|export| export.options.devices) => {
+                return Ok(OperationResult::error(OP_CREATE, NfsStatus::BadType));
+            }
+            NF4BLK | NF4CHR if !state.cred.is_root() => {
+                return Ok(OperationResult::error(OP_CREATE, NfsStatus::Perm));
+            }
+            // The mode is set afterwards from the create attributes, as for
+            // other objects.
+            NF4BLK | NF4CHR | NF4FIFO | NF4SOCK => {
+                let spec = args.spec_data.unwrap_or_default();
+                self.backend.create(&new_path, args.object_type, spec).await
+            }
+            _ => return Ok(OperationResult::error(OP_CREATE, NfsStatus::BadType)),
+        };
+        if let Err(e) = created {
+            return Ok(OperationResult::error(OP_CREATE, io_error_status(&e)));
+        }
+
+        let (status, attrset) = self.set_new_attributes(&new_path, &state.cred, create_attrs).await?;
+        if status != NfsStatus::Ok {
+            // Do not leave behind an object with the wrong attributes.
+            let _ = self.backend.remove(&new_path, args.object_type == NF4DIR).await;
+            return Ok(OperationResult::error(OP_CREATE, status));
+        }
+
+        let after = self.dir_change(&parent_path).await;
+        match self.register_handle(new_path).await {
+            Ok(fh) => state.current_fh = Some(fh),
+            Err(status) => return Ok(OperationResult::error(OP_CREATE, status)),
+        }
+
+        Ok(OperationResult::ok(
+            OP_CREATE,
+            Some(OperationData::Create(CreateResult {
+                change_info: ChangeInfo {
+                    atomic: false,
+                    before,
+                    after,
+                },
+                attrset,
+            })),
+        ))
+    }
+
+    async fn handle_create_session(&self, args: CreateSessionOperation) -> Result<OperationResult> {
+        let mut clients = self.clients.write().await;
+        match clients.check_create_session(args.clientid, args.sequence) {
+            Ok(Some(reply)) => {
+                return Ok(OperationResult::ok(OP_CREATE_SESSION, Some(OperationData::CreateSession(reply))));
+            }
+            Ok(None) => {}
+            Err(status) => return Ok(OperationResult::error(OP_CREATE_SESSION, status)),
+        }
+
+        // Sessions are not persistent and there is no backchannel, so the
+        // reply sets none of the flags. Without one, the client is not
+        // given delegations.
+        let reply = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.create(&args);
+            CreateSessionResult {
+                sessionid: session.sessionid,
+                sequence: args.sequence,
+                flags: 0,
+                fore_chan_attrs: session.fore_chan_attrs.clone(),
+                back_chan_attrs: session.back_chan_attrs.clone(),
+            }
+        };
+        let previous = clients.session_created(args.clientid, reply.clone());
+        drop(clients);
+
+        if let Some(previous) = previous {
+            self.release_client_state(previous).await;
+        }
+        Ok(OperationResult::ok(OP_CREATE_SESSION, Some(OperationData::CreateSession(reply))))
+    }
+
+    async fn handle_delegreturn(&self, args: DelegReturnOperation, state: &CompoundState) -> Result<OperationResult> {
+        let file_key = match self.current_path(&state.current_fh).await {
+            Ok(path) => self.file_key(&path).await,
+            Err(status) => Err(status),
+        };
+        let file_key = match file_key {
+            Ok(file_key) => file_key,
+            Err(status) => return Ok(OperationResult::error(OP_DELEGRETURN, status)),
+        };
+
+        let other = stateid_other(&args.stateid);
+        let mut delegations = self.delegations.write().await;
+        let checked = match delegations.get(&other) {
+            Some(delegation) if delegation.file_key != file_key => Err(NfsStatus::BadStateid),
+            Some(delegation) => check_current_seqid(delegation.seqid, &args.stateid),
+            None if delegations.is_revoked(&other) => Err(revoked_status(state.slot.is_some())),
+            None => {
+                drop(delegations);
+                let status = self.stateid_error(&args.stateid).await;
+                return Ok(OperationResult::error(OP_DELEGRETURN, status));
+            }
+        };
+        if let Err(status) = checked {
+            return Ok(OperationResult::error(OP_DELEGRETURN, status));
+        }
+
+        if let Some(delegation) = delegations.remove(&other) {
+            drop(delegations);
+            self.renew_lease(delegation.clientid).await;
+        }
+        Ok(OperationResult::ok(OP_DELEGRETURN, None))
+    }
+
+    async fn handle_destroy_clientid(&self, args: DestroyClientIdOperation) -> Result<OperationResult> {
+        // A client must destroy its sessions and give back its state first.
+        let busy = self.sessions.read().await.client_has_sessions(args.clientid)
+            || self.stateids.read().await.values().any(|state| state.clientid == args.clientid);
+        if busy {
+            return Ok(OperationResult::error(OP_DESTROY_CLIENTID, NfsStatus::ClientidBusy));
+        }
+
+        match self.clients.write().await.remove(args.clientid) {
+            Ok(()) => Ok(OperationResult::ok(OP_DESTROY_CLIENTID, None)),
+            Err(status) => Ok(OperationResult::error(OP_DESTROY_CLIENTID, status)),
+        }
+    }
+
+    async fn handle_destroy_session(&self, args: DestroySessionOperation) -> Result<OperationResult> {
+        match self.sessions.write().await.destroy(&args.sessionid) {
+            Ok(_) => Ok(OperationResult::ok(OP_DESTROY_SESSION, None)),
+            Err(status) => Ok(OperationResult::error(OP_DESTROY_SESSION, status)),
+        }
+    }
+
+    async fn handle_exchange_id(&self, args: ExchangeIdOperation) -> Result<OperationResult> {
+        const CLIENT_FLAGS: u32 = EXCHGID4_FLAG_SUPP_MOVED_REFER
+            | EXCHGID4_FLAG_SUPP_MOVED_MIGR
+            | EXCHGID4_FLAG_BIND_PRINC_STATEID
+            | EXCHGID4_FLAG_USE_NON_PNFS
+            | EXCHGID4_FLAG_USE_PNFS_MDS
+            | EXCHGID4_FLAG_USE_PNFS_DS
+            | EXCHGID4_FLAG_UPD_CONFIRMED_REC_A;
+        if args.flags & !CLIENT_FLAGS != 0 {
+            return Ok(OperationResult::error(OP_EXCHANGE_ID, NfsStatus::Inval));
+        }
+        // Only SP4_NONE is offered.
+        if args.state_protect != StateProtect::None {
+            return Ok(OperationResult::error(OP_EXCHANGE_ID, NfsStatus::NotSupp));
+        }
+
+        let (clientid, sequenceid, confirmed) = match self.clients.write().await.exchange_id(&args.owner, args.flags) {
+            Ok(res) => res,
+            Err(status) => return Ok(OperationResult::error(OP_EXCHANGE_ID, status)),
+        };
+
+        let mut flags = EXCHGID4_FLAG_USE_NON_PNFS;
+        if confirmed {
+            flags |= EXCHGID4_FLAG_CONFIRMED_R;
+        }
+        Ok(OperationResult::ok(
+            OP_EXCHANGE_ID,
+            Some(OperationData::ExchangeId(ExchangeIdResult {
+                clientid,
+                sequenceid,
+                flags,
+                server_owner: ServerOwner {
+                    minor_id: 0,
+                    major_id: self.server_owner.clone(),
+                },
+                server_scope: self.server_owner.clone(),
+                impl_id: None,
+            })),
+        ))
+    }
+
+    async fn handle_getattr(&self, args: GetAttrOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
+        if current_fh.is_some() && WRITE_ONLY_ATTRS.iter().any(|&bit| bitmap_isset(&args.attr_request, bit)) {
+            return Ok(OperationResult::error(OP_GETATTR, NfsStatus::Inval));
+        }
+        if let (Some(node), Some(fh)) = (self.pseudo_dir(current_fh), current_fh) {
+            let attrs = self.pseudo_attributes(node, fh, &args.attr_request);
+            return Ok(OperationResult::ok(OP_GETATTR, Some(OperationData::GetAttr(attrs))));
+        }
+        let path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_GETATTR, status)),
+        };
+        let export = match self.export_of(&path) {
+            Ok(export) => export,
+            Err(status) => return Ok(OperationResult::error(OP_GETATTR, status)),
+        };
+
+        let metadata = match self.metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(OperationResult::error(OP_GETATTR, io_error_status(&e))),
+        };
+
+        match self.file_attributes(&export, &path, &metadata, current_fh.as_ref(), &args.attr_request).await {
+            Ok(attrs) => Ok(OperationResult::ok(OP_GETATTR, Some(OperationData::GetAttr(attrs)))),
+            Err(status) => Ok(OperationResult::error(OP_GETATTR, status)),
+        }
+    }
+
+    async fn handle_getfh(&self, _args: GetFhOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
+        match current_fh {
+            Some(fh) => Ok(OperationResult::ok(OP_GETFH, Some(OperationData::GetFh(fh.clone())))),
+            None => Ok(OperationResult::error(OP_GETFH, NfsStatus::NoFileHandle)),
+        }
+    }
+
+    // LINK gives the file of the saved filehandle another name in the
+    // current filehandle's directory.
+    async fn handle_link(&self, args: LinkOperation, state: &CompoundState) -> Result<OperationResult> {
+        let source = match self.current_path(&state.saved_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_LINK, status)),
+        };
+        let dir_path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_LINK, status)),
+        };
+        if let Err(status) = check_name(&args.new_name) {
+            return Ok(OperationResult::error(OP_LINK, status));
+        }
+
+        let source_meta = match self.metadata(&source).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(OperationResult::error(OP_LINK, io_error_status(&e))),
+        };
+        if source_meta.is_dir() {
+            return Ok(OperationResult::error(OP_LINK, NfsStatus::IsDir));
+        }
+        if let Err(status) = self.check_dir_write(&dir_path, &state.cred).await {
+            return Ok(OperationResult::error(OP_LINK, status));
+        }
+        // As with fs.protected_hardlinks, linking to a file takes owning it
+        // or being allowed to read and write it.
+        let cred = &state.cred;
+        let may_link = cred.owns(&source_meta) || (source_meta.is_file() && cred.may(&source_meta, MAY_READ | MAY_WRITE));
+        if !may_link {
+            return Ok(OperationResult::error(OP_LINK, NfsStatus::Access));
+        }
+        match self.metadata(&dir_path).await {
+            Ok(dir_meta) if dir_meta.dev != source_meta.dev => {
+                return Ok(OperationResult::error(OP_LINK, NfsStatus::XDev));
+            }
+            Ok(_) => {}
+            Err(e) => return Ok(OperationResult::error(OP_LINK, io_error_status(&e))),
+        }
+
+        // The new link changes the link count and ctime a delegation
+        // holder has cached.
+        if let Err(status) = self.recall_delegations(&source, None, true).await {
+            return Ok(OperationResult::error(OP_LINK, status));
+        }
+
+        let before = self.dir_change(&dir_path).await;
+        // Not following a symlink, so a link to one is another symlink.
+        if let Err(e) = self.backend.link(&source, &dir_path.join(&args.new_name)).await {
+            return Ok(OperationResult::error(OP_LINK, io_error_status(&e)));
+        }
+        let after = self.dir_change(&dir_path).await;
+
+        Ok(OperationResult::ok(
+            OP_LINK,
+            Some(OperationData::Link(ChangeInfo {
+                atomic: false,
+                before,
+                after,
+            })),
+        ))
+    }
+
+    async fn handle_lock(&self, args: LockOperation, sessions: bool) -> Result<OperationResult> {
+        let end = match range_end(args.offset, args.length) {
+            Ok(end) => end,
+            Err(status) => return Ok(OperationResult::error(OP_LOCK, status)),
+        };
+        if !(READ_LT..=WRITEW_LT).contains(&args.locktype) {
+            return Ok(OperationResult::error(OP_LOCK, NfsStatus::Inval));
+        }
+        // Nothing survives a restart, so there is never a grace period to
+        // reclaim locks in.
+        if args.reclaim {
+            return Ok(OperationResult::error(OP_LOCK, NfsStatus::NoGrace));
+        }
+
+        match args.locker {
+            Locker::New {
+                open_seqid,
+                open_stateid,
+                lock_seqid,
+                lock_owner,
+            } => {
+                let (open_key, open_other) = {
+                    let stateids = self.stateids.read().await;
+                    match stateids.get(&stateid_other(&open_stateid)) {
+                        Some(state) if state.open.is_none() => {
+                            if let Err(status) = check_stateid_seqid(state, &open_stateid) {
+                                return Ok(OperationResult::error(OP_LOCK, status));
+                            }
+                            ((state.clientid, state.owner.clone()), stateid_other(&open_stateid))
+                        }
+                        Some(_) => return Ok(OperationResult::error(OP_LOCK, NfsStatus::BadStateid)),
+                        None => return Ok(OperationResult::error(OP_LOCK, self.stateid_error(&open_stateid).await)),
+                    }
+                };
+                // The lock-owner belongs to the client holding the open.
+                let lock_key = (open_key.0, lock_owner.owner);
+
+                if sessions {
+                    return Ok(self.lock(&lock_key, open_other, args.locktype, args.offset, end).await);
+                }
+                match self.check_owner_seqid(&open_key, OP_LOCK, open_seqid).await {
+                    Ok(SeqidCheck::New) => {}
+                    Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+                    Err(status) => return Ok(OperationResult::error(OP_LOCK, status)),
+                }
+                let result = self.lock(&lock_key, open_other, args.locktype, args.offset, end).await;
+                self.record_owner_reply(&open_key, open_seqid, &result, None).await;
+                if result.status == NfsStatus::Ok {
+                    let mut lock_owners = self.lock_owners.write().await;
+                    lock_owners.entry(lock_key).or_default().record(lock_seqid, &result, None);
+                }
+                Ok(result)
+            }
+            Locker::Existing {
+                lock_stateid,
+                lock_seqid,
+            } => {
+                let (lock_key, open_other) = {
+                    let stateids = self.stateids.read().await;
+                    match self.lock_state(&stateids, &lock_stateid).await {
+                        Ok(state) => ((state.clientid, state.owner.clone()), state.open.unwrap()),
+                        Err(status) => return Ok(OperationResult::error(OP_LOCK, status)),
+                    }
+                };
+
+                if sessions {
+                    return Ok(self.lock(&lock_key, open_other, args.locktype, args.offset, end).await);
+                }
+                match self.check_lock_owner_seqid(&lock_key, OP_LOCK, lock_seqid).await {
+                    Ok(SeqidCheck::New) => {}
+                    Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+                    Err(status) => return Ok(OperationResult::error(OP_LOCK, status)),
+                }
+                let result = self.lock(&lock_key, open_other, args.locktype, args.offset, end).await;
+                self.record_lock_owner_reply(&lock_key, lock_seqid, &result).await;
+                Ok(result)
+            }
+        }
+    }
+
+    // Take a lock for a lock-owner on the file of an open, returning the
+    // lock-owner's stateid for the file.
+    async fn lock(&self, lock_key: &OwnerKey, open_other: [u8; 12], locktype: u32, offset: u64, end: u64) -> OperationResult {
+        let mut stateids = self.stateids.write().await;
+        let (clientid, file_key, open_mode) = match stateids.get(&open_other) {
+            Some(open) => (open.clientid, open.file_key, open.open_mode),
+            None => return OperationResult::error(OP_LOCK, NfsStatus::BadStateid),
+        };
+        let write = locktype == WRITE_LT || locktype == WRITEW_LT;
+        if write && open_mode & OPEN4_SHARE_ACCESS_WRITE == 0 {
+            return OperationResult::error(OP_LOCK, NfsStatus::OpenMode);
+        }
+
+        if let Err(denied) = self.locks.write().await.lock(&file_key, lock_key, offset, end, locktype) {
+            return OperationResult {
+                op: OP_LOCK,
+                status: NfsStatus::Denied,
+                result: Some(OperationData::LockDenied(denied)),
+            };
+        }
+
+        let existing = stateids
+            .iter_mut()
+            .find(|(_, state)| state.open == Some(open_other) && state.clientid == clientid && state.owner == lock_key.1);
+        let stateid = match existing {
+            Some((other, state)) => {
+                state.seqid = state.seqid.wrapping_add(1);
+                make_stateid(state.seqid, other)
+            }
+            None => {
+                let stateid = self.clients.write().await.new_stateid(clientid);
+                stateids.insert(
+                    stateid_other(&stateid),
+                    FileState {
+                        clientid,
+                        owner: lock_key.1.clone(),
+                        file_key,
+                        open_mode,
+                        share_deny: 0,
+                        access_modes: 0,
+                        deny_modes: 0,
+                        seqid: stateid_seqid(&stateid),
+                        confirmed: true,
+                        file: None,
+                        open: Some(open_other),
+                    },
+                );
+                stateid
+            }
+        };
+        drop(stateids);
+
+        self.renew_lease(clientid).await;
+        OperationResult::ok(OP_LOCK, Some(OperationData::Lock(stateid)))
+    }
+
+    async fn handle_lockt(&self, args: LocktOperation, state: &CompoundState) -> Result<OperationResult> {
+        let path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_LOCKT, status)),
+        };
+        let end = match range_end(args.offset, args.length) {
+            Ok(end) => end,
+            Err(status) => return Ok(OperationResult::error(OP_LOCKT, status)),
+        };
+        if !(READ_LT..=WRITEW_LT).contains(&args.locktype) {
+            return Ok(OperationResult::error(OP_LOCKT, NfsStatus::Inval));
+        }
+        if self.is_dir(&path).await {
+            return Ok(OperationResult::error(OP_LOCKT, NfsStatus::IsDir));
+        }
+        let file_key = match self.file_key(&path).await {
+            Ok(file_key) => file_key,
+            Err(status) => return Ok(OperationResult::error(OP_LOCKT, status)),
+        };
+
+        // NFSv4.1 ignores the clientid in the owner in favour of the
+        // session's.
+        let clientid = match &state.slot {
+            Some(slot) => slot.clientid,
+            None => args.owner.clientid,
+        };
+        if let Err(status) = self.clients.write().await.renew(clientid) {
+            return Ok(OperationResult::error(OP_LOCKT, status));
+        }
+
+        let write = args.locktype == WRITE_LT || args.locktype == WRITEW_LT;
+        let key = (clientid, args.owner.owner);
+        match self.locks.read().await.test(&file_key, &key, args.offset, end, write) {
+            Ok(()) => Ok(OperationResult::ok(OP_LOCKT, None)),
+            Err(denied) => Ok(OperationResult {
+                op: OP_LOCKT,
+                status: NfsStatus::Denied,
+                result: Some(OperationData::LockDenied(denied)),
+            }),
+        }
+    }
+
+    async fn handle_locku(&self, args: LockuOperation, sessions: bool) -> Result<OperationResult> {
+        let end = match range_end(args.offset, args.length) {
+            Ok(end) => end,
+            Err(status) => return Ok(OperationResult::error(OP_LOCKU, status)),
+        };
+        let lock_key = {
+            let stateids = self.stateids.read().await;
+            match self.lock_state(&stateids, &args.lock_stateid).await {
+                Ok(state) => (state.clientid, state.owner.clone()),
+                Err(status) => return Ok(OperationResult::error(OP_LOCKU, status)),
+            }
+        };
+
+        if sessions {
+            return Ok(self.locku(&args, end).await);
+        }
+        match self.check_lock_owner_seqid(&lock_key, OP_LOCKU, args.seqid).await {
+            Ok(SeqidCheck::New) => {}
+            Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+            Err(status) => return Ok(OperationResult::error(OP_LOCKU, status)),
+        }
+        let result = self.locku(&args, end).await;
+        self.record_lock_owner_reply(&lock_key, args.seqid, &result).await;
+        Ok(result)
+    }
+
+    async fn locku(&self, args: &LockuOperation, end: u64) -> OperationResult {
+        let other = stateid_other(&args.lock_stateid);
+        let mut stateids = self.stateids.write().await;
+        let state = match stateids.get_mut(&other) {
+            Some(state) => state,
+            None => return OperationResult::error(OP_LOCKU, NfsStatus::BadStateid),
+        };
+        if let Err(status) = check_stateid_seqid(state, &args.lock_stateid) {
+            return OperationResult::error(OP_LOCKU, status);
+        }
+
+        let key = (state.clientid, state.owner.clone());
+        self.locks.write().await.unlock(&state.file_key, &key, args.offset, end);
+        state.seqid = state.seqid.wrapping_add(1);
+        let stateid = make_stateid(state.seqid, &other);
+        drop(stateids);
+
+        self.renew_lease(key.0).await;
+        OperationResult::ok(OP_LOCKU, Some(OperationData::Locku(stateid)))
+    }
+
+    async fn handle_lookup(&self, args: LookupOperation, current_fh: &mut Option<NfsFileHandle>) -> Result<OperationResult> {
+        if let Some(node) = self.pseudo_dir(current_fh) {
+            if let Err(status) = check_name(&args.object_name) {
+                return Ok(OperationResult::error(OP_LOOKUP, status));
+            }
+            let entry = self
+                .exports
+                .pseudo_node(node)
+                .and_then(|node| node.entries.get(&args.object_name).copied())
+                .filter(|&entry| self.pseudo_visible(entry));
+            let Some(entry) = entry else {
+                return Ok(OperationResult::error(OP_LOOKUP, NfsStatus::NoEnt));
+            };
+            match self.pseudo_entry_fh(entry).await {
+                Ok(fh) => *current_fh = Some(fh),
+                Err(status) => return Ok(OperationResult::error(OP_LOOKUP, status)),
+            }
+            return Ok(OperationResult::ok(OP_LOOKUP, None));
+        }
+
+        let parent_path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_LOOKUP, status)),
+        };
+        match self.metadata(&parent_path).await {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Ok(OperationResult::error(OP_LOOKUP, NfsStatus::Symlink));
+            }
+            Ok(metadata) if metadata.is_dir() => {}
+            _ => return Ok(OperationResult::error(OP_LOOKUP, NfsStatus::NotDir)),
+        }
+        if let Err(status) = check_name(&args.object_name) {
+            return Ok(OperationResult::error(OP_LOOKUP, status));
+        }
+
+        // The entry itself, even if it is a symlink, dangling or not.
+        if self.backend.lookup(&parent_path, &args.object_name).await.is_err() {
+            return Ok(OperationResult::error(OP_LOOKUP, NfsStatus::NoEnt));
+        }
+        let path = parent_path.join(&args.object_name);
+
+        match self.register_handle(path).await {
+            Ok(fh) => *current_fh = Some(fh),
+            Err(status) => return Ok(OperationResult::error(OP_LOOKUP, status)),
+        }
+        Ok(OperationResult::ok(OP_LOOKUP, None))
+    }
+
+    async fn handle_lookupp(&self, _args: LookuppOperation, current_fh: &mut Option<NfsFileHandle>) -> Result<OperationResult> {
+        if let Some(node) = self.pseudo_dir(current_fh) {
+            if node == 0 {
+                return Ok(OperationResult::error(OP_LOOKUPP, NfsStatus::NoEnt));
+            }
+            let parent = self.exports.pseudo_node(node).map(|node| node.parent).unwrap_or_default();
+            *current_fh = Some(self.pseudo_fh(parent));
+            return Ok(OperationResult::ok(OP_LOOKUPP, None));
+        }
+
+        let path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_LOOKUPP, status)),
+        };
+        if !self.is_dir(&path).await {
+            return Ok(OperationResult::error(OP_LOOKUPP, NfsStatus::NotDir));
+        }
+        // The parent of an export root is the pseudo-filesystem directory
+        // it appears in, if there is one.
+        if let Some(export) = self.exports.containing(&path).filter(|export| export.root == path) {
+            match export.parent {
+                Some(parent) => *current_fh = Some(self.pseudo_fh(parent)),
+                None => return Ok(OperationResult::error(OP_LOOKUPP, NfsStatus::NoEnt)),
+            }
+            return Ok(OperationResult::ok(OP_LOOKUPP, None));
+        }
+
+        let parent = match path.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return Ok(OperationResult::error(OP_LOOKUPP, NfsStatus::NoEnt)),
+        };
+        match self.register_handle(parent).await {
+            Ok(fh) => *current_fh = Some(fh),
+            Err(status) => return Ok(OperationResult::error(OP_LOOKUPP, status)),
+        }
+        Ok(OperationResult::ok(OP_LOOKUPP, None))
+    }
+
+    async fn handle_putrootfh(&self, op: u32, current_fh: &mut Option<NfsFileHandle>) -> Result<OperationResult> {
+        match self.root_fh().await {
+            Ok(fh) => {
+                *current_fh = Some(fh);
+                Ok(OperationResult::ok(op, None))
+            }
+            Err(status) => Ok(OperationResult::error(op, status)),
+        }
+    }
+
+    async fn handle_putfh(&self, args: PutFhOperation, current_fh: &mut Option<NfsFileHandle>) -> Result<OperationResult> {
+        if args.object.data.is_empty() || args.object.data.len() > NFS4_FHSIZE {
+            return Ok(OperationResult::error(OP_PUTFH, NfsStatus::BadHandle));
+        }
+        let object = Some(args.object);
+        if self.pseudo_dir(&object).is_none() {
+            if let Err(status) = self.current_path(&object).await {
+                return Ok(OperationResult::error(OP_PUTFH, status));
+            }
+        }
+
+        *current_fh = object;
+        Ok(OperationResult::ok(OP_PUTFH, None))
+    }
+
+    async fn handle_open(&self, mut args: OpenOperation, state: &mut CompoundState) -> Result<OperationResult> {
+        // NFSv4.1 opens belong to the client of the session, and the slot
+        // does the job of the owner's seqid.
+        if let Some(slot) = &state.slot {
+            args.clientid = slot.clientid;
+            return self.open(args, &mut state.current_fh, &state.cred, true, true).await;
+        }
+
+        let key = (args.clientid, args.owner.clone());
+        let seqid = args.seqid;
+        let confirmed = match self.open_owners.read().await.get(&key) {
+            Some(owner) => match owner.check_seqid(OP_OPEN, seqid) {
+                Ok(SeqidCheck::Replay(reply, fh)) => {
+                    state.current_fh = fh;
+                    return Ok(reply);
+                }
+                Ok(SeqidCheck::New) if owner.confirmed => true,
+                Err(status) if owner.confirmed => return Ok(OperationResult::error(OP_OPEN, status)),
+                _ => false,
+            },
+            None => false,
+        };
+
+        // A new owner, or one whose first OPEN was never confirmed and is
+        // now starting over with another (RFC 7530 section 16.16.5).
+        if !confirmed {
+            self.stateids
+                .write()
+                .await
+                .retain(|_, state| state.clientid != key.0 || state.owner != key.1);
+            self.open_owners.write().await.insert(key.clone(), StateOwner::default());
+        }
+
+        let result = self.open(args, &mut state.current_fh, &state.cred, confirmed, false).await?;
+        let fh = if result.status == NfsStatus::Ok { state.current_fh.clone() } else { None };
+        self.record_owner_reply(&key, seqid, &result, fh).await;
+        Ok(result)
+    }
+
+    // OPEN itself. `confirmed` is false for the first OPEN of a new NFSv4.0
+    // open-owner, whose stateid cannot be used until OPEN_CONFIRM.
+    async fn open(
+        &self,
+        args: OpenOperation,
+        current_fh: &mut Option<NfsFileHandle>,
+        cred: &Credentials,
+        confirmed: bool,
+        sessions: bool,
+    ) -> Result<OperationResult> {
+        if let Some(node) = self.pseudo_dir(current_fh) {
+            return Ok(OperationResult::error(OP_OPEN, self.pseudo_open_status(node, &args)));
+        }
+        let current_path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_OPEN, status)),
+        };
+
+        if let Err(status) = self.clients.write().await.renew(args.clientid) {
+            return Ok(OperationResult::error(OP_OPEN, status));
+        }
+
+        // The CLAIM_DELEGATE_CUR variants are the holder of a delegation
+        // telling the server about opens it made locally, before returning
+        // the delegation.
+        let current_is_dir = self.is_dir(&current_path).await;
+        let (dir_path, full_path, claimed) = match &args.open_claim {
+            OpenClaim::Null(_) | OpenClaim::Delegate(..) if !current_is_dir => {
+                return Ok(OperationResult::error(OP_OPEN, NfsStatus::NotDir));
+            }
+            OpenClaim::Null(name) => (current_path.clone(), current_path.join(name), None),
+            OpenClaim::Delegate(stateid, name) => (current_path.clone(), current_path.join(name), Some(*stateid)),
+            // CLAIM_FH opens the current filehandle itself, so there is no
+            // name to create.
+            OpenClaim::Fh | OpenClaim::DelegateCurFh(_) if matches!(args.open_how, OpenHow::Create(_)) => {
+                return Ok(OperationResult::error(OP_OPEN, NfsStatus::Inval));
+            }
+            OpenClaim::Fh => {
+                let dir_path = current_path.parent().unwrap_or(&current_path).to_path_buf();
+                (dir_path, current_path, None)
+            }
+            OpenClaim::DelegateCurFh(stateid) => {
+                let dir_path = current_path.parent().unwrap_or(&current_path).to_path_buf();
+                (dir_path, current_path, Some(*stateid))
+            }
+            _ => return Ok(OperationResult::error(OP_OPEN, NfsStatus::NotSupp)),
+        };
+        if let OpenClaim::Null(name) | OpenClaim::Delegate(_, name) = &args.open_claim {
+            if let Err(status) = check_name(name) {
+                return Ok(OperationResult::error(OP_OPEN, status));
+            }
+        }
+
+        // A file that does not exist yet has no opens or delegations.
+        let file_key = self.file_key(&full_path).await.ok();
+        if let Some(stateid) = &claimed {
+            let other = stateid_other(stateid);
+            let delegations = self.delegations.read().await;
+            let status = match delegations.get(&other) {
+                Some(delegation) if delegation.clientid != args.clientid || Some(delegation.file_key) != file_key => {
+                    Some(NfsStatus::BadStateid)
+                }
+                Some(delegation) => check_current_seqid(delegation.seqid, stateid).err(),
+                None if delegations.is_revoked(&other) => Some(revoked_status(sessions)),
+                None => Some(NfsStatus::BadStateid),
+            };
+            if let Some(status) = status {
+                return Ok(OperationResult::error(OP_OPEN, status));
+            }
+        }
+
+        // NFSv4.1 clients put delegation wants in the upper bits of
+        // share_access.
+        let access = args.share_access & OPEN4_SHARE_ACCESS_BOTH;
+        let deny = args.share_deny;
+        if access == 0 || deny > OPEN4_SHARE_DENY_BOTH {
+            return Ok(OperationResult::error(OP_OPEN, NfsStatus::Inval));
+        }
+        if access & OPEN4_SHARE_ACCESS_WRITE != 0 || matches!(args.open_how, OpenHow::Create(_)) {
+            if let Err(status) = self.check_writable(&full_path) {
+                return Ok(OperationResult::error(OP_OPEN, status));
+            }
+        }
+
+        // An owner opening a file it already has open gets the same
+        // stateid back, with the access and deny modes of both opens.
+        let existing = self
+            .stateids
+            .read()
+            .await
+            .iter()
+            .find(|(_, state)| {
+                state.open.is_none()
+                    && state.clientid == args.clientid
+                    && state.owner == args.owner
+                    && Some(state.file_key) == file_key
+            })
+            .map(|(other, state)| (*other, state.open_mode));
+
+        let write = access & OPEN4_SHARE_ACCESS_WRITE != 0;
+        if let Err(status) = self.recall_delegations(&full_path, Some(args.clientid), write).await {
+            return Ok(OperationResult::error(OP_OPEN, status));
+        }
+
+        // Checked before the create step as well, which may truncate the
+        // file.
+        let conflict = match &file_key {
+            Some(file_key) => share_conflict(
+                &*self.stateids.read().await,
+                file_key,
+                existing.as_ref().map(|(other, _)| other),
+                access,
+                deny,
+            ),
+            None => false,
+        };
+        if conflict {
+            return Ok(OperationResult::error(OP_OPEN, NfsStatus::ShareDenied));
+        }
+
+        // An existing file must allow the access asked for, and creating
+        // one takes write permission on the directory. A file the OPEN
+        // creates is opened whatever mode it is created with.
+        let mut wanted = 0;
+        if access & OPEN4_SHARE_ACCESS_READ != 0 {
+            wanted |= MAY_READ;
+        }
+        if access & OPEN4_SHARE_ACCESS_WRITE != 0 {
+            wanted |= MAY_WRITE;
+        }
+        match self.metadata(&full_path).await {
+            Ok(metadata) if metadata.is_file() && !cred.may(&metadata, wanted) => {
+                return Ok(OperationResult::error(OP_OPEN, NfsStatus::Access));
+            }
+            Err(_) if matches!(args.open_how, OpenHow::Create(_)) => {
+                if let Err(status) = self.check_dir_write(&dir_path, cred).await {
+                    return Ok(OperationResult::error(OP_OPEN, status));
+                }
+            }
+            _ => {}
+        }
+
+        let before = self.dir_change(&dir_path).await;
+        let mut attrset = Vec::new();
+
+        if let OpenHow::Create(how) = &args.open_how {
+            match self.open_create(&full_path, how, cred).await? {
+                Ok(set) => attrset = set,
+                Err(status) => return Ok(OperationResult::error(OP_OPEN, status)),
+            }
+        }
+
+        match self.metadata(&full_path).await {
+            Ok(metadata) if metadata.is_dir() => return Ok(OperationResult::error(OP_OPEN, NfsStatus::IsDir)),
+            Ok(metadata) if metadata.is_symlink() => {
+                return Ok(OperationResult::error(OP_OPEN, NfsStatus::Symlink));
+            }
+            Ok(metadata) if !metadata.is_file() => return Ok(OperationResult::error(OP_OPEN, NfsStatus::Inval)),
+            Ok(_) => {}
+            Err(e) => return Ok(OperationResult::error(OP_OPEN, io_error_status(&e))),
+        }
+
+        let share_access = match existing {
+            Some((_, open_mode)) => access | open_mode,
+            None => access,
+        };
+
+        let file = self
+            .backend
+            .open(
+                &full_path,
+                (share_access & OPEN4_SHARE_ACCESS_READ) != 0,
+                (share_access & OPEN4_SHARE_ACCESS_WRITE) != 0,
+            )
+            .await;
+
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => return Ok(OperationResult::error(OP_OPEN, io_error_status(&e))),
+        };
+        let after = self.dir_change(&dir_path).await;
+
+        let fh = match self.register_handle(full_path.clone()).await {
+            Ok(fh) => fh,
+            Err(status) => return Ok(OperationResult::error(OP_OPEN, status)),
+        };
+        let file_key = match FileId::from_handle(&fh) {
+            Ok(id) => id.key(),
+            Err(status) => return Ok(OperationResult::error(OP_OPEN, status)),
+        };
+
+        let stateid = {
+            let mut stateids = self.stateids.write().await;
+            // Another open may have got in while the file was being opened.
+            if share_conflict(&stateids, &file_key, existing.as_ref().map(|(other, _)| other), access, deny) {
+                return Ok(OperationResult::error(OP_OPEN, NfsStatus::ShareDenied));
+            }
+            match existing.and_then(|(other, _)| stateids.get_mut(&other).map(|state| (other, state))) {
+                Some((other, state)) => {
+                    state.open_mode |= access;
+                    state.share_deny |= deny;
+                    state.access_modes |= 1 << access;
+                    state.deny_modes |= 1 << deny;
+                    state.seqid = state.seqid.wrapping_add(1);
+                    state.file = Some(file);
+                    make_stateid(state.seqid, &other)
+                }
+                None => {
+                    let stateid = self.clients.write().await.new_stateid(args.clientid);
+                    stateids.insert(
+                        stateid_other(&stateid),
+                        FileState {
+                            clientid: args.clientid,
+                            owner: args.owner.clone(),
+                            file_key,
+                            open_mode: access,
+                            share_deny: deny,
+                            access_modes: 1 << access,
+                            deny_modes: 1 << deny,
+                            seqid: stateid_seqid(&stateid),
+                            confirmed,
+                            file: Some(file),
+                            open: None,
+                        },
+                    );
+                    stateid
+                }
+            }
+        };
+
+        // Like the stateid, a delegation is no use to an owner that has yet
+        // to confirm its first OPEN.
+        let delegation = match claimed {
+            None if confirmed => self.grant_delegation(args.clientid, file_key, &full_path, &fh, share_access).await,
+            _ => OpenDelegation::None,
+        };
+
+        // OPEN leaves the opened file as the current filehandle.
+        *current_fh = Some(fh);
+
+        Ok(OperationResult::ok(
+            OP_OPEN,
+            Some(OperationData::Open(OpenResult {
+                stateid,
+                change_info: ChangeInfo {
+                    atomic: false,
+                    before,
+                    after,
+                },
+                rflags: if confirmed {
+                    OPEN4_RESULT_LOCKTYPE_POSIX
+                } else {
+                    OPEN4_RESULT_LOCKTYPE_POSIX | OPEN4_RESULT_CONFIRM
+                },
+                attrset,
+                delegation,
+            })),
+        ))
+    }
+
+    // Nothing in the pseudo-filesystem is a file, and nothing can be
+    // created there.
+    fn pseudo_open_status(&self, node: usize, args: &OpenOperation) -> NfsStatus {
+        let entries = self.exports.pseudo_node(node).map(|node| &node.entries);
+        match &args.open_claim {
+            OpenClaim::Null(name) => match check_name(name) {
+                Err(status) => status,
+                Ok(()) if entries.and_then(|entries| entries.get(name)).is_some_and(|&entry| self.pseudo_visible(entry)) => {
+                    NfsStatus::IsDir
+                }
+                Ok(()) if matches!(args.open_how, OpenHow::Create(_)) => NfsStatus::RoFs,
+                Ok(()) => NfsStatus::NoEnt,
+            },
+            OpenClaim::Fh => NfsStatus::IsDir,
+            OpenClaim::Delegate(..) | OpenClaim::DelegateCurFh(_) => NfsStatus::BadStateid,
+            _ => NfsStatus::NotSupp,
+        }
+    }
+
+    // Create step of OPEN4_CREATE. Returns the attributes that were set on
+    // the file, or the status the OPEN should fail with.
+    async fn open_create(
+        &self,
+        path: &Path,
+        how: &CreateHow,
+        cred: &Credentials,
+    ) -> Result<std::result::Result<Vec<u32>, NfsStatus>> {
+        let attrs = match how {
+            CreateHow::Unchecked(attrs) | CreateHow::Guarded(attrs) => match decode_settable(attrs, &self.idmap) {
+                Ok(attrs) => attrs,
+                Err(status) => return Ok(Err(status)),
+            },
+            CreateHow::Exclusive(verifier) => exclusive_attributes(verifier, NfsSetAttributes::default()),
+            // The times are where the verifier goes, so the client may not
+            // set them itself.
+            CreateHow::Exclusive41(verifier, attrs) => match decode_settable(attrs, &self.idmap) {
+                Ok(attrs) if attrs.time_access.is_none() && attrs.time_modify.is_none() => {
+                    exclusive_attributes(verifier, attrs)
+                }
+                Ok(_) => return Ok(Err(NfsStatus::Inval)),
+                Err(status) => return Ok(Err(status)),
+            },
+        };
+
+        let created = match self.backend.create(path, NF4REG, SpecData::default()).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+            Err(e) => return Ok(Err(io_error_status(&e))),
+        };
+
+        // An existing file may have gone again since the create step found
+        // it, which fails just this OPEN.
+        let metadata = match created {
+            true => None,
+            false => match self.metadata(path).await {
+                Ok(metadata) => Some(metadata),
+                Err(e) => return Ok(Err(io_error_status(&e))),
+            },
+        };
+        let attrs = match (how, metadata) {
+            (_, None) => attrs,
+            // An existing file is opened as is, except that a size of zero
+            // still truncates it.
+            (CreateHow::Unchecked(_), Some(metadata)) if attrs.size.is_some() && !cred.may(&metadata, MAY_WRITE) => {
+                return Ok(Err(NfsStatus::Access));
+            }
+            (CreateHow::Unchecked(_), _) => NfsSetAttributes {
+                size: attrs.size,
+                ..Default::default()
+            },
+            (CreateHow::Guarded(_), _) => return Ok(Err(NfsStatus::Exist)),
+            (CreateHow::Exclusive(_) | CreateHow::Exclusive41(..), Some(metadata)) => {
+                let matches = Some(SetTime::ClientTime(NfsTime {
+                    seconds: metadata.atime.seconds,
+                    nseconds: 0,
+                })) == attrs.time_access
+                    && Some(SetTime::ClientTime(NfsTime {
+                        seconds: metadata.mtime.seconds,
+                        nseconds: 0,
+                    })) == attrs.time_modify;
+                return Ok(if matches { Ok(Vec::new()) } else { Err(NfsStatus::Exist) });
+            }
+        };
+
+        let (status, attrset) = if created {
+            self.set_new_attributes(path, cred, attrs).await?
+        } else {
+            self.set_attributes(path, attrs).await?
+        };
+        if status != NfsStatus::Ok {
+            if created {
+                let _ = self.backend.remove(path, false).await;
+            }
+            return Ok(Err(status));
+        }
+        Ok(Ok(attrset))
+    }
+
+    async fn handle_open_confirm(&self, args: OpenConfirmOperation) -> Result<OperationResult> {
+        let other = stateid_other(&args.open_stateid);
+        let key = self
+            .stateids
+            .read()
+            .await
+            .get(&other)
+            .filter(|state| state.open.is_none())
+            .map(|state| (state.clientid, state.owner.clone()));
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(OperationResult::error(OP_OPEN_CONFIRM, self.stateid_error(&args.open_stateid).await)),
+        };
+
+        match self.check_owner_seqid(&key, OP_OPEN_CONFIRM, args.seqid).await {
+            Ok(SeqidCheck::New) => {}
+            Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+            Err(status) => return Ok(OperationResult::error(OP_OPEN_CONFIRM, status)),
+        }
+
+        let result = {
+            let mut stateids = self.stateids.write().await;
+            match stateids.get_mut(&other) {
+                // Only the first OPEN of an owner needs confirming.
+                Some(state) if state.confirmed => OperationResult::error(OP_OPEN_CONFIRM, NfsStatus::BadStateid),
+                Some(state) if stateid_seqid(&args.open_stateid) != state.seqid => {
+                    OperationResult::error(OP_OPEN_CONFIRM, NfsStatus::BadStateid)
+                }
+                Some(state) => {
+                    state.confirmed = true;
+                    state.seqid = state.seqid.wrapping_add(1);
+                    OperationResult::ok(
+                        OP_OPEN_CONFIRM,
+                        Some(OperationData::OpenConfirm(make_stateid(state.seqid, &other))),
+                    )
+                }
+                None => OperationResult::error(OP_OPEN_CONFIRM, NfsStatus::BadStateid),
+            }
+        };
+        if result.status == NfsStatus::Ok {
+            if let Some(owner) = self.open_owners.write().await.get_mut(&key) {
+                owner.confirmed = true;
+            }
+            self.renew_lease(key.0).await;
+        }
+        self.record_owner_reply(&key, args.seqid, &result, None).await;
+        Ok(result)
+    }
+
+    async fn handle_open_downgrade(&self, args: OpenDowngradeOperation, sessions: bool) -> Result<OperationResult> {
+        if sessions {
+            return Ok(self.open_downgrade(&args).await);
+        }
+
+        let other = stateid_other(&args.open_stateid);
+        let key = self
+            .stateids
+            .read()
+            .await
+            .get(&other)
+            .filter(|state| state.open.is_none())
+            .map(|state| (state.clientid, state.owner.clone()));
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(OperationResult::error(OP_OPEN_DOWNGRADE, self.stateid_error(&args.open_stateid).await)),
+        };
+
+        match self.check_owner_seqid(&key, OP_OPEN_DOWNGRADE, args.seqid).await {
+            Ok(SeqidCheck::New) => {}
+            Ok(SeqidCheck::Replay(reply, _)) => return Ok(reply),
+            Err(status) => return Ok(OperationResult::error(OP_OPEN_DOWNGRADE, status)),
+        }
+        let result = self.open_downgrade(&args).await;
+        self.record_owner_reply(&key, args.seqid, &result, None).await;
+        Ok(result)
+    }
+
+    async fn open_downgrade(&self, args: &OpenDowngradeOperation) -> OperationResult {
+        let other = stateid_other(&args.open_stateid);
+        let mut stateids = self.stateids.write().await;
+        let state = match stateids.get_mut(&other) {
+            Some(state) if state.open.is_none() => state,
+            Some(_) => return OperationResult::error(OP_OPEN_DOWNGRADE, NfsStatus::BadStateid),
+            None => return OperationResult::error(OP_OPEN_DOWNGRADE, self.stateid_error(&args.open_stateid).await),
+        };
+        if let Err(status) = check_stateid_seqid(state, &args.open_stateid) {
+            return OperationResult::error(OP_OPEN_DOWNGRADE, status);
+        }
+
+        // The new modes must be ones the owner opened the file with.
+        let access = args.share_access & OPEN4_SHARE_ACCESS_BOTH;
+        let deny = args.share_deny;
+        if access == 0
+            || deny > OPEN4_SHARE_DENY_BOTH
+            || state.access_modes & (1 << access) == 0
+            || state.deny_modes & (1 << deny) == 0
+        {
+            return OperationResult::error(OP_OPEN_DOWNGRADE, NfsStatus::Inval);
+        }
+
+        state.open_mode = access;
+        state.share_deny = deny;
+        // Modes that are no longer a subset of the open's can not be gone
+        // back to either.
+        state.access_modes &= (0..=OPEN4_SHARE_ACCESS_BOTH)
+            .filter(|mode| mode & !access == 0)
+            .fold(0, |bits, mode| bits | 1 << mode);
+        state.deny_modes &= (0..=OPEN4_SHARE_DENY_BOTH)
+            .filter(|mode| mode & !deny == 0)
+            .fold(0, |bits, mode| bits | 1 << mode);
+        state.seqid = state.seqid.wrapping_add(1);
+        let stateid = make_stateid(state.seqid, &other);
+        let clientid = state.clientid;
+        drop(stateids);
+
+        self.renew_lease(clientid).await;
+        OperationResult::ok(OP_OPEN_DOWNGRADE, Some(OperationData::OpenDowngrade(stateid)))
+    }
+
+    async fn handle_read(
+        &self,
+        args: ReadOperation,
+        current_fh: &Option<NfsFileHandle>,
+        sessions: bool,
+    ) -> Result<OperationResult> {
+        let (clientid, access, file) = match self.io_state(&args.stateid, current_fh, sessions).await {
+            Ok(state) => state,
+            Err(status) => return Ok(OperationResult::error(OP_READ, status)),
+        };
+        if access & OPEN4_SHARE_ACCESS_READ == 0 {
+            return Ok(OperationResult::error(OP_READ, NfsStatus::OpenMode));
+        }
+        self.renew_lease(clientid).await;
+
+        match self.backend.read(&file, args.offset, args.count.min(MAX_IO_SIZE)).await {
+            Ok((data, eof)) => Ok(OperationResult::ok(OP_READ, Some(OperationData::Read(ReadResult { eof, data })))),
+            Err(e) => Ok(OperationResult::error(OP_READ, io_error_status(&e))),
+        }
+    }
+
+    async fn handle_readdir(&self, args: ReadDirOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
+        if let Some(node) = self.pseudo_dir(current_fh) {
+            return self.pseudo_readdir(node, args).await;
+        }
+        let dir_path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_READDIR, status)),
+        };
+        let export = match self.export_of(&dir_path) {
+            Ok(export) => export,
+            Err(status) => return Ok(OperationResult::error(OP_READDIR, status)),
+        };
+
+        let dir_metadata = match self.metadata(&dir_path).await {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(OperationResult::error(OP_READDIR, NfsStatus::NoEnt)),
+        };
+        if !dir_metadata.is_dir() {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::NotDir));
+        }
+        if args.cookie != 0 && args.cookie < COOKIE_BASE {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::BadCookie));
+        }
+
+        // Cookies are positions in the sorted listing, so they are only
+        // meaningful while the directory is unchanged. The verifier tracks
+        // the directory's change attribute to let clients detect that.
+        let cookieverf = dir_metadata.change().to_be_bytes();
+        if args.cookie != 0 && args.cookieverf != cookieverf {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::NotSame));
+        }
+
+        let listing = match self.backend.readdir(&dir_path).await {
+            Ok(listing) => listing,
+            Err(e) => return Ok(OperationResult::error(OP_READDIR, io_error_status(&e))),
+        };
+        let mut names = Vec::new();
+        for name in listing {
+            match name.into_string() {
+                Ok(name) => names.push(name),
+                Err(name) => debug!("Skipping non UTF-8 directory entry {:?}", name),
+            }
+        }
+        names.sort();
+
+        let start = if args.cookie == 0 { 0 } else { (args.cookie - COOKIE_BASE + 1) as usize };
+        if start > names.len() {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::BadCookie));
+        }
+
+        let mut entries = Vec::new();
+        let mut reply_size = READDIR_RESOK_BASE;
+        let mut dir_bytes = 0usize;
+        let mut eof = true;
+
+        for (index, name) in names.iter().enumerate().skip(start) {
+            // Entries removed since the listing was taken are skipped.
+            let entry_path = dir_path.join(name);
+            let metadata = match self.metadata(&entry_path).await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let filehandle = if bitmap_isset(&args.attr_request, FATTR4_FILEHANDLE) {
+                self.register_handle(entry_path.clone()).await.ok()
+            } else {
+                None
+            };
+            let attrs = match self.file_attributes(&export, &entry_path, &metadata, filehandle.as_ref(), &args.attr_request).await {
+                Ok(attrs) => attrs,
+                Err(status) if bitmap_isset(&args.attr_request, FATTR4_RDATTR_ERROR) => rdattr_error(status),
+                Err(status) => return Ok(OperationResult::error(OP_READDIR, status)),
+            };
+
+            let entry_size = 4 + 8 + 4 + name.len().div_ceil(4) * 4 + fattr4_encoded_len(&attrs);
+            dir_bytes += 8 + name.len();
+            let over_dircount = args.dircount > 0 && dir_bytes > args.dircount as usize;
+            if reply_size + entry_size > args.maxcount as usize || (over_dircount && !entries.is_empty()) {
+                eof = false;
+                break;
+            }
+
+            reply_size += entry_size;
+            entries.push(DirEntry {
+                cookie: index as u64 + COOKIE_BASE,
+                name: name.clone(),
+                attrs,
+            });
+        }
+
+        if !eof && entries.is_empty() {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::TooSmall));
+        }
+
+        Ok(OperationResult::ok(
+            OP_READDIR,
+            Some(OperationData::ReadDir(ReadDirResult {
+                cookieverf,
+                entries,
+                eof,
+            })),
+        ))
+    }
+
+    // READDIR of a pseudo-filesystem directory lists the directories and
+    // exports in it, the exports with the attributes of their root. It
+    // does not change while the server runs, so the cookie verifier is
+    // fixed.
+    async fn pseudo_readdir(&self, node: usize, args: ReadDirOperation) -> Result<OperationResult> {
+        if args.cookie != 0 && args.cookie < COOKIE_BASE {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::BadCookie));
+        }
+        let cookieverf = self.write_verifier;
+        if args.cookie != 0 && args.cookieverf != cookieverf {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::NotSame));
+        }
+        let listing: Vec<(String, PseudoEntry)> = match self.exports.pseudo_node(node) {
+            Some(node) => node
+                .entries
+                .iter()
+                .filter(|(_, &entry)| self.pseudo_visible(entry))
+                .map(|(name, entry)| (name.clone(), *entry))
+                .collect(),
+            None => return Ok(OperationResult::error(OP_READDIR, NfsStatus::StaleFileHandle)),
+        };
+        let start = if args.cookie == 0 { 0 } else { (args.cookie - COOKIE_BASE + 1) as usize };
+        if start > listing.len() {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::BadCookie));
+        }
+
+        let mut entries = Vec::new();
+        let mut reply_size = READDIR_RESOK_BASE;
+        let mut dir_bytes = 0usize;
+        let mut eof = true;
+
+        for (index, (name, entry)) in listing.into_iter().enumerate().skip(start) {
+            let fh = match self.pseudo_entry_fh(entry).await {
+                Ok(fh) => fh,
+                Err(status) => return Ok(OperationResult::error(OP_READDIR, status)),
+            };
+            let attrs = match entry {
+                PseudoEntry::Dir(child) => Ok(self.pseudo_attributes(child, &fh, &args.attr_request)),
+                PseudoEntry::Export(id) => {
+                    let export = self.exports.get(id).cloned().expect("export in the pseudo-filesystem");
+                    match self.metadata(&export.root).await {
+                        Ok(metadata) => {
+                            self.file_attributes(&export, &export.root, &metadata, Some(&fh), &args.attr_request).await
+                        }
+                        Err(e) => Err(io_error_status(&e)),
+                    }
+                }
+            };
+            let attrs = match attrs {
+                Ok(attrs) => attrs,
+                Err(status) if bitmap_isset(&args.attr_request, FATTR4_RDATTR_ERROR) => rdattr_error(status),
+                Err(status) => return Ok(OperationResult::error(OP_READDIR, status)),
+            };
+
+            let entry_size = 4 + 8 + 4 + name.len().div_ceil(4) * 4 + fattr4_encoded_len(&attrs);
+            dir_bytes += 8 + name.len();
+            let over_dircount = args.dircount > 0 && dir_bytes > args.dircount as usize;
+            if reply_size + entry_size > args.maxcount as usize || (over_dircount && !entries.is_empty()) {
+                eof = false;
+                break;
+            }
+
+            reply_size += entry_size;
+            entries.push(DirEntry {
+                cookie: index as u64 + COOKIE_BASE,
+                name,
+                attrs,
+            });
+        }
+
+        if !eof && entries.is_empty() {
+            return Ok(OperationResult::error(OP_READDIR, NfsStatus::TooSmall));
+        }
+
+        Ok(OperationResult::ok(
+            OP_READDIR,
+            Some(OperationData::ReadDir(ReadDirResult {
+                cookieverf,
+                entries,
+                eof,
+            })),
+        ))
+    }
+
+    async fn handle_readlink(&self, _args: ReadLinkOperation, current_fh: &Option<NfsFileHandle>) -> Result<OperationResult> {
+        if self.pseudo_dir(current_fh).is_some() {
+            return Ok(OperationResult::error(OP_READLINK, NfsStatus::Inval));
+        }
+        let path = match self.current_path(current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_READLINK, status)),
+        };
+        match self.metadata(&path).await {
+            Ok(metadata) if metadata.is_symlink() => {}
+            Ok(_) => return Ok(OperationResult::error(OP_READLINK, NfsStatus::Inval)),
+            Err(e) => return Ok(OperationResult::error(OP_READLINK, io_error_status(&e))),
+        }
+
+        // Link text is UTF-8 on the wire; anything else is passed on as
+        // best it can be.
+        match self.backend.readlink(&path).await {
+            Ok(target) => Ok(OperationResult::ok(
+                OP_READLINK,
+                Some(OperationData::ReadLink(target.to_string_lossy().into_owned())),
+            )),
+            Err(e) => Ok(OperationResult::error(OP_READLINK, io_error_status(&e))),
+        }
+    }
+
+    async fn handle_reclaim_complete(
+        &self,
+        _args: ReclaimCompleteOperation,
+        state: &CompoundState,
+    ) -> Result<OperationResult> {
+        // The server keeps no state across restarts, so there is nothing to
+        // reclaim; this only records that the client is done.
+        let clientid = match &state.slot {
+            Some(slot) => slot.clientid,
+            None => return Ok(OperationResult::error(OP_RECLAIM_COMPLETE, NfsStatus::OpNotInSession)),
+        };
+        match self.clients.write().await.reclaim_complete(clientid) {
+            Ok(()) => Ok(OperationResult::ok(OP_RECLAIM_COMPLETE, None)),
+            Err(status) => Ok(OperationResult::error(OP_RECLAIM_COMPLETE, status)),
+        }
+    }
+
+    async fn handle_release_lockowner(&self, args: ReleaseLockOwnerOperation) -> Result<OperationResult> {
+        let key = (args.lock_owner.clientid, args.lock_owner.owner);
+        if let Err(status) = self.clients.write().await.renew(key.0) {
+            return Ok(OperationResult::error(OP_RELEASE_LOCKOWNER, status));
+        }
+        if self.locks.read().await.owner_has_locks(&key) {
+            return Ok(OperationResult::error(OP_RELEASE_LOCKOWNER, NfsStatus::LocksHeld));
+        }
+
+        self.lock_owners.write().await.remove(&key);
+        self.stateids
+            .write()
+            .await
+            .retain(|_, state| state.open.is_none() || state.clientid != key.0 || state.owner != key.1);
+        Ok(OperationResult::ok(OP_RELEASE_LOCKOWNER, None))
+    }
+
+    async fn handle_remove(&self, args: RemoveOperation, state: &CompoundState) -> Result<OperationResult> {
+        let dir_path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_REMOVE, status)),
+        };
+        let dir_meta = match self.metadata(&dir_path).await {
+            Ok(metadata) if metadata.is_dir() => metadata,
+            _ => return Ok(OperationResult::error(OP_REMOVE, NfsStatus::NotDir)),
+        };
+        if let Err(status) = check_name(&args.target) {
+            return Ok(OperationResult::error(OP_REMOVE, status));
+        }
+        if let Err(status) = self.check_writable(&dir_path) {
+            return Ok(OperationResult::error(OP_REMOVE, status));
+        }
+
+        let target = dir_path.join(&args.target);
+        let metadata = match self.metadata(&target).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(OperationResult::error(OP_REMOVE, io_error_status(&e))),
+        };
+        if !state.cred.may_unlink(&dir_meta, &metadata) {
+            return Ok(OperationResult::error(OP_REMOVE, NfsStatus::Access));
+        }
+        if let Err(status) = self.recall_delegations(&target, None, true).await {
+            return Ok(OperationResult::error(OP_REMOVE, status));
+        }
+
+        let before = self.dir_change(&dir_path).await;
+        if let Err(e) = self.backend.remove(&target, metadata.is_dir()).await {
+            // Some filesystems report a non-empty directory as EEXIST.
+            let status = match io_error_status(&e) {
+                NfsStatus::Exist => NfsStatus::NotEmpty,
+                status => status,
+            };
+            return Ok(OperationResult::error(OP_REMOVE, status));
+        }
+        let after = self.dir_change(&dir_path).await;
+
+        self.forget_handles(&target).await;
+
+        Ok(OperationResult::ok(
+            OP_REMOVE,
+            Some(OperationData::Remove(ChangeInfo {
+                atomic: false,
+                before,
+                after,
+            })),
+        ))
+    }
+
+    // RENAME moves oldname in the saved filehandle's directory to newname
+    // in the current filehandle's directory.
+    async fn handle_rename(&self, args: RenameOperation, state: &CompoundState) -> Result<OperationResult> {
+        let source_dir = match self.current_path(&state.saved_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_RENAME, status)),
+        };
+        let target_dir = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_RENAME, status)),
+        };
+        for name in [&args.old_name, &args.new_name] {
+            if let Err(status) = check_name(name) {
+                return Ok(OperationResult::error(OP_RENAME, status));
+            }
+        }
+
+        let (source_dir_meta, target_dir_meta) = match (self.metadata(&source_dir).await, self.metadata(&target_dir).await) {
+            (Ok(s), Ok(t)) => (s, t),
+            (Err(e), _) | (_, Err(e)) => return Ok(OperationResult::error(OP_RENAME, io_error_status(&e))),
+        };
+        if !source_dir_meta.is_dir() || !target_dir_meta.is_dir() {
+            return Ok(OperationResult::error(OP_RENAME, NfsStatus::NotDir));
+        }
+        if source_dir_meta.dev != target_dir_meta.dev {
+            return Ok(OperationResult::error(OP_RENAME, NfsStatus::XDev));
+        }
+        if let Err(status) = self.check_writable(&target_dir) {
+            return Ok(OperationResult::error(OP_RENAME, status));
+        }
+
+        let source = source_dir.join(&args.old_name);
+        let target = target_dir.join(&args.new_name);
+        let source_meta = match self.metadata(&source).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(OperationResult::error(OP_RENAME, io_error_status(&e))),
+        };
+
+        // Moving an entry out of one directory is removing it from there,
+        // and replacing an existing target is removing that.
+        let cred = &state.cred;
+        let allowed = cred.may_unlink(&source_dir_meta, &source_meta)
+            && match self.metadata(&target).await {
+                Ok(target_meta) => cred.may_unlink(&target_dir_meta, &target_meta),
+                Err(_) => cred.may(&target_dir_meta, MAY_WRITE | MAY_EXEC),
+            };
+        if !allowed {
+            return Ok(OperationResult::error(OP_RENAME, NfsStatus::Access));
+        }
+
+        // Renaming a delegated file, or replacing one, changes what its
+        // holder would see.
+        for path in [&source, &target] {
+            if let Err(status) = self.recall_delegations(path, None, true).await {
+                return Ok(OperationResult::error(OP_RENAME, status));
+            }
+        }
+
+        let source_before = self.dir_change(&source_dir).await;
+        let target_before = self.dir_change(&target_dir).await;
+
+        if let Ok(target_meta) = self.metadata(&target).await {
+            let same_file = source_meta.dev == target_meta.dev && source_meta.ino == target_meta.ino;
+            if same_file {
+                // Renaming a file onto itself (or another link to it) succeeds
+                // without doing anything.
+                return Ok(OperationResult::ok(
+                    OP_RENAME,
+                    Some(OperationData::Rename(RenameResult {
+                        source_cinfo: ChangeInfo {
+                            atomic: true,
+                            before: source_before,
+                            after: source_before,
+                        },
+                        target_cinfo: ChangeInfo {
+                            atomic: true,
+                            before: target_before,
+                            after: target_before,
+                        },
+                    })),
+                ));
+            }
+
+            let status = match (source_meta.is_dir(), target_meta.is_dir()) {
+                (true, false) => Some(NfsStatus::Exist),
+                (false, true) => Some(NfsStatus::IsDir),
+                _ => None,
+            };
+            if let Some(status) = status {
+                return Ok(OperationResult::error(OP_RENAME, status));
+            }
+        }
+
+        if let Err(e) = self.backend.rename(&source, &target).await {
+            let status = match io_error_status(&e) {
+                NfsStatus::Exist => NfsStatus::NotEmpty,
+                status => status,
+            };
+            return Ok(OperationResult::error(OP_RENAME, status));
+        }
+
+        let source_after = self.dir_change(&source_dir).await;
+        let target_after = self.dir_change(&target_dir).await;
+
+        self.forget_handles(&target).await;
+        self.move_handles(&source, &target).await;
+
+        Ok(OperationResult::ok(
+            OP_RENAME,
+            Some(OperationData::Rename(RenameResult {
+                source_cinfo: ChangeInfo {
+                    atomic: false,
+                    before: source_before,
+                    after: source_after,
+                },
+                target_cinfo: ChangeInfo {
+                    atomic: false,
+                    before: target_before,
+                    after: target_after,
+                },
+            })),
+        ))
+    }
+
+    async fn handle_renew(&self, args: RenewOperation) -> Result<OperationResult> {
+        if let Err(status) = self.clients.write().await.renew(args.clientid) {
+            return Ok(OperationResult::error(OP_RENEW, status));
+        }
+
+        // A client holding delegations is told when the server can no
+        // longer reach it to recall them (RFC 7530 section 16.29.5).
+        let callback_up = self.clients.read().await.client(args.clientid).is_some_and(|c| c.callback_up);
+        if !callback_up && self.delegations.read().await.client_has_delegations(args.clientid) {
+            return Ok(OperationResult::error(OP_RENEW, NfsStatus::CbPathDown));
+        }
+        Ok(OperationResult::ok(OP_RENEW, None))
+    }
+
+    async fn handle_sequence(
+        &self,
+        args: SequenceOperation,
+        op_count: usize,
+        state: &mut CompoundState,
+    ) -> Result<OperationResult> {
+        let (clientid, highest_slotid) = {
+            let mut sessions = self.sessions.write().await;
+            let session = match sessions.get_mut(&args.sessionid) {
+                Some(session) => session,
+                None => return Ok(OperationResult::error(OP_SEQUENCE, NfsStatus::BadSession)),
+            };
+            if op_count > session.max_operations() as usize {
+                return Ok(OperationResult::error(OP_SEQUENCE, NfsStatus::TooManyOps));
+            }
+            match session.check_slot(args.slotid, args.sequenceid) {
+                Ok(SlotCheck::New) => {}
+                Ok(SlotCheck::Replay(reply)) => {
+                    state.replay = Some(reply);
+                    return Ok(OperationResult::ok(OP_SEQUENCE, None));
+                }
+                Err(status) => return Ok(OperationResult::error(OP_SEQUENCE, status)),
+            }
+            (session.clientid, session.highest_slotid())
+        };
+
+        state.slot = Some(SessionSlot {
+            sessionid: args.sessionid,
+            slotid: args.slotid,
+            clientid,
+            cache_this: args.cache_this,
+        });
+        self.renew_lease(clientid).await;
+
+        Ok(OperationResult::ok(
+            OP_SEQUENCE,
+            Some(OperationData::Sequence(SequenceResult {
+                sessionid: args.sessionid,
+                sequenceid: args.sequenceid,
+                slotid: args.slotid,
+                highest_slotid,
+                target_highest_slotid: highest_slotid,
+                status_flags: 0,
+            })),
+        ))
+    }
+
+    async fn handle_setattr(&self, args: SetAttrOperation, state: &CompoundState) -> Result<OperationResult> {
+        let path = match self.current_path(&state.current_fh).await {
+            Ok(path) => path,
+            Err(status) => return Ok(OperationResult::error(OP_SETATTR, status)),
+        };
+        let mut attrs = match decode_settable(&args.attributes, &self.idmap) {
+            Ok(attrs) => attrs,
+            Err(status) => return Ok(OperationResult::error(OP_SETATTR, status)),
+        };
+        if let Err(status) = self.check_writable(&path) {
+            return Ok(OperationResult::error(OP_SETATTR, status));
+        }
+        let metadata = match self.metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(OperationResult::error(OP_SETATTR, io_error_status(&e))),
+        };
+        if let Err(status) = check_setattr(&state.cred, &metadata, &mut attrs) {
+            return Ok(OperationResult::error(OP_SETATTR, status));
+        }
+
+        // A size change is a write, so a real stateid must refer to an open
+        // that allows writing, which OPEN checked the caller may do. The
+        // special all-zeros and all-ones stateids stand for a client
+        // without an open, which needs write permission here and is refused
+        // while another open denies writing. Either way, delegations held
+        // by other clients are recalled first.
+        if attrs.size.is_some() && (args.stateid == [0u8; 16] || args.stateid == [0xffu8; 16]) {
+            if !state.cred.may(&metadata, MAY_WRITE) {
+                return Ok(OperationResult::error(OP_SETATTR, NfsStatus::Access));
+            }
+            if let Err(status) = self.recall_delegations(&path, None, true).await {
+                return Ok(OperationResult::error(OP_SETATTR, status));
+            }
+            let file_key = match self.file_key(&path).await {
+                Ok(file_key) => file_key,
+                Err(status) => return Ok(OperationResult::error(OP_SETATTR, status)),
+            };
+            if share_conflict(&*self.stateids.read().await, &file_key, None, OPEN4_SHARE_ACCESS_WRITE, 0) {
+                return Ok(OperationResult::error(OP_SETATTR, NfsStatus::Locked));
+            }
+        } else if attrs.size.is_some() {
+            let clientid = match self.io_state(&args.stateid, &state.current_fh, state.slot.is_some()).await {
+                Err(status) => return Ok(OperationResult::error(OP_SETATTR, status)),
+                Ok((_, access, _)) if access & OPEN4_SHARE_ACCESS_WRITE == 0 => {
+                    return Ok(OperationResult::error(OP_SETATTR, NfsStatus::OpenMode));
+                }
+                Ok((clientid, _, _)) => clientid,
+            };
+            if let Err(status) = self.recall_delegations(&path, Some(clientid), true).await {
+                return Ok(OperationResult::error(OP_SETATTR, status));
+            }
+            self.renew_lease(clientid).await;
+        }
+
+        let (status, attrsset) = self.set_attributes(&path, attrs).await?;
+        Ok(OperationResult {
+            op: OP_SETATTR,
+            status,
+            result: Some(OperationData::SetAttr(attrsset)),
+        })
+    }
+
+    async fn handle_setclientid(&self, args: SetClientIdOperation) -> Result<OperationResult> {
+        let res = self.clients.write().await.setclientid(args);
+        Ok(OperationResult::ok(OP_SETCLIENTID, Some(OperationData::SetClientId(res))))
+    }
+
+    async fn handle_setclientid_confirm(&self, args: SetClientIdConfirmOperation) -> Result<OperationResult> {
+        let confirmed = self.clients.write().await.confirm(args.clientid, args.confirm);
+        match confirmed {
+            Ok(previous) => {
+                // The client rebooted, so whatever its previous
+                // incarnation held is gone.
+                if let Some(previous) = previous {
+                    self.release_client_state(previous).await;
+                }
+                self.probe_callback(args.clientid);
+                Ok(OperationResult::ok(OP_SETCLIENTID_CONFIRM, None))
+            }
+            Err(status) => Ok(OperationResult::error(OP_SETCLIENTID_CONFIRM, status)),
+        }
+    }
+
+    async fn handle_write(
+        &self,
+        args: WriteOperation,
+        current_fh: &Option<NfsFileHandle>,
+        sessions: bool,
+    ) -> Result<OperationResult> {
+        let (clientid, access, file) = match self.io_state(&args.stateid, current_fh, sessions).await {
+            Ok(state) => state,
+            Err(status) => return Ok(OperationResult::error(OP_WRITE, status)),
+        };
+        if access & OPEN4_SHARE_ACCESS_WRITE == 0 {
+            return Ok(OperationResult::error(OP_WRITE, NfsStatus::OpenMode));
+        }
+        self.renew_lease(clientid).await;
+
+        match self.backend.write(&file, args.offset, &args.data).await {
+            Ok(()) => {
+                let committed = if args.stable != UNSTABLE4 {
+                    if let Err(e) = self.backend.sync(&file).await {
+                        return Ok(OperationResult::error(OP_WRITE, io_error_status(&e)));
+                    }
+                    FILE_SYNC4
+                } else {
+                    UNSTABLE4
+                };
+                Ok(OperationResult::ok(
+                    OP_WRITE,
+                    Some(OperationData::Write(WriteResult {
+                        count: args.data.len() as u32,
+                        committed,
+                        verifier: self.write_verifier,
+                    })),
+                ))
+            }
+            Err(e) => Ok(OperationResult::error(OP_WRITE, io_error_status(&e))),
+        }
+    }
+}
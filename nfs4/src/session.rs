@@ -0,0 +1,145 @@
+use rand::Rng;
+use std::collections::HashMap;
+
+use crate::protocol::*;
+
+// NFSv4.1 sessions (RFC 8881 section 2.10).
+//
+// Every request on a session names a slot and a sequence id for it. A slot
+// carries one request at a time; the next request on it must use the next
+// sequence id, and a request reusing the current one is a retransmission
+// that gets the cached reply instead of being executed again.
+
+// Limits the server offers on the fore channel. Clients asking for more
+// are given these.
+const MAX_SLOTS: u32 = 64;
+const MAX_OPERATIONS: u32 = 64;
+const MAX_REQUEST_SIZE: u32 = 1024 * 1024 + 64 * 1024;
+const MAX_RESPONSE_SIZE: u32 = 1024 * 1024 + 64 * 1024;
+const MAX_RESPONSE_SIZE_CACHED: u32 = 64 * 1024;
+
+#[derive(Debug, Default)]
+struct Slot {
+    sequenceid: u32,
+    in_use: bool,
+    // None when the client did not ask for the reply to be cached.
+    reply: Option<CompoundResponse>,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub sessionid: [u8; 16],
+    pub clientid: u64,
+    pub fore_chan_attrs: ChannelAttrs,
+    pub back_chan_attrs: ChannelAttrs,
+    pub cb_program: u32,
+    slots: Vec<Slot>,
+}
+
+// Outcome of the slot check made by SEQUENCE.
+pub enum SlotCheck {
+    // A new request; the slot is now busy until `finish` is called.
+    New,
+    // A retransmission of the request the slot last carried.
+    Replay(CompoundResponse),
+}
+
+impl Session {
+    pub fn max_operations(&self) -> u32 {
+        self.fore_chan_attrs.max_operations
+    }
+
+    pub fn highest_slotid(&self) -> u32 {
+        self.slots.len() as u32 - 1
+    }
+
+    // RFC 8881 section 2.10.6.1
+    pub fn check_slot(&mut self, slotid: u32, sequenceid: u32) -> Result<SlotCheck, NfsStatus> {
+        let slot = self.slots.get_mut(slotid as usize).ok_or(NfsStatus::BadSlot)?;
+        if slot.in_use {
+            return Err(NfsStatus::Delay);
+        }
+        if sequenceid == slot.sequenceid {
+            return match &slot.reply {
+                Some(reply) => Ok(SlotCheck::Replay(reply.clone())),
+                None => Err(NfsStatus::RetryUncachedRep),
+            };
+        }
+        if sequenceid != slot.sequenceid.wrapping_add(1) {
+            return Err(NfsStatus::SeqMisordered);
+        }
+
+        slot.sequenceid = sequenceid;
+        slot.in_use = true;
+        slot.reply = None;
+        Ok(SlotCheck::New)
+    }
+
+    pub fn finish(&mut self, slotid: u32, reply: Option<CompoundResponse>) {
+        if let Some(slot) = self.slots.get_mut(slotid as usize) {
+            slot.in_use = false;
+            slot.reply = reply;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SessionTable {
+    sessions: HashMap<[u8; 16], Session>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Create a session for a client, trimming the requested channel
+    // attributes to what the server supports.
+    pub fn create(&mut self, args: &CreateSessionOperation) -> &Session {
+        let requested = &args.fore_chan_attrs;
+        let fore_chan_attrs = ChannelAttrs {
+            header_pad_size: 0,
+            max_request_size: requested.max_request_size.min(MAX_REQUEST_SIZE),
+            max_response_size: requested.max_response_size.min(MAX_RESPONSE_SIZE),
+            max_response_size_cached: requested.max_response_size_cached.min(MAX_RESPONSE_SIZE_CACHED),
+            max_operations: requested.max_operations.clamp(1, MAX_OPERATIONS),
+            max_requests: requested.max_requests.clamp(1, MAX_SLOTS),
+            rdma_ird: None,
+        };
+        let back_chan_attrs = ChannelAttrs {
+            header_pad_size: 0,
+            rdma_ird: None,
+            ..args.back_chan_attrs.clone()
+        };
+
+        let mut sessionid = [0u8; 16];
+        sessionid[..8].copy_from_slice(&args.clientid.to_be_bytes());
+        rand::thread_rng().fill(&mut sessionid[8..]);
+
+        let slots = (0..fore_chan_attrs.max_requests).map(|_| Slot::default()).collect();
+        self.sessions.entry(sessionid).or_insert(Session {
+            sessionid,
+            clientid: args.clientid,
+            fore_chan_attrs,
+            back_chan_attrs,
+            cb_program: args.cb_program,
+            slots,
+        })
+    }
+
+    pub fn get_mut(&mut self, sessionid: &[u8; 16]) -> Option<&mut Session> {
+        self.sessions.get_mut(sessionid)
+    }
+
+    pub fn destroy(&mut self, sessionid: &[u8; 16]) -> Result<Session, NfsStatus> {
+        self.sessions.remove(sessionid).ok_or(NfsStatus::BadSession)
+    }
+
+    pub fn destroy_client(&mut self, clientid: u64) {
+        self.sessions.retain(|_, session| session.clientid != clientid);
+    }
+
+    pub fn client_has_sessions(&self, clientid: u64) -> bool {
+        self.sessions.values().any(|session| session.clientid == clientid)
+    }
+}
@@ -0,0 +1,424 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::*;
+
+// NFSv4.0 client records (RFC 7530 section 9.1.1 and the SETCLIENTID
+// description in section 16.33).
+//
+// A client identifies itself with a long-lived id string plus a verifier
+// that changes every time it reboots. SETCLIENTID creates an unconfirmed
+// record and SETCLIENTID_CONFIRM promotes it, replacing any confirmed
+// record the same client had before. Confirmed clients hold a lease that
+// RENEW, or any operation using their clientid or stateids, extends.
+//
+// NFSv4.1 clients (RFC 8881 section 2.4) register with EXCHANGE_ID instead
+// and are confirmed by their first CREATE_SESSION. Their leases are renewed
+// by SEQUENCE.
+
+#[derive(Debug)]
+pub struct ClientRecord {
+    pub clientid: u64,
+    pub id: Vec<u8>,
+    pub verifier: [u8; 8],
+    pub confirm: [u8; 8],
+    // NFSv4.0 callback address; NFSv4.1 callbacks use the session instead.
+    pub callback: Option<CbClient>,
+    pub callback_ident: u32,
+    // Whether the callback program last answered. Delegations are only
+    // handed out while it does, since they could not be recalled otherwise.
+    pub callback_up: bool,
+    // NFSv4.1 CREATE_SESSION sequence and the reply to the last one, kept
+    // so a retransmission gets the same session back.
+    pub sequenceid: u32,
+    pub create_session_reply: Option<CreateSessionResult>,
+    pub reclaim_complete: bool,
+    pub last_renewed: Instant,
+    // Set once the lease has run out and the client's state was released.
+    pub expired: bool,
+}
+
+#[derive(Debug)]
+pub struct ClientTable {
+    // Upper half of every clientid issued since the server started, used to
+    // recognise ids and stateids from before a restart.
+    boot: u32,
+    next_client: u32,
+    next_stateid: u32,
+    lease: Duration,
+    confirmed: HashMap<u64, ClientRecord>,
+    unconfirmed: HashMap<u64, ClientRecord>,
+}
+
+impl ClientTable {
+    pub fn new(lease: Duration) -> Self {
+        let boot = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or_default();
+
+        Self {
+            boot,
+            next_client: 1,
+            next_stateid: 1,
+            lease,
+            confirmed: HashMap::new(),
+            unconfirmed: HashMap::new(),
+        }
+    }
+
+    fn new_clientid(&mut self) -> u64 {
+        let clientid = (self.boot as u64) << 32 | self.next_client as u64;
+        self.next_client = self.next_client.wrapping_add(1);
+        clientid
+    }
+
+    pub fn setclientid(&mut self, args: SetClientIdOperation) -> SetClientIdResult {
+        // A client has at most one unconfirmed record; a new SETCLIENTID
+        // replaces it.
+        self.unconfirmed.retain(|_, c| c.id != args.client.id);
+
+        // The same verifier means the client is only updating its callback
+        // and keeps its clientid. A new verifier means it rebooted.
+        let existing = self
+            .confirmed
+            .values()
+            .find(|c| c.id == args.client.id && c.verifier == args.client.verifier && !c.expired)
+            .map(|c| c.clientid);
+        let clientid = match existing {
+            Some(clientid) => clientid,
+            None => self.new_clientid(),
+        };
+
+        let mut confirm = [0u8; 8];
+        rand::thread_rng().fill(&mut confirm[..]);
+
+        self.unconfirmed.insert(
+            clientid,
+            ClientRecord {
+                clientid,
+                id: args.client.id,
+                verifier: args.client.verifier,
+                confirm,
+                callback: Some(args.callback),
+                callback_ident: args.callback_ident,
+                callback_up: false,
+                sequenceid: 0,
+                create_session_reply: None,
+                reclaim_complete: false,
+                last_renewed: Instant::now(),
+                expired: false,
+            },
+        );
+
+        SetClientIdResult { clientid, confirm }
+    }
+
+    // Confirm a SETCLIENTID. Returns the clientid of a previous incarnation
+    // of the client whose state must now be released, if there was one.
+    pub fn confirm(&mut self, clientid: u64, confirm: [u8; 8]) -> Result<Option<u64>, NfsStatus> {
+        match self.unconfirmed.get(&clientid) {
+            Some(record) if record.confirm == confirm => {}
+            Some(_) => return Err(NfsStatus::StaleClientid),
+            None => {
+                // A retransmitted confirm of an already confirmed record.
+                return match self.confirmed.get_mut(&clientid) {
+                    Some(record) if record.confirm == confirm && !record.expired => {
+                        record.last_renewed = Instant::now();
+                        Ok(None)
+                    }
+                    _ => Err(NfsStatus::StaleClientid),
+                };
+            }
+        }
+
+        let mut record = self.unconfirmed.remove(&clientid).unwrap();
+        record.last_renewed = Instant::now();
+
+        let previous = self
+            .confirmed
+            .values()
+            .find(|c| c.id == record.id && c.clientid != clientid)
+            .map(|c| c.clientid);
+        if let Some(previous) = previous {
+            self.confirmed.remove(&previous);
+        }
+
+        self.confirmed.insert(clientid, record);
+        Ok(previous)
+    }
+
+    // EXCHANGE_ID (RFC 8881 section 18.35.5). Returns the clientid, the
+    // sequenceid the first CREATE_SESSION must use and whether the record
+    // is already confirmed.
+    pub fn exchange_id(&mut self, owner: &ClientOwner, flags: u32) -> Result<(u64, u32, bool), NfsStatus> {
+        let confirmed = self
+            .confirmed
+            .values()
+            .find(|c| c.id == owner.ownerid && !c.expired)
+            .map(|c| (c.clientid, c.sequenceid, c.verifier == owner.verifier));
+
+        if flags & EXCHGID4_FLAG_UPD_CONFIRMED_REC_A != 0 {
+            return match confirmed {
+                Some((clientid, sequenceid, true)) => Ok((clientid, sequenceid, true)),
+                Some(_) => Err(NfsStatus::NotSame),
+                None => Err(NfsStatus::NoEnt),
+            };
+        }
+        if let Some((clientid, sequenceid, true)) = confirmed {
+            return Ok((clientid, sequenceid, true));
+        }
+
+        // A new client, or one that rebooted. The previous incarnation
+        // keeps its state until the new record is confirmed.
+        self.unconfirmed.retain(|_, c| c.id != owner.ownerid);
+        let clientid = self.new_clientid();
+        self.unconfirmed.insert(
+            clientid,
+            ClientRecord {
+                clientid,
+                id: owner.ownerid.clone(),
+                verifier: owner.verifier,
+                confirm: [0u8; 8],
+                callback: None,
+                callback_ident: 0,
+                callback_up: false,
+                sequenceid: 1,
+                create_session_reply: None,
+                reclaim_complete: false,
+                last_renewed: Instant::now(),
+                expired: false,
+            },
+        );
+        Ok((clientid, 1, false))
+    }
+
+    // Check the sequence of a CREATE_SESSION. Returns the cached reply when
+    // it is a retransmission of the previous one.
+    pub fn check_create_session(
+        &self,
+        clientid: u64,
+        sequence: u32,
+    ) -> Result<Option<CreateSessionResult>, NfsStatus> {
+        let record = match self.confirmed.get(&clientid) {
+            Some(record) if record.expired => return Err(NfsStatus::StaleClientid),
+            Some(record) => record,
+            None => self.unconfirmed.get(&clientid).ok_or(NfsStatus::StaleClientid)?,
+        };
+        if sequence == record.sequenceid {
+            return Ok(None);
+        }
+        match &record.create_session_reply {
+            Some(reply) if sequence == record.sequenceid.wrapping_sub(1) => Ok(Some(reply.clone())),
+            _ => Err(NfsStatus::SeqMisordered),
+        }
+    }
+
+    // Record a successful CREATE_SESSION, confirming the client if this
+    // was its first. Returns the clientid of a previous incarnation whose
+    // state must now be released, if there was one.
+    pub fn session_created(&mut self, clientid: u64, reply: CreateSessionResult) -> Option<u64> {
+        let mut previous = None;
+        if let Some(mut record) = self.unconfirmed.remove(&clientid) {
+            previous = self
+                .confirmed
+                .values()
+                .find(|c| c.id == record.id)
+                .map(|c| c.clientid);
+            if let Some(previous) = previous {
+                self.confirmed.remove(&previous);
+            }
+            record.last_renewed = Instant::now();
+            self.confirmed.insert(clientid, record);
+        }
+
+        if let Some(record) = self.confirmed.get_mut(&clientid) {
+            record.sequenceid = record.sequenceid.wrapping_add(1);
+            record.create_session_reply = Some(reply);
+        }
+        previous
+    }
+
+    pub fn reclaim_complete(&mut self, clientid: u64) -> Result<(), NfsStatus> {
+        match self.confirmed.get_mut(&clientid) {
+            Some(record) if record.reclaim_complete => Err(NfsStatus::CompleteAlready),
+            Some(record) => {
+                record.reclaim_complete = true;
+                Ok(())
+            }
+            None => Err(NfsStatus::StaleClientid),
+        }
+    }
+
+    pub fn remove(&mut self, clientid: u64) -> Result<(), NfsStatus> {
+        let confirmed = self.confirmed.remove(&clientid);
+        let unconfirmed = self.unconfirmed.remove(&clientid);
+        match confirmed.or(unconfirmed) {
+            Some(_) => Ok(()),
+            None => Err(NfsStatus::StaleClientid),
+        }
+    }
+
+    // Extend a client's lease. Used by RENEW and implicitly by operations
+    // that carry a clientid.
+    pub fn renew(&mut self, clientid: u64) -> Result<(), NfsStatus> {
+        match self.confirmed.get_mut(&clientid) {
+            Some(record) if record.expired => Err(NfsStatus::Expired),
+            Some(record) => {
+                record.last_renewed = Instant::now();
+                Ok(())
+            }
+            None => Err(NfsStatus::StaleClientid),
+        }
+    }
+
+    pub fn client(&self, clientid: u64) -> Option<&ClientRecord> {
+        self.confirmed.get(&clientid).filter(|c| !c.expired)
+    }
+
+    pub fn set_callback_up(&mut self, clientid: u64, up: bool) {
+        if let Some(record) = self.confirmed.get_mut(&clientid) {
+            record.callback_up = up;
+        }
+    }
+
+    // Mark clients whose lease has run out as expired and return their ids
+    // so the caller can release their state. Expired records are kept for
+    // another lease period so the client is told NFS4ERR_EXPIRED rather
+    // than NFS4ERR_STALE_CLIENTID, then dropped along with unconfirmed
+    // records nobody confirmed.
+    pub fn expire(&mut self, now: Instant) -> Vec<u64> {
+        let lease = self.lease;
+        let mut expired = Vec::new();
+
+        self.confirmed.retain(|_, c| now.duration_since(c.last_renewed) < lease * 2);
+        self.unconfirmed.retain(|_, c| now.duration_since(c.last_renewed) < lease);
+
+        for record in self.confirmed.values_mut() {
+            if !record.expired && now.duration_since(record.last_renewed) >= lease {
+                record.expired = true;
+                expired.push(record.clientid);
+            }
+        }
+        expired
+    }
+
+    // stateid4 is a seqid followed by twelve opaque bytes. The server puts
+    // the owning clientid and a counter there so that an unknown stateid
+    // can still be traced back to its client.
+    pub fn new_stateid(&mut self, clientid: u64) -> [u8; 16] {
+        let mut stateid = [0u8; 16];
+        stateid[0..4].copy_from_slice(&1u32.to_be_bytes());
+        stateid[4..12].copy_from_slice(&clientid.to_be_bytes());
+        stateid[12..16].copy_from_slice(&self.next_stateid.to_be_bytes());
+        self.next_stateid = self.next_stateid.wrapping_add(1);
+        stateid
+    }
+
+    // The error for a stateid that is not in the state table.
+    pub fn stateid_error(&self, stateid: &[u8; 16]) -> NfsStatus {
+        let clientid = stateid_clientid(stateid);
+        if (clientid >> 32) as u32 != self.boot {
+            return NfsStatus::StaleStateid;
+        }
+        match self.confirmed.get(&clientid) {
+            Some(record) if record.expired => NfsStatus::Expired,
+            _ => NfsStatus::BadStateid,
+        }
+    }
+}
+
+fn stateid_clientid(stateid: &[u8; 16]) -> u64 {
+    u64::from_be_bytes(stateid[4..12].try_into().unwrap())
+}
+
+pub fn stateid_seqid(stateid: &[u8; 16]) -> u32 {
+    u32::from_be_bytes(stateid[0..4].try_into().unwrap())
+}
+
+// The part of a stateid that stays the same as its seqid advances.
+pub fn stateid_other(stateid: &[u8; 16]) -> [u8; 12] {
+    stateid[4..16].try_into().unwrap()
+}
+
+pub fn make_stateid(seqid: u32, other: &[u8; 12]) -> [u8; 16] {
+    let mut stateid = [0u8; 16];
+    stateid[0..4].copy_from_slice(&seqid.to_be_bytes());
+    stateid[4..16].copy_from_slice(other);
+    stateid
+}
+
+// NFSv4.0 open-owners and lock-owners (RFC 7530 section 9.1.7).
+//
+// Each OPEN, OPEN_CONFIRM, CLOSE, LOCK and LOCKU carries the next seqid for
+// its owner, which lets the server execute them in order and exactly once
+// over a transport that may retransmit. A request with the previous seqid
+// is a retransmission and gets the last reply again. A new open-owner must
+// confirm its first OPEN with OPEN_CONFIRM before its stateids can be
+// used. NFSv4.1 sessions make all of this unnecessary.
+
+#[derive(Debug, Default)]
+pub struct StateOwner {
+    pub seqid: u32,
+    pub confirmed: bool,
+    // Reply to the operation with that seqid, and the filehandle it left
+    // current, for retransmissions.
+    last_reply: Option<OperationResult>,
+    last_fh: Option<NfsFileHandle>,
+}
+
+pub enum SeqidCheck {
+    New,
+    Replay(OperationResult, Option<NfsFileHandle>),
+}
+
+impl StateOwner {
+    pub fn check_seqid(&self, op: u32, seqid: u32) -> Result<SeqidCheck, NfsStatus> {
+        if seqid == self.seqid.wrapping_add(1) {
+            return Ok(SeqidCheck::New);
+        }
+        match &self.last_reply {
+            Some(reply) if seqid == self.seqid && reply.op == op => {
+                Ok(SeqidCheck::Replay(reply.clone(), self.last_fh.clone()))
+            }
+            _ => Err(NfsStatus::BadSeqid),
+        }
+    }
+
+    // Record the reply to an operation. Errors that mean the request could
+    // not be tied to this owner's sequence leave the seqid where it was.
+    pub fn record(&mut self, seqid: u32, reply: &OperationResult, fh: Option<NfsFileHandle>) {
+        if matches!(
+            reply.status,
+            NfsStatus::StaleClientid
+                | NfsStatus::StaleStateid
+                | NfsStatus::BadStateid
+                | NfsStatus::BadSeqid
+                | NfsStatus::BadXdr
+                | NfsStatus::Resource
+                | NfsStatus::NoFileHandle
+                | NfsStatus::Moved
+        ) {
+            return;
+        }
+        self.seqid = seqid;
+        self.last_reply = Some(reply.clone());
+        self.last_fh = fh;
+    }
+
+    // The reply to a CLOSE of `stateid` if it was this owner's last
+    // operation. The open state is gone by the time the CLOSE is
+    // retransmitted, so this is the only place left to find it.
+    pub fn closed(&self, seqid: u32, stateid: &[u8; 16]) -> Option<OperationResult> {
+        match &self.last_reply {
+            Some(reply) if seqid == self.seqid && reply.op == OP_CLOSE => match &reply.result {
+                Some(OperationData::Close(closed)) if stateid_other(closed) == stateid_other(stateid) => {
+                    Some(reply.clone())
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
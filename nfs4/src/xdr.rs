@@ -0,0 +1,131 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+// XDR (RFC 4506) primitives shared by the NFS and RPC codecs. Everything on
+// the wire is big-endian and padded to a multiple of four bytes.
+
+#[derive(Debug, Error)]
+pub enum XdrError {
+    #[error("unexpected end of XDR data")]
+    UnexpectedEof,
+    #[error("invalid {what} discriminant: {value}")]
+    InvalidDiscriminant { what: &'static str, value: u32 },
+    #[error("invalid UTF-8 in XDR string")]
+    InvalidUtf8,
+    #[error("XDR length {0} exceeds remaining data")]
+    LengthTooLarge(usize),
+}
+
+pub type XdrResult<T> = std::result::Result<T, XdrError>;
+
+pub trait XdrEncode {
+    fn encode(&self, buf: &mut BytesMut);
+}
+
+pub trait XdrDecode: Sized {
+    fn decode(buf: &mut Bytes) -> XdrResult<Self>;
+}
+
+fn padding(len: usize) -> usize {
+    (4 - (len % 4)) % 4
+}
+
+fn ensure(buf: &Bytes, len: usize) -> XdrResult<()> {
+    if buf.remaining() < len {
+        return Err(XdrError::UnexpectedEof);
+    }
+    Ok(())
+}
+
+pub fn get_u32(buf: &mut Bytes) -> XdrResult<u32> {
+    ensure(buf, 4)?;
+    Ok(buf.get_u32())
+}
+
+pub fn get_u64(buf: &mut Bytes) -> XdrResult<u64> {
+    ensure(buf, 8)?;
+    Ok(buf.get_u64())
+}
+
+pub fn get_i64(buf: &mut Bytes) -> XdrResult<i64> {
+    ensure(buf, 8)?;
+    Ok(buf.get_i64())
+}
+
+pub fn get_bool(buf: &mut Bytes) -> XdrResult<bool> {
+    match get_u32(buf)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(XdrError::InvalidDiscriminant { what: "bool", value }),
+    }
+}
+
+pub fn get_fixed_opaque<const N: usize>(buf: &mut Bytes) -> XdrResult<[u8; N]> {
+    ensure(buf, N + padding(N))?;
+    let mut out = [0u8; N];
+    buf.copy_to_slice(&mut out);
+    buf.advance(padding(N));
+    Ok(out)
+}
+
+pub fn get_opaque(buf: &mut Bytes) -> XdrResult<Vec<u8>> {
+    let len = get_u32(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(XdrError::LengthTooLarge(len));
+    }
+    ensure(buf, len + padding(len))?;
+    let out = buf.split_to(len).to_vec();
+    buf.advance(padding(len));
+    Ok(out)
+}
+
+pub fn get_string(buf: &mut Bytes) -> XdrResult<String> {
+    String::from_utf8(get_opaque(buf)?).map_err(|_| XdrError::InvalidUtf8)
+}
+
+// Variable-length array. Every XDR item is at least four bytes, so a count
+// larger than the remaining data can be rejected before allocating.
+pub fn get_array<T>(buf: &mut Bytes, mut item: impl FnMut(&mut Bytes) -> XdrResult<T>) -> XdrResult<Vec<T>> {
+    let count = get_u32(buf)? as usize;
+    if count.saturating_mul(4) > buf.remaining() {
+        return Err(XdrError::LengthTooLarge(count));
+    }
+    let mut out = Vec::with_capacity(count);
+    for _ in 0..count {
+        out.push(item(buf)?);
+    }
+    Ok(out)
+}
+
+pub fn put_bool(buf: &mut BytesMut, value: bool) {
+    buf.put_u32(value as u32);
+}
+
+pub fn put_fixed_opaque(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_slice(data);
+    buf.put_bytes(0, padding(data.len()));
+}
+
+pub fn put_opaque(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    put_fixed_opaque(buf, data);
+}
+
+pub fn put_string(buf: &mut BytesMut, value: &str) {
+    put_opaque(buf, value.as_bytes());
+}
+
+pub fn put_array<T>(buf: &mut BytesMut, items: &[T], mut item: impl FnMut(&mut BytesMut, &T)) {
+    buf.put_u32(items.len() as u32);
+    for value in items {
+        item(buf, value);
+    }
+}
+
+pub fn put_u32_array(buf: &mut BytesMut, items: &[u32]) {
+    put_array(buf, items, |buf, v| buf.put_u32(*v));
+}
+
+pub fn get_u32_array(buf: &mut Bytes) -> XdrResult<Vec<u32>> {
+    get_array(buf, get_u32)
+}
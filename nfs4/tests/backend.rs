@@ -0,0 +1,595 @@
+// The compound engine over a backend that keeps everything in memory:
+// files created, written and read through the server never reach the disk.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use nfs4::attr::{NfsSetAttributes, SetTime};
+use nfs4::backend::{FileAttr, FsStats};
+use nfs4::export::Export;
+use nfs4::protocol::*;
+use nfs4::{FileSystemBackend, NfsServer};
+use nix::errno::Errno;
+
+mod common;
+use common::{
+    create_dir, delegating_client, delegation_stateid, link, lookup, open, open_delegated, open_file,
+    putrootfh, read, rename, savefh, setattr_mode, setattr_size, status, write,
+};
+
+type Data = Arc<Mutex<Vec<u8>>>;
+
+// Clones are links to the same file. A symlink's data is its target.
+#[derive(Debug, Clone)]
+struct Node {
+    attr: Arc<Mutex<FileAttr>>,
+    data: Data,
+    generation: u32,
+}
+
+// Clones share the same files, so a test can look at what the server did
+// with them, and make the next call of a method fail.
+#[derive(Debug, Clone, Default)]
+struct MemFs {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+    next_ino: Arc<AtomicU64>,
+    faults: Arc<Mutex<HashMap<&'static str, Errno>>>,
+}
+
+const ROOT: &str = "/mem";
+
+impl MemFs {
+    fn new() -> Self {
+        let fs = MemFs::default();
+        let root = fs.node(NF4DIR);
+        fs.nodes.lock().unwrap().insert(PathBuf::from(ROOT), root);
+        fs
+    }
+
+    fn node(&self, ftype: u32) -> Node {
+        let time = NfsTime { seconds: 0, nseconds: 0 };
+        let attr = FileAttr {
+            ftype,
+            mode: match ftype {
+                NF4DIR => 0o755,
+                NF4LNK => 0o777,
+                _ => 0o644,
+            },
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            used: 0,
+            rdev: SpecData { major: 0, minor: 0 },
+            dev: 1,
+            ino: self.next_ino.fetch_add(1, Ordering::Relaxed) + 1,
+            atime: time.clone(),
+            mtime: time.clone(),
+            ctime: time,
+        };
+        Node {
+            attr: Arc::new(Mutex::new(attr)),
+            data: Data::default(),
+            generation: 1,
+        }
+    }
+
+    // Fail the next call of `method` with `errno`.
+    fn fail(&self, method: &'static str, errno: Errno) {
+        self.faults.lock().unwrap().insert(method, errno);
+    }
+
+    fn fault(&self, method: &str) -> io::Result<()> {
+        match self.faults.lock().unwrap().remove(method) {
+            Some(errno) => Err(errno.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Node> {
+        self.nodes.lock().unwrap().get(path).cloned().ok_or_else(|| Errno::ENOENT.into())
+    }
+
+    fn attr(&self, path: &Path) -> io::Result<FileAttr> {
+        Ok(self.get(path)?.attr.lock().unwrap().clone())
+    }
+
+    // Add a new name to the tree, in a directory that is there.
+    fn insert(&self, path: &Path, node: Node) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(path.parent().unwrap()) {
+            Some(parent) if parent.attr.lock().unwrap().is_dir() => {}
+            Some(_) => return Err(Errno::ENOTDIR.into()),
+            None => return Err(Errno::ENOENT.into()),
+        }
+        if nodes.contains_key(path) {
+            return Err(Errno::EEXIST.into());
+        }
+        nodes.insert(path.to_path_buf(), node);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileSystemBackend for MemFs {
+    type File = Data;
+
+    async fn getattr(&self, path: &Path) -> io::Result<FileAttr> {
+        self.fault("getattr")?;
+        let node = self.get(path)?;
+        let mut attr = node.attr.lock().unwrap().clone();
+        attr.size = node.data.lock().unwrap().len() as u64;
+        Ok(attr)
+    }
+
+    async fn setattr(&self, path: &Path, attrs: &NfsSetAttributes) -> io::Result<()> {
+        self.fault("setattr")?;
+        let node = self.get(path)?;
+        let mut attr = node.attr.lock().unwrap();
+        if let Some(uid) = attrs.owner {
+            attr.uid = uid;
+        }
+        if let Some(gid) = attrs.owner_group {
+            attr.gid = gid;
+        }
+        if let Some(mode) = attrs.mode {
+            attr.mode = mode & 0o7777;
+        }
+        if let Some(size) = attrs.size {
+            node.data.lock().unwrap().resize(size as usize, 0);
+        }
+        if let Some(SetTime::ClientTime(time)) = &attrs.time_access {
+            attr.atime = time.clone();
+        }
+        if let Some(SetTime::ClientTime(time)) = &attrs.time_modify {
+            attr.mtime = time.clone();
+        }
+        Ok(())
+    }
+
+    async fn open(&self, path: &Path, _read: bool, _write: bool) -> io::Result<Data> {
+        self.fault("open")?;
+        let node = self.get(path)?;
+        if !node.attr.lock().unwrap().is_file() {
+            return Err(Errno::EISDIR.into());
+        }
+        Ok(node.data)
+    }
+
+    async fn read(&self, file: &Data, offset: u64, count: u32) -> io::Result<(Vec<u8>, bool)> {
+        self.fault("read")?;
+        let data = file.lock().unwrap();
+        let start = (offset as usize).min(data.len());
+        let end = (start + count as usize).min(data.len());
+        Ok((data[start..end].to_vec(), end == data.len()))
+    }
+
+    async fn write(&self, file: &Data, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        self.fault("write")?;
+        let mut data = file.lock().unwrap();
+        let end = offset as usize + bytes.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    async fn sync(&self, _file: &Data) -> io::Result<()> {
+        self.fault("sync")?;
+        Ok(())
+    }
+
+    async fn create(&self, path: &Path, ftype: u32, _rdev: SpecData) -> io::Result<()> {
+        self.fault("create")?;
+        if ftype != NF4REG && ftype != NF4DIR {
+            return Err(Errno::EINVAL.into());
+        }
+        self.insert(path, self.node(ftype))
+    }
+
+    async fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        self.fault("remove")?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get(path).ok_or(Errno::ENOENT)?;
+        if node.attr.lock().unwrap().is_dir() != is_dir {
+            return Err(if is_dir { Errno::ENOTDIR } else { Errno::EISDIR }.into());
+        }
+        if nodes.keys().any(|other| other.parent() == Some(path)) {
+            return Err(Errno::ENOTEMPTY.into());
+        }
+        let node = nodes.remove(path).unwrap();
+        node.attr.lock().unwrap().nlink -= 1;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.fault("rename")?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(from) {
+            return Err(Errno::ENOENT.into());
+        }
+        let moved: Vec<PathBuf> = nodes.keys().filter(|path| path.starts_with(from)).cloned().collect();
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            nodes.insert(to.join(path.strip_prefix(from).unwrap()), node);
+        }
+        Ok(())
+    }
+
+    async fn readdir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.fault("readdir")?;
+        let nodes = self.nodes.lock().unwrap();
+        Ok(nodes
+            .keys()
+            .filter(|other| other.parent() == Some(path))
+            .map(|other| other.file_name().unwrap().to_os_string())
+            .collect())
+    }
+
+    async fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.fault("link")?;
+        let node = self.get(from)?;
+        if node.attr.lock().unwrap().is_dir() {
+            return Err(Errno::EPERM.into());
+        }
+        self.insert(to, node.clone())?;
+        node.attr.lock().unwrap().nlink += 1;
+        Ok(())
+    }
+
+    async fn symlink(&self, target: &str, path: &Path) -> io::Result<()> {
+        self.fault("symlink")?;
+        let node = self.node(NF4LNK);
+        *node.data.lock().unwrap() = target.as_bytes().to_vec();
+        self.insert(path, node)
+    }
+
+    async fn readlink(&self, path: &Path) -> io::Result<OsString> {
+        self.fault("readlink")?;
+        let node = self.get(path)?;
+        if !node.attr.lock().unwrap().is_symlink() {
+            return Err(Errno::EINVAL.into());
+        }
+        let target = node.data.lock().unwrap().clone();
+        Ok(OsString::from_vec(target))
+    }
+
+    async fn generation(&self, path: &Path, _attr: &FileAttr) -> u32 {
+        self.get(path).map_or(0, |node| node.generation)
+    }
+
+    async fn statfs(&self, _path: &Path) -> io::Result<FsStats> {
+        self.fault("statfs")?;
+        Ok(FsStats {
+            name_max: 255,
+            ..Default::default()
+        })
+    }
+}
+
+fn serve(fs: &MemFs) -> NfsServer<MemFs> {
+    NfsServer::with_backend(fs.clone(), vec![Export::new("/", PathBuf::from(ROOT))]).unwrap()
+}
+
+fn commit() -> NfsOperation {
+    NfsOperation::Commit(CommitOperation { offset: 0, count: 0 })
+}
+
+fn symlink(name: &str, target: &str) -> NfsOperation {
+    NfsOperation::Create(CreateOperation {
+        object_type: NF4LNK,
+        link_data: Some(target.to_string()),
+        spec_data: None,
+        object_name: name.to_string(),
+        attributes: Fattr4::default(),
+    })
+}
+
+fn readlink() -> NfsOperation {
+    NfsOperation::ReadLink(ReadLinkOperation)
+}
+
+fn readdir() -> NfsOperation {
+    NfsOperation::ReadDir(ReadDirOperation {
+        cookie: 0,
+        cookieverf: [0; 8],
+        dircount: 1024,
+        maxcount: 4096,
+        attr_request: Vec::new(),
+    })
+}
+
+// Run a COMPOUND that must succeed.
+async fn run(server: &NfsServer<MemFs>, operations: Vec<NfsOperation>) -> CompoundResponse {
+    let response = common::run(server, operations).await;
+    assert_eq!(response.status, NfsStatus::Ok, "{:?}", response);
+    response
+}
+
+#[tokio::test]
+async fn files_live_in_the_backend() {
+    let fs = MemFs::new();
+    let nodes = fs.nodes.clone();
+    let server = serve(&fs);
+    let clientid = common::client(&server, b"backend").await;
+
+    let create = NfsOperation::Create(CreateOperation {
+        object_type: NF4DIR,
+        link_data: None,
+        spec_data: None,
+        object_name: "d".to_string(),
+        attributes: Fattr4::default(),
+    });
+    run(&server, vec![putrootfh(), create]).await;
+
+    let open = NfsOperation::Open(OpenOperation {
+        seqid: 0,
+        share_access: OPEN4_SHARE_ACCESS_BOTH,
+        share_deny: OPEN4_SHARE_DENY_NONE,
+        clientid,
+        owner: b"owner".to_vec(),
+        open_how: OpenHow::Create(CreateHow::Unchecked(Fattr4::default())),
+        open_claim: OpenClaim::Null("f".to_string()),
+    });
+    let response = run(&server, vec![putrootfh(), lookup("d"), open]).await;
+    let stateid = match &response.results[2].result {
+        Some(OperationData::Open(res)) => res.stateid,
+        other => panic!("OPEN: {:?}", other),
+    };
+    let confirm = NfsOperation::OpenConfirm(OpenConfirmOperation {
+        open_stateid: stateid,
+        seqid: 1,
+    });
+    let response = run(&server, vec![putrootfh(), lookup("d"), lookup("f"), confirm]).await;
+    let stateid = match &response.results[3].result {
+        Some(OperationData::OpenConfirm(stateid)) => *stateid,
+        other => panic!("OPEN_CONFIRM: {:?}", other),
+    };
+
+    let write = NfsOperation::Write(WriteOperation {
+        stateid,
+        offset: 0,
+        stable: FILE_SYNC4,
+        data: b"in memory".to_vec(),
+    });
+    let read = NfsOperation::Read(ReadOperation {
+        stateid,
+        offset: 3,
+        count: 100,
+    });
+    let response = run(&server, vec![putrootfh(), lookup("d"), lookup("f"), write, read]).await;
+    match &response.results[4].result {
+        Some(OperationData::Read(res)) => {
+            assert_eq!(res.data, b"memory");
+            assert!(res.eof);
+        }
+        other => panic!("READ: {:?}", other),
+    }
+    let data = nodes.lock().unwrap()[Path::new("/mem/d/f")].data.clone();
+    assert_eq!(*data.lock().unwrap(), b"in memory");
+
+    let rename = NfsOperation::Rename(RenameOperation {
+        old_name: "d".to_string(),
+        new_name: "e".to_string(),
+    });
+    let readdir = NfsOperation::ReadDir(ReadDirOperation {
+        cookie: 0,
+        cookieverf: [0; 8],
+        dircount: 1024,
+        maxcount: 4096,
+        attr_request: vec![1 << FATTR4_SIZE],
+    });
+    let response = run(
+        &server,
+        vec![
+            putrootfh(),
+            NfsOperation::SaveFh(SaveFhOperation),
+            putrootfh(),
+            rename,
+            lookup("e"),
+            readdir,
+        ],
+    )
+    .await;
+    match &response.results[5].result {
+        Some(OperationData::ReadDir(res)) => {
+            let names: Vec<_> = res.entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, vec!["f"]);
+            assert!(res.eof);
+        }
+        other => panic!("READDIR: {:?}", other),
+    }
+    let paths: Vec<_> = nodes.lock().unwrap().keys().cloned().collect();
+    assert_eq!(paths, vec![PathBuf::from("/mem"), PathBuf::from("/mem/e"), PathBuf::from("/mem/e/f")]);
+}
+
+#[tokio::test]
+async fn lockt_asks_the_backend_whether_a_file_is_a_directory() {
+    let server = serve(&MemFs::new());
+    let clientid = common::client(&server, b"backend").await;
+    let lockt = NfsOperation::Lockt(LocktOperation {
+        locktype: READ_LT,
+        offset: 0,
+        length: u64::MAX,
+        owner: LockOwner {
+            clientid,
+            owner: b"owner".to_vec(),
+        },
+    });
+    assert_eq!(common::status(common::run(&server, vec![putrootfh(), lockt]).await), NfsStatus::IsDir);
+}
+
+#[tokio::test]
+async fn backend_errors_fail_just_the_operation() {
+    let fs = MemFs::new();
+    let server = serve(&fs);
+    let clientid = common::client(&server, b"backend").await;
+
+    // The create step of OPEN finds the file there, and it is gone again
+    // by the time the server looks at it.
+    fs.fail("create", Errno::EEXIST);
+    let create = OpenHow::Create(CreateHow::Unchecked(Fattr4::default()));
+    let response = common::run(&server, vec![putrootfh(), open(clientid, create, OpenClaim::Null("f".to_string()))]).await;
+    assert_eq!(response.status, NfsStatus::NoEnt);
+
+    let stateid = open_file(&server, clientid, vec![putrootfh()], "f", OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_DENY_NONE)
+        .await
+        .unwrap();
+    fs.fail("sync", Errno::ENOSPC);
+    let ops = vec![putrootfh(), lookup("f"), write(stateid, 0, b"data")];
+    assert_eq!(status(common::run(&server, ops).await), NfsStatus::NoSpace);
+
+    fs.fail("sync", Errno::EIO);
+    assert_eq!(status(common::run(&server, vec![putrootfh(), lookup("f"), commit()]).await), NfsStatus::IoError);
+    assert_eq!(status(common::run(&server, vec![putrootfh(), lookup("f"), commit()]).await), NfsStatus::Ok);
+}
+
+// The data READ returns at the end of `operations`.
+async fn read_data(server: &NfsServer<MemFs>, operations: Vec<NfsOperation>) -> Vec<u8> {
+    match common::last_result(run(server, operations).await) {
+        OperationData::Read(res) => res.data,
+        other => panic!("READ: {:?}", other),
+    }
+}
+
+async fn names(server: &NfsServer<MemFs>, mut operations: Vec<NfsOperation>) -> Vec<String> {
+    operations.push(readdir());
+    match common::last_result(run(server, operations).await) {
+        OperationData::ReadDir(res) => {
+            let mut names: Vec<_> = res.entries.into_iter().map(|entry| entry.name).collect();
+            names.sort();
+            names
+        }
+        other => panic!("READDIR: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn reads_and_writes_use_the_backend_file() {
+    let fs = MemFs::new();
+    let server = serve(&fs);
+    let clientid = common::client(&server, b"backend").await;
+    let data = |path: &str| fs.get(Path::new(path)).unwrap().data.lock().unwrap().clone();
+
+    let f = || vec![putrootfh(), lookup("f")];
+    let stateid = open_file(&server, clientid, vec![putrootfh()], "f", OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_DENY_NONE)
+        .await
+        .unwrap();
+    run(&server, [f(), vec![write(stateid, 0, b"open file"), commit()]].concat()).await;
+    assert_eq!(data("/mem/f"), b"open file");
+    assert_eq!(read_data(&server, [f(), vec![read(stateid, 5, 100)]].concat()).await, b"file");
+
+    // Opens served from a delegation use the file the server opened for it.
+    let (holder, _callbacks) = delegating_client(&server, b"holder", NfsStatus::Ok).await;
+    let g = || vec![putrootfh(), lookup("g")];
+    let (_, delegation) = open_delegated(&server, holder, vec![putrootfh()], "g", OPEN4_SHARE_ACCESS_BOTH).await;
+    let stateid = delegation_stateid(&delegation);
+    run(&server, [g(), vec![write(stateid, 0, b"delegated file"), commit()]].concat()).await;
+    assert_eq!(data("/mem/g"), b"delegated file");
+    assert_eq!(read_data(&server, [g(), vec![read(stateid, 10, 100)]].concat()).await, b"file");
+
+    fs.fail("read", Errno::EIO);
+    assert_eq!(status(common::run(&server, [g(), vec![read(stateid, 0, 1)]].concat()).await), NfsStatus::IoError);
+    fs.fail("open", Errno::EACCES);
+    assert_eq!(status(common::run(&server, [g(), vec![commit()]].concat()).await), NfsStatus::Access);
+    fs.fail("write", Errno::EFBIG);
+    let ops = [g(), vec![write(stateid, 1 << 40, b"x")]].concat();
+    assert_eq!(status(common::run(&server, ops).await), NfsStatus::FBig);
+    assert_eq!(data("/mem/g"), b"delegated file");
+}
+
+#[tokio::test]
+async fn names_live_in_the_backend() {
+    let fs = MemFs::new();
+    let server = serve(&fs);
+    fs.create(Path::new("/mem/f"), NF4REG, SpecData { major: 0, minor: 0 }).await.unwrap();
+
+    run(&server, vec![putrootfh(), create_dir("d")]).await;
+    run(&server, vec![putrootfh(), lookup("f"), savefh(), putrootfh(), lookup("d"), link("g")]).await;
+    let f = fs.attr(Path::new("/mem/f")).unwrap();
+    let g = fs.attr(Path::new("/mem/d/g")).unwrap();
+    assert_eq!((f.ino, f.nlink), (g.ino, 2));
+    // Both names lead to the same file, with the same filehandle.
+    let f_fh = common::fh(&server, vec![putrootfh(), lookup("f")]).await;
+    assert_eq!(common::fh(&server, vec![putrootfh(), lookup("d"), lookup("g")]).await, f_fh);
+
+    run(&server, vec![putrootfh(), lookup("d"), lookup("g"), setattr_mode(0o600), setattr_size(3)]).await;
+    assert_eq!(fs.attr(Path::new("/mem/f")).unwrap().mode, 0o600);
+    assert_eq!(fs.getattr(Path::new("/mem/f")).await.unwrap().size, 3);
+
+    run(&server, vec![putrootfh(), symlink("s", "d/g")]).await;
+    match common::last_result(run(&server, vec![putrootfh(), lookup("s"), readlink()]).await) {
+        OperationData::ReadLink(target) => assert_eq!(target, "d/g"),
+        other => panic!("READLINK: {:?}", other),
+    }
+    let ops = vec![putrootfh(), lookup("f"), readlink()];
+    assert_eq!(status(common::run(&server, ops).await), NfsStatus::Inval);
+
+    run(&server, vec![putrootfh(), savefh(), putrootfh(), rename("d", "e")]).await;
+    assert_eq!(names(&server, vec![putrootfh()]).await, vec!["e", "f", "s"]);
+    assert_eq!(names(&server, vec![putrootfh(), lookup("e")]).await, vec!["g"]);
+    assert_eq!(common::fh(&server, vec![putrootfh(), lookup("e"), lookup("g")]).await, f_fh);
+}
+
+#[tokio::test]
+async fn backend_errors_map_to_nfs_errors() {
+    let fs = MemFs::new();
+    let server = serve(&fs);
+    fs.create(Path::new("/mem/f"), NF4REG, SpecData { major: 0, minor: 0 }).await.unwrap();
+    fs.symlink("f", Path::new("/mem/s")).await.unwrap();
+    let failed = |errno, method, operations: Vec<NfsOperation>| {
+        fs.fail(method, errno);
+        let server = server.clone();
+        async move { status(common::run(&server, operations).await) }
+    };
+
+    let ops = vec![putrootfh(), readdir()];
+    assert_eq!(failed(Errno::EACCES, "readdir", ops).await, NfsStatus::Access);
+    let ops = vec![putrootfh(), lookup("f"), setattr_mode(0o600)];
+    assert_eq!(failed(Errno::EPERM, "setattr", ops).await, NfsStatus::Perm);
+    let ops = vec![putrootfh(), lookup("f"), savefh(), putrootfh(), link("g")];
+    assert_eq!(failed(Errno::EMLINK, "link", ops).await, NfsStatus::MLink);
+    let ops = vec![putrootfh(), savefh(), putrootfh(), rename("f", "g")];
+    assert_eq!(failed(Errno::EXDEV, "rename", ops).await, NfsStatus::XDev);
+    let ops = vec![putrootfh(), symlink("t", "f")];
+    assert_eq!(failed(Errno::EDQUOT, "symlink", ops).await, NfsStatus::DQuot);
+    let ops = vec![putrootfh(), lookup("s"), readlink()];
+    assert_eq!(failed(Errno::EIO, "readlink", ops).await, NfsStatus::IoError);
+    let ops = vec![putrootfh(), create_dir("d")];
+    assert_eq!(failed(Errno::ENOSPC, "create", ops).await, NfsStatus::NoSpace);
+
+    // Nothing was changed, and the next calls go through.
+    assert_eq!(names(&server, vec![putrootfh()]).await, vec!["f", "s"]);
+    assert_eq!(fs.attr(Path::new("/mem/f")).unwrap().mode, 0o644);
+}
+
+#[tokio::test]
+async fn handles_of_a_reused_file_number_are_stale() {
+    let fs = MemFs::new();
+    let server = serve(&fs);
+    let path = Path::new("/mem/f");
+    fs.create(path, NF4REG, SpecData { major: 0, minor: 0 }).await.unwrap();
+    let fh = common::fh(&server, vec![putrootfh(), lookup("f")]).await;
+
+    // The file is deleted, and its inode number given to a new one, which
+    // the filesystem tells apart by its generation.
+    let ino = fs.attr(path).unwrap().ino;
+    fs.remove(path, false).await.unwrap();
+    let mut node = fs.node(NF4REG);
+    node.attr.lock().unwrap().ino = ino;
+    node.generation = 2;
+    fs.insert(path, node).unwrap();
+
+    let ops = vec![common::putfh(fh.clone()), common::getattr(vec![1 << FATTR4_SIZE])];
+    assert_eq!(status(common::run(&server, ops).await), NfsStatus::StaleFileHandle);
+    let new_fh = common::fh(&server, vec![putrootfh(), lookup("f")]).await;
+    assert_ne!(new_fh, fh);
+    run(&server, vec![common::putfh(new_fh), common::getattr(vec![1 << FATTR4_SIZE])]).await;
+}
@@ -0,0 +1,421 @@
+// Helpers shared by the integration tests: COMPOUNDs built from
+// operations, run against a server as a given caller, and the clients
+// and opens that stateful operations need.
+//
+// Each test file uses only some of them.
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use nfs4::auth::Credentials;
+use nfs4::protocol::*;
+use nfs4::rpc::{write_rpc_message, RecordReader, RpcMsg, RpcMsgBody};
+use nfs4::{FileSystemBackend, NfsServer};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+// XDR words, as calls and replies are laid out by hand.
+pub fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+// A server for an empty scratch directory.
+pub fn server() -> (TempDir, NfsServer) {
+    let dir = tempfile::tempdir().unwrap();
+    let server = NfsServer::new(dir.path().to_path_buf()).unwrap();
+    (dir, server)
+}
+
+pub fn root() -> Credentials {
+    Credentials {
+        uid: 0,
+        gid: 0,
+        gids: Vec::new(),
+    }
+}
+
+pub fn user(uid: u32) -> Credentials {
+    Credentials {
+        uid,
+        gid: uid,
+        gids: Vec::new(),
+    }
+}
+
+// Run an NFSv4.0 COMPOUND as root.
+pub async fn run<B: FileSystemBackend>(server: &NfsServer<B>, operations: Vec<NfsOperation>) -> CompoundResponse {
+    run_as(server, root(), operations).await
+}
+
+pub async fn run_as<B: FileSystemBackend>(
+    server: &NfsServer<B>,
+    cred: Credentials,
+    operations: Vec<NfsOperation>,
+) -> CompoundResponse {
+    let request = CompoundRequest {
+        tag: String::new(),
+        minor_version: 0,
+        operations,
+    };
+    server.handle_compound(request, cred).await.unwrap()
+}
+
+// Status of the last operation run, which is the one that failed.
+pub fn status(response: CompoundResponse) -> NfsStatus {
+    response.results.last().unwrap().status
+}
+
+// Result of the last operation, which must have succeeded.
+pub fn last_result(response: CompoundResponse) -> OperationData {
+    let last = response.results.last().unwrap();
+    assert_eq!(last.status, NfsStatus::Ok, "{:?}", response);
+    last.result.clone().unwrap()
+}
+
+// The current filehandle after `operations`.
+pub async fn fh<B: FileSystemBackend>(server: &NfsServer<B>, mut operations: Vec<NfsOperation>) -> NfsFileHandle {
+    operations.push(getfh());
+    match last_result(run(server, operations).await) {
+        OperationData::GetFh(fh) => fh,
+        other => panic!("GETFH: {:?}", other),
+    }
+}
+
+// A confirmed NFSv4.0 client, for OPEN.
+pub async fn client<B: FileSystemBackend>(server: &NfsServer<B>, id: &[u8]) -> u64 {
+    confirmed_client(server, setclientid(id)).await
+}
+
+async fn confirmed_client<B: FileSystemBackend>(server: &NfsServer<B>, setclientid: NfsOperation) -> u64 {
+    let (clientid, confirm) = match last_result(run(server, vec![setclientid]).await) {
+        OperationData::SetClientId(res) => (res.clientid, res.confirm),
+        other => panic!("SETCLIENTID: {:?}", other),
+    };
+    let confirm = SetClientIdConfirmOperation { clientid, confirm };
+    assert_eq!(status(run(server, vec![NfsOperation::SetClientIdConfirm(confirm)]).await), NfsStatus::Ok);
+    clientid
+}
+
+pub fn setclientid(id: &[u8]) -> NfsOperation {
+    setclientid_callback(id, "127.0.0.1.0.0")
+}
+
+// SETCLIENTID with the universal address of the client's callback service.
+pub fn setclientid_callback(id: &[u8], uaddr: &str) -> NfsOperation {
+    NfsOperation::SetClientId(SetClientIdOperation {
+        client: NfsClientId {
+            verifier: *b"verifier",
+            id: id.to_vec(),
+        },
+        callback: CbClient {
+            program: 0x40000000,
+            location: NetAddr {
+                netid: "tcp".to_string(),
+                addr: uaddr.to_string(),
+            },
+        },
+        callback_ident: 1,
+    })
+}
+
+// A client with a callback service of its own, which the server may give
+// delegations to. CB_RECALLs get `status` as their answer.
+pub async fn delegating_client<B: FileSystemBackend>(
+    server: &NfsServer<B>,
+    id: &[u8],
+    status: NfsStatus,
+) -> (u64, Callbacks) {
+    let (uaddr, mut callbacks) = callback_service(status).await;
+    let clientid = confirmed_client(server, setclientid_callback(id, &uaddr)).await;
+    // The server probes the callback path with CB_NULL after the client is
+    // confirmed, and marks it up once the probe is answered.
+    assert_eq!(callbacks.recv().await, Some(Callback::Null));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    (clientid, callbacks)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Callback {
+    Null,
+    Compound(CbCompoundRequest),
+}
+
+pub type Callbacks = mpsc::UnboundedReceiver<Callback>;
+
+// A callback service on a loopback port, and the calls it gets, each sent
+// once it has been answered. Returns the service's universal address.
+pub async fn callback_service(status: NfsStatus) -> (String, Callbacks) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let Some(callback) = answer_callback(&mut stream, status).await else {
+                    return;
+                };
+                // The server closes the connection once it has the reply.
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+                let _ = tx.send(callback);
+            });
+        }
+    });
+    (format!("127.0.0.1.{}.{}", port >> 8, port & 0xff), rx)
+}
+
+async fn answer_callback(stream: &mut TcpStream, status: NfsStatus) -> Option<Callback> {
+    let mut reader = RecordReader::new(64 * 1024);
+    let mut buf = BytesMut::new();
+    let record = loop {
+        if let Some(record) = reader.read(&mut buf).ok()? {
+            break record;
+        }
+        if stream.read_buf(&mut buf).await.ok()? == 0 {
+            return None;
+        }
+    };
+    let msg = RpcMsg::decode(&record).ok()?;
+    let RpcMsgBody::Call(call) = msg.body else {
+        return None;
+    };
+    let (callback, results) = match call.proc {
+        CB_NULL => (Callback::Null, Vec::new()),
+        CB_COMPOUND => {
+            let request = CbCompoundRequest::decode(&call.data).ok()?;
+            let response = CbCompoundResponse {
+                status,
+                tag: request.tag.clone(),
+                results: request
+                    .operations
+                    .iter()
+                    .map(|op| CbOperationResult { op: op.opcode(), status })
+                    .collect(),
+            };
+            (Callback::Compound(request), response.encode())
+        }
+        _ => return None,
+    };
+    let mut reply = BytesMut::new();
+    write_rpc_message(&RpcMsg::new_success_reply(msg.xid, results), &mut reply);
+    stream.write_all(&reply).await.ok()?;
+    Some(callback)
+}
+
+pub fn putrootfh() -> NfsOperation {
+    NfsOperation::PutRootFh(PutRootFhOperation)
+}
+
+pub fn putfh(object: NfsFileHandle) -> NfsOperation {
+    NfsOperation::PutFh(PutFhOperation { object })
+}
+
+pub fn getfh() -> NfsOperation {
+    NfsOperation::GetFh(GetFhOperation)
+}
+
+pub fn savefh() -> NfsOperation {
+    NfsOperation::SaveFh(SaveFhOperation)
+}
+
+pub fn getattr(attr_request: Vec<u32>) -> NfsOperation {
+    NfsOperation::GetAttr(GetAttrOperation { attr_request })
+}
+
+pub fn lookup(name: &str) -> NfsOperation {
+    NfsOperation::Lookup(LookupOperation {
+        object_name: name.to_string(),
+    })
+}
+
+pub fn lookupp() -> NfsOperation {
+    NfsOperation::Lookupp(LookuppOperation)
+}
+
+pub fn create_dir(name: &str) -> NfsOperation {
+    NfsOperation::Create(CreateOperation {
+        object_type: NF4DIR,
+        link_data: None,
+        spec_data: None,
+        object_name: name.to_string(),
+        attributes: Fattr4::default(),
+    })
+}
+
+pub fn remove(name: &str) -> NfsOperation {
+    NfsOperation::Remove(RemoveOperation {
+        target: name.to_string(),
+    })
+}
+
+// Rename from the saved filehandle's directory into the current one's.
+pub fn rename(old: &str, new: &str) -> NfsOperation {
+    NfsOperation::Rename(RenameOperation {
+        old_name: old.to_string(),
+        new_name: new.to_string(),
+    })
+}
+
+// A link to the saved filehandle in the current directory.
+pub fn link(name: &str) -> NfsOperation {
+    NfsOperation::Link(LinkOperation {
+        new_name: name.to_string(),
+    })
+}
+
+// SETATTR with the anonymous stateid.
+pub fn setattr(attrmask: Vec<u32>, attr_vals: Vec<u8>) -> NfsOperation {
+    NfsOperation::SetAttr(SetAttrOperation {
+        stateid: [0; 16],
+        attributes: Fattr4 { attrmask, attr_vals },
+    })
+}
+
+// An XDR string, padded to a whole number of words.
+pub fn xdr_string(s: &str) -> Vec<u8> {
+    let mut out = (s.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(s.as_bytes());
+    out.resize(out.len().next_multiple_of(4), 0);
+    out
+}
+
+pub fn setattr_mode(mode: u32) -> NfsOperation {
+    setattr(vec![0, 1 << (FATTR4_MODE - 32)], mode.to_be_bytes().to_vec())
+}
+
+pub fn setattr_size(size: u64) -> NfsOperation {
+    setattr(vec![1 << FATTR4_SIZE], size.to_be_bytes().to_vec())
+}
+
+// Each OPEN comes from a new open-owner, so that none is taken for a
+// retransmission of the one before.
+pub fn open(clientid: u64, how: OpenHow, claim: OpenClaim) -> NfsOperation {
+    static OWNERS: AtomicU32 = AtomicU32::new(0);
+    open_as(clientid, &OWNERS.fetch_add(1, Ordering::Relaxed).to_be_bytes(), 0, how, claim)
+}
+
+pub fn open_as(clientid: u64, owner: &[u8], seqid: u32, how: OpenHow, claim: OpenClaim) -> NfsOperation {
+    NfsOperation::Open(OpenOperation {
+        seqid,
+        share_access: OPEN4_SHARE_ACCESS_BOTH,
+        share_deny: OPEN4_SHARE_DENY_NONE,
+        clientid,
+        owner: owner.to_vec(),
+        open_how: how,
+        open_claim: claim,
+    })
+}
+
+// Open, and create if need be, `name` in the directory `dir` leads to
+// with the given access and deny modes, as a new open-owner, and confirm
+// the open. Returns the confirmed stateid, or the status OPEN failed with.
+pub async fn open_file<B: FileSystemBackend>(
+    server: &NfsServer<B>,
+    clientid: u64,
+    dir: Vec<NfsOperation>,
+    name: &str,
+    share_access: u32,
+    share_deny: u32,
+) -> Result<[u8; 16], NfsStatus> {
+    static OWNERS: AtomicU32 = AtomicU32::new(0);
+    let owner = format!("open_file {}", OWNERS.fetch_add(1, Ordering::Relaxed)).into_bytes();
+    let open = NfsOperation::Open(OpenOperation {
+        seqid: 0,
+        share_access,
+        share_deny,
+        clientid,
+        owner,
+        open_how: OpenHow::Create(CreateHow::Unchecked(Fattr4::default())),
+        open_claim: OpenClaim::Null(name.to_string()),
+    });
+    let mut ops = dir;
+    ops.push(open);
+    let response = run(server, ops).await;
+    let res = match response.results.last().unwrap() {
+        OperationResult {
+            result: Some(OperationData::Open(res)),
+            ..
+        } => res.clone(),
+        other => return Err(other.status),
+    };
+    if res.rflags & OPEN4_RESULT_CONFIRM == 0 {
+        return Ok(res.stateid);
+    }
+    let confirm = NfsOperation::OpenConfirm(OpenConfirmOperation {
+        open_stateid: res.stateid,
+        seqid: 1,
+    });
+    match last_result(run(server, vec![confirm]).await) {
+        OperationData::OpenConfirm(stateid) => Ok(stateid),
+        other => panic!("OPEN_CONFIRM: {:?}", other),
+    }
+}
+
+// Open, and create if need be, `name` in the directory `dir` leads to as
+// a new open-owner, then open it again once the owner is confirmed, as the
+// server only offers delegations to confirmed owners. Returns the open
+// stateid and the delegation offered with the second OPEN.
+pub async fn open_delegated<B: FileSystemBackend>(
+    server: &NfsServer<B>,
+    clientid: u64,
+    dir: Vec<NfsOperation>,
+    name: &str,
+    share_access: u32,
+) -> ([u8; 16], OpenDelegation) {
+    static OWNERS: AtomicU32 = AtomicU32::new(0);
+    let owner = format!("open_delegated {}", OWNERS.fetch_add(1, Ordering::Relaxed)).into_bytes();
+    let open = |seqid, open_how| {
+        NfsOperation::Open(OpenOperation {
+            seqid,
+            share_access,
+            share_deny: OPEN4_SHARE_DENY_NONE,
+            clientid,
+            owner: owner.clone(),
+            open_how,
+            open_claim: OpenClaim::Null(name.to_string()),
+        })
+    };
+    let mut ops = dir.clone();
+    ops.push(open(0, OpenHow::Create(CreateHow::Unchecked(Fattr4::default()))));
+    let stateid = match last_result(run(server, ops).await) {
+        OperationData::Open(res) => res.stateid,
+        other => panic!("OPEN: {:?}", other),
+    };
+    let confirm = NfsOperation::OpenConfirm(OpenConfirmOperation {
+        open_stateid: stateid,
+        seqid: 1,
+    });
+    assert_eq!(status(run(server, vec![confirm]).await), NfsStatus::Ok);
+    let mut ops = dir;
+    ops.push(open(2, OpenHow::NoCreate));
+    match last_result(run(server, ops).await) {
+        OperationData::Open(res) => (res.stateid, res.delegation),
+        other => panic!("OPEN: {:?}", other),
+    }
+}
+
+// The stateid of a delegation that was offered.
+pub fn delegation_stateid(delegation: &OpenDelegation) -> [u8; 16] {
+    match delegation {
+        OpenDelegation::Read(read) => read.stateid,
+        OpenDelegation::Write(write) => write.stateid,
+        OpenDelegation::None => panic!("no delegation"),
+    }
+}
+
+pub fn read(stateid: [u8; 16], offset: u64, count: u32) -> NfsOperation {
+    NfsOperation::Read(ReadOperation { stateid, offset, count })
+}
+
+pub fn write(stateid: [u8; 16], offset: u64, data: &[u8]) -> NfsOperation {
+    NfsOperation::Write(WriteOperation {
+        stateid,
+        offset,
+        stable: FILE_SYNC4,
+        data: data.to_vec(),
+    })
+}
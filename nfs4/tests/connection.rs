@@ -0,0 +1,119 @@
+// Calls sent down a connection without waiting for the replies, as clients
+// do with several requests outstanding. Each must get exactly one reply,
+// matched to it by xid.
+
+use std::collections::HashMap;
+
+use bytes::BytesMut;
+use nfs4::connection::{serve, ConnectionLimits};
+use nfs4::rpc::{write_rpc_message, RecordReader, RpcMsg, RpcMsgBody, ReplyData};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+mod common;
+use common::words;
+
+fn connect(limits: ConnectionLimits) -> (tempfile::TempDir, DuplexStream, tokio::task::JoinHandle<anyhow::Result<()>>) {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "f").unwrap();
+    let (client, stream) = tokio::io::duplex(64 * 1024);
+    let serving = tokio::spawn(serve(stream, server, "192.0.2.1".parse().unwrap(), limits));
+    (dir, client, serving)
+}
+
+// PUTROOTFH, LOOKUP "f", GETATTR of the type.
+fn lookup_call(xid: u32) -> RpcMsg {
+    let args = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000003, // 3 operations
+        0x00000018, // OP_PUTROOTFH
+        0x0000000f, // OP_LOOKUP
+        0x00000001, 0x66000000, // "f"
+        0x00000009, // OP_GETATTR
+        0x00000001, // bitmap4 length 1
+        0x00000004, // FATTR4_TYPE
+    ]);
+    RpcMsg::new_call(xid, 100003, 4, 1, args)
+}
+
+// Read replies until the server closes the connection, by xid. Replies
+// may come in any order.
+async fn replies(client: &mut DuplexStream) -> HashMap<u32, Vec<RpcMsg>> {
+    let mut buf = BytesMut::new();
+    client.read_buf(&mut buf).await.unwrap();
+    let mut records = RecordReader::new(1024 * 1024);
+    let mut replies: HashMap<u32, Vec<RpcMsg>> = HashMap::new();
+    loop {
+        while let Some(record) = records.read(&mut buf).unwrap() {
+            let reply = RpcMsg::decode(&record).unwrap();
+            replies.entry(reply.xid).or_default().push(reply);
+        }
+        if client.read_buf(&mut buf).await.unwrap() == 0 {
+            return replies;
+        }
+    }
+}
+
+fn accept_stat(reply: &RpcMsg) -> u32 {
+    match &reply.body {
+        RpcMsgBody::Reply(reply) => match &reply.data {
+            ReplyData::Accepted(accepted) => accepted.stat,
+            other => panic!("not accepted: {:?}", other),
+        },
+        other => panic!("not a reply: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn pipelined_calls_each_get_one_reply() {
+    let limits = ConnectionLimits {
+        max_in_flight: 4,
+        ..Default::default()
+    };
+    let (_dir, mut client, serving) = connect(limits);
+
+    let mut out = BytesMut::new();
+    for xid in 1..=32 {
+        let call = match xid % 3 {
+            0 => RpcMsg::new_call(xid, 100003, 4, 0, Vec::new()),
+            1 => lookup_call(xid),
+            _ => RpcMsg::new_call(xid, 100003, 4, 7, Vec::new()),
+        };
+        write_rpc_message(&call, &mut out);
+    }
+    // A reply, which gets no answer, and a message that is not RPC at all
+    write_rpc_message(&RpcMsg::new_success_reply(100, Vec::new()), &mut out);
+    out.extend(words(&[0x80000004, 0x00000065]));
+    client.write_all(&out).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let replies = replies(&mut client).await;
+    serving.await.unwrap().unwrap();
+    assert_eq!(replies.len(), 32);
+    for xid in 1..=32 {
+        let reply = &replies[&xid];
+        assert_eq!(reply.len(), 1, "xid {}", xid);
+        let expected = if xid % 3 == 2 { 3 } else { 0 }; // PROC_UNAVAIL or SUCCESS
+        assert_eq!(accept_stat(&reply[0]), expected, "xid {}", xid);
+    }
+}
+
+#[tokio::test]
+async fn oversized_records_close_the_connection() {
+    let limits = ConnectionLimits {
+        max_record: 1024,
+        ..Default::default()
+    };
+    let (_dir, mut client, serving) = connect(limits);
+
+    let mut out = BytesMut::new();
+    write_rpc_message(&lookup_call(1), &mut out);
+    out.extend(words(&[0x80000404])); // a fragment of 1028 bytes
+    client.write_all(&out).await.unwrap();
+
+    // The call before it is still answered.
+    let replies = replies(&mut client).await;
+    serving.await.unwrap().unwrap();
+    assert_eq!(replies.keys().copied().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(accept_stat(&replies[&1][0]), 0);
+}
@@ -0,0 +1,305 @@
+// Delegations: when the server offers them, the CB_RECALL sent to a
+// client's callback service when someone else wants the file, and what
+// becomes of a delegation that is returned or revoked. A delegation
+// belongs to the file, so it is recalled for access through any of its
+// names.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use nfs4::callback::parse_uaddr;
+use nfs4::delegation::{Delegation, DelegationTable};
+use nfs4::protocol::*;
+use nfs4::state::make_stateid;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{
+    delegating_client, delegation_stateid, lookup, open_delegated, open_file, putrootfh, read, remove, rename,
+    run, savefh, status, Callback, Callbacks,
+};
+
+fn uaddr(netid: &str, addr: &str) -> Option<SocketAddr> {
+    parse_uaddr(&NetAddr {
+        netid: netid.to_string(),
+        addr: addr.to_string(),
+    })
+}
+
+#[test]
+fn universal_addresses() {
+    assert_eq!(uaddr("tcp", "127.0.0.1.8.1"), Some("127.0.0.1:2049".parse().unwrap()));
+    assert_eq!(uaddr("tcp6", "::1.3.255"), Some("[::1]:1023".parse().unwrap()));
+    assert_eq!(uaddr("tcp", "10.0.0.1.0.0"), Some("10.0.0.1:0".parse().unwrap()));
+    // Only TCP callbacks are made.
+    assert_eq!(uaddr("udp", "127.0.0.1.8.1"), None);
+    // The port is two bytes.
+    assert_eq!(uaddr("tcp", "127.0.0.1.256.1"), None);
+    assert_eq!(uaddr("tcp", "127.0.0.1.8"), None);
+    assert_eq!(uaddr("tcp", "localhost.8.1"), None);
+}
+
+// A server for a scratch directory holding the file "a", with a second
+// link to it as "b", and a client holding a write delegation of it.
+async fn delegated(recall_status: NfsStatus) -> (TempDir, NfsServer, u64, [u8; 16], Callbacks) {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("a"), "a").unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let (holder, callbacks) = delegating_client(&server, b"holder", recall_status).await;
+    let (_, delegation) = open_delegated(&server, holder, vec![putrootfh()], "a", OPEN4_SHARE_ACCESS_BOTH).await;
+    assert!(matches!(delegation, OpenDelegation::Write(_)), "{:?}", delegation);
+    (dir, server, holder, delegation_stateid(&delegation), callbacks)
+}
+
+// The stateid CB_RECALL was sent for.
+async fn recalled(callbacks: &mut Callbacks) -> [u8; 16] {
+    match callbacks.recv().await {
+        Some(Callback::Compound(request)) => match &request.operations[..] {
+            [CbOperation::Recall(recall)] => recall.stateid,
+            other => panic!("CB_COMPOUND: {:?}", other),
+        },
+        other => panic!("callback: {:?}", other),
+    }
+}
+
+fn delegreturn(stateid: [u8; 16]) -> NfsOperation {
+    NfsOperation::DelegReturn(DelegReturnOperation { stateid })
+}
+
+#[tokio::test]
+async fn delegations_go_only_to_clients_that_can_be_recalled() {
+    let (_dir, server) = common::server();
+    let root = || vec![putrootfh()];
+
+    let (holder, _callbacks) = delegating_client(&server, b"holder", NfsStatus::Ok).await;
+    let (_, delegation) = open_delegated(&server, holder, root(), "r", OPEN4_SHARE_ACCESS_READ).await;
+    assert!(matches!(delegation, OpenDelegation::Read(_)), "{:?}", delegation);
+
+    // Without a callback service there is no way to recall one.
+    let plain = common::client(&server, b"plain").await;
+    let (_, delegation) = open_delegated(&server, plain, root(), "p", OPEN4_SHARE_ACCESS_BOTH).await;
+    assert_eq!(delegation, OpenDelegation::None);
+
+    // Nor is one offered for a file someone else has open, or that is
+    // delegated already.
+    let (_, delegation) = open_delegated(&server, holder, root(), "p", OPEN4_SHARE_ACCESS_BOTH).await;
+    assert_eq!(delegation, OpenDelegation::None);
+    let (_, delegation) = open_delegated(&server, holder, root(), "r", OPEN4_SHARE_ACCESS_READ).await;
+    assert_eq!(delegation, OpenDelegation::None);
+}
+
+#[tokio::test]
+async fn conflicting_opens_wait_for_the_delegation_to_be_returned() {
+    let (_dir, server, holder, stateid, mut callbacks) = delegated(NfsStatus::Ok).await;
+    let root = || vec![putrootfh()];
+    let other = common::client(&server, b"other").await;
+
+    let open = open_file(&server, other, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(open, Err(NfsStatus::Delay));
+    assert_eq!(recalled(&mut callbacks).await, stateid);
+    // Until the holder answers, the client is told to come back later,
+    // and the recall is not sent again.
+    let open = open_file(&server, other, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(open, Err(NfsStatus::Delay));
+    // The holder itself is not held up.
+    open_file(&server, holder, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await.unwrap();
+
+    let ops = vec![putrootfh(), lookup("a"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+    open_file(&server, other, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await.unwrap();
+
+    let ops = vec![putrootfh(), lookup("a"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    assert!(callbacks.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn delegations_are_returned_for_the_file_they_are_of() {
+    let (dir, server, _holder, stateid, _callbacks) = delegated(NfsStatus::Ok).await;
+    std::fs::write(dir.path().join("c"), "c").unwrap();
+    let ops = vec![putrootfh(), lookup("c"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    // The other link is the same file.
+    let ops = vec![putrootfh(), lookup("b"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+}
+
+#[tokio::test]
+async fn refused_recalls_revoke_the_delegation() {
+    let (_dir, server, _holder, stateid, mut callbacks) = delegated(NfsStatus::BadHandle).await;
+    let root = || vec![putrootfh()];
+    let other = common::client(&server, b"other").await;
+
+    let open = open_file(&server, other, root(), "a", OPEN4_SHARE_ACCESS_WRITE, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(open, Err(NfsStatus::Delay));
+    assert_eq!(recalled(&mut callbacks).await, stateid);
+    // The server revokes the delegation once it has the refusal.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    open_file(&server, other, root(), "a", OPEN4_SHARE_ACCESS_WRITE, OPEN4_SHARE_DENY_NONE).await.unwrap();
+
+    // The holder is told the delegation is gone, which NFSv4.0 has no
+    // better error for than NFS4ERR_EXPIRED.
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("a"), read(stateid, 0, 1)]).await), NfsStatus::Expired);
+    let ops = vec![putrootfh(), lookup("a"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Expired);
+}
+
+#[tokio::test]
+async fn delegations_are_recalled_through_every_link() {
+    let (_dir, server, _holder, stateid, mut callbacks) = delegated(NfsStatus::Ok).await;
+    let other = common::client(&server, b"other").await;
+
+    let open = open_file(&server, other, vec![putrootfh()], "b", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(open, Err(NfsStatus::Delay));
+    assert_eq!(recalled(&mut callbacks).await, stateid);
+    assert_eq!(status(run(&server, vec![putrootfh(), remove("b")]).await), NfsStatus::Delay);
+}
+
+#[tokio::test]
+async fn renaming_a_delegated_file_recalls_it() {
+    let (dir, server, _holder, stateid, mut callbacks) = delegated(NfsStatus::Ok).await;
+    std::fs::write(dir.path().join("c"), "c").unwrap();
+
+    let rename_ops = |old: &str, new: &str| vec![putrootfh(), savefh(), putrootfh(), rename(old, new)];
+    assert_eq!(status(run(&server, rename_ops("b", "d")).await), NfsStatus::Delay);
+    assert_eq!(recalled(&mut callbacks).await, stateid);
+    // Nor can another file take its place.
+    assert_eq!(status(run(&server, rename_ops("c", "a")).await), NfsStatus::Delay);
+
+    let ops = vec![putrootfh(), lookup("a"), delegreturn(stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, rename_ops("b", "d")).await), NfsStatus::Ok);
+    assert!(dir.path().join("d").exists());
+}
+
+fn delegation(clientid: u64, file_key: (u32, u64, u64), write: bool) -> Delegation<()> {
+    Delegation {
+        clientid,
+        file_key,
+        fh: NfsFileHandle { data: vec![1] },
+        write,
+        seqid: 1,
+        file: (),
+        recalled: None,
+    }
+}
+
+#[test]
+fn unreturned_delegations_are_revoked_after_the_recall_time() {
+    let recall_time = Duration::from_secs(90);
+    let mut table = DelegationTable::new(recall_time);
+    let (read, write) = ([1; 12], [2; 12]);
+    table.grant(read, delegation(1, (0, 1, 1), false));
+    table.grant(write, delegation(1, (0, 1, 2), true));
+
+    // Readers do not get in the way of other readers, and nobody gets in
+    // the way of the holder.
+    assert!(table.check_conflicts(&(0, 1, 1), Some(2), false).is_ok());
+    assert!(table.check_conflicts(&(0, 1, 2), Some(1), true).is_ok());
+
+    let recalls = table.check_conflicts(&(0, 1, 2), Some(2), false).unwrap_err();
+    assert_eq!(recalls.len(), 1);
+    assert_eq!(recalls[0].stateid, make_stateid(1, &write));
+    // Recalled once, and in the way until it is gone.
+    assert!(table.check_conflicts(&(0, 1, 2), None, false).unwrap_err().is_empty());
+
+    let recalled = Instant::now();
+    assert!(table.revoke_expired(recalled + recall_time / 2).is_empty());
+    let revoked = table.revoke_expired(recalled + recall_time * 2);
+    assert_eq!(revoked.len(), 1);
+    assert_eq!(revoked[0].file_key, (0, 1, 2));
+    assert!(table.is_revoked(&write));
+    assert!(table.get(&write).is_none());
+    assert!(table.check_conflicts(&(0, 1, 2), Some(2), true).is_ok());
+
+    // The read delegation was never recalled.
+    assert!(table.get(&read).is_some());
+    table.release_client(1);
+    assert!(!table.is_revoked(&write));
+    assert!(!table.client_has_delegations(1));
+
+    // A revoked delegation is remembered for a lease period, even while
+    // its client lives on.
+    table.grant(write, delegation(2, (0, 1, 2), true));
+    assert_eq!(table.check_conflicts(&(0, 1, 2), Some(3), false).unwrap_err().len(), 1);
+    let recalled = Instant::now();
+    assert_eq!(table.revoke_expired(recalled + recall_time).len(), 1);
+    assert!(table.revoke_expired(recalled + recall_time * 3 / 2).is_empty());
+    assert!(table.is_revoked(&write));
+    assert!(table.revoke_expired(recalled + recall_time * 2).is_empty());
+    assert!(!table.is_revoked(&write));
+}
+
+async fn run_v41(server: &NfsServer, operations: Vec<NfsOperation>) -> CompoundResponse {
+    let request = CompoundRequest {
+        tag: String::new(),
+        minor_version: 1,
+        operations,
+    };
+    server.handle_compound(request, common::root()).await.unwrap()
+}
+
+// NFSv4.1 clients are called back over a session backchannel, which the
+// server does not offer, so they are not given delegations even when they
+// ask for a backchannel.
+#[tokio::test]
+async fn nfsv41_clients_get_no_delegations() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "f").unwrap();
+
+    let exchange_id = NfsOperation::ExchangeId(ExchangeIdOperation {
+        owner: ClientOwner {
+            verifier: [1; 8],
+            ownerid: b"nfsv41".to_vec(),
+        },
+        flags: EXCHGID4_FLAG_USE_NON_PNFS,
+        state_protect: StateProtect::None,
+        impl_id: None,
+    });
+    let (clientid, sequence) = match common::last_result(run_v41(&server, vec![exchange_id]).await) {
+        OperationData::ExchangeId(res) => (res.clientid, res.sequenceid),
+        other => panic!("EXCHANGE_ID: {:?}", other),
+    };
+    let channel = ChannelAttrs {
+        max_request_size: 1 << 20,
+        max_response_size: 1 << 20,
+        max_response_size_cached: 4096,
+        max_operations: 16,
+        max_requests: 4,
+        ..Default::default()
+    };
+    let create_session = NfsOperation::CreateSession(CreateSessionOperation {
+        clientid,
+        sequence,
+        flags: CREATE_SESSION4_FLAG_CONN_BACK_CHAN,
+        fore_chan_attrs: channel.clone(),
+        back_chan_attrs: channel,
+        cb_program: 0x4000_0000,
+        sec_parms: vec![CallbackSecParms::AuthNone],
+    });
+    let sessionid = match common::last_result(run_v41(&server, vec![create_session]).await) {
+        OperationData::CreateSession(res) => {
+            assert_eq!(res.flags & CREATE_SESSION4_FLAG_CONN_BACK_CHAN, 0);
+            res.sessionid
+        }
+        other => panic!("CREATE_SESSION: {:?}", other),
+    };
+
+    // The second OPEN is from an owner that has opened before, which is
+    // when an NFSv4.0 client would get one.
+    for sequenceid in 1..=2 {
+        let sequence = NfsOperation::Sequence(SequenceOperation {
+            sessionid,
+            sequenceid,
+            slotid: 0,
+            highest_slotid: 0,
+            cache_this: false,
+        });
+        let open = common::open_as(clientid, b"owner", 0, OpenHow::NoCreate, OpenClaim::Null("f".to_string()));
+        match common::last_result(run_v41(&server, vec![sequence, putrootfh(), open]).await) {
+            OperationData::Open(res) => assert_eq!(res.delegation, OpenDelegation::None),
+            other => panic!("OPEN: {:?}", other),
+        }
+    }
+}
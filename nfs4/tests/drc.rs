@@ -0,0 +1,129 @@
+// Retransmitted calls, as a client sends them after reconnecting: the
+// same xid and arguments from the same address. Non-idempotent NFSv4.0
+// calls must get the reply to the original rather than being run again.
+
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+
+use nfs4::dispatch::dispatch;
+use nfs4::drc::{DrcCheck, DrcKey, DrcStats, DuplicateRequestCache};
+use nfs4::protocol::*;
+use nfs4::rpc::{RpcMsg, RpcMsgBody, ReplyData};
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{lookup, putrootfh};
+
+// Calls are made with AUTH_NONE, so anyone may change the export.
+fn server() -> (TempDir, NfsServer) {
+    let (dir, server) = common::server();
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+    for name in ["a", "b", "c"] {
+        std::fs::write(dir.path().join(name), name).unwrap();
+    }
+    (dir, server)
+}
+
+fn addr(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+}
+
+fn compound(xid: u32, minor_version: u32, operations: Vec<NfsOperation>) -> RpcMsg {
+    let request = CompoundRequest {
+        tag: String::new(),
+        minor_version,
+        operations,
+    };
+    RpcMsg::new_call(xid, NFS_PROGRAM, NFS_VERSION, 1, request.encode())
+}
+
+fn remove(xid: u32, name: &str) -> RpcMsg {
+    compound(xid, 0, vec![putrootfh(), common::remove(name)])
+}
+
+async fn status(server: &NfsServer, client: &str, call: RpcMsg) -> NfsStatus {
+    let reply = dispatch(server, addr(client), call).await.unwrap();
+    let data = match reply.body {
+        RpcMsgBody::Reply(reply) => match reply.data {
+            ReplyData::Accepted(accepted) => accepted.data,
+            other => panic!("not accepted: {:?}", other),
+        },
+        other => panic!("not a reply: {:?}", other),
+    };
+    CompoundResponse::decode(&data).unwrap().status
+}
+
+#[tokio::test]
+async fn retransmitted_remove_gets_the_original_reply() {
+    let (dir, server) = server();
+
+    assert_eq!(status(&server, "192.0.2.1", remove(1, "a")).await, NfsStatus::Ok);
+    assert!(!dir.path().join("a").exists());
+    // Run again, REMOVE would fail with NFS4ERR_NOENT.
+    assert_eq!(status(&server, "192.0.2.1", remove(1, "a")).await, NfsStatus::Ok);
+    assert_eq!(
+        server.drc_stats().await,
+        DrcStats {
+            hits: 1,
+            misses: 1,
+            in_progress: 0,
+            entries: 1,
+        }
+    );
+
+    // Another client, or another call with the same xid, is not a
+    // retransmission.
+    assert_eq!(status(&server, "192.0.2.2", remove(1, "a")).await, NfsStatus::NoEnt);
+    assert_eq!(status(&server, "192.0.2.1", remove(1, "b")).await, NfsStatus::Ok);
+    assert!(!dir.path().join("b").exists());
+    let stats = server.drc_stats().await;
+    assert_eq!((stats.hits, stats.misses), (1, 3));
+}
+
+#[tokio::test]
+async fn idempotent_and_minor_version_1_calls_are_not_cached() {
+    let (_dir, server) = server();
+
+    let lookup = |xid| {
+        compound(xid, 0, vec![putrootfh(), lookup("c")])
+    };
+    assert_eq!(status(&server, "192.0.2.1", lookup(1)).await, NfsStatus::Ok);
+    assert_eq!(status(&server, "192.0.2.1", lookup(1)).await, NfsStatus::Ok);
+
+    // NFSv4.1 replays through session slots. Without SEQUENCE first, the
+    // compound fails either way, and it must not be kept.
+    let v41 = compound(2, 1, vec![putrootfh(), common::remove("c")]);
+    assert_eq!(status(&server, "192.0.2.1", v41.clone()).await, NfsStatus::OpNotInSession);
+    assert_eq!(status(&server, "192.0.2.1", v41).await, NfsStatus::OpNotInSession);
+
+    assert_eq!(server.drc_stats().await, DrcStats::default());
+}
+
+#[test]
+fn calls_in_progress_and_eviction() {
+    let mut cache = DuplicateRequestCache::new(2);
+    let key = |xid| DrcKey::new(addr("192.0.2.1"), xid, b"args");
+    let reply = |xid| RpcMsg::new_success_reply(xid, vec![0; 4]);
+
+    assert!(matches!(cache.check(key(1)), DrcCheck::New));
+    assert!(matches!(cache.check(key(1)), DrcCheck::InProgress));
+    cache.finish(key(1), Some(reply(1)));
+    assert!(matches!(cache.check(key(1)), DrcCheck::Replay(r) if r == reply(1)));
+
+    // A call without a reply to keep is forgotten.
+    assert!(matches!(cache.check(key(2)), DrcCheck::New));
+    cache.finish(key(2), None);
+    assert!(matches!(cache.check(key(2)), DrcCheck::New));
+    cache.finish(key(2), Some(reply(2)));
+
+    // The oldest entry makes way for a new one.
+    assert!(matches!(cache.check(key(3)), DrcCheck::New));
+    cache.finish(key(3), Some(reply(3)));
+    assert!(matches!(cache.check(key(2)), DrcCheck::Replay(_)));
+    assert!(matches!(cache.check(key(3)), DrcCheck::Replay(_)));
+    assert!(matches!(cache.check(key(1)), DrcCheck::New));
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.in_progress, stats.entries), (3, 5, 1, 2));
+}
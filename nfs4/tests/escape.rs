@@ -0,0 +1,251 @@
+// Attempts to reach files outside the export, through names a client
+// sends and through symlinks found inside the export. Every one of them
+// must fail without touching anything outside.
+//
+// Each test builds a scratch tree:
+//
+//   outside/secret          a file that must stay out of reach
+//   export/                 the export root
+//   export/d/f              an ordinary directory and file
+//   export/up -> ../outside
+//   export/abs -> <absolute path of outside>
+//   export/leak -> ../outside/secret
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use nfs4::protocol::*;
+use nfs4::resolve::ExportRoot;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{create_dir, link, lookup, open, putrootfh, remove, rename, savefh, setattr_mode, setattr_size};
+
+struct Tree {
+    _dir: TempDir,
+    export: PathBuf,
+    outside: PathBuf,
+    server: NfsServer,
+}
+
+fn tree() -> Tree {
+    let dir = tempfile::tempdir().unwrap();
+    let export = dir.path().join("export");
+    let outside = dir.path().join("outside");
+    std::fs::create_dir_all(export.join("d")).unwrap();
+    std::fs::create_dir(&outside).unwrap();
+    std::fs::write(export.join("d/f"), "inside").unwrap();
+    std::fs::write(outside.join("secret"), "secret").unwrap();
+    std::fs::set_permissions(outside.join("secret"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    std::os::unix::fs::symlink("../outside", export.join("up")).unwrap();
+    std::os::unix::fs::symlink(&outside, export.join("abs")).unwrap();
+    std::os::unix::fs::symlink("../outside/secret", export.join("leak")).unwrap();
+    let server = NfsServer::new(export.clone()).unwrap();
+    Tree {
+        _dir: dir,
+        export,
+        outside,
+        server,
+    }
+}
+
+impl Tree {
+    async fn run(&self, operations: Vec<NfsOperation>) -> CompoundResponse {
+        common::run(&self.server, operations).await
+    }
+
+    async fn status(&self, operations: Vec<NfsOperation>) -> NfsStatus {
+        common::status(self.run(operations).await)
+    }
+
+    async fn client(&self) -> u64 {
+        common::client(&self.server, b"escape").await
+    }
+
+    fn secret_intact(&self) {
+        let secret = self.outside.join("secret");
+        assert_eq!(std::fs::read_to_string(&secret).unwrap(), "secret");
+        assert_eq!(std::fs::metadata(&secret).unwrap().permissions().mode() & 0o777, 0o600);
+        let names: Vec<_> = std::fs::read_dir(&self.outside).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec!["secret"]);
+    }
+}
+
+#[tokio::test]
+async fn bad_components() {
+    let tree = tree();
+    let long = "x".repeat(256);
+    let cases = [
+        ("", NfsStatus::Inval),
+        (".", NfsStatus::BadName),
+        ("..", NfsStatus::BadName),
+        ("../outside", NfsStatus::BadName),
+        ("d/f", NfsStatus::BadName),
+        ("/etc", NfsStatus::BadName),
+        ("a\0b", NfsStatus::BadName),
+        (long.as_str(), NfsStatus::NameTooLong),
+    ];
+    for (name, status) in cases {
+        assert_eq!(tree.status(vec![putrootfh(), lookup(name)]).await, status, "LOOKUP {:?}", name);
+        assert_eq!(tree.status(vec![putrootfh(), create_dir(name)]).await, status, "CREATE {:?}", name);
+        assert_eq!(tree.status(vec![putrootfh(), remove(name)]).await, status, "REMOVE {:?}", name);
+    }
+
+    let clientid = tree.client().await;
+    for (name, status) in cases {
+        let claim = OpenClaim::Null(name.to_string());
+        let create = OpenHow::Create(CreateHow::Unchecked(Fattr4::default()));
+        assert_eq!(tree.status(vec![putrootfh(), open(clientid, create, claim)]).await, status, "OPEN {:?}", name);
+    }
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn parent_of_root() {
+    let tree = tree();
+    let lookupp = NfsOperation::Lookupp(LookuppOperation);
+    assert_eq!(tree.status(vec![putrootfh(), lookupp]).await, NfsStatus::NoEnt);
+}
+
+#[tokio::test]
+async fn symlinks_are_not_followed_by_lookup() {
+    let tree = tree();
+    for link in ["up", "abs"] {
+        // The symlink itself can be looked up, but not searched.
+        assert_eq!(tree.status(vec![putrootfh(), lookup(link)]).await, NfsStatus::Ok);
+        assert_eq!(tree.status(vec![putrootfh(), lookup(link), lookup("secret")]).await, NfsStatus::Symlink);
+        let readdir = NfsOperation::ReadDir(ReadDirOperation {
+            cookie: 0,
+            cookieverf: [0; 8],
+            dircount: 0,
+            maxcount: 4096,
+            attr_request: Vec::new(),
+        });
+        assert_eq!(tree.status(vec![putrootfh(), lookup(link), readdir]).await, NfsStatus::NotDir);
+    }
+}
+
+#[tokio::test]
+async fn readlink_returns_the_link_text() {
+    let tree = tree();
+    let response = tree.run(vec![putrootfh(), lookup("up"), NfsOperation::ReadLink(ReadLinkOperation)]).await;
+    assert_eq!(response.results[2].result, Some(OperationData::ReadLink("../outside".to_string())));
+}
+
+#[tokio::test]
+async fn open_does_not_follow_symlinks() {
+    let tree = tree();
+    let clientid = tree.client().await;
+    let claim = OpenClaim::Null("leak".to_string());
+    assert_eq!(tree.status(vec![putrootfh(), open(clientid, OpenHow::NoCreate, claim.clone())]).await, NfsStatus::Symlink);
+    let create = OpenHow::Create(CreateHow::Unchecked(Fattr4::default()));
+    assert_eq!(tree.status(vec![putrootfh(), open(clientid, create, claim)]).await, NfsStatus::Symlink);
+
+    // Opening by name in a symlink to a directory.
+    let claim = OpenClaim::Null("secret".to_string());
+    let create = OpenHow::Create(CreateHow::Unchecked(Fattr4::default()));
+    assert_eq!(tree.status(vec![putrootfh(), lookup("up"), open(clientid, create, claim)]).await, NfsStatus::NotDir);
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn nothing_is_created_through_symlinks() {
+    let tree = tree();
+    for link in ["up", "abs"] {
+        assert_eq!(tree.status(vec![putrootfh(), lookup(link), create_dir("x")]).await, NfsStatus::NotDir);
+        let symlink = NfsOperation::Create(CreateOperation {
+            object_type: NF4LNK,
+            link_data: Some("/".to_string()),
+            spec_data: None,
+            object_name: "x".to_string(),
+            attributes: Fattr4::default(),
+        });
+        assert_eq!(tree.status(vec![putrootfh(), lookup(link), symlink]).await, NfsStatus::NotDir);
+    }
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn nothing_is_removed_or_moved_through_symlinks() {
+    let tree = tree();
+    assert_eq!(tree.status(vec![putrootfh(), lookup("up"), remove("secret")]).await, NfsStatus::NotDir);
+
+    // Out of a symlinked directory, and into one.
+    let save = savefh();
+    let ops = vec![putrootfh(), lookup("up"), save.clone(), putrootfh(), rename("secret", "stolen")];
+    assert_eq!(tree.status(ops).await, NfsStatus::NotDir);
+    let ops = vec![putrootfh(), lookup("d"), save.clone(), putrootfh(), lookup("abs"), rename("f", "planted")];
+    assert_eq!(tree.status(ops).await, NfsStatus::NotDir);
+
+    // Renaming the symlink itself moves the link, not what it points to.
+    assert_eq!(tree.status(vec![putrootfh(), save, rename("leak", "leak2")]).await, NfsStatus::Ok);
+    assert_eq!(std::fs::read_link(tree.export.join("leak2")).unwrap(), Path::new("../outside/secret"));
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn links_stay_inside() {
+    let tree = tree();
+    let save = savefh();
+
+    // A new name in a symlinked directory.
+    let ops = vec![putrootfh(), lookup("d"), lookup("f"), save.clone(), putrootfh(), lookup("up"), link("planted")];
+    assert_eq!(tree.status(ops).await, NfsStatus::NotDir);
+
+    // A link to a symlink is another symlink, not a link to its target.
+    let ops = vec![putrootfh(), lookup("leak"), save, putrootfh(), link("leak2")];
+    assert_eq!(tree.status(ops).await, NfsStatus::Ok);
+    let metadata = std::fs::symlink_metadata(tree.export.join("leak2")).unwrap();
+    assert!(metadata.file_type().is_symlink());
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn setattr_does_not_follow_symlinks() {
+    let tree = tree();
+    assert_eq!(tree.status(vec![putrootfh(), lookup("leak"), setattr_mode(0o777)]).await, NfsStatus::Inval);
+    assert_eq!(tree.status(vec![putrootfh(), lookup("leak"), setattr_size(0)]).await, NfsStatus::Inval);
+    tree.secret_intact();
+}
+
+#[tokio::test]
+async fn directory_swapped_for_a_symlink() {
+    let tree = tree();
+    let fh = common::fh(&tree.server, vec![putrootfh(), lookup("d")]).await;
+
+    // Something other than the server puts a symlink where the directory
+    // was. The handle is stale rather than leading through the symlink.
+    std::fs::rename(tree.export.join("d"), tree.export.join("moved")).unwrap();
+    std::os::unix::fs::symlink("../outside", tree.export.join("d")).unwrap();
+    let putfh = common::putfh(fh);
+    assert_eq!(tree.status(vec![putfh.clone(), lookup("secret")]).await, NfsStatus::StaleFileHandle);
+    assert_eq!(tree.status(vec![putfh, create_dir("x")]).await, NfsStatus::StaleFileHandle);
+    assert!(!tree.export.join("moved/x").exists());
+    tree.secret_intact();
+}
+
+#[test]
+fn export_root_resolution() {
+    let tree = tree();
+    let root = ExportRoot::new(&tree.export).unwrap();
+    let errno = |result: std::io::Result<std::fs::Metadata>| result.unwrap_err().raw_os_error();
+
+    assert!(root.metadata(&tree.export.join("d/f")).is_ok());
+    // Symlinks are only ever the last component, and are not followed.
+    assert!(root.metadata(&tree.export.join("leak")).unwrap().file_type().is_symlink());
+    assert_eq!(errno(root.metadata(&tree.export.join("up/secret"))), Some(nix::libc::ELOOP));
+    assert_eq!(errno(root.metadata(&tree.export.join("abs/secret"))), Some(nix::libc::ELOOP));
+    assert!(root.open(&tree.export.join("leak"), nix::fcntl::OFlag::O_RDONLY).is_err());
+    // Paths that are not plainly below the root are refused outright.
+    assert_eq!(errno(root.metadata(&tree.outside.join("secret"))), Some(nix::libc::EXDEV));
+    assert_eq!(errno(root.metadata(&tree.export.join("../outside/secret"))), Some(nix::libc::EXDEV));
+    assert_eq!(errno(root.metadata(&tree.export.join("d/../../outside"))), Some(nix::libc::EXDEV));
+
+    assert!(root.rename(&tree.export.join("up/secret"), &tree.export.join("stolen")).is_err());
+    assert!(root.hard_link(&tree.export.join("abs/secret"), &tree.export.join("stolen")).is_err());
+    assert!(root.set_mode(&tree.export.join("leak"), 0o777).is_err());
+    assert!(root.set_owner(&tree.export.join("up/secret"), Some(0), Some(0)).is_err());
+    assert!(root.remove(&tree.export.join("up/secret"), false).is_err());
+    tree.secret_intact();
+}
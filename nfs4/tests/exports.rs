@@ -0,0 +1,444 @@
+// Several exports joined by a pseudo-filesystem (RFC 7530 section 7).
+//
+// Each test serves three scratch directories:
+//
+//   /srv/one  -> one/     holding the file "f"
+//   /srv/two  -> two/
+//   /three    -> three/
+//
+// so the pseudo-filesystem is "/" holding "srv" and "three", and "/srv"
+// holding "one" and "two".
+
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+
+use nfs4::auth::Credentials;
+use nfs4::export::{ClientNet, Export, ExportOptions, ExportTable, Squash};
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{create_dir, getattr, lookup, lookupp, putfh, putrootfh, remove, rename, run, run_as, savefh, status};
+
+struct Tree {
+    dir: TempDir,
+    server: NfsServer,
+}
+
+fn tree() -> Tree {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["one", "two", "three"] {
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    std::fs::write(dir.path().join("one/f"), "one").unwrap();
+    let server = NfsServer::from_exports(exports(&dir, &[("/srv/one", "one"), ("/srv/two", "two"), ("/three", "three")]))
+        .unwrap();
+    Tree { dir, server }
+}
+
+fn exports(dir: &TempDir, table: &[(&str, &str)]) -> Vec<Export> {
+    table.iter().map(|(path, name)| Export::new(path, dir.path().join(name))).collect()
+}
+
+impl Tree {
+    async fn run(&self, operations: Vec<NfsOperation>) -> CompoundResponse {
+        run(&self.server, operations).await
+    }
+
+    async fn status(&self, operations: Vec<NfsOperation>) -> NfsStatus {
+        status(self.run(operations).await)
+    }
+
+    async fn fh(&self, operations: Vec<NfsOperation>) -> NfsFileHandle {
+        common::fh(&self.server, operations).await
+    }
+
+    // The type and fsid of the current filehandle after `operations`.
+    async fn type_and_fsid(&self, mut operations: Vec<NfsOperation>) -> (u32, u64, u64) {
+        operations.push(getattr(vec![(1 << FATTR4_TYPE) | (1 << FATTR4_FSID)]));
+        let attrs = match common::last_result(self.run(operations).await) {
+            OperationData::GetAttr(attrs) => attrs,
+            other => panic!("GETATTR: {:?}", other),
+        };
+        let vals = &attrs.attr_vals;
+        (
+            u32::from_be_bytes(vals[0..4].try_into().unwrap()),
+            u64::from_be_bytes(vals[4..12].try_into().unwrap()),
+            u64::from_be_bytes(vals[12..20].try_into().unwrap()),
+        )
+    }
+}
+
+fn readdir() -> NfsOperation {
+    NfsOperation::ReadDir(ReadDirOperation {
+        cookie: 0,
+        cookieverf: [0; 8],
+        dircount: 0,
+        maxcount: 4096,
+        attr_request: vec![1 << FATTR4_FSID],
+    })
+}
+
+#[tokio::test]
+async fn root_is_the_pseudo_filesystem() {
+    let tree = tree();
+    assert_eq!(tree.type_and_fsid(vec![putrootfh()]).await, (NF4DIR, 0, 0));
+    assert_eq!(tree.type_and_fsid(vec![putrootfh(), lookup("srv")]).await, (NF4DIR, 0, 0));
+
+    let response = tree.run(vec![putrootfh(), readdir()]).await;
+    let entries = match &response.results[1].result {
+        Some(OperationData::ReadDir(res)) => res.entries.clone(),
+        other => panic!("READDIR: {:?}", other),
+    };
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, vec!["srv", "three"]);
+    // An export is listed with the attributes of its root.
+    assert_eq!(entries[1].attrs.attr_vals[..16], [0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    assert_eq!(tree.status(vec![putrootfh(), lookup("one")]).await, NfsStatus::NoEnt);
+    assert_eq!(tree.status(vec![putrootfh(), lookupp()]).await, NfsStatus::NoEnt);
+}
+
+#[tokio::test]
+async fn lookup_crosses_into_exports() {
+    let tree = tree();
+    let one = vec![putrootfh(), lookup("srv"), lookup("one")];
+    assert_eq!(tree.type_and_fsid(one.clone()).await, (NF4DIR, 1, 0));
+    assert_eq!(tree.type_and_fsid(vec![putrootfh(), lookup("srv"), lookup("two")]).await, (NF4DIR, 2, 0));
+    assert_eq!(tree.type_and_fsid(vec![putrootfh(), lookup("three")]).await, (NF4DIR, 3, 0));
+
+    let mut f = one.clone();
+    f.push(lookup("f"));
+    assert_eq!(tree.type_and_fsid(f).await, (NF4REG, 1, 0));
+
+    // Back out of the export into the pseudo-filesystem.
+    let mut up = one.clone();
+    up.push(lookupp());
+    assert_eq!(tree.fh(up).await, tree.fh(vec![putrootfh(), lookup("srv")]).await);
+    let mut top = one;
+    top.extend([lookupp(), lookupp()]);
+    assert_eq!(tree.fh(top).await, tree.fh(vec![putrootfh()]).await);
+
+    // Handles lead back to where they were issued.
+    let fh = tree.fh(vec![putrootfh(), lookup("srv")]).await;
+    let putfh = NfsOperation::PutFh(PutFhOperation { object: fh });
+    assert_eq!(tree.type_and_fsid(vec![putfh, lookup("two")]).await, (NF4DIR, 2, 0));
+}
+
+#[tokio::test]
+async fn pseudo_filesystem_is_read_only() {
+    let tree = tree();
+    assert_eq!(tree.status(vec![putrootfh(), create_dir("x")]).await, NfsStatus::RoFs);
+    assert_eq!(tree.status(vec![putrootfh(), remove("three")]).await, NfsStatus::RoFs);
+    assert_eq!(tree.status(vec![putrootfh(), common::setattr_mode(0o777)]).await, NfsStatus::RoFs);
+    let readlink = NfsOperation::ReadLink(ReadLinkOperation);
+    assert_eq!(tree.status(vec![putrootfh(), readlink]).await, NfsStatus::Inval);
+
+    let access = NfsOperation::Access(AccessOperation {
+        access: ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_MODIFY | ACCESS4_DELETE,
+    });
+    let response = tree.run(vec![putrootfh(), access]).await;
+    match &response.results[1].result {
+        Some(OperationData::Access(res)) => assert_eq!(res.access, ACCESS4_READ | ACCESS4_LOOKUP),
+        other => panic!("ACCESS: {:?}", other),
+    }
+
+    // Moving a file between exports is not a rename.
+    let ops = vec![putrootfh(), lookup("srv"), lookup("one"), savefh(), putrootfh(), lookup("three"), rename("f", "f")];
+    assert_eq!(tree.status(ops).await, NfsStatus::XDev);
+    assert!(tree.dir.path().join("one/f").exists());
+}
+
+#[tokio::test]
+async fn handles_of_a_removed_export_are_stale() {
+    let tree = tree();
+    let fh = tree.fh(vec![putrootfh(), lookup("three")]).await;
+    let srv = tree.fh(vec![putrootfh(), lookup("srv")]).await;
+
+    // The same directories served again, without /three and with a
+    // different pseudo-filesystem.
+    let server = NfsServer::from_exports(exports(&tree.dir, &[("/one", "one"), ("/two", "two")])).unwrap();
+    for object in [fh, srv] {
+        let response = run(&server, vec![putfh(object)]).await;
+        assert_eq!(response.status, NfsStatus::StaleFileHandle);
+    }
+}
+
+#[test]
+fn bad_export_tables() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a", "b"] {
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    let table = |exports: &[(&str, PathBuf)]| {
+        ExportTable::new(exports.iter().map(|(path, dir)| Export::new(path, dir.clone())).collect())
+    };
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+
+    assert!(table(&[("/a", a.clone()), ("/b", b.clone())]).is_ok());
+    assert!(table(&[]).is_err());
+    assert!(table(&[("a", a.clone())]).is_err());
+    assert!(table(&[("/x/../a", a.clone())]).is_err());
+    assert!(table(&[("/", a.clone()), ("/b", b.clone())]).is_err());
+    assert!(table(&[("/b", b.clone()), ("/", a.clone())]).is_err());
+    assert!(table(&[("/a", a.clone()), ("/a", b.clone())]).is_err());
+    assert!(table(&[("/a", a.clone()), ("/a/b", b.clone())]).is_err());
+    assert!(table(&[("/a/b", b.clone()), ("/a", a.clone())]).is_err());
+    assert!(table(&[("/a", dir.path().to_path_buf()), ("/b", b.clone())]).is_err());
+    assert!(table(&[("/a", a.clone()), ("/b", a.join("."))]).is_err());
+    assert!(table(&[("/a", dir.path().join("missing"))]).is_err());
+}
+
+// Serves "/ro" read-only and "/rw" with `options`, each holding a directory
+// "open" anyone may write to and a file "f" owned by root.
+fn policy_tree(options: ExportOptions) -> (TempDir, NfsServer) {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["ro", "rw"] {
+        std::fs::create_dir_all(dir.path().join(name).join("open")).unwrap();
+        std::fs::set_permissions(dir.path().join(name).join("open"), std::fs::Permissions::from_mode(0o777)).unwrap();
+        std::fs::write(dir.path().join(name).join("f"), "f").unwrap();
+    }
+    let read_only = ExportOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let server = NfsServer::from_exports(vec![
+        Export::new("/ro", dir.path().join("ro")).with_options(read_only),
+        Export::new("/rw", dir.path().join("rw")).with_options(options),
+    ])
+    .unwrap();
+    (dir, server)
+}
+
+#[tokio::test]
+async fn read_only_exports_refuse_changes() {
+    let (dir, server) = policy_tree(ExportOptions::default());
+    let ro = || vec![putrootfh(), lookup("ro")];
+    let with = |mut ops: Vec<NfsOperation>, op: NfsOperation| {
+        ops.push(op);
+        ops
+    };
+
+    assert_eq!(status(run(&server, with(ro(), create_dir("x"))).await), NfsStatus::RoFs);
+    assert_eq!(status(run(&server, with(ro(), remove("f"))).await), NfsStatus::RoFs);
+    let mut ops = with(ro(), savefh());
+    ops.extend([lookup("open"), lookupp(), rename("f", "g")]);
+    assert_eq!(status(run(&server, ops).await), NfsStatus::RoFs);
+    let mut ops = ro();
+    ops.extend([lookup("f"), common::setattr_size(0)]);
+    assert_eq!(status(run(&server, ops).await), NfsStatus::RoFs);
+
+    let access = NfsOperation::Access(AccessOperation {
+        access: ACCESS4_READ | ACCESS4_MODIFY | ACCESS4_EXTEND,
+    });
+    let mut ops = ro();
+    ops.extend([lookup("f"), access]);
+    match &run(&server, ops).await.results[3].result {
+        Some(OperationData::Access(res)) => assert_eq!(res.access, ACCESS4_READ),
+        other => panic!("ACCESS: {:?}", other),
+    }
+
+    // Opening for reading is fine, for writing or creating is not.
+    let clientid = common::client(&server, b"exports").await;
+    let cases = [
+        (OPEN4_SHARE_ACCESS_READ, OpenHow::NoCreate, "f", NfsStatus::Ok),
+        (OPEN4_SHARE_ACCESS_BOTH, OpenHow::NoCreate, "f", NfsStatus::RoFs),
+        (OPEN4_SHARE_ACCESS_READ, OpenHow::Create(CreateHow::Unchecked(Fattr4::default())), "new", NfsStatus::RoFs),
+    ];
+    for (owner, (share_access, open_how, name, expected)) in cases.into_iter().enumerate() {
+        let open = NfsOperation::Open(OpenOperation {
+            seqid: 0,
+            share_access,
+            share_deny: OPEN4_SHARE_DENY_NONE,
+            clientid,
+            owner: vec![owner as u8],
+            open_how,
+            open_claim: OpenClaim::Null(name.to_string()),
+        });
+        assert_eq!(status(run(&server, with(ro(), open)).await), expected, "OPEN {} {}", share_access, name);
+    }
+
+    // The other export is writable.
+    let rw = vec![putrootfh(), lookup("rw"), create_dir("x")];
+    assert_eq!(status(run(&server, rw).await), NfsStatus::Ok);
+    assert!(!dir.path().join("ro/x").exists());
+    assert!(dir.path().join("rw/x").is_dir());
+}
+
+#[tokio::test]
+async fn squashed_callers_act_as_the_anonymous_user() {
+    let user = Credentials {
+        uid: 1000,
+        gid: 1000,
+        gids: vec![0, 10],
+    };
+    let root_squash = ExportOptions {
+        squash: Squash::Root,
+        anonuid: 4000,
+        anongid: 4001,
+        ..Default::default()
+    };
+    let (dir, server) = policy_tree(root_squash);
+    let create = |name: &str| vec![putrootfh(), lookup("rw"), lookup("open"), create_dir(name)];
+    let owner = |name: &str| {
+        let metadata = std::fs::metadata(dir.path().join("rw/open").join(name)).unwrap();
+        (metadata.uid(), metadata.gid())
+    };
+
+    // Root is squashed, and may no longer write where only root may.
+    assert_eq!(status(run(&server, create("root")).await), NfsStatus::Ok);
+    assert_eq!(owner("root"), (4000, 4001));
+    let ops = vec![putrootfh(), lookup("rw"), create_dir("top")];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Access);
+    // Other users are left alone.
+    assert_eq!(status(run_as(&server, user.clone(), create("user")).await), NfsStatus::Ok);
+    assert_eq!(owner("user"), (1000, 1000));
+
+    let all_squash = ExportOptions {
+        squash: Squash::All,
+        anonuid: 4000,
+        anongid: 4001,
+        ..Default::default()
+    };
+    let (dir, server) = policy_tree(all_squash);
+    assert_eq!(status(run_as(&server, user, create("user")).await), NfsStatus::Ok);
+    let metadata = std::fs::metadata(dir.path().join("rw/open/user")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (4000, 4001));
+
+    // Squashing only applies on the export it is set for.
+    let ops = vec![putrootfh(), lookup("ro"), lookup("open"), common::getfh()];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+}
+
+#[tokio::test]
+async fn clients_only_reach_the_exports_served_to_them() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["lan", "all"] {
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    let lan = ExportOptions::parse("client=10.0.0.0/8,client=fd00::/8").unwrap();
+    let server = NfsServer::from_exports(vec![
+        Export::new("/private/lan", dir.path().join("lan")).with_options(lan),
+        Export::new("/all", dir.path().join("all")),
+    ])
+    .unwrap();
+    let lan_fh = common::fh(&server, vec![putrootfh(), lookup("private"), lookup("lan")]).await;
+
+    let inside = server.for_client("10.1.2.3".parse().unwrap()).unwrap();
+    let ops = vec![putrootfh(), lookup("private"), lookup("lan")];
+    assert_eq!(status(run(&inside, ops.clone()).await), NfsStatus::Ok);
+    let mapped = server.for_client("::ffff:10.1.2.3".parse().unwrap()).unwrap();
+    assert_eq!(status(run(&mapped, ops.clone()).await), NfsStatus::Ok);
+
+    // Exports that are not served to a client are not there for it, nor
+    // are the directories leading only to them.
+    let outside = server.for_client("192.168.1.1".parse().unwrap()).unwrap();
+    assert_eq!(status(run(&outside, vec![putrootfh(), lookup("private")]).await), NfsStatus::NoEnt);
+    let response = run(&outside, vec![putrootfh(), readdir()]).await;
+    match &response.results[1].result {
+        Some(OperationData::ReadDir(res)) => {
+            let names: Vec<_> = res.entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, vec!["all"]);
+        }
+        other => panic!("READDIR: {:?}", other),
+    }
+    assert_eq!(status(run(&outside, vec![putfh(lan_fh)]).await), NfsStatus::Access);
+
+    // A client no export is served to is turned away.
+    let only_lan = NfsServer::from_exports(vec![Export::new("/", dir.path().join("lan"))
+        .with_options(ExportOptions::parse("client=10.0.0.0/8").unwrap())])
+    .unwrap();
+    assert!(only_lan.for_client("192.168.1.1".parse().unwrap()).is_none());
+    assert!(only_lan.for_client("10.0.0.1".parse().unwrap()).is_some());
+}
+
+fn create_node(name: &str, object_type: u32, major: u32, minor: u32) -> NfsOperation {
+    NfsOperation::Create(CreateOperation {
+        object_type,
+        link_data: None,
+        spec_data: Some(SpecData { major, minor }),
+        object_name: name.to_string(),
+        attributes: Fattr4::default(),
+    })
+}
+
+#[tokio::test]
+async fn device_nodes_need_the_devices_option() {
+    let (dir, server) = policy_tree(ExportOptions::default());
+    let rw = |op: NfsOperation| vec![putrootfh(), lookup("rw"), op];
+
+    assert_eq!(status(run(&server, rw(create_node("c", NF4CHR, 1, 3))).await), NfsStatus::BadType);
+    assert_eq!(status(run(&server, rw(create_node("b", NF4BLK, 7, 0))).await), NfsStatus::BadType);
+    assert!(!dir.path().join("rw/c").exists() && !dir.path().join("rw/b").exists());
+
+    // FIFOs and sockets are no way into anything else, so any export may
+    // have them.
+    assert_eq!(status(run(&server, rw(create_node("p", NF4FIFO, 0, 0))).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, rw(create_node("s", NF4SOCK, 0, 0))).await), NfsStatus::Ok);
+    assert!(std::fs::symlink_metadata(dir.path().join("rw/p")).unwrap().file_type().is_fifo());
+    assert!(std::fs::symlink_metadata(dir.path().join("rw/s")).unwrap().file_type().is_socket());
+    for (name, ftype) in [("p", NF4FIFO), ("s", NF4SOCK)] {
+        let ops = vec![putrootfh(), lookup("rw"), lookup(name), getattr(vec![1 << FATTR4_TYPE])];
+        match &run(&server, ops).await.results[3].result {
+            Some(OperationData::GetAttr(attrs)) => assert_eq!(attrs.attr_vals, ftype.to_be_bytes(), "{}", name),
+            other => panic!("GETATTR: {:?}", other),
+        }
+    }
+
+    let devices = ExportOptions {
+        devices: true,
+        ..Default::default()
+    };
+    let (dir, server) = policy_tree(devices);
+    assert_eq!(status(run(&server, rw(create_node("c", NF4CHR, 1, 3))).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, rw(create_node("b", NF4BLK, 7, 0))).await), NfsStatus::Ok);
+    let c = std::fs::symlink_metadata(dir.path().join("rw/c")).unwrap();
+    assert!(c.file_type().is_char_device());
+    assert_eq!(c.rdev(), nix::sys::stat::makedev(1, 3));
+    assert!(std::fs::symlink_metadata(dir.path().join("rw/b")).unwrap().file_type().is_block_device());
+    let ops = vec![putrootfh(), lookup("rw"), lookup("c"), getattr(vec![0, 1 << (FATTR4_RAWDEV - 32)])];
+    match &run(&server, ops).await.results[3].result {
+        Some(OperationData::GetAttr(attrs)) => assert_eq!(attrs.attr_vals, common::words(&[1, 3])),
+        other => panic!("GETATTR: {:?}", other),
+    }
+
+    // Only root may make them, as with mknod.
+    let ops = vec![putrootfh(), lookup("rw"), lookup("open"), create_node("c", NF4CHR, 1, 3)];
+    assert_eq!(status(run_as(&server, common::user(1000), ops).await), NfsStatus::Perm);
+    assert!(!dir.path().join("rw/open/c").exists());
+
+    assert!(ExportOptions::parse("devices").unwrap().devices);
+    assert!(!ExportOptions::parse("devices,nodevices").unwrap().devices);
+}
+
+#[test]
+fn export_options() {
+    let options = ExportOptions::parse("ro,all_squash,anonuid=5,anongid=6,client=10.0.0.0/8,client=::1").unwrap();
+    assert!(options.read_only);
+    assert_eq!(options.squash, Squash::All);
+    assert_eq!((options.anonuid, options.anongid), (5, 6));
+    assert_eq!(options.clients, vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse::<ClientNet>().unwrap()]);
+
+    let defaults = ExportOptions::parse("").unwrap();
+    assert!(!defaults.read_only);
+    assert_eq!(defaults.squash, Squash::None);
+    assert_eq!((defaults.anonuid, defaults.anongid), (65534, 65534));
+    assert!(defaults.allows("192.0.2.1".parse().unwrap()));
+
+    for bad in ["ro=1", "anonuid", "anonuid=x", "client=10.0.0.0/33", "client=::/129", "client=host", "squash"] {
+        assert!(ExportOptions::parse(bad).is_err(), "{:?}", bad);
+    }
+
+    let net = |net: &str| net.parse::<ClientNet>().unwrap();
+    let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+    assert!(net("10.0.0.0/8").contains(addr("10.255.0.1")));
+    assert!(!net("10.0.0.0/8").contains(addr("11.0.0.1")));
+    assert!(net("192.168.1.7").contains(addr("192.168.1.7")));
+    assert!(!net("192.168.1.7").contains(addr("192.168.1.8")));
+    assert!(net("0.0.0.0/0").contains(addr("203.0.113.9")));
+    assert!(net("2001:db8::/32").contains(addr("2001:db8:1::1")));
+    assert!(!net("2001:db8::/32").contains(addr("2001:db9::1")));
+    assert!(!net("::/0").contains(addr("10.0.0.1")));
+}
@@ -0,0 +1,95 @@
+// Filehandles are built from the identity of the file rather than handed
+// out at random, so a file has the same handle however it is reached, from
+// any server for the same exports, for as long as it exists. Servers find
+// the file a handle is for through the paths in their handle index.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use nfs4::filehandle::{FileId, HandleIndex};
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{getattr, lookup, putfh, putrootfh, rename, run, savefh, status};
+
+async fn fileid(server: &NfsServer, fh: NfsFileHandle) -> Result<u64, NfsStatus> {
+    let response = run(server, vec![putfh(fh), getattr(vec![1 << FATTR4_FILEID])]).await;
+    match response.results.last().unwrap() {
+        OperationResult {
+            result: Some(OperationData::GetAttr(attrs)),
+            ..
+        } => Ok(u64::from_be_bytes(attrs.attr_vals[..8].try_into().unwrap())),
+        other => Err(other.status),
+    }
+}
+
+// A server keeping its handle index in `index`.
+fn serve(dir: &TempDir, index: &Path) -> NfsServer {
+    NfsServer::new(dir.path().to_path_buf())
+        .unwrap()
+        .with_handle_index(HandleIndex::open(index).unwrap())
+}
+
+#[tokio::test]
+async fn handles_outlive_the_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    let index = state.path().join("handles");
+    let server = serve(&dir, &index);
+    std::fs::create_dir(dir.path().join("d")).unwrap();
+    std::fs::write(dir.path().join("d/f"), "").unwrap();
+    let ino = std::fs::metadata(dir.path().join("d/f")).unwrap().ino();
+
+    let fh = common::fh(&server, vec![putrootfh(), lookup("d"), lookup("f")]).await;
+    assert_eq!(common::fh(&server, vec![putrootfh(), lookup("d"), lookup("f")]).await, fh);
+
+    // A new server for the same export gives the same handle, and finds
+    // the file from one it has never given out.
+    let restarted = serve(&dir, &index);
+    assert_eq!(fileid(&restarted, fh.clone()).await, Ok(ino));
+    assert_eq!(common::fh(&restarted, vec![putrootfh(), lookup("d"), lookup("f")]).await, fh);
+
+    // Even after the file was renamed.
+    let ops = vec![putrootfh(), savefh(), rename("d", "e")];
+    assert_eq!(status(run(&restarted, ops).await), NfsStatus::Ok);
+    let restarted = serve(&dir, &index);
+    assert_eq!(fileid(&restarted, fh.clone()).await, Ok(ino));
+
+    // A server without the index only knows its export root.
+    let root = common::fh(&server, vec![putrootfh()]).await;
+    let forgetful = NfsServer::new(dir.path().to_path_buf()).unwrap();
+    assert!(fileid(&forgetful, root).await.is_ok());
+    assert_eq!(fileid(&forgetful, fh.clone()).await, Err(NfsStatus::StaleFileHandle));
+
+    // Handles are not looked for once their file has been moved behind the
+    // server's back, or is gone.
+    std::fs::rename(dir.path().join("e/f"), dir.path().join("g")).unwrap();
+    assert_eq!(fileid(&restarted, fh.clone()).await, Err(NfsStatus::StaleFileHandle));
+    std::fs::rename(dir.path().join("g"), dir.path().join("e/f")).unwrap();
+    std::fs::remove_file(dir.path().join("e/f")).unwrap();
+    assert_eq!(fileid(&serve(&dir, &index), fh).await, Err(NfsStatus::StaleFileHandle));
+}
+
+#[tokio::test]
+async fn handles_of_other_servers_are_refused() {
+    let (_dir, server) = common::server();
+    let fh = common::fh(&server, vec![putrootfh()]).await;
+    let id = FileId::from_handle(&fh).unwrap();
+    assert_eq!(id.to_handle(), fh);
+
+    // Not one of ours at all.
+    for data in [Vec::new(), vec![0; 16], [&fh.data[..], &[0]].concat()] {
+        assert_eq!(status(run(&server, vec![putfh(NfsFileHandle { data })]).await), NfsStatus::BadHandle);
+    }
+    let mut data = fh.data.clone();
+    data[0] = 2;
+    assert_eq!(status(run(&server, vec![putfh(NfsFileHandle { data })]).await), NfsStatus::BadHandle);
+
+    // Ours in form, but for an export or a file that is not there.
+    let other_export = FileId { export_id: id.export_id + 100, ..id }.to_handle();
+    assert_eq!(fileid(&server, other_export).await, Err(NfsStatus::StaleFileHandle));
+    let other_file = FileId { ino: u64::MAX, ..id }.to_handle();
+    assert_eq!(fileid(&server, other_file).await, Err(NfsStatus::StaleFileHandle));
+}
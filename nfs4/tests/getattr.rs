@@ -0,0 +1,129 @@
+// GETATTR: the attributes asked for come back in a bitmap of their own,
+// less the ones the server does not have, with their values in bit order.
+
+use std::os::unix::fs::MetadataExt;
+
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+
+mod common;
+use common::{getattr, lookup, putrootfh, run, status};
+
+fn bitmap(attrs: &[u32]) -> Vec<u32> {
+    let mut bitmap = Vec::new();
+    for &attr in attrs {
+        let word = (attr / 32) as usize;
+        if bitmap.len() <= word {
+            bitmap.resize(word + 1, 0);
+        }
+        bitmap[word] |= 1 << (attr % 32);
+    }
+    bitmap
+}
+
+async fn attributes(server: &NfsServer, name: &str, attrs: &[u32]) -> Fattr4 {
+    let mut ops = vec![putrootfh()];
+    if !name.is_empty() {
+        ops.push(lookup(name));
+    }
+    ops.push(getattr(bitmap(attrs)));
+    match common::last_result(run(server, ops).await) {
+        OperationData::GetAttr(attrs) => attrs,
+        other => panic!("GETATTR: {:?}", other),
+    }
+}
+
+fn u32_at(vals: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(vals[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(vals: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(vals[offset..offset + 8].try_into().unwrap())
+}
+
+#[tokio::test]
+async fn only_the_requested_attributes_are_returned() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "12345").unwrap();
+    let metadata = std::fs::metadata(dir.path().join("f")).unwrap();
+
+    let attrs = attributes(&server, "f", &[FATTR4_MODE, FATTR4_TYPE, FATTR4_NUMLINKS, FATTR4_SIZE, FATTR4_FILEID]).await;
+    assert_eq!(attrs.attrmask, bitmap(&[FATTR4_TYPE, FATTR4_SIZE, FATTR4_FILEID, FATTR4_MODE, FATTR4_NUMLINKS]));
+    let vals = &attrs.attr_vals;
+    assert_eq!(vals.len(), 4 + 8 + 8 + 4 + 4);
+    assert_eq!(u32_at(vals, 0), NF4REG);
+    assert_eq!(u64_at(vals, 4), 5);
+    assert_eq!(u64_at(vals, 12), metadata.ino());
+    assert_eq!(u32_at(vals, 20), metadata.mode() & 0o7777);
+    assert_eq!(u32_at(vals, 24), 1);
+
+    // An empty request gets an empty reply.
+    let attrs = attributes(&server, "f", &[]).await;
+    assert!(attrs.attrmask.iter().all(|&word| word == 0) && attrs.attr_vals.is_empty());
+}
+
+#[tokio::test]
+async fn unsupported_attributes_are_left_out() {
+    let (_dir, server) = common::server();
+
+    // ACLs, the Windows flags and times the server does not keep, and
+    // bits past the last attribute RFC 7530 defines.
+    let attrs = attributes(
+        &server,
+        "",
+        &[FATTR4_TYPE, FATTR4_ACL, FATTR4_ARCHIVE, FATTR4_HIDDEN, FATTR4_TIME_BACKUP, 60, 70],
+    )
+    .await;
+    assert_eq!(attrs.attrmask, bitmap(&[FATTR4_TYPE]));
+    assert_eq!(attrs.attr_vals, NF4DIR.to_be_bytes());
+
+    let attrs = attributes(&server, "", &[FATTR4_SUPPORTED_ATTRS]).await;
+    let vals = &attrs.attr_vals;
+    let words = u32_at(vals, 0) as usize;
+    let supported: Vec<u32> = (0..words).map(|i| u32_at(vals, 4 + 4 * i)).collect();
+    let has = |attr: u32| supported.get((attr / 32) as usize).is_some_and(|word| word & (1 << (attr % 32)) != 0);
+    for attr in [FATTR4_TYPE, FATTR4_FILEHANDLE, FATTR4_MODE, FATTR4_RAWDEV, FATTR4_TIME_MODIFY_SET] {
+        assert!(has(attr), "{}", attr);
+    }
+    for attr in [FATTR4_ACL, FATTR4_ARCHIVE, FATTR4_HIDDEN, FATTR4_TIME_BACKUP] {
+        assert!(!has(attr), "{}", attr);
+    }
+
+    // Attributes that can only be set cannot be asked for.
+    let ops = vec![putrootfh(), getattr(bitmap(&[FATTR4_TYPE, FATTR4_TIME_ACCESS_SET]))];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Inval);
+}
+
+#[tokio::test]
+async fn every_type_of_file_is_told_apart() {
+    let (dir, server) = common::server();
+    std::fs::create_dir(dir.path().join("dir")).unwrap();
+    std::fs::write(dir.path().join("file"), "").unwrap();
+    std::os::unix::fs::symlink("file", dir.path().join("symlink")).unwrap();
+    let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("socket")).unwrap();
+    nix::unistd::mkfifo(&dir.path().join("fifo"), Mode::from_bits_truncate(0o644)).unwrap();
+    let mode = Mode::from_bits_truncate(0o600);
+    mknod(&dir.path().join("chr"), SFlag::S_IFCHR, mode, makedev(1, 3)).unwrap();
+    mknod(&dir.path().join("blk"), SFlag::S_IFBLK, mode, makedev(7, 0)).unwrap();
+
+    let types = [
+        ("dir", NF4DIR),
+        ("file", NF4REG),
+        ("symlink", NF4LNK),
+        ("socket", NF4SOCK),
+        ("fifo", NF4FIFO),
+        ("chr", NF4CHR),
+        ("blk", NF4BLK),
+    ];
+    for (name, ftype) in types {
+        let attrs = attributes(&server, name, &[FATTR4_TYPE]).await;
+        assert_eq!(u32_at(&attrs.attr_vals, 0), ftype, "{}", name);
+    }
+
+    // Devices have their numbers in rawdev, and nothing else does.
+    for (name, major, minor) in [("chr", 1, 3), ("blk", 7, 0), ("file", 0, 0)] {
+        let attrs = attributes(&server, name, &[FATTR4_RAWDEV]).await;
+        assert_eq!((u32_at(&attrs.attr_vals, 0), u32_at(&attrs.attr_vals, 4)), (major, minor), "{}", name);
+    }
+}
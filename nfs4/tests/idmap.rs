@@ -0,0 +1,105 @@
+// Owner names: ids mapped to and from "name@domain" strings, the mapping
+// files they come from, and what the server sends and accepts with them.
+
+use std::os::unix::fs::MetadataExt;
+
+use nfs4::idmap::IdMap;
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+
+mod common;
+use common::{getattr, lookup, putrootfh, run, setattr, status, xdr_string};
+
+fn mapping(contents: &str) -> anyhow::Result<IdMap> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("idmap");
+    std::fs::write(&path, contents).unwrap();
+    IdMap::from_file(&path, "Example.COM")
+}
+
+const MAPPING: &str = "\
+# test accounts
+user alice 1000
+
+user bob 1001
+user alias 1000
+group staff 50
+";
+
+#[test]
+fn names_map_both_ways() {
+    let idmap = mapping(MAPPING).unwrap();
+    assert_eq!(idmap.domain(), "example.com");
+    assert_eq!(idmap.user_name(1000), "alice@example.com");
+    assert_eq!(idmap.user_name(1001), "bob@example.com");
+    assert_eq!(idmap.group_name(50), "staff@example.com");
+    assert_eq!(idmap.uid("alice@example.com"), Some(1000));
+    assert_eq!(idmap.uid("bob@EXAMPLE.com"), Some(1001));
+    // A second name for an id is accepted, but not sent.
+    assert_eq!(idmap.uid("alias@example.com"), Some(1000));
+    assert_eq!(idmap.gid("staff@example.com"), Some(50));
+
+    // Ids without a name go out as numbers, and numbers are taken as ids.
+    assert_eq!(idmap.user_name(7), "7");
+    assert_eq!(idmap.group_name(1000), "1000");
+    assert_eq!(idmap.uid("7"), Some(7));
+
+    // Names that are not ours are unknown.
+    assert_eq!(idmap.uid("alice@example.org"), None);
+    assert_eq!(idmap.uid("alice"), None);
+    assert_eq!(idmap.uid("carol@example.com"), None);
+    assert_eq!(idmap.gid("alice@example.com"), None);
+}
+
+#[test]
+fn numeric_ids_send_no_names() {
+    let idmap = IdMap::numeric();
+    assert_eq!(idmap.user_name(1000), "1000");
+    assert_eq!(idmap.group_name(0), "0");
+    assert_eq!(idmap.uid("1000"), Some(1000));
+    assert_eq!(idmap.uid("root@localdomain"), None);
+}
+
+#[test]
+fn bad_mapping_files() {
+    for bad in ["user alice", "user alice 1000 extra", "user alice x", "user alice -1", "owner alice 1", "user a@b 1"] {
+        assert!(mapping(bad).is_err(), "{:?}", bad);
+    }
+    let missing = IdMap::from_file(std::path::Path::new("/nonexistent/idmap"), "example.com");
+    assert!(missing.is_err());
+}
+
+fn owner(name: &str) -> NfsOperation {
+    setattr(vec![0, 1 << (FATTR4_OWNER - 32)], xdr_string(name))
+}
+
+#[tokio::test]
+async fn owners_travel_as_names() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "f").unwrap();
+    let server = server.with_idmap(mapping(MAPPING).unwrap());
+
+    let ops = vec![putrootfh(), lookup("f"), owner("bob@example.com")];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+    assert_eq!(std::fs::metadata(dir.path().join("f")).unwrap().uid(), 1001);
+
+    let ops = vec![putrootfh(), lookup("f"), getattr(vec![0, 1 << (FATTR4_OWNER - 32)])];
+    match common::last_result(run(&server, ops).await) {
+        OperationData::GetAttr(attrs) => assert_eq!(attrs.attr_vals, xdr_string("bob@example.com")),
+        other => panic!("GETATTR: {:?}", other),
+    }
+
+    for unknown in ["carol@example.com", "bob@example.org", "bob"] {
+        let ops = vec![putrootfh(), lookup("f"), owner(unknown)];
+        assert_eq!(status(run(&server, ops).await), NfsStatus::BadOwner, "{:?}", unknown);
+    }
+    assert_eq!(std::fs::metadata(dir.path().join("f")).unwrap().uid(), 1001);
+
+    // Without names, the same file's owner goes out as its id.
+    let numeric = NfsServer::new(dir.path().to_path_buf()).unwrap();
+    let ops = vec![putrootfh(), lookup("f"), getattr(vec![0, 1 << (FATTR4_OWNER - 32)])];
+    match common::last_result(run(&numeric, ops).await) {
+        OperationData::GetAttr(attrs) => assert_eq!(attrs.attr_vals, xdr_string("1001")),
+        other => panic!("GETATTR: {:?}", other),
+    }
+}
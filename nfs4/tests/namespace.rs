@@ -0,0 +1,128 @@
+// REMOVE and RENAME: the change_info4 they return for the directories they
+// change, and the errors for names that cannot be removed or replaced.
+
+use std::time::Duration;
+
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+
+mod common;
+use common::{create_dir, getattr, lookup, putrootfh, remove, rename, run, savefh, status};
+
+// The change attribute of the directory `dir` leads to.
+async fn change(server: &NfsServer, mut dir: Vec<NfsOperation>) -> u64 {
+    dir.push(getattr(vec![1 << FATTR4_CHANGE]));
+    match common::last_result(run(server, dir).await) {
+        OperationData::GetAttr(attrs) => u64::from_be_bytes(attrs.attr_vals[..8].try_into().unwrap()),
+        other => panic!("GETATTR: {:?}", other),
+    }
+}
+
+// The change attribute comes from the ctime, which may only move on with
+// the next clock tick.
+async fn tick() {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn remove_reports_the_change_to_the_directory() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "").unwrap();
+    let before = change(&server, vec![putrootfh()]).await;
+    tick().await;
+
+    let cinfo = match common::last_result(run(&server, vec![putrootfh(), remove("f")]).await) {
+        OperationData::Remove(cinfo) => cinfo,
+        other => panic!("REMOVE: {:?}", other),
+    };
+    let after = change(&server, vec![putrootfh()]).await;
+    assert_ne!(before, after);
+    assert_eq!(cinfo, ChangeInfo { atomic: false, before, after });
+    assert!(!dir.path().join("f").exists());
+}
+
+#[tokio::test]
+async fn remove_errors() {
+    let (dir, server) = common::server();
+    std::fs::create_dir_all(dir.path().join("d/e")).unwrap();
+    std::fs::write(dir.path().join("f"), "").unwrap();
+
+    assert_eq!(status(run(&server, vec![putrootfh(), remove("missing")]).await), NfsStatus::NoEnt);
+    assert_eq!(status(run(&server, vec![putrootfh(), remove("d")]).await), NfsStatus::NotEmpty);
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("f"), remove("x")]).await), NfsStatus::NotDir);
+    assert_eq!(status(run(&server, vec![putrootfh(), remove("..")]).await), NfsStatus::BadName);
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("d"), remove("e")]).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, vec![putrootfh(), remove("d")]).await), NfsStatus::Ok);
+}
+
+#[tokio::test]
+async fn rename_reports_the_change_to_both_directories() {
+    let (dir, server) = common::server();
+    std::fs::create_dir(dir.path().join("a")).unwrap();
+    std::fs::create_dir(dir.path().join("b")).unwrap();
+    std::fs::write(dir.path().join("a/f"), "").unwrap();
+    let a = || vec![putrootfh(), lookup("a")];
+    let b = || vec![putrootfh(), lookup("b")];
+    let (a_before, b_before) = (change(&server, a()).await, change(&server, b()).await);
+    tick().await;
+
+    let ops = vec![putrootfh(), lookup("a"), savefh(), putrootfh(), lookup("b"), rename("f", "g")];
+    let res = match common::last_result(run(&server, ops).await) {
+        OperationData::Rename(res) => res,
+        other => panic!("RENAME: {:?}", other),
+    };
+    let (a_after, b_after) = (change(&server, a()).await, change(&server, b()).await);
+    assert_ne!(a_before, a_after);
+    assert_ne!(b_before, b_after);
+    assert_eq!(
+        res,
+        RenameResult {
+            source_cinfo: ChangeInfo {
+                atomic: false,
+                before: a_before,
+                after: a_after,
+            },
+            target_cinfo: ChangeInfo {
+                atomic: false,
+                before: b_before,
+                after: b_after,
+            },
+        }
+    );
+    assert!(dir.path().join("b/g").exists());
+}
+
+#[tokio::test]
+async fn rename_keeps_files_and_directories_apart() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("f"), "").unwrap();
+    std::fs::hard_link(dir.path().join("f"), dir.path().join("link")).unwrap();
+    std::fs::create_dir(dir.path().join("d")).unwrap();
+    std::fs::create_dir_all(dir.path().join("full/x")).unwrap();
+    let rename = |old: &str, new: &str| vec![putrootfh(), savefh(), putrootfh(), rename(old, new)];
+
+    assert_eq!(status(run(&server, rename("f", "d")).await), NfsStatus::IsDir);
+    assert_eq!(status(run(&server, rename("d", "f")).await), NfsStatus::Exist);
+    assert_eq!(status(run(&server, rename("d", "full")).await), NfsStatus::NotEmpty);
+    assert_eq!(status(run(&server, rename("missing", "g")).await), NfsStatus::NoEnt);
+    assert_eq!(status(run(&server, rename("f", "full/x")).await), NfsStatus::BadName);
+    let ops = vec![putrootfh(), lookup("f"), savefh(), putrootfh(), common::rename("x", "y")];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::NotDir);
+
+    // Renaming onto another link to the same file leaves both alone.
+    let before = change(&server, vec![putrootfh()]).await;
+    match common::last_result(run(&server, rename("f", "link")).await) {
+        OperationData::Rename(res) => assert_eq!(res.source_cinfo, ChangeInfo { atomic: true, before, after: before }),
+        other => panic!("RENAME: {:?}", other),
+    }
+    assert!(dir.path().join("f").exists() && dir.path().join("link").exists());
+
+    // A directory may replace an empty one, and a file another file.
+    assert_eq!(status(run(&server, vec![putrootfh(), create_dir("empty")]).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, rename("d", "empty")).await), NfsStatus::Ok);
+    std::fs::remove_file(dir.path().join("link")).unwrap();
+    std::fs::write(dir.path().join("g"), "g").unwrap();
+    assert_eq!(status(run(&server, rename("g", "f")).await), NfsStatus::Ok);
+    assert_eq!(std::fs::read(dir.path().join("f")).unwrap(), b"g");
+    assert!(!dir.path().join("d").exists() && dir.path().join("empty").is_dir());
+}
@@ -0,0 +1,109 @@
+// READDIR: listings longer than one reply are read a page at a time, each
+// continuing from the cookie of the last entry of the one before, for as
+// long as the directory is unchanged.
+
+use std::time::Duration;
+
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{putrootfh, run, status};
+
+fn readdir(cookie: u64, cookieverf: [u8; 8], maxcount: u32) -> NfsOperation {
+    NfsOperation::ReadDir(ReadDirOperation {
+        cookie,
+        cookieverf,
+        dircount: 0,
+        maxcount,
+        attr_request: vec![1 << FATTR4_SIZE],
+    })
+}
+
+async fn page(server: &NfsServer, cookie: u64, cookieverf: [u8; 8], maxcount: u32) -> ReadDirResult {
+    match common::last_result(run(server, vec![putrootfh(), readdir(cookie, cookieverf, maxcount)]).await) {
+        OperationData::ReadDir(res) => res,
+        other => panic!("READDIR: {:?}", other),
+    }
+}
+
+// A server for a scratch directory holding ten files.
+fn ten_files() -> (TempDir, NfsServer) {
+    let (dir, server) = common::server();
+    for i in 0..10 {
+        std::fs::write(dir.path().join(format!("f{}", i)), "").unwrap();
+    }
+    (dir, server)
+}
+
+// After the 16 bytes of an empty reply, each entry takes 4 + 8 + 4 + 4
+// for the value-follows flag, cookie and name, and 4 + 4 + 4 + 8 for its
+// size attribute.
+const THREE_ENTRIES: u32 = 16 + 3 * 40;
+
+#[tokio::test]
+async fn listings_continue_from_the_last_cookie() {
+    let (_dir, server) = ten_files();
+
+    let mut names = Vec::new();
+    let mut pages = 0;
+    let (mut cookie, mut cookieverf) = (0, [0; 8]);
+    loop {
+        let res = page(&server, cookie, cookieverf, THREE_ENTRIES).await;
+        assert!(!res.entries.is_empty());
+        assert!(res.entries.len() <= 3);
+        if pages > 0 {
+            assert_eq!(res.cookieverf, cookieverf);
+        }
+        pages += 1;
+        for entry in &res.entries {
+            assert!(entry.cookie > cookie && entry.cookie > 2, "{:?}", entry);
+            cookie = entry.cookie;
+        }
+        names.extend(res.entries.into_iter().map(|entry| entry.name));
+        cookieverf = res.cookieverf;
+        if res.eof {
+            break;
+        }
+    }
+    assert_eq!(pages, 4);
+    let expected: Vec<String> = (0..10).map(|i| format!("f{}", i)).collect();
+    assert_eq!(names, expected);
+
+    // Starting from the last cookie gives nothing more.
+    let res = page(&server, cookie, cookieverf, THREE_ENTRIES).await;
+    assert!(res.entries.is_empty() && res.eof);
+}
+
+#[tokio::test]
+async fn cookies_are_only_good_while_the_directory_is_unchanged() {
+    let (dir, server) = ten_files();
+    let res = page(&server, 0, [0; 8], THREE_ENTRIES).await;
+    let cookie = res.entries.last().unwrap().cookie;
+    let readdir = |cookie, cookieverf| vec![putrootfh(), readdir(cookie, cookieverf, THREE_ENTRIES)];
+
+    assert_eq!(status(run(&server, readdir(cookie, [0xff; 8])).await), NfsStatus::NotSame);
+    // Cookies the server never gave out.
+    assert_eq!(status(run(&server, readdir(1, res.cookieverf)).await), NfsStatus::BadCookie);
+    assert_eq!(status(run(&server, readdir(1000, res.cookieverf)).await), NfsStatus::BadCookie);
+
+    // The change attribute comes from the ctime, which may only move on
+    // with the next clock tick.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    std::fs::write(dir.path().join("new"), "").unwrap();
+    assert_eq!(status(run(&server, readdir(cookie, res.cookieverf)).await), NfsStatus::NotSame);
+    // Starting over is always allowed, whatever the verifier.
+    let again = page(&server, 0, res.cookieverf, THREE_ENTRIES).await;
+    assert_ne!(again.cookieverf, res.cookieverf);
+}
+
+#[tokio::test]
+async fn replies_too_small_for_an_entry_are_refused() {
+    let (_dir, server) = ten_files();
+    let ops = vec![putrootfh(), readdir(0, [0; 8], 16 + 39)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::TooSmall);
+    let res = page(&server, 0, [0; 8], 16 + 40).await;
+    assert_eq!(res.entries.len(), 1);
+    assert!(!res.eof);
+}
@@ -0,0 +1,240 @@
+// RPC calls laid out by hand from RFC 5531, and the replies the dispatcher
+// must give to them, and the record marking they travel in. Each reply is
+// checked word for word.
+
+use bytes::{BufMut, BytesMut};
+use nfs4::dispatch::dispatch;
+use nfs4::rpc::{write_record, write_rpc_message, RecordError, RecordReader, RpcMsg};
+use nfs4::NfsServer;
+
+mod common;
+use common::{server, words};
+
+const CLIENT: &str = "192.0.2.1";
+
+// A call with an AUTH_NONE credential and verifier.
+fn call(rpc_vers: u32, prog: u32, vers: u32, proc: u32, args: &[u32]) -> Vec<u8> {
+    let mut call = words(&[
+        0x00000042, // xid
+        0x00000000, // CALL
+        rpc_vers,
+        prog,
+        vers,
+        proc,
+        0x00000000, 0x00000000, // cred AUTH_NONE
+        0x00000000, 0x00000000, // verf AUTH_NONE
+    ]);
+    call.extend(words(args));
+    call
+}
+
+async fn reply(server: &NfsServer, call: &[u8]) -> Option<Vec<u8>> {
+    let msg = RpcMsg::decode(call).unwrap();
+    dispatch(server, CLIENT.parse().unwrap(), msg).await.map(|reply| reply.encode())
+}
+
+// An accepted reply with an AUTH_NONE verifier, then `rest`.
+fn accepted(rest: &[u32]) -> Vec<u8> {
+    let mut reply = words(&[
+        0x00000042, // xid
+        0x00000001, // REPLY
+        0x00000000, // MSG_ACCEPTED
+        0x00000000, 0x00000000, // verf AUTH_NONE
+    ]);
+    reply.extend(words(rest));
+    reply
+}
+
+#[tokio::test]
+async fn null_procedure() {
+    let (_dir, server) = server();
+    let reply = reply(&server, &call(2, 100003, 4, 0, &[])).await;
+    assert_eq!(reply, Some(accepted(&[0x00000000]))); // SUCCESS, no results
+}
+
+#[tokio::test]
+async fn compound_procedure() {
+    let (_dir, server) = server();
+    let args = [
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x00000018, // OP_PUTROOTFH
+    ];
+    let reply = reply(&server, &call(2, 100003, 4, 1, &args)).await;
+    let results = [
+        0x00000000, // SUCCESS
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x00000018, // OP_PUTROOTFH
+        0x00000000, // NFS4_OK
+    ];
+    assert_eq!(reply, Some(accepted(&results)));
+}
+
+#[tokio::test]
+async fn unavailable_and_mismatched_calls() {
+    let (_dir, server) = server();
+    let reply_to = |call: Vec<u8>| {
+        let server = server.clone();
+        async move { reply(&server, &call).await.unwrap() }
+    };
+
+    // Unknown procedure of the NFS program
+    assert_eq!(reply_to(call(2, 100003, 4, 2, &[])).await, accepted(&[0x00000003])); // PROC_UNAVAIL
+
+    // MOUNT, which NFSv4 does without
+    assert_eq!(reply_to(call(2, 100005, 3, 0, &[])).await, accepted(&[0x00000001])); // PROG_UNAVAIL
+
+    // NFSv3, with the versions the server does have
+    let mismatch = accepted(&[
+        0x00000002, // PROG_MISMATCH
+        0x00000004, // low
+        0x00000004, // high
+    ]);
+    assert_eq!(reply_to(call(2, 100003, 3, 0, &[])).await, mismatch);
+
+    // RPC version 3
+    let rpc_mismatch = words(&[
+        0x00000042, // xid
+        0x00000001, // REPLY
+        0x00000001, // MSG_DENIED
+        0x00000000, // RPC_MISMATCH
+        0x00000002, // low
+        0x00000002, // high
+    ]);
+    assert_eq!(reply_to(call(3, 100003, 4, 0, &[])).await, rpc_mismatch);
+}
+
+#[tokio::test]
+async fn garbage_args() {
+    let (_dir, server) = server();
+
+    // COMPOUND cut off in the middle of its operation count
+    let mut truncated = call(2, 100003, 4, 1, &[0x00000000, 0x00000000]);
+    truncated.extend([0x00, 0x00]);
+    assert_eq!(reply(&server, &truncated).await, Some(accepted(&[0x00000004]))); // GARBAGE_ARGS
+
+    // An operation number that does not exist is not garbage: it gets an
+    // OP_ILLEGAL result in an ordinary reply.
+    let unknown_op = call(2, 100003, 4, 1, &[0x00000000, 0x00000000, 0x00000001, 0x0000ffff]);
+    let results = [
+        0x00000000, // SUCCESS
+        0x0000273c, // NFS4ERR_OP_ILLEGAL
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x0000273c, // OP_ILLEGAL
+        0x0000273c, // NFS4ERR_OP_ILLEGAL
+    ];
+    assert_eq!(reply(&server, &unknown_op).await, Some(accepted(&results)));
+}
+
+#[tokio::test]
+async fn bad_credentials() {
+    let (_dir, server) = server();
+    let mut call = call(2, 100003, 4, 0, &[]);
+    call[24..28].copy_from_slice(&6u32.to_be_bytes()); // RPCSEC_GSS
+    let reply = reply(&server, &call).await;
+    let denied = words(&[
+        0x00000042, // xid
+        0x00000001, // REPLY
+        0x00000001, // MSG_DENIED
+        0x00000001, // AUTH_ERROR
+        0x00000001, // AUTH_BADCRED
+    ]);
+    assert_eq!(reply, Some(denied));
+}
+
+#[tokio::test]
+async fn replies_are_not_answered() {
+    let (_dir, server) = server();
+    assert_eq!(reply(&server, &accepted(&[0x00000000])).await, None);
+}
+
+#[test]
+fn malformed_headers() {
+    // Neither a CALL nor a REPLY
+    assert!(RpcMsg::decode(&words(&[0x00000042, 0x00000002])).is_err());
+    // Credential body longer than the 400 bytes RFC 5531 allows
+    let mut call = call(2, 100003, 4, 0, &[]);
+    call.truncate(28);
+    call.extend(words(&[401]));
+    call.extend(vec![0; 404]);
+    call.extend(words(&[0, 0]));
+    assert!(RpcMsg::decode(&call).is_err());
+}
+
+#[test]
+fn records_of_several_fragments() {
+    let mut reader = RecordReader::new(1024);
+    let mut buf = BytesMut::new();
+    buf.extend(words(&[
+        0x00000004, // fragment of 4 bytes
+        0x00000001,
+        0x80000008, // last fragment, 8 bytes
+        0x00000002,
+        0x00000003,
+        0x80000004, // a second record, in one fragment
+        0x00000004,
+    ]));
+    assert_eq!(reader.read(&mut buf).unwrap().unwrap().to_vec(), words(&[1, 2, 3]));
+    assert_eq!(reader.read(&mut buf).unwrap().unwrap().to_vec(), words(&[4]));
+    assert!(reader.read(&mut buf).unwrap().is_none());
+
+    // Fragments arriving a piece at a time
+    let record = words(&[0x00000004, 0x00000005, 0x80000004, 0x00000006]);
+    for byte in &record[..record.len() - 1] {
+        buf.put_u8(*byte);
+        assert!(reader.read(&mut buf).unwrap().is_none());
+    }
+    buf.put_u8(record[record.len() - 1]);
+    assert_eq!(reader.read(&mut buf).unwrap().unwrap().to_vec(), words(&[5, 6]));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn oversized_and_empty_records() {
+    // Refused on the fragment header, before the data arrives
+    let mut reader = RecordReader::new(16);
+    let mut buf = BytesMut::from(&words(&[0x80000014])[..]);
+    assert!(matches!(reader.read(&mut buf), Err(RecordError::TooLarge(16))));
+
+    // Fragments that are each small enough but add up to too much
+    let mut reader = RecordReader::new(16);
+    let mut buf = BytesMut::from(&words(&[0x0000000c, 0, 0, 0, 0x00000008])[..]);
+    assert!(matches!(reader.read(&mut buf), Err(RecordError::TooLarge(16))));
+
+    // Exactly the maximum is fine
+    let mut reader = RecordReader::new(16);
+    let mut buf = BytesMut::from(&words(&[0x80000010, 0, 0, 0, 0])[..]);
+    assert_eq!(reader.read(&mut buf).unwrap().unwrap().len(), 16);
+
+    let mut buf = BytesMut::from(&words(&[0x00000000, 0x80000000])[..]);
+    assert!(matches!(reader.read(&mut buf), Err(RecordError::Empty)));
+}
+
+#[test]
+fn written_records_are_fragmented() {
+    let data: Vec<u8> = (0..10).collect();
+    let mut buf = BytesMut::new();
+    write_record(&data, 4, &mut buf);
+    let mut expected = Vec::new();
+    expected.extend(words(&[0x00000004]));
+    expected.extend(&data[0..4]);
+    expected.extend(words(&[0x00000004]));
+    expected.extend(&data[4..8]);
+    expected.extend(words(&[0x80000002]));
+    expected.extend(&data[8..10]);
+    assert_eq!(buf.to_vec(), expected);
+
+    let mut reader = RecordReader::new(1024);
+    assert_eq!(reader.read(&mut buf).unwrap().unwrap().to_vec(), data);
+
+    // A reply of a single fragment
+    let reply = RpcMsg::new_success_reply(0x42, Vec::new());
+    write_rpc_message(&reply, &mut buf);
+    let mut expected = words(&[0x80000018]);
+    expected.extend(accepted(&[0x00000000]));
+    assert_eq!(buf.to_vec(), expected);
+}
@@ -0,0 +1,124 @@
+// NFSv4.0 open-owner seqids: each OPEN, OPEN_CONFIRM and CLOSE of an
+// owner carries the next seqid, a retransmission of the last one gets the
+// same reply again without being executed twice, and anything else is
+// NFS4ERR_BAD_SEQID.
+
+use nfs4::protocol::*;
+
+mod common;
+use common::{getfh, lookup, open_as, putrootfh, read, run, status};
+
+const OWNER: &[u8] = b"seqid";
+
+fn create(seqid: u32, clientid: u64, name: &str) -> NfsOperation {
+    let how = OpenHow::Create(CreateHow::Unchecked(Fattr4::default()));
+    open_as(clientid, OWNER, seqid, how, OpenClaim::Null(name.to_string()))
+}
+
+fn open_confirm(seqid: u32, open_stateid: [u8; 16]) -> NfsOperation {
+    NfsOperation::OpenConfirm(OpenConfirmOperation { open_stateid, seqid })
+}
+
+fn close(seqid: u32, open_stateid: [u8; 16]) -> NfsOperation {
+    NfsOperation::Close(CloseOperation { seqid, open_stateid })
+}
+
+fn opened(response: &CompoundResponse) -> OpenResult {
+    match &response.results[1].result {
+        Some(OperationData::Open(res)) => res.clone(),
+        other => panic!("OPEN: {:?}", other),
+    }
+}
+
+fn confirmed(response: CompoundResponse) -> [u8; 16] {
+    match common::last_result(response) {
+        OperationData::OpenConfirm(stateid) => stateid,
+        other => panic!("OPEN_CONFIRM: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn retransmitted_opens_get_the_same_reply() {
+    let (dir, server) = common::server();
+    let clientid = common::client(&server, b"seqid").await;
+
+    let first = run(&server, vec![putrootfh(), create(0, clientid, "f"), getfh()]).await;
+    let res = opened(&first);
+    assert_ne!(res.rflags & OPEN4_RESULT_CONFIRM, 0);
+    // The file was created by the first OPEN, and is not created again:
+    // the retransmission gets the change_info of the first, and the
+    // filehandle it left current.
+    std::fs::write(dir.path().join("f"), "written in between").unwrap();
+    let again = run(&server, vec![putrootfh(), create(0, clientid, "f"), getfh()]).await;
+    assert_eq!(again, first);
+    assert_eq!(std::fs::read(dir.path().join("f")).unwrap(), b"written in between");
+
+    let stateid = confirmed(run(&server, vec![open_confirm(1, res.stateid)]).await);
+    assert_eq!(&stateid[4..], &res.stateid[4..]);
+    assert_eq!(confirmed(run(&server, vec![open_confirm(1, res.stateid)]).await), stateid);
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("f"), read(stateid, 0, 100)]).await), NfsStatus::Ok);
+}
+
+#[tokio::test]
+async fn opens_must_be_confirmed_before_use() {
+    let (_dir, server) = common::server();
+    let clientid = common::client(&server, b"seqid").await;
+
+    let res = opened(&run(&server, vec![putrootfh(), create(0, clientid, "f")]).await);
+    let ops = vec![putrootfh(), lookup("f"), read(res.stateid, 0, 100)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    assert_eq!(status(run(&server, vec![open_confirm(5, res.stateid)]).await), NfsStatus::BadSeqid);
+
+    // An unconfirmed owner that opens again starts over, and the first
+    // stateid is forgotten.
+    let res2 = opened(&run(&server, vec![putrootfh(), create(7, clientid, "g")]).await);
+    assert_ne!(res2.rflags & OPEN4_RESULT_CONFIRM, 0);
+    assert_eq!(status(run(&server, vec![open_confirm(8, res.stateid)]).await), NfsStatus::BadStateid);
+    confirmed(run(&server, vec![open_confirm(8, res2.stateid)]).await);
+
+    // Only the first OPEN of an owner is confirmed.
+    let res3 = opened(&run(&server, vec![putrootfh(), create(9, clientid, "f")]).await);
+    assert_eq!(res3.rflags & OPEN4_RESULT_CONFIRM, 0);
+    assert_eq!(status(run(&server, vec![open_confirm(10, res3.stateid)]).await), NfsStatus::BadStateid);
+}
+
+#[tokio::test]
+async fn out_of_sequence_requests_are_refused() {
+    let (_dir, server) = common::server();
+    let clientid = common::client(&server, b"seqid").await;
+
+    let res = opened(&run(&server, vec![putrootfh(), create(0, clientid, "f")]).await);
+    let stateid = confirmed(run(&server, vec![open_confirm(1, res.stateid)]).await);
+
+    // Ahead of the sequence, behind it, and the last seqid for an
+    // operation other than the last one.
+    for seqid in [3, 0, 1] {
+        let ops = vec![putrootfh(), create(seqid, clientid, "f")];
+        assert_eq!(status(run(&server, ops).await), NfsStatus::BadSeqid, "{}", seqid);
+    }
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("f"), close(5, stateid)]).await), NfsStatus::BadSeqid);
+
+    // None of them moved the sequence on.
+    let res = opened(&run(&server, vec![putrootfh(), create(2, clientid, "f")]).await);
+    assert_eq!(res.rflags & OPEN4_RESULT_CONFIRM, 0);
+}
+
+#[tokio::test]
+async fn retransmitted_closes_get_the_same_reply() {
+    let (_dir, server) = common::server();
+    let clientid = common::client(&server, b"seqid").await;
+
+    let res = opened(&run(&server, vec![putrootfh(), create(0, clientid, "f")]).await);
+    let stateid = confirmed(run(&server, vec![open_confirm(1, res.stateid)]).await);
+
+    let first = run(&server, vec![putrootfh(), lookup("f"), close(2, stateid)]).await;
+    assert_eq!(first.status, NfsStatus::Ok);
+    // The open is gone, but the reply to closing it is not.
+    let again = run(&server, vec![putrootfh(), lookup("f"), close(2, stateid)]).await;
+    assert_eq!(again, first);
+
+    let ops = vec![putrootfh(), lookup("f"), read(stateid, 0, 100)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    let ops = vec![putrootfh(), lookup("f"), close(3, stateid)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+}
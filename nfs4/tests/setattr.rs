@@ -0,0 +1,220 @@
+// SETATTR, and the attributes CREATE and OPEN apply to what they create:
+// which attributes are set, in what order, and the attrsset bitmap that
+// says which were, even when a later one fails.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nfs4::auth::Credentials;
+use nfs4::protocol::*;
+
+mod common;
+use common::{lookup, putrootfh, run, run_as, setattr, setattr_mode, setattr_size, status, xdr_string};
+
+// A bitmap4 with the given attributes.
+fn bitmap(attrs: &[u32]) -> Vec<u32> {
+    let mut bitmap = vec![0; 2];
+    for attr in attrs {
+        bitmap[(attr / 32) as usize] |= 1 << (attr % 32);
+    }
+    bitmap
+}
+
+fn client_time(seconds: i64, nseconds: u32) -> Vec<u8> {
+    let mut value = SET_TO_CLIENT_TIME4.to_be_bytes().to_vec();
+    value.extend(seconds.to_be_bytes());
+    value.extend(nseconds.to_be_bytes());
+    value
+}
+
+// The attrsset of the last SETATTR, which is returned whether or not it
+// succeeded. One that failed before setting anything goes out with an
+// empty bitmap.
+fn attrsset(response: &CompoundResponse) -> Vec<u32> {
+    match &response.results.last().unwrap().result {
+        Some(OperationData::SetAttr(attrsset)) => attrsset.clone(),
+        None => Vec::new(),
+        other => panic!("SETATTR: {:?}", other),
+    }
+}
+
+// Bitmaps compare equal whatever trailing zero words they have.
+fn trimmed(mut bitmap: Vec<u32>) -> Vec<u32> {
+    while bitmap.last() == Some(&0) {
+        bitmap.pop();
+    }
+    bitmap
+}
+
+#[tokio::test]
+async fn size_truncates_and_extends() {
+    let (dir, server) = common::server();
+    let path = dir.path().join("f");
+    std::fs::write(&path, "0123456789").unwrap();
+
+    let response = run(&server, vec![putrootfh(), lookup("f"), setattr_size(4)]).await;
+    assert_eq!(response.status, NfsStatus::Ok);
+    assert_eq!(trimmed(attrsset(&response)), trimmed(bitmap(&[FATTR4_SIZE])));
+    assert_eq!(std::fs::read(&path).unwrap(), b"0123");
+
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("f"), setattr_size(6)]).await), NfsStatus::Ok);
+    assert_eq!(std::fs::read(&path).unwrap(), b"0123\0\0");
+}
+
+// A stateid only covers I/O to the file it was opened on.
+#[tokio::test]
+async fn size_changes_need_a_stateid_for_the_file() {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("a"), "aaaa").unwrap();
+    std::fs::write(dir.path().join("b"), "bbbb").unwrap();
+    let clientid = common::client(&server, b"setattr stateid").await;
+    let open = |name| common::open_file(&server, clientid, vec![putrootfh()], name, OPEN4_SHARE_ACCESS_BOTH, 0);
+    let (a, b) = (open("a").await.unwrap(), open("b").await.unwrap());
+    let truncate = |stateid| {
+        NfsOperation::SetAttr(SetAttrOperation {
+            stateid,
+            attributes: Fattr4 {
+                attrmask: vec![1 << FATTR4_SIZE],
+                attr_vals: 0u64.to_be_bytes().to_vec(),
+            },
+        })
+    };
+
+    let ops = vec![putrootfh(), lookup("b"), truncate(a)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    let ops = vec![putrootfh(), lookup("b"), common::write(a, 0, b"a")];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    let ops = vec![putrootfh(), lookup("b"), common::read(a, 0, 4)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::BadStateid);
+    assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b"bbbb");
+
+    let ops = vec![putrootfh(), lookup("b"), truncate(b)];
+    assert_eq!(status(run(&server, ops).await), NfsStatus::Ok);
+    assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b"");
+}
+
+#[tokio::test]
+async fn mode_and_ownership() {
+    let (dir, server) = common::server();
+    let path = dir.path().join("f");
+    std::fs::write(&path, "").unwrap();
+
+    // Values follow in the order of their attribute numbers.
+    let mut values = 0o4640u32.to_be_bytes().to_vec();
+    values.extend(xdr_string("1000"));
+    values.extend(xdr_string("50"));
+    let set = setattr(bitmap(&[FATTR4_MODE, FATTR4_OWNER, FATTR4_OWNER_GROUP]), values);
+    let response = run(&server, vec![putrootfh(), lookup("f"), set]).await;
+    assert_eq!(response.status, NfsStatus::Ok);
+    assert_eq!(attrsset(&response), bitmap(&[FATTR4_MODE, FATTR4_OWNER, FATTR4_OWNER_GROUP]));
+
+    // The owner was changed first, or chown would have cleared the setuid
+    // bit the mode sets.
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o4640);
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 50));
+
+    // Only root may give a file away, and only its owner change its mode.
+    let ops = vec![putrootfh(), lookup("f"), setattr_mode(0o600)];
+    let response = run_as(&server, common::user(1001), ops).await;
+    assert_eq!(response.status, NfsStatus::Perm);
+    assert_eq!(trimmed(attrsset(&response)), Vec::<u32>::new());
+    let set = setattr(bitmap(&[FATTR4_OWNER]), xdr_string("1001"));
+    assert_eq!(status(run_as(&server, common::user(1000), vec![putrootfh(), lookup("f"), set]).await), NfsStatus::Perm);
+    let ops = vec![putrootfh(), lookup("f"), setattr_mode(0o600)];
+    assert_eq!(status(run_as(&server, common::user(1000), ops).await), NfsStatus::Ok);
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o600);
+
+    // The owner only keeps the setgid bit it asks for when it is in the
+    // file's group.
+    let ops = vec![putrootfh(), lookup("f"), setattr_mode(0o2750)];
+    assert_eq!(status(run_as(&server, common::user(1000), ops).await), NfsStatus::Ok);
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o750);
+    let member = Credentials {
+        gids: vec![50],
+        ..common::user(1000)
+    };
+    let ops = vec![putrootfh(), lookup("f"), setattr_mode(0o2750)];
+    assert_eq!(status(run_as(&server, member, ops).await), NfsStatus::Ok);
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o2750);
+}
+
+#[tokio::test]
+async fn times_from_the_client_or_the_server() {
+    let (dir, server) = common::server();
+    let path = dir.path().join("f");
+    std::fs::write(&path, "").unwrap();
+
+    let mut values = client_time(1_000_000, 5);
+    values.extend(client_time(2_000_000, 7));
+    let set = setattr(bitmap(&[FATTR4_TIME_ACCESS_SET, FATTR4_TIME_MODIFY_SET]), values);
+    let response = run(&server, vec![putrootfh(), lookup("f"), set]).await;
+    assert_eq!(response.status, NfsStatus::Ok);
+    assert_eq!(attrsset(&response), bitmap(&[FATTR4_TIME_ACCESS_SET, FATTR4_TIME_MODIFY_SET]));
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!((metadata.atime(), metadata.atime_nsec()), (1_000_000, 5));
+    assert_eq!((metadata.mtime(), metadata.mtime_nsec()), (2_000_000, 7));
+
+    let set = setattr(bitmap(&[FATTR4_TIME_MODIFY_SET]), SET_TO_SERVER_TIME4.to_be_bytes().to_vec());
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("f"), set]).await), NfsStatus::Ok);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert!((now - metadata.mtime()).abs() < 60, "{}", metadata.mtime());
+    assert_eq!(metadata.atime(), 1_000_000);
+}
+
+#[tokio::test]
+async fn attributes_set_before_a_failure_are_reported() {
+    let (dir, server) = common::server();
+    std::fs::create_dir(dir.path().join("d")).unwrap();
+    std::os::unix::fs::symlink("d", dir.path().join("s")).unwrap();
+
+    // A directory has no size to set, but its mode was set first.
+    let mut values = 0u64.to_be_bytes().to_vec();
+    values.extend(0o700u32.to_be_bytes());
+    let set = setattr(bitmap(&[FATTR4_SIZE, FATTR4_MODE]), values);
+    let response = run(&server, vec![putrootfh(), lookup("d"), set]).await;
+    assert_eq!(response.status, NfsStatus::IsDir);
+    assert_eq!(attrsset(&response), bitmap(&[FATTR4_MODE]));
+    assert_eq!(std::fs::metadata(dir.path().join("d")).unwrap().permissions().mode() & 0o7777, 0o700);
+
+    // A symlink has an owner, but no mode of its own.
+    let mut values = 0o600u32.to_be_bytes().to_vec();
+    values.extend(xdr_string("1000"));
+    let set = setattr(bitmap(&[FATTR4_MODE, FATTR4_OWNER]), values);
+    let response = run(&server, vec![putrootfh(), lookup("s"), set]).await;
+    assert_eq!(response.status, NfsStatus::Inval);
+    assert_eq!(attrsset(&response), bitmap(&[FATTR4_OWNER]));
+    assert_eq!(std::fs::symlink_metadata(dir.path().join("s")).unwrap().uid(), 1000);
+}
+
+#[tokio::test]
+async fn created_objects_get_their_attributes() {
+    let (dir, server) = common::server();
+    let clientid = common::client(&server, b"setattr").await;
+    let mode = |mode: u32| Fattr4 {
+        attrmask: bitmap(&[FATTR4_MODE]),
+        attr_vals: mode.to_be_bytes().to_vec(),
+    };
+
+    let create = NfsOperation::Create(CreateOperation {
+        object_type: NF4DIR,
+        link_data: None,
+        spec_data: None,
+        object_name: "d".to_string(),
+        attributes: mode(0o750),
+    });
+    match common::last_result(run(&server, vec![putrootfh(), create]).await) {
+        OperationData::Create(res) => assert_eq!(res.attrset, bitmap(&[FATTR4_MODE])),
+        other => panic!("CREATE: {:?}", other),
+    }
+    assert_eq!(std::fs::metadata(dir.path().join("d")).unwrap().permissions().mode() & 0o7777, 0o750);
+
+    let how = OpenHow::Create(CreateHow::Guarded(mode(0o604)));
+    let open = common::open(clientid, how, OpenClaim::Null("f".to_string()));
+    match common::last_result(run(&server, vec![putrootfh(), open]).await) {
+        OperationData::Open(res) => assert_eq!(res.attrset, bitmap(&[FATTR4_MODE])),
+        other => panic!("OPEN: {:?}", other),
+    }
+    assert_eq!(std::fs::metadata(dir.path().join("f")).unwrap().permissions().mode() & 0o7777, 0o604);
+}
@@ -0,0 +1,98 @@
+// Open state kept by the server for a file: share reservations, byte-range
+// locks and open-owner seqids. State belongs to the file rather than the
+// name it was opened by, so it applies through every link to the file and
+// stays with it when it is renamed.
+
+use nfs4::protocol::*;
+use nfs4::NfsServer;
+use tempfile::TempDir;
+
+mod common;
+use common::{lookup, open_file, putrootfh, rename, run, savefh, status};
+
+// A server for a scratch directory holding the file "a", with a second
+// link to it as "b", and a client of it.
+async fn linked() -> (TempDir, NfsServer, u64) {
+    let (dir, server) = common::server();
+    std::fs::write(dir.path().join("a"), "a").unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let clientid = common::client(&server, b"state").await;
+    (dir, server, clientid)
+}
+
+#[tokio::test]
+async fn share_reservations_apply_through_every_link() {
+    let (_dir, server, clientid) = linked().await;
+    let root = || vec![putrootfh()];
+
+    open_file(&server, clientid, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_WRITE).await.unwrap();
+    let write = open_file(&server, clientid, root(), "b", OPEN4_SHARE_ACCESS_WRITE, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(write, Err(NfsStatus::ShareDenied));
+    // Reading is still allowed, as long as it does not deny the access
+    // the first open has.
+    open_file(&server, clientid, root(), "b", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_NONE).await.unwrap();
+    let deny_read = open_file(&server, clientid, root(), "b", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_READ).await;
+    assert_eq!(deny_read, Err(NfsStatus::ShareDenied));
+}
+
+#[tokio::test]
+async fn share_reservations_follow_renames() {
+    let (_dir, server, clientid) = linked().await;
+    let root = || vec![putrootfh()];
+
+    open_file(&server, clientid, root(), "a", OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_DENY_WRITE).await.unwrap();
+    assert_eq!(status(run(&server, vec![putrootfh(), savefh(), putrootfh(), rename("a", "c")]).await), NfsStatus::Ok);
+    let write = open_file(&server, clientid, root(), "c", OPEN4_SHARE_ACCESS_WRITE, OPEN4_SHARE_DENY_NONE).await;
+    assert_eq!(write, Err(NfsStatus::ShareDenied));
+    // A new file under the old name is another file.
+    open_file(&server, clientid, root(), "a", OPEN4_SHARE_ACCESS_WRITE, OPEN4_SHARE_DENY_NONE).await.unwrap();
+}
+
+fn lock(open_stateid: [u8; 16], open_seqid: u32, owner: &[u8], clientid: u64) -> NfsOperation {
+    NfsOperation::Lock(LockOperation {
+        locktype: WRITE_LT,
+        reclaim: false,
+        offset: 0,
+        length: 100,
+        locker: Locker::New {
+            open_seqid,
+            open_stateid,
+            lock_seqid: 0,
+            lock_owner: LockOwner {
+                clientid,
+                owner: owner.to_vec(),
+            },
+        },
+    })
+}
+
+fn lockt(owner: &[u8], clientid: u64) -> NfsOperation {
+    NfsOperation::Lockt(LocktOperation {
+        locktype: WRITE_LT,
+        offset: 50,
+        length: 1,
+        owner: LockOwner {
+            clientid,
+            owner: owner.to_vec(),
+        },
+    })
+}
+
+#[tokio::test]
+async fn locks_apply_through_every_link_and_follow_renames() {
+    let (_dir, server, clientid) = linked().await;
+    let root = || vec![putrootfh()];
+
+    // The open-owner's OPEN and OPEN_CONFIRM took seqids 0 and 1.
+    let a = open_file(&server, clientid, root(), "a", OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_DENY_NONE).await.unwrap();
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("a"), lock(a, 2, b"x", clientid)]).await), NfsStatus::Ok);
+
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("b"), lockt(b"y", clientid)]).await), NfsStatus::Denied);
+    let b = open_file(&server, clientid, root(), "b", OPEN4_SHARE_ACCESS_BOTH, OPEN4_SHARE_DENY_NONE).await.unwrap();
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("b"), lock(b, 2, b"y", clientid)]).await), NfsStatus::Denied);
+
+    assert_eq!(status(run(&server, vec![putrootfh(), savefh(), putrootfh(), rename("a", "c")]).await), NfsStatus::Ok);
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("c"), lockt(b"y", clientid)]).await), NfsStatus::Denied);
+    // The lock-owner holding the lock may take it again.
+    assert_eq!(status(run(&server, vec![putrootfh(), lookup("c"), lockt(b"x", clientid)]).await), NfsStatus::Ok);
+}
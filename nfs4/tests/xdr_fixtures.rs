@@ -0,0 +1,709 @@
+// Wire images of COMPOUND calls and replies, laid out by hand from the
+// RFC 7531 and RFC 5662 XDR definitions. Each fixture must decode to the
+// expected structure and re-encode to the identical bytes.
+
+use nfs4::protocol::*;
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+#[test]
+fn lookup_getfh_getattr_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000003, // 3 operations
+        0x0000000f, // OP_LOOKUP
+        0x00000009, // objname length 9
+        0x68656c6c, 0x6f2e7478, 0x74000000, // "hello.txt" + padding
+        0x0000000a, // OP_GETFH
+        0x00000009, // OP_GETATTR
+        0x00000002, // bitmap4 length 2
+        0x00000012,
+        0x0020a032,
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request,
+        CompoundRequest {
+            tag: String::new(),
+            minor_version: 0,
+            operations: vec![
+                NfsOperation::Lookup(LookupOperation {
+                    object_name: "hello.txt".to_string(),
+                }),
+                NfsOperation::GetFh(GetFhOperation),
+                NfsOperation::GetAttr(GetAttrOperation {
+                    attr_request: vec![0x00000012, 0x0020a032],
+                }),
+            ],
+        }
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn open_create_unchecked_args() {
+    let bytes = words(&[
+        0x00000004, 0x6f70656e, // tag "open"
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x00000012, // OP_OPEN
+        0x00000001, // seqid
+        0x00000002, // OPEN4_SHARE_ACCESS_WRITE
+        0x00000000, // OPEN4_SHARE_DENY_NONE
+        0x01234567, 0x89abcdef, // clientid
+        0x00000008, 0x6f776e65, 0x722d3031, // owner "owner-01"
+        0x00000001, // OPEN4_CREATE
+        0x00000000, // UNCHECKED4
+        0x00000002, 0x00000000, 0x00000002, // attrmask {mode}
+        0x00000004, 0x000001a4, // mode 0644
+        0x00000000, // CLAIM_NULL
+        0x00000007, 0x6e65772e, 0x74787400, // "new.txt"
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(request.tag, "open");
+    assert_eq!(
+        request.operations,
+        vec![NfsOperation::Open(OpenOperation {
+            seqid: 1,
+            share_access: OPEN4_SHARE_ACCESS_WRITE,
+            share_deny: OPEN4_SHARE_DENY_NONE,
+            clientid: 0x0123456789abcdef,
+            owner: b"owner-01".to_vec(),
+            open_how: OpenHow::Create(CreateHow::Unchecked(Fattr4 {
+                attrmask: vec![0, 1 << (FATTR4_MODE - 32)],
+                attr_vals: vec![0x00, 0x00, 0x01, 0xa4],
+            })),
+            open_claim: OpenClaim::Null("new.txt".to_string()),
+        })]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn read_write_args() {
+    let stateid = [
+        0x00, 0x00, 0x00, 0x01, 0x5f, 0x3a, 0x12, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03,
+    ];
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000002, // 2 operations
+        0x00000026, // OP_WRITE
+        0x00000001, 0x5f3a1200, 0x00000002, 0x00000003, // stateid
+        0x00000000, 0x00001000, // offset 4096
+        0x00000002, // FILE_SYNC4
+        0x00000005, 0x68656c6c, 0x6f000000, // data "hello"
+        0x00000019, // OP_READ
+        0x00000001, 0x5f3a1200, 0x00000002, 0x00000003, // stateid
+        0x00000000, 0x00000000, // offset 0
+        0x00008000, // count 32768
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![
+            NfsOperation::Write(WriteOperation {
+                stateid,
+                offset: 4096,
+                stable: FILE_SYNC4,
+                data: b"hello".to_vec(),
+            }),
+            NfsOperation::Read(ReadOperation {
+                stateid,
+                offset: 0,
+                count: 32768,
+            }),
+        ]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn unknown_operation_stops_decoding() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000002, // 2 operations
+        0x00002710, // not an NFSv4 opcode
+        0xdeadbeef, // arguments the decoder cannot interpret
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(request.operations, vec![NfsOperation::Unsupported(10000)]);
+}
+
+#[test]
+fn truncated_args_are_rejected() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x0000000f, // OP_LOOKUP
+        0x00000009, // objname length 9, but no data follows
+    ]);
+
+    assert!(CompoundRequest::decode(&bytes).is_err());
+}
+
+#[test]
+fn lookup_getfh_getattr_res() {
+    let bytes = words(&[
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000003, // 3 results
+        0x0000000f, 0x00000000, // OP_LOOKUP, NFS4_OK
+        0x0000000a, 0x00000000, // OP_GETFH, NFS4_OK
+        0x00000008, 0x01020304, 0x05060708, // filehandle
+        0x00000009, 0x00000000, // OP_GETATTR, NFS4_OK
+        0x00000001, 0x00000012, // attrmask {type, size}
+        0x0000000c, 0x00000001, 0x00000000, 0x00000400, // NF4REG, size 1024
+    ]);
+
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response,
+        CompoundResponse {
+            tag: String::new(),
+            status: NfsStatus::Ok,
+            results: vec![
+                OperationResult::ok(OP_LOOKUP, None),
+                OperationResult::ok(
+                    OP_GETFH,
+                    Some(OperationData::GetFh(NfsFileHandle {
+                        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                    })),
+                ),
+                OperationResult::ok(
+                    OP_GETATTR,
+                    Some(OperationData::GetAttr(Fattr4 {
+                        attrmask: vec![0x00000012],
+                        attr_vals: words(&[NF4REG, 0, 1024]),
+                    })),
+                ),
+            ],
+        }
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn failed_compound_res() {
+    let bytes = words(&[
+        0x00000002, // NFS4ERR_NOENT
+        0x00000000, // tag ""
+        0x00000002, // 2 results
+        0x00000003, 0x00000000, // OP_ACCESS, NFS4_OK
+        0x0000001f, 0x00000003, // supported, access
+        0x0000000f, 0x00000002, // OP_LOOKUP, NFS4ERR_NOENT
+    ]);
+
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(response.status, NfsStatus::NoEnt);
+    assert_eq!(
+        response.results,
+        vec![
+            OperationResult::ok(
+                OP_ACCESS,
+                Some(OperationData::Access(AccessResult {
+                    supported: 0x1f,
+                    access: 0x03,
+                })),
+            ),
+            OperationResult::error(OP_LOOKUP, NfsStatus::NoEnt),
+        ]
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn open_read_write_res() {
+    let stateid = [0u8, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9];
+    let bytes = words(&[
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000003, // 3 results
+        0x00000012, 0x00000000, // OP_OPEN, NFS4_OK
+        0x00000001, 0x00000000, 0x00000000, 0x00000009, // stateid
+        0x00000000, // cinfo.atomic
+        0x00000000, 0x00000064, // cinfo.before
+        0x00000000, 0x000000c8, // cinfo.after
+        0x00000004, // OPEN4_RESULT_LOCKTYPE_POSIX
+        0x00000000, // attrset
+        0x00000000, // OPEN_DELEGATE_NONE
+        0x00000026, 0x00000000, // OP_WRITE, NFS4_OK
+        0x00000003, 0x00000002, // count 3, FILE_SYNC4
+        0x11223344, 0x55667788, // verifier
+        0x00000019, 0x00000000, // OP_READ, NFS4_OK
+        0x00000001, // eof
+        0x00000003, 0x61626300, // "abc"
+    ]);
+
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results,
+        vec![
+            OperationResult::ok(
+                OP_OPEN,
+                Some(OperationData::Open(OpenResult {
+                    stateid,
+                    change_info: ChangeInfo {
+                        atomic: false,
+                        before: 100,
+                        after: 200,
+                    },
+                    rflags: OPEN4_RESULT_LOCKTYPE_POSIX,
+                    attrset: vec![],
+                    delegation: OpenDelegation::None,
+                })),
+            ),
+            OperationResult::ok(
+                OP_WRITE,
+                Some(OperationData::Write(WriteResult {
+                    count: 3,
+                    committed: FILE_SYNC4,
+                    verifier: [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88],
+                })),
+            ),
+            OperationResult::ok(
+                OP_READ,
+                Some(OperationData::Read(ReadResult {
+                    eof: true,
+                    data: b"abc".to_vec(),
+                })),
+            ),
+        ]
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn filehandle_ops_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000005, // 5 operations
+        0x00000018, // OP_PUTROOTFH
+        0x00000020, // OP_SAVEFH
+        0x00000016, // OP_PUTFH
+        0x00000004, 0xcafef00d, // filehandle
+        0x0000001f, // OP_RESTOREFH
+        0x00000017, // OP_PUTPUBFH
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![
+            NfsOperation::PutRootFh(PutRootFhOperation),
+            NfsOperation::SaveFh(SaveFhOperation),
+            NfsOperation::PutFh(PutFhOperation {
+                object: NfsFileHandle {
+                    data: vec![0xca, 0xfe, 0xf0, 0x0d],
+                },
+            }),
+            NfsOperation::RestoreFh(RestoreFhOperation),
+            NfsOperation::PutPubFh(PutPubFhOperation),
+        ]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn setclientid_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x00000023, // OP_SETCLIENTID
+        0x01020304, 0x05060708, // verifier
+        0x00000006, 0x6c696e75, 0x78310000, // id "linux1"
+        0x40000000, // cb_program
+        0x00000003, 0x74637000, // r_netid "tcp"
+        0x0000000d, 0x3132372e, 0x302e302e, 0x312e342e, 0x31000000, // r_addr "127.0.0.1.4.1"
+        0x00000001, // callback_ident
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![NfsOperation::SetClientId(SetClientIdOperation {
+            client: NfsClientId {
+                verifier: [1, 2, 3, 4, 5, 6, 7, 8],
+                id: b"linux1".to_vec(),
+            },
+            callback: CbClient {
+                program: 0x40000000,
+                location: NetAddr {
+                    netid: "tcp".to_string(),
+                    addr: "127.0.0.1.4.1".to_string(),
+                },
+            },
+            callback_ident: 1,
+        })]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn setclientid_res() {
+    let bytes = words(&[
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x00000023, 0x00000000, // OP_SETCLIENTID, NFS4_OK
+        0x5f3a1200, 0x00000001, // clientid
+        0xaabbccdd, 0xeeff0011, // setclientid_confirm
+    ]);
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results,
+        vec![OperationResult::ok(
+            OP_SETCLIENTID,
+            Some(OperationData::SetClientId(SetClientIdResult {
+                clientid: 0x5f3a120000000001,
+                confirm: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x00, 0x11],
+            })),
+        )]
+    );
+    assert_eq!(response.encode(), bytes);
+
+    // NFS4ERR_CLID_INUSE carries the address the id is in use from.
+    let bytes = words(&[
+        0x00002721, // NFS4ERR_CLID_INUSE
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x00000023, 0x00002721, // OP_SETCLIENTID, NFS4ERR_CLID_INUSE
+        0x00000003, 0x74637000, // r_netid "tcp"
+        0x0000000a, 0x31302e30, 0x2e302e32, 0x2e340000, // r_addr "10.0.0.2.4"
+    ]);
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results[0].result,
+        Some(OperationData::ClientInUse(NetAddr {
+            netid: "tcp".to_string(),
+            addr: "10.0.0.2.4".to_string(),
+        }))
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn sequence_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000001, // minorversion 1
+        0x00000002, // 2 operations
+        0x00000035, // OP_SEQUENCE
+        0x00112233, 0x44556677, 0x8899aabb, 0xccddeeff, // sessionid
+        0x00000007, // sequenceid
+        0x00000002, // slotid
+        0x0000000f, // highest_slotid
+        0x00000001, // cachethis
+        0x00000018, // OP_PUTROOTFH
+    ]);
+    let sessionid = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+    ];
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(request.minor_version, 1);
+    assert_eq!(
+        request.operations,
+        vec![
+            NfsOperation::Sequence(SequenceOperation {
+                sessionid,
+                sequenceid: 7,
+                slotid: 2,
+                highest_slotid: 15,
+                cache_this: true,
+            }),
+            NfsOperation::PutRootFh(PutRootFhOperation),
+        ]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn sequence_res() {
+    let bytes = words(&[
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x00000035, 0x00000000, // OP_SEQUENCE, NFS4_OK
+        0x00112233, 0x44556677, 0x8899aabb, 0xccddeeff, // sessionid
+        0x00000007, // sequenceid
+        0x00000002, // slotid
+        0x0000003f, // highest_slotid
+        0x0000003f, // target_highest_slotid
+        0x00000000, // status_flags
+    ]);
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results,
+        vec![OperationResult::ok(
+            OP_SEQUENCE,
+            Some(OperationData::Sequence(SequenceResult {
+                sessionid: [
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+                ],
+                sequenceid: 7,
+                slotid: 2,
+                highest_slotid: 63,
+                target_highest_slotid: 63,
+                status_flags: 0,
+            })),
+        )]
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn lock_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x0000000c, // OP_LOCK
+        0x00000002, // WRITE_LT
+        0x00000000, // reclaim
+        0x00000000, 0x00001000, // offset
+        0xffffffff, 0xffffffff, // length, to end of file
+        0x00000001, // new_lock_owner
+        0x00000003, // open_seqid
+        0x00000002, 0x5f3a1200, 0x00000001, 0x00000007, // open_stateid
+        0x00000001, // lock_seqid
+        0x5f3a1200, 0x00000001, // clientid
+        0x00000004, 0x6c6f636b, // owner "lock"
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![NfsOperation::Lock(LockOperation {
+            locktype: WRITE_LT,
+            reclaim: false,
+            offset: 0x1000,
+            length: u64::MAX,
+            locker: Locker::New {
+                open_seqid: 3,
+                open_stateid: [0, 0, 0, 2, 0x5f, 0x3a, 0x12, 0, 0, 0, 0, 1, 0, 0, 0, 7],
+                lock_seqid: 1,
+                lock_owner: LockOwner {
+                    clientid: 0x5f3a120000000001,
+                    owner: b"lock".to_vec(),
+                },
+            },
+        })]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn lock_denied_res() {
+    // NFS4ERR_DENIED carries the conflicting lock.
+    let bytes = words(&[
+        0x0000271a, // NFS4ERR_DENIED
+        0x00000000, // tag ""
+        0x00000001, // 1 result
+        0x0000000c, 0x0000271a, // OP_LOCK, NFS4ERR_DENIED
+        0x00000000, 0x00000000, // offset
+        0x00000000, 0x00000064, // length
+        0x00000001, // READ_LT
+        0x5f3a1200, 0x00000002, // clientid
+        0x00000002, 0x6c620000, // owner "lb"
+    ]);
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results[0].result,
+        Some(OperationData::LockDenied(LockDenied {
+            offset: 0,
+            length: 100,
+            locktype: READ_LT,
+            owner: LockOwner {
+                clientid: 0x5f3a120000000002,
+                owner: b"lb".to_vec(),
+            },
+        }))
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn open_downgrade_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000001, // 1 operation
+        0x00000015, // OP_OPEN_DOWNGRADE
+        0x00000003, 0x5f3a1200, 0x00000001, 0x00000007, // open_stateid
+        0x00000005, // seqid
+        0x00000001, // OPEN4_SHARE_ACCESS_READ
+        0x00000000, // OPEN4_SHARE_DENY_NONE
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![NfsOperation::OpenDowngrade(OpenDowngradeOperation {
+            open_stateid: [0, 0, 0, 3, 0x5f, 0x3a, 0x12, 0, 0, 0, 0, 1, 0, 0, 0, 7],
+            seqid: 5,
+            share_access: OPEN4_SHARE_ACCESS_READ,
+            share_deny: OPEN4_SHARE_DENY_NONE,
+        })]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn cb_recall_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000007, // callback_ident
+        0x00000001, // 1 operation
+        0x00000004, // OP_CB_RECALL
+        0x00000001, 0x5f3a1200, 0x00000001, 0x00000009, // stateid
+        0x00000000, // truncate
+        0x00000008, 0x01000000, 0x0000002a, // fh
+    ]);
+
+    let request = CbCompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(request.callback_ident, 7);
+    assert_eq!(
+        request.operations,
+        vec![CbOperation::Recall(CbRecallOperation {
+            stateid: [0, 0, 0, 1, 0x5f, 0x3a, 0x12, 0, 0, 0, 0, 1, 0, 0, 0, 9],
+            truncate: false,
+            fh: NfsFileHandle {
+                data: vec![1, 0, 0, 0, 0, 0, 0, 0x2a],
+            },
+        })]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn symlink_link_readlink_args() {
+    let bytes = words(&[
+        0x00000000, // tag ""
+        0x00000000, // minorversion 0
+        0x00000004, // 4 operations
+        0x00000006, // OP_CREATE
+        0x00000005, // NF4LNK
+        0x00000004, 0x2e2e2f74, // linkdata "../t"
+        0x00000001, 0x6c000000, // objname "l" + padding
+        0x00000000, // empty bitmap4
+        0x00000000, // no attribute values
+        0x00000020, // OP_SAVEFH
+        0x0000000b, // OP_LINK
+        0x00000002, 0x6c320000, // newname "l2" + padding
+        0x0000001b, // OP_READLINK
+    ]);
+
+    let request = CompoundRequest::decode(&bytes).unwrap();
+    assert_eq!(
+        request.operations,
+        vec![
+            NfsOperation::Create(CreateOperation {
+                object_type: NF4LNK,
+                link_data: Some("../t".to_string()),
+                spec_data: None,
+                object_name: "l".to_string(),
+                attributes: Fattr4 {
+                    attrmask: vec![],
+                    attr_vals: vec![],
+                },
+            }),
+            NfsOperation::SaveFh(SaveFhOperation),
+            NfsOperation::Link(LinkOperation {
+                new_name: "l2".to_string(),
+            }),
+            NfsOperation::ReadLink(ReadLinkOperation),
+        ]
+    );
+    assert_eq!(request.encode(), bytes);
+}
+
+#[test]
+fn link_readlink_res() {
+    let bytes = words(&[
+        0x00000000, // NFS4_OK
+        0x00000000, // tag ""
+        0x00000002, // 2 results
+        0x0000000b, 0x00000000, // OP_LINK, NFS4_OK
+        0x00000001, // cinfo.atomic
+        0x00000000, 0x00000010, // cinfo.before
+        0x00000000, 0x00000011, // cinfo.after
+        0x0000001b, 0x00000000, // OP_READLINK, NFS4_OK
+        0x00000004, 0x2e2e2f74, // link "../t"
+    ]);
+
+    let response = CompoundResponse::decode(&bytes).unwrap();
+    assert_eq!(
+        response.results,
+        vec![
+            OperationResult::ok(
+                OP_LINK,
+                Some(OperationData::Link(ChangeInfo {
+                    atomic: true,
+                    before: 0x10,
+                    after: 0x11,
+                })),
+            ),
+            OperationResult::ok(OP_READLINK, Some(OperationData::ReadLink("../t".to_string()))),
+        ]
+    );
+    assert_eq!(response.encode(), bytes);
+}
+
+#[test]
+fn auth_sys_credential() {
+    use nfs4::auth::Credentials;
+    use nfs4::rpc::{OpaqueAuth, AUTH_SHORT, AUTH_SYS};
+
+    let body = words(&[
+        0x5f5e0100, // stamp
+        0x00000006, // machinename length 6
+        0x636c6965, 0x6e740000, // "client" + padding
+        0x000003e8, // uid 1000
+        0x00000064, // gid 100
+        0x00000002, // 2 gids
+        0x0000000a,
+        0x000001f4,
+    ]);
+
+    let cred = OpaqueAuth {
+        flavor: AUTH_SYS,
+        body: body.clone(),
+    };
+    assert_eq!(
+        Credentials::from_auth(&cred),
+        Some(Credentials {
+            uid: 1000,
+            gid: 100,
+            gids: vec![10, 500],
+        })
+    );
+
+    // More than the 16 supplementary groups RFC 5531 allows.
+    let mut too_many = body[..24].to_vec();
+    too_many.extend(words(&[17]));
+    too_many.extend(words(&[0; 17]));
+    let cred = OpaqueAuth {
+        flavor: AUTH_SYS,
+        body: too_many,
+    };
+    assert_eq!(Credentials::from_auth(&cred), None);
+
+    let cred = OpaqueAuth {
+        flavor: AUTH_SHORT,
+        body: Vec::new(),
+    };
+    assert_eq!(Credentials::from_auth(&cred), None);
+}